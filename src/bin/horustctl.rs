@@ -0,0 +1,265 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use structopt::clap::Shell;
+use structopt::StructOpt;
+
+/// Appended to the bash completion generated by clap: the names of the services are asked to
+/// the running horust (`status`), using the same socket path.
+const BASH_SERVICES_COMPLETION: &str = r#"
+_horustctl_services() {
+    local i word subcommand="" socket_args=() positionals=0
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        word=${COMP_WORDS[i]}
+        case $word in
+            --socket-path)
+                socket_args=(--socket-path "${COMP_WORDS[i + 1]}")
+                ((i++)) ;;
+            -l|--selector|--target) ((i++)) ;;
+            -*) ;;
+            *)
+                if [[ -z $subcommand ]]; then
+                    subcommand=$word
+                else
+                    ((positionals++))
+                fi ;;
+        esac
+    done
+    _horustctl "$@"
+    case $subcommand in
+        status|start|stop|mask|unmask|restart|scale|history)
+            if [[ $positionals -eq 0 && ${COMP_WORDS[COMP_CWORD]} != -* ]]; then
+                case ${COMP_WORDS[COMP_CWORD - 1]} in
+                    -l|--selector) return ;;
+                esac
+                local services
+                services=$(horustctl "${socket_args[@]}" status 2>/dev/null | cut -d' ' -f1)
+                COMPREPLY=($(compgen -W "$services" -- "${COMP_WORDS[COMP_CWORD]}"))
+            fi ;;
+    esac
+}
+complete -F _horustctl_services -o bashdefault -o default horustctl
+"#;
+
+#[derive(StructOpt, Debug)]
+#[structopt(author)]
+/// Horustctl is used for querying a running horust instance, through its control socket.
+struct Opts {
+    #[structopt(long, default_value = "/var/run/horust/control.sock")]
+    /// Path to horust's control socket (check horust's `--control-socket`).
+    socket_path: PathBuf,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Checks that horust is alive and not stalled. Exits unsuccessfully otherwise.
+    Ping,
+    /// Prints the status of the services, including the result of their last healthcheck.
+    Status {
+        /// Only print this service.
+        #[structopt(conflicts_with = "selector")]
+        service: Option<String>,
+        /// Only print the services having these labels, e.g. `tier=backend,team=payments`.
+        #[structopt(short = "l", long)]
+        selector: Option<String>,
+    },
+    /// Prints the healthchecks metrics, using the prometheus text format.
+    Metrics,
+    /// Dumps the internal state of the runtime and of every service, for troubleshooting.
+    Debug,
+    /// Follows the events of horust, one per line: first the current state of the services,
+    /// then every event as it happens. Until horust exits.
+    Events {
+        /// Print json records instead (check `horust events-schema`), starting with the version
+        /// of their schema.
+        #[structopt(long)]
+        json: bool,
+        /// Use this version of the events schema, failing if horust doesn't support it. The
+        /// latest one by default.
+        #[structopt(long, requires = "json")]
+        schema_version: Option<u32>,
+    },
+    /// Starts again a service stopped because it was idle (check `stop-after-idle`).
+    Start { service: String },
+    /// Prints the last runs of the service, the oldest first: when they've started, for how long
+    /// they've run, and how they've ended (exit code, or the signal which has killed them).
+    History { service: String },
+    /// Stops the service (or all of its instances if it's replicated), without starting it again.
+    Stop {
+        #[structopt(required_unless = "selector", conflicts_with = "selector")]
+        service: Option<String>,
+        /// Stop all the services having these labels, e.g. `tier=backend,team=payments`.
+        #[structopt(short = "l", long)]
+        selector: Option<String>,
+    },
+    /// Masks the service (or all of its instances if it's replicated): it's stopped, and it's not
+    /// started again until it's unmasked, even if horust is restarted (check `state-directory`).
+    Mask {
+        #[structopt(required_unless = "selector", conflicts_with = "selector")]
+        service: Option<String>,
+        /// Mask all the services having these labels, e.g. `tier=backend,team=payments`.
+        #[structopt(short = "l", long)]
+        selector: Option<String>,
+    },
+    /// Unmasks the service, starting it again. Disabled services (`enabled = false`) are started
+    /// as well, until horust is restarted.
+    Unmask {
+        #[structopt(required_unless = "selector", conflicts_with = "selector")]
+        service: Option<String>,
+        /// Unmask all the services having these labels, e.g. `tier=backend,team=payments`.
+        #[structopt(short = "l", long)]
+        selector: Option<String>,
+    },
+    /// Restarts the service, or all of its instances if it's replicated.
+    Restart {
+        #[structopt(required_unless = "selector", conflicts_with = "selector")]
+        service: Option<String>,
+        /// Restart all the services having these labels, e.g. `tier=backend,team=payments`.
+        #[structopt(short = "l", long, conflicts_with = "blue-green")]
+        selector: Option<String>,
+        /// Restart the instances one at a time, waiting for each one to be running again.
+        /// Stops at the first instance which fails.
+        #[structopt(long)]
+        rolling: bool,
+        /// Start the new instance alongside the old one, which is stopped once the new one is
+        /// running. Only for services using socket activation (check `listen`).
+        #[structopt(long, conflicts_with = "rolling")]
+        blue_green: bool,
+    },
+    /// Stops all the services, then horust exits.
+    Shutdown,
+    /// Shows what reloading the services directories would change: the services which would be
+    /// added, removed, restarted (with their changed settings) or kept. Only `--dry-run` is
+    /// supported: nothing is applied.
+    Reload {
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Changes the number of instances of a replicated service (check `replicas`).
+    Scale { service: String, replicas: u32 },
+    /// Changes horust's own log level at runtime: off|error|warn|info|debug|trace, or `default`
+    /// for going back to the HORUST_LOG filter.
+    LogLevel {
+        level: String,
+        /// Only change the level of: all|runtime|healthcheck|service:<name>
+        #[structopt(long, default_value = "all")]
+        target: String,
+    },
+    /// Prints the completion script of horustctl for the shell: bash|zsh|fish|powershell|elvish.
+    /// Using bash, the names of the services are completed as well, asking them to horust.
+    Completions {
+        #[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+        shell: Shell,
+    },
+}
+
+impl Command {
+    fn as_request(&self) -> String {
+        match self {
+            Command::Ping => "ping".to_string(),
+            Command::Status {
+                service: None,
+                selector: None,
+            } => "status".to_string(),
+            Command::Status { service, selector } => {
+                format!("status {}", target(service, selector))
+            }
+            Command::Metrics => "metrics".to_string(),
+            Command::Debug => "debug".to_string(),
+            Command::Events { json: false, .. } => "events".to_string(),
+            Command::Events {
+                json: true,
+                schema_version: None,
+            } => "events --json".to_string(),
+            Command::Events {
+                json: true,
+                schema_version: Some(version),
+            } => format!("events --json {}", version),
+            Command::Start { service } => format!("start {}", service),
+            Command::History { service } => format!("history {}", service),
+            Command::Stop { service, selector } => format!("stop {}", target(service, selector)),
+            Command::Mask { service, selector } => format!("mask {}", target(service, selector)),
+            Command::Unmask { service, selector } => {
+                format!("unmask {}", target(service, selector))
+            }
+            Command::Restart {
+                service,
+                selector,
+                rolling: true,
+                ..
+            } => format!("rolling-restart {}", target(service, selector)),
+            Command::Restart {
+                service,
+                selector,
+                blue_green: true,
+                ..
+            } => format!("blue-green-restart {}", target(service, selector)),
+            Command::Restart {
+                service, selector, ..
+            } => format!("restart {}", target(service, selector)),
+            Command::Shutdown => "shutdown".to_string(),
+            Command::Reload { dry_run: true } => "reload --dry-run".to_string(),
+            Command::Reload { dry_run: false } => "reload".to_string(),
+            Command::Scale { service, replicas } => format!("scale {} {}", service, replicas),
+            Command::LogLevel { level, target } => format!("log-level {} {}", level, target),
+            Command::Completions { .. } => unreachable!("Handled without connecting to horust"),
+        }
+    }
+}
+
+/// Either the service, or `-l <selector>`.
+fn target(service: &Option<String>, selector: &Option<String>) -> String {
+    match (service, selector) {
+        (_, Some(selector)) => format!("-l {}", selector),
+        (Some(service), None) => service.clone(),
+        (None, None) => String::new(),
+    }
+}
+
+/// The status line of the response, and the reader of the rest: the body is streamed (`events`).
+fn send_request(
+    socket_path: &PathBuf,
+    request: &str,
+) -> io::Result<(String, BufReader<UnixStream>)> {
+    let mut stream = UnixStream::connect(socket_path)?;
+    stream.write_all(format!("{}\n", request).as_bytes())?;
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    reader.read_line(&mut status)?;
+    Ok((status, reader))
+}
+
+fn main() {
+    let opts = Opts::from_args();
+    if let Command::Completions { shell } = opts.command {
+        Opts::clap().gen_completions_to("horustctl", shell, &mut io::stdout());
+        if let Shell::Bash = shell {
+            print!("{}", BASH_SERVICES_COMPLETION);
+        }
+        return;
+    }
+    let (status, mut body) =
+        match send_request(&opts.socket_path, opts.command.as_request().as_str()) {
+            Ok(response) => response,
+            Err(error) => {
+                eprintln!(
+                    "Cannot connect to horust using {}: {}",
+                    opts.socket_path.display(),
+                    error
+                );
+                std::process::exit(1);
+            }
+        };
+    let status = status.trim_end();
+    if let Err(error) = io::copy(&mut body, &mut io::stdout()) {
+        eprintln!("Error reading the response of horust: {}", error);
+        std::process::exit(1);
+    }
+    if status != "OK" {
+        eprintln!("{}", status.trim_start_matches("ERROR ").trim());
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,267 @@
+//! Progress of the boot on the console (`--boot-progress`), like the boot output of OpenRC or
+//! systemd: a line for every service reaching a state, and a summary once all of them have
+//! settled. On a terminal the states are colored, and a spinner lists the services still being
+//! waited for.
+use crate::horust::bus::Subscriber;
+use crate::horust::formats::{Event, ServiceName, ServiceStatus};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SPINNER: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+/// Clears the line of the spinner.
+const CLEAR_LINE: &str = "\r\x1b[K";
+
+pub(crate) fn spawn(subscriber: Subscriber, services: Vec<ServiceName>) {
+    let is_tty = unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+    thread::Builder::new()
+        .name("boot-progress".into())
+        .spawn(move || run(subscriber, services, is_tty))
+        .expect("Failed spawning the boot progress thread");
+}
+
+fn run(subscriber: Subscriber, services: Vec<ServiceName>, is_tty: bool) {
+    let mut progress = BootProgress::new(services, is_tty);
+    let mut stdout = io::stdout();
+    let mut frame = 0;
+    loop {
+        let mut output = String::new();
+        for ev in subscriber.try_get_events() {
+            match ev {
+                Event::StatusChanged(s_name, status) => {
+                    output.push_str(&progress.status_changed(s_name, status));
+                }
+                Event::ShuttingDownInitiated(_) => {
+                    output.push_str(&progress.finish(true));
+                    break;
+                }
+                _ => (),
+            }
+        }
+        if progress.is_settled() && !progress.is_over {
+            output.push_str(&progress.finish(false));
+        }
+        if is_tty {
+            output = format!("{}{}", CLEAR_LINE, output);
+            if !progress.is_over {
+                output.push_str(&progress.spinner(frame));
+                frame += 1;
+            }
+        }
+        let _ = stdout.write_all(output.as_bytes());
+        let _ = stdout.flush();
+        // Leaving the bus: it was subscribed late, so it's not missed.
+        if progress.is_over {
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[derive(Debug)]
+struct BootProgress {
+    statuses: BTreeMap<ServiceName, ServiceStatus>,
+    started: Instant,
+    is_tty: bool,
+    is_over: bool,
+}
+
+impl BootProgress {
+    fn new(services: Vec<ServiceName>, is_tty: bool) -> Self {
+        Self {
+            statuses: services
+                .into_iter()
+                .map(|name| (name, ServiceStatus::Initial))
+                .collect(),
+            started: Instant::now(),
+            is_tty,
+            is_over: false,
+        }
+    }
+
+    /// The line to print for the new status, if any.
+    fn status_changed(&mut self, s_name: ServiceName, status: ServiceStatus) -> String {
+        if self.is_over || self.statuses.get(&s_name) == Some(&status) {
+            return String::new();
+        }
+        let line = match &status {
+            ServiceStatus::Starting => format!("         Starting {}...\n", s_name),
+            ServiceStatus::Running => format!("{} {} is running\n", self.label(Label::Ok), s_name),
+            ServiceStatus::Finished => {
+                format!("{} {} has finished\n", self.label(Label::Ok), s_name)
+            }
+            ServiceStatus::Failed => {
+                format!("{} {} has failed\n", self.label(Label::Failed), s_name)
+            }
+            ServiceStatus::FinishedFailed | ServiceStatus::PermanentlyFailed => format!(
+                "{} {} has failed, giving up\n",
+                self.label(Label::Failed),
+                s_name
+            ),
+            ServiceStatus::Disabled => {
+                format!("{} {} is disabled\n", self.label(Label::Skipped), s_name)
+            }
+            _ => String::new(),
+        };
+        self.statuses.insert(s_name, status);
+        line
+    }
+
+    /// Every service has either started or given up.
+    fn is_settled(&self) -> bool {
+        self.statuses.values().all(|status| !is_pending(status))
+    }
+
+    /// The summary of the boot, printed once.
+    fn finish(&mut self, is_interrupted: bool) -> String {
+        if self.is_over {
+            return String::new();
+        }
+        self.is_over = true;
+        let count = |matches: &dyn Fn(&ServiceStatus) -> bool| {
+            self.statuses
+                .values()
+                .filter(|status| matches(status))
+                .count()
+        };
+        let running = count(&|status| *status == ServiceStatus::Running);
+        let finished = count(&|status| *status == ServiceStatus::Finished);
+        let failed = count(&|status| {
+            matches!(
+                status,
+                ServiceStatus::FinishedFailed | ServiceStatus::PermanentlyFailed
+            )
+        });
+        let skipped = count(&|status| *status == ServiceStatus::Disabled);
+        let label = if failed > 0 || is_interrupted {
+            self.label(Label::Failed)
+        } else {
+            self.label(Label::Ok)
+        };
+        format!(
+            "{} Boot {} after {:.1}s: {} running, {} finished, {} failed, {} skipped\n",
+            label,
+            if is_interrupted {
+                "interrupted by the shutdown"
+            } else {
+                "completed"
+            },
+            self.started.elapsed().as_secs_f64(),
+            running,
+            finished,
+            failed,
+            skipped
+        )
+    }
+
+    /// The line of the spinner, without a newline: it's cleared before printing anything else.
+    fn spinner(&self, frame: usize) -> String {
+        let waiting: Vec<&str> = self
+            .statuses
+            .iter()
+            .filter(|(_, status)| is_pending(status))
+            .map(|(s_name, _)| s_name.as_str())
+            .collect();
+        format!(
+            "{} Booting ({:.1}s), waiting for: {}",
+            SPINNER[frame % SPINNER.len()],
+            self.started.elapsed().as_secs_f64(),
+            waiting.join(", ")
+        )
+    }
+
+    fn label(&self, label: Label) -> String {
+        let (text, color) = match label {
+            Label::Ok => ("  OK  ", GREEN),
+            Label::Failed => ("FAILED", RED),
+            Label::Skipped => (" SKIP ", YELLOW),
+        };
+        if self.is_tty {
+            format!("[{}{}{}]", color, text, RESET)
+        } else {
+            format!("[{}]", text)
+        }
+    }
+}
+
+/// Not started yet, failed but might be restarted, or about to be finished.
+fn is_pending(status: &ServiceStatus) -> bool {
+    matches!(
+        status,
+        ServiceStatus::Initial
+            | ServiceStatus::Starting
+            | ServiceStatus::Started
+            | ServiceStatus::Failed
+            | ServiceStatus::InKilling
+            | ServiceStatus::Success
+    )
+}
+
+enum Label {
+    Ok,
+    Failed,
+    Skipped,
+}
+
+#[cfg(test)]
+mod test {
+    use super::BootProgress;
+    use crate::horust::formats::ServiceStatus;
+
+    #[test]
+    fn test_boot_progress() {
+        let mut progress = BootProgress::new(
+            vec!["db.toml".into(), "web.toml".into(), "cron.toml".into()],
+            false,
+        );
+        assert_eq!(
+            progress.status_changed("db.toml".into(), ServiceStatus::Starting),
+            "         Starting db.toml...\n"
+        );
+        assert_eq!(
+            progress.status_changed("db.toml".into(), ServiceStatus::Started),
+            ""
+        );
+        assert_eq!(
+            progress.status_changed("db.toml".into(), ServiceStatus::Running),
+            "[  OK  ] db.toml is running\n"
+        );
+        assert_eq!(
+            progress.status_changed("cron.toml".into(), ServiceStatus::Disabled),
+            "[ SKIP ] cron.toml is disabled\n"
+        );
+        assert_eq!(
+            progress.status_changed("web.toml".into(), ServiceStatus::Failed),
+            "[FAILED] web.toml has failed\n"
+        );
+        // It might still be restarted:
+        assert!(!progress.is_settled());
+        assert!(progress.spinner(0).ends_with("waiting for: web.toml"));
+        assert_eq!(
+            progress.status_changed("web.toml".into(), ServiceStatus::FinishedFailed),
+            "[FAILED] web.toml has failed, giving up\n"
+        );
+        assert!(progress.is_settled());
+        let summary = progress.finish(false);
+        assert!(summary.starts_with("[FAILED] Boot completed after "));
+        assert!(summary.ends_with(": 1 running, 0 finished, 1 failed, 1 skipped\n"));
+        // Printed once, and nothing afterwards:
+        assert_eq!(progress.finish(true), "");
+        assert_eq!(
+            progress.status_changed("db.toml".into(), ServiceStatus::InKilling),
+            ""
+        );
+
+        let mut progress = BootProgress::new(vec!["db.toml".into()], true);
+        assert_eq!(
+            progress.status_changed("db.toml".into(), ServiceStatus::Running),
+            "[\x1b[32m  OK  \x1b[0m] db.toml is running\n"
+        );
+        assert!(progress.spinner(1).starts_with("⠙ Booting"));
+    }
+}
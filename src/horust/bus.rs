@@ -1,35 +1,157 @@
-use crossbeam::channel::{unbounded, Receiver, Sender};
-use std::fmt::Debug;
+//! The event bus: every component of horust (runtime, healthchecks, control socket...) talks to
+//! the others only by publishing and consuming `Event`s.
+//!
+//! Ordering guarantee: the events go through a single queue, dispatched by a single thread. So
+//! every subscriber sees the same events in the same (total) order, and the events published
+//! through a handle are delivered in the order they were published. Nothing is guaranteed between
+//! two publishers, besides being consistent across the subscribers.
+use crate::horust::formats::{Event, ServiceName, ShutdownReason};
+use crossbeam::channel::{self, select, unbounded, Receiver, Sender};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
 
-/// A simple bus implementation: distributes the messages among the queues
-/// There is one single input pipe (`public_sender` ; `receiver`). The sender side is shared among
-/// all the publishers. The bus reads from the receiver, and publishes to all the `senders`.
+/// There is one single input pipe (`shared_sender` ; `receiver`). The sender side is shared among
+/// all the publishers. The bus reads from the receiver, and publishes to all the subscribers.
+/// Components can subscribe once the bus is running, through a `Registrar`.
 #[derive(Debug)]
-pub struct Bus<T>
-where
-    T: Clone + Debug,
-{
+pub struct EventBus {
     /// Bus input - sender side
-    shared_sender: Sender<Message<T>>,
+    shared_sender: Sender<Message>,
     /// Bus input - receiver side
-    receiver: Receiver<Message<T>>,
-    /// Bus output - all the senders
-    senders: Vec<(u64, Sender<Message<T>>)>,
+    receiver: Receiver<Message>,
+    /// Subscriptions made while the bus is running - sender side
+    registration_sender: Sender<Registration>,
+    /// Subscriptions made while the bus is running - receiver side
+    registrations: Receiver<Registration>,
+    /// Bus output
+    dispatcher: Dispatcher,
+}
+
+/// Publishes the messages to all the subscribers.
+#[derive(Debug)]
+struct Dispatcher {
+    subscriptions: Vec<Subscription>,
     /// Forward the message to the sender as well.
     forward_to_sender: bool,
+    supervision: Option<Supervision>,
+    /// Once shutting down, the subscribers are expected to leave.
+    is_shutting_down: bool,
+    /// What a late subscriber needs to know about what happened before it joined.
+    state: Snapshot,
+}
+
+/// A subscriber, as seen by the dispatcher. Named after the component using it.
+#[derive(Debug)]
+struct Subscription {
+    id: u64,
+    name: String,
+    /// Horust cannot work without it (e.g. the runtime).
+    is_critical: bool,
+    /// Subscribed through a registrar: it's expected to leave at any time.
+    is_late: bool,
+    sender: Sender<Message>,
+    /// Since when its backlog is above the threshold.
+    stalled_since: Option<Instant>,
+    /// The backlog at the last warning: warned again once it has doubled.
+    warned_backlog: usize,
+    has_escalated: bool,
+}
+
+/// A subscription requested through a `Registrar`.
+#[derive(Debug)]
+struct Registration {
+    name: String,
+    sender: Sender<Message>,
+}
+
+/// Detection of the subscribers which have stopped consuming their events: either they're gone
+/// (e.g. their thread has panicked), or their backlog keeps growing.
+#[derive(Debug)]
+pub(crate) struct Supervision {
+    /// If a critical subscriber is gone or stalled, horust shuts down
+    /// (`ShutdownReason::ComponentStalled`). Otherwise it's only logged.
+    pub(crate) escalate: bool,
+    /// A subscriber with more events than this waiting to be consumed is stalled.
+    pub(crate) stall_threshold: usize,
+    /// A critical subscriber stalled for this long is escalated.
+    pub(crate) stall_timeout: Duration,
+}
+
+impl Supervision {
+    pub(crate) fn new(escalate: bool) -> Self {
+        Self {
+            escalate,
+            stall_threshold: 1000,
+            stall_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The latest state of every service, as told by the events dispatched so far.
+#[derive(Debug, Default)]
+struct Snapshot {
+    /// The services added at runtime (`Event::ServiceCreated`).
+    created: Vec<Event>,
+    /// The last `PidChanged` or `PidAdopted` of the services which haven't exited since.
+    pids: BTreeMap<ServiceName, Event>,
+    /// The last `StatusChanged` of every service.
+    statuses: BTreeMap<ServiceName, Event>,
+    shutting_down: Option<Event>,
+}
+
+impl Snapshot {
+    fn update(&mut self, ev: &Event) {
+        match ev {
+            Event::ServiceCreated(_) => self.created.push(ev.clone()),
+            Event::PidChanged(s_name, _) | Event::PidAdopted(s_name, _) => {
+                self.pids.insert(s_name.clone(), ev.clone());
+            }
+            Event::ServiceExited(s_name, _) => {
+                self.pids.remove(s_name);
+            }
+            Event::StatusChanged(s_name, _) => {
+                self.statuses.insert(s_name.clone(), ev.clone());
+            }
+            Event::ShuttingDownInitiated(_) if self.shutting_down.is_none() => {
+                self.shutting_down = Some(ev.clone());
+            }
+            _ => (),
+        }
+    }
+
+    /// The created services first, then the pid and the status of every service (sorted by
+    /// name), and at last whether horust is shutting down.
+    fn events(&self) -> Vec<Event> {
+        let mut events = self.created.clone();
+        let mut services: Vec<&ServiceName> =
+            self.pids.keys().chain(self.statuses.keys()).collect();
+        services.sort();
+        services.dedup();
+        for s_name in services {
+            events.extend(self.pids.get(s_name).cloned());
+            events.extend(self.statuses.get(s_name).cloned());
+        }
+        events.extend(self.shutting_down.clone());
+        events
+    }
 }
 
-impl<T> Bus<T>
-where
-    T: Clone + Debug,
-{
+impl EventBus {
     pub fn new() -> Self {
         let (public_sender, receiver) = unbounded();
-        Bus {
+        let (registration_sender, registrations) = unbounded();
+        EventBus {
             shared_sender: public_sender,
             receiver,
-            senders: Default::default(),
-            forward_to_sender: true,
+            registration_sender,
+            registrations,
+            dispatcher: Dispatcher {
+                subscriptions: Default::default(),
+                forward_to_sender: true,
+                supervision: None,
+                is_shutting_down: false,
+                state: Default::default(),
+            },
         }
     }
 
@@ -38,91 +160,237 @@ where
         self.dispatch();
     }
 
+    pub(crate) fn supervise(&mut self, supervision: Supervision) {
+        self.dispatcher.supervision = Some(supervision);
+    }
+
     /// Add another connection to the bus
-    pub fn join_bus(&mut self) -> BusConnector<T> {
+    #[cfg(test)]
+    pub fn join_bus(&mut self) -> BusConnector {
+        self.join_bus_as("test", false)
+    }
+
+    /// Add another connection to the bus, for the component `name`. It receives every event
+    /// published once the bus is running.
+    pub(crate) fn join_bus_as(&mut self, name: &str, is_critical: bool) -> BusConnector {
         let (sender, receiver) = unbounded();
-        self.senders.push((self.senders.len() as u64, sender));
-        BusConnector::new(
-            self.shared_sender.clone(),
-            receiver,
-            self.senders.len() as u64,
-        )
+        let subscriptions = &mut self.dispatcher.subscriptions;
+        let id = subscriptions.len() as u64;
+        subscriptions.push(Subscription::new(id, name, is_critical, sender));
+        BusConnector {
+            publisher: Publisher {
+                sender: self.shared_sender.clone(),
+                id,
+            },
+            subscriber: Subscriber { receiver },
+        }
+    }
+
+    /// For subscribing once the bus is running. The registrar doesn't keep the bus alive.
+    pub(crate) fn registrar(&self) -> Registrar {
+        Registrar {
+            sender: self.registration_sender.clone(),
+        }
     }
 
     /// Dispatching loop
     /// As soon as we don't have any senders it will exit
-    fn dispatch(mut self) {
+    fn dispatch(self) {
         drop(self.shared_sender);
-        if self.forward_to_sender {
-            for ev in self.receiver {
-                self.senders
-                    .retain(|(_idx, sender)| sender.send(ev.clone()).is_ok());
+        drop(self.registration_sender);
+        let mut dispatcher = self.dispatcher;
+        let mut registrations = self.registrations;
+        loop {
+            select! {
+                recv(self.receiver) -> message => match message {
+                    Ok(message) => {
+                        // The subscriptions made before the message was published come first.
+                        registrations
+                            .try_iter()
+                            .for_each(|registration| dispatcher.register(registration));
+                        dispatcher.publish(message)
+                    }
+                    Err(_) => break,
+                },
+                recv(registrations) -> registration => match registration {
+                    Ok(registration) => dispatcher.register(registration),
+                    // No registrar is left.
+                    Err(_) => registrations = channel::never(),
+                },
+            }
+        }
+    }
+}
+
+impl Subscription {
+    fn new(id: u64, name: &str, is_critical: bool, sender: Sender<Message>) -> Self {
+        Self {
+            id,
+            name: name.to_string(),
+            is_critical,
+            is_late: false,
+            sender,
+            stalled_since: None,
+            warned_backlog: 0,
+            has_escalated: false,
+        }
+    }
+}
+
+impl Dispatcher {
+    /// The late subscriber gets the snapshot first: the events which are dispatched afterwards
+    /// are the ones it would have received after them.
+    fn register(&mut self, registration: Registration) {
+        let Registration { name, sender } = registration;
+        // Late subscribers don't publish: no message is coming from this id.
+        let id = u64::MAX - 1 - self.subscriptions.len() as u64;
+        let snapshot = self.state.events();
+        debug!(
+            "The {} has subscribed to the bus ({} events in the snapshot)",
+            name,
+            snapshot.len()
+        );
+        for ev in snapshot {
+            if sender.send(Message::new(id, ev)).is_err() {
+                return;
             }
-        } else {
-            for ev in self.receiver {
-                self.senders.retain(|(idx, sender)| {
-                    if *idx != ev.sender_id {
-                        sender.send(ev.clone()).is_ok()
-                    } else {
-                        true
+        }
+        self.subscriptions.push(Subscription {
+            is_late: true,
+            ..Subscription::new(id, &name, false, sender)
+        });
+    }
+
+    fn publish(&mut self, message: Message) {
+        self.state.update(&message.payload);
+        let supervision = match &self.supervision {
+            Some(supervision) => supervision,
+            None => {
+                let forward_to_sender = self.forward_to_sender;
+                self.subscriptions.retain(|subscription| {
+                    if !forward_to_sender && subscription.id == message.sender_id {
+                        return true;
                     }
+                    subscription.sender.send(message.clone()).is_ok()
                 });
+                return;
+            }
+        };
+        self.is_shutting_down |= matches!(message.payload, Event::ShuttingDownInitiated(_));
+        let is_shutting_down = self.is_shutting_down;
+        let forward_to_sender = self.forward_to_sender;
+        let mut escalated: Vec<String> = vec![];
+        self.subscriptions.retain_mut(|subscription| {
+            if !forward_to_sender && subscription.id == message.sender_id {
+                return true;
+            }
+            if subscription.sender.send(message.clone()).is_err() {
+                if subscription.is_late {
+                    debug!("The {} has left the bus", subscription.name);
+                } else if !is_shutting_down {
+                    error!(
+                        "The {} has stopped consuming the events of the bus, they're dropped",
+                        subscription.name
+                    );
+                    if subscription.is_critical {
+                        escalated.push(subscription.name.clone());
+                    }
+                }
+                return false;
+            }
+            if !is_shutting_down && check_backlog(subscription, supervision) {
+                escalated.push(subscription.name.clone());
             }
+            true
+        });
+        if let (true, Some(name)) = (supervision.escalate, escalated.first()) {
+            error!("{} is a core component: shutting down", name);
+            let payload =
+                Event::ShuttingDownInitiated(ShutdownReason::ComponentStalled(name.clone()));
+            self.publish(Message::new(u64::MAX, payload));
+        }
+    }
+}
+
+/// Warns about the backlog of the subscriber while it's growing. True if the subscriber is
+/// critical and it's been stalled for too long (only once).
+fn check_backlog(subscription: &mut Subscription, supervision: &Supervision) -> bool {
+    let backlog = subscription.sender.len();
+    if backlog < supervision.stall_threshold {
+        if subscription.stalled_since.take().is_some() {
+            info!("The {} is consuming its events again", subscription.name);
+            subscription.warned_backlog = 0;
         }
+        return false;
+    }
+    let stalled_since = *subscription.stalled_since.get_or_insert_with(Instant::now);
+    if backlog >= subscription.warned_backlog * 2 {
+        warn!(
+            "The {} is not consuming its events: {} are waiting, since {:?}",
+            subscription.name,
+            backlog,
+            stalled_since.elapsed()
+        );
+        subscription.warned_backlog = backlog;
     }
+    if subscription.is_critical
+        && !subscription.has_escalated
+        && stalled_since.elapsed() >= supervision.stall_timeout
+    {
+        subscription.has_escalated = true;
+        return true;
+    }
+    false
 }
 
-/// The payload with wrapped with some metadata
+/// The event wrapped with some metadata
 #[derive(Clone, Debug)]
-struct Message<T>
-where
-    T: Clone + Debug,
-{
+struct Message {
     sender_id: u64,
-    payload: T,
+    payload: Event,
 }
-impl<T> Message<T>
-where
-    T: Clone + Debug,
-{
-    pub fn new(sender_id: u64, payload: T) -> Self {
+impl Message {
+    fn new(sender_id: u64, payload: Event) -> Self {
         Self { payload, sender_id }
     }
 
     /// Consume the messages into the payload
-    pub fn into_payload(self) -> T {
+    fn into_payload(self) -> Event {
         self.payload
     }
 }
 
-/// A connector to the shared bus
+/// The sending side of a connection to the bus. It can be cloned and handed over to other threads
+/// (e.g. the spawners), which only publish.
 #[derive(Debug, Clone)]
-pub struct BusConnector<T>
-where
-    T: Clone + Debug,
-{
-    sender: Sender<Message<T>>,
-    receiver: Receiver<Message<T>>,
+pub struct Publisher {
+    sender: Sender<Message>,
     id: u64,
 }
-impl<T> BusConnector<T>
-where
-    T: Clone + Debug,
-{
-    fn new(sender: Sender<Message<T>>, receiver: Receiver<Message<T>>, id: u64) -> Self {
-        Self {
-            sender,
-            receiver,
-            id,
-        }
+
+impl Publisher {
+    /// Events waiting to be dispatched by the bus.
+    pub(crate) fn bus_queue_len(&self) -> usize {
+        self.sender.len()
     }
-    fn wrap(&self, payload: T) -> Message<T> {
-        Message::new(self.id, payload)
+
+    pub(crate) fn send_event(&self, ev: Event) {
+        self.sender
+            .send(Message::new(self.id, ev))
+            .expect("Failed sending update event!");
     }
+}
+
+/// The receiving side of a connection to the bus. Once dropped, the bus stops delivering to it.
+#[derive(Debug)]
+pub struct Subscriber {
+    receiver: Receiver<Message>,
+}
 
+impl Subscriber {
     /// Blocking
     #[cfg(test)]
-    pub fn get_n_events_blocking(&self, quantity: usize) -> Vec<T> {
+    pub fn get_n_events_blocking(&self, quantity: usize) -> Vec<Event> {
         self.receiver
             .iter()
             .map(|m| m.into_payload())
@@ -130,39 +398,100 @@ where
             .collect()
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+    /// Blocking, until the bus is over.
+    pub fn iter(&self) -> impl Iterator<Item = Event> + '_ {
         self.receiver.iter().map(|message| message.into_payload())
     }
 
     /// Non blocking
-    /// Todo: rename to be generic.
-    pub fn try_get_events(&self) -> Vec<T> {
+    pub fn try_get_events(&self) -> Vec<Event> {
         self.receiver.try_iter().map(|m| m.into_payload()).collect()
     }
 
-    pub(crate) fn send_event(&self, ev: T) {
-        self.sender
-            .send(self.wrap(ev))
-            .expect("Failed sending update event!");
+    /// Events dispatched to this subscriber, waiting to be consumed.
+    pub(crate) fn queue_len(&self) -> usize {
+        self.receiver.len()
+    }
+}
+
+/// A connection to the bus, for the components joining it before it runs: both a publisher and
+/// a subscriber.
+#[derive(Debug)]
+pub struct BusConnector {
+    publisher: Publisher,
+    subscriber: Subscriber,
+}
+
+impl BusConnector {
+    /// A handle for publishing from other threads.
+    pub(crate) fn publisher(&self) -> Publisher {
+        self.publisher.clone()
+    }
+
+    /// Blocking
+    #[cfg(test)]
+    pub fn get_n_events_blocking(&self, quantity: usize) -> Vec<Event> {
+        self.subscriber.get_n_events_blocking(quantity)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Event> + '_ {
+        self.subscriber.iter()
+    }
+
+    /// Non blocking
+    pub fn try_get_events(&self) -> Vec<Event> {
+        self.subscriber.try_get_events()
+    }
+
+    /// Events waiting to be dispatched by the bus.
+    pub(crate) fn bus_queue_len(&self) -> usize {
+        self.publisher.bus_queue_len()
+    }
+
+    /// Events dispatched to this connector, waiting to be consumed.
+    pub(crate) fn queue_len(&self) -> usize {
+        self.subscriber.queue_len()
+    }
+
+    pub(crate) fn send_event(&self, ev: Event) {
+        self.publisher.send_event(ev)
+    }
+}
+
+/// Subscribes to the bus while it's running (e.g. for a client of the control socket). The new
+/// subscriber receives a snapshot of the current state first: the services created at runtime,
+/// then the pid and the status of every service, then whether horust is shutting down. Then it
+/// receives every event dispatched after the snapshot was taken, so nothing is missed or repeated.
+#[derive(Debug, Clone)]
+pub(crate) struct Registrar {
+    sender: Sender<Registration>,
+}
+
+impl Registrar {
+    /// None if the bus is over.
+    pub(crate) fn subscribe(&self, name: &str) -> Option<Subscriber> {
+        let (sender, receiver) = unbounded();
+        let registration = Registration {
+            name: name.to_string(),
+            sender,
+        };
+        self.sender.send(registration).ok()?;
+        Some(Subscriber { receiver })
     }
 }
 
 #[cfg(test)]
 mod test {
 
-    use crate::horust::bus::{Bus, BusConnector};
-    //TODO: remove this reference:
-    use crate::horust::formats::{Event, ServiceStatus};
+    use crate::horust::bus::{BusConnector, EventBus, Supervision};
+    use crate::horust::formats::{Event, ServiceStatus, ShutdownReason};
     use crossbeam::channel;
+    use nix::unistd::Pid;
     use std::thread;
     use std::time::Duration;
 
-    fn init_bus() -> (
-        BusConnector<Event>,
-        BusConnector<Event>,
-        channel::Receiver<()>,
-    ) {
-        let mut bus = Bus::new();
+    fn init_bus() -> (BusConnector, BusConnector, channel::Receiver<()>) {
+        let mut bus = EventBus::new();
         let a = bus.join_bus();
         let b = bus.join_bus();
         let (sender, receiver) = channel::bounded(48);
@@ -191,7 +520,7 @@ mod test {
             b.get_n_events_blocking(3);
 
             a.send_event(ev.clone());
-            while a.receiver.is_empty() && b.receiver.is_empty() {
+            while a.queue_len() == 0 && b.queue_len() == 0 {
                 thread::sleep(Duration::from_millis(200));
             }
             assert_eq!(a.try_get_events().len(), 1);
@@ -213,8 +542,8 @@ mod test {
         let (a, b, receiver) = init_bus();
         let ev = Event::new_status_changed(&"sample".to_string(), ServiceStatus::Initial);
         a.send_event(ev.clone());
-        assert_eq!(a.receiver.recv().unwrap().into_payload(), ev);
-        assert_eq!(b.receiver.recv().unwrap().into_payload(), ev);
+        assert_eq!(a.get_n_events_blocking(1), vec![ev.clone()]);
+        assert_eq!(b.get_n_events_blocking(1), vec![ev]);
         drop(a);
         drop(b);
         receiver
@@ -224,7 +553,7 @@ mod test {
 
     #[test]
     fn test_stress() {
-        let mut bus = Bus::new();
+        let mut bus = EventBus::new();
         let mut connectors = vec![];
         let last = bus.join_bus();
         for _i in 0..100 {
@@ -238,24 +567,103 @@ mod test {
                 .expect("test didn't terminate in time, so chan is closed!");
         });
         let ev = Event::new_status_changed(&"sample".to_string(), ServiceStatus::Initial);
-        let exit_ev = Event::ShuttingDownInitiated;
+        let exit_ev = Event::ShuttingDownInitiated(ShutdownReason::ServicesFinished);
 
         for _i in 0..100 {
             last.send_event(ev.clone());
             for recv in &connectors {
-                assert_eq!(recv.receiver.recv().unwrap().into_payload(), ev);
+                assert_eq!(recv.get_n_events_blocking(1), vec![ev.clone()]);
             }
             let to_stop = connectors.pop().unwrap();
             to_stop.send_event(exit_ev.clone());
             for recv in &connectors {
-                assert_eq!(recv.receiver.recv().unwrap().into_payload(), exit_ev);
+                assert_eq!(recv.get_n_events_blocking(1), vec![exit_ev.clone()]);
             }
         }
-        last.send_event(Event::ShuttingDownInitiated);
+        last.send_event(exit_ev);
         drop(connectors);
         drop(last);
         receiver
             .recv_timeout(Duration::from_secs(15))
             .expect("Didn't receive an answer on time.");
     }
+
+    #[test]
+    fn test_supervision() {
+        let mut bus = EventBus::new();
+        bus.supervise(Supervision {
+            stall_threshold: 2,
+            stall_timeout: Duration::from_millis(0),
+            ..Supervision::new(true)
+        });
+        let observer = bus.join_bus_as("observer", false);
+        let stalled = bus.join_bus_as("runtime", true);
+        let dead = bus.join_bus_as("chaos", false);
+        drop(dead);
+        thread::spawn(move || bus.run());
+        let ev = Event::new_status_changed(&String::from("sample"), ServiceStatus::Initial);
+        let stalled_ev =
+            Event::ShuttingDownInitiated(ShutdownReason::ComponentStalled("runtime".into()));
+        // The dead subscriber is not critical: it's just dropped.
+        observer.send_event(ev.clone());
+        assert_eq!(observer.get_n_events_blocking(1), vec![ev.clone()]);
+        // The runtime is not consuming its events: once the threshold is reached, it's escalated.
+        observer.send_event(ev.clone());
+        assert_eq!(
+            observer.get_n_events_blocking(2),
+            vec![ev.clone(), stalled_ev.clone()]
+        );
+        // Only once.
+        observer.send_event(ev.clone());
+        assert_eq!(observer.get_n_events_blocking(1), vec![ev.clone()]);
+        assert_eq!(stalled.try_get_events().len(), 4);
+    }
+
+    #[test]
+    fn test_late_subscriber() {
+        let mut bus = EventBus::new();
+        let connector = bus.join_bus();
+        let registrar = bus.registrar();
+        let (sender, receiver) = channel::bounded(1);
+        thread::spawn(move || {
+            bus.run();
+            sender.send(()).unwrap();
+        });
+        let pid = Pid::from_raw(42);
+        let events = vec![
+            Event::new_status_changed("b.toml", ServiceStatus::Starting),
+            Event::new_pid_changed("b.toml".into(), pid),
+            Event::new_status_changed("b.toml", ServiceStatus::Started),
+            Event::new_status_changed("a.toml", ServiceStatus::Starting),
+            Event::new_pid_changed("a.toml".into(), pid),
+            Event::new_service_exited("a.toml".into(), 0),
+            Event::new_status_changed("a.toml", ServiceStatus::Success),
+            Event::Ping(1),
+        ];
+        events
+            .iter()
+            .for_each(|ev| connector.send_event(ev.clone()));
+        assert_eq!(connector.get_n_events_blocking(events.len()), events);
+
+        // The exited service has no pid anymore, and the other events are not part of the state.
+        let subscriber = registrar.subscribe("late").unwrap();
+        let live = Event::new_status_changed("b.toml", ServiceStatus::Running);
+        connector.send_event(live.clone());
+        assert_eq!(
+            subscriber.get_n_events_blocking(4),
+            vec![
+                Event::new_status_changed("a.toml", ServiceStatus::Success),
+                Event::new_pid_changed("b.toml".into(), pid),
+                Event::new_status_changed("b.toml", ServiceStatus::Started),
+                live,
+            ]
+        );
+        // The registrar doesn't keep the bus running.
+        drop(connector);
+        assert!(subscriber.iter().next().is_none());
+        receiver
+            .recv_timeout(Duration::from_secs(3))
+            .expect("Didn't receive an answer on time.");
+        assert!(registrar.subscribe("too late").is_none());
+    }
 }
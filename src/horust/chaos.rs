@@ -0,0 +1,132 @@
+//! Fault injection for resilience testing (`--chaos`): the running services are randomly killed
+//! and their healthcheck results are randomly delayed, as configured in the `[chaos]` section.
+//! Every injected fault is announced on the bus (`Event::FaultInjected`), so it's logged and it
+//! can be told apart from an actual failure.
+use crate::horust::bus::BusConnector;
+use crate::horust::formats::{ChaosConfig, Event, Fault, ServiceName, ServiceStatus};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use rand::Rng;
+use std::collections::{BTreeSet, HashMap};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Decides which faults to inject. It's shared with the healthchecks, for delaying their results.
+#[derive(Debug, Clone)]
+pub(crate) struct FaultInjector {
+    config: ChaosConfig,
+}
+
+impl FaultInjector {
+    /// None if the chaos is not enabled.
+    pub(crate) fn new(config: &ChaosConfig) -> Option<Self> {
+        if config.enabled {
+            Some(Self {
+                config: config.clone(),
+            })
+        } else {
+            None
+        }
+    }
+
+    fn targets(&self, service_name: &str) -> bool {
+        self.config.services.is_empty()
+            || self.config.services.iter().any(|name| name == service_name)
+    }
+
+    /// For how long the result of a healthcheck of the service should be held back, if at all.
+    pub(crate) fn healthcheck_delay(&self, service_name: &str) -> Option<Duration> {
+        if self.targets(service_name) && roll(self.config.healthcheck_delay_probability) {
+            Some(self.config.healthcheck_delay)
+        } else {
+            None
+        }
+    }
+
+    fn should_kill(&self, service_name: &str) -> bool {
+        self.targets(service_name) && roll(self.config.kill_probability)
+    }
+}
+
+/// True with the given probability.
+fn roll(probability: f64) -> bool {
+    rand::thread_rng().gen::<f64>() < probability
+}
+
+pub(crate) fn spawn(bus: BusConnector, injector: FaultInjector) {
+    thread::Builder::new()
+        .name("chaos".into())
+        .spawn(move || run(bus, injector))
+        .expect("Failed spawning the chaos thread");
+}
+
+fn run(bus: BusConnector, injector: FaultInjector) {
+    warn!(
+        "Chaos is enabled: the services are going to be killed at random, every {:?}",
+        injector.config.interval
+    );
+    let mut pids: HashMap<ServiceName, Pid> = HashMap::new();
+    let mut running: BTreeSet<ServiceName> = BTreeSet::new();
+    let mut next_round = Instant::now() + injector.config.interval;
+    loop {
+        for ev in bus.try_get_events() {
+            match ev {
+                Event::PidChanged(s_name, pid) | Event::PidAdopted(s_name, pid) => {
+                    pids.insert(s_name, pid);
+                }
+                Event::StatusChanged(s_name, ServiceStatus::Running) => {
+                    running.insert(s_name);
+                }
+                Event::StatusChanged(s_name, _) => {
+                    running.remove(&s_name);
+                }
+                // Faults during the shutdown would only be noise.
+                Event::ShuttingDownInitiated(_) => return,
+                _ => (),
+            }
+        }
+        if Instant::now() >= next_round {
+            next_round += injector.config.interval;
+            for s_name in running.iter().filter(|s_name| injector.should_kill(s_name)) {
+                let pid = match pids.get(s_name) {
+                    Some(pid) => *pid,
+                    None => continue,
+                };
+                bus.send_event(Event::FaultInjected(s_name.clone(), Fault::Killed(pid)));
+                if let Err(error) = signal::kill(pid, Signal::SIGKILL) {
+                    error!("Chaos: cannot kill {} ({}): {}", s_name, pid, error);
+                }
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{roll, FaultInjector};
+    use crate::horust::formats::ChaosConfig;
+    use std::time::Duration;
+
+    #[test]
+    fn test_fault_injector() {
+        assert!(FaultInjector::new(&ChaosConfig::default()).is_none());
+        let config = ChaosConfig {
+            enabled: true,
+            kill_probability: 1.0,
+            healthcheck_delay_probability: 1.0,
+            healthcheck_delay: Duration::from_secs(2),
+            services: vec!["web.toml".into()],
+            ..Default::default()
+        };
+        let injector = FaultInjector::new(&config).unwrap();
+        assert!(injector.should_kill("web.toml"));
+        assert!(!injector.should_kill("db.toml"));
+        assert_eq!(
+            injector.healthcheck_delay("web.toml"),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(injector.healthcheck_delay("db.toml"), None);
+        assert!((0..100).all(|_| !roll(0.0)));
+    }
+}
@@ -0,0 +1,400 @@
+//! Agent reporting to a central collector (`--collector`): the state changes and the metrics of
+//! the services are posted as json to an http endpoint, along with the identity of this node, so
+//! a fleet of containers supervised by horust can be observed from one place.
+use crate::horust::bus::Subscriber;
+use crate::horust::formats::{
+    Environment, Event, EventRecord, ServiceName, ServiceStatus, EVENTS_SCHEMA_VERSION,
+};
+use crate::horust::status_board::{ServiceSnapshot, StatusBoard};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// For connecting, sending the report and reading the response.
+const TIMEOUT: Duration = Duration::from_secs(3);
+/// While the collector is unreachable, the oldest events are dropped beyond this.
+const MAX_PENDING_EVENTS: usize = 1000;
+
+/// A plain http url, e.g. `http://collector:9000/horust`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Endpoint {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("not an http:// url: {}", url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => (&rest[..idx], &rest[idx..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rfind(':') {
+            Some(idx) if !authority[idx..].contains(']') => {
+                let port = authority[idx + 1..]
+                    .parse()
+                    .map_err(|_| format!("invalid port in the url: {}", url))?;
+                (&authority[..idx], port)
+            }
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("missing host in the url: {}", url));
+        }
+        Ok(Self {
+            host: host.trim_start_matches('[').trim_end_matches(']').into(),
+            port,
+            path: path.into(),
+        })
+    }
+}
+
+impl Endpoint {
+    /// Posts the json body, expecting a 2xx status.
+    pub(crate) fn post(&self, body: &str) -> io::Result<()> {
+        let address = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "cannot resolve the host"))?;
+        let mut stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            self.port,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes())?;
+        // E.g. `HTTP/1.1 204 No Content`
+        let mut status_line = String::new();
+        BufReader::new(&stream).read_line(&mut status_line)?;
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            _ => Err(io::Error::other(format!(
+                "unexpected response: {}",
+                status_line.trim()
+            ))),
+        }
+    }
+}
+
+/// Who is reporting: sent with every report.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Identity {
+    /// `--collector-node`, or the hostname.
+    pub(crate) node: String,
+    pub(crate) hostname: String,
+    /// The id of the container horust is running in, if it can be told from its cgroup.
+    pub(crate) container: Option<String>,
+    pub(crate) pid: u32,
+}
+
+impl Identity {
+    pub(crate) fn new(node: Option<String>) -> Self {
+        let hostname = Environment::get_hostname_val().trim().to_string();
+        Self {
+            node: node.unwrap_or_else(|| hostname.clone()),
+            hostname,
+            container: own_container_id(),
+            pid: std::process::id(),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn own_container_id() -> Option<String> {
+    std::fs::read_to_string("/proc/self/cgroup")
+        .ok()
+        .and_then(|cgroup| container_id(&cgroup))
+}
+
+/// There are no cgroups elsewhere: the container is never reported.
+#[cfg(not(target_os = "linux"))]
+fn own_container_id() -> Option<String> {
+    None
+}
+
+/// The container id found in the cgroup paths, e.g. `/docker/<id>` or
+/// `/system.slice/docker-<id>.scope` (cgroup v1 only: v2 hides them inside the container).
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn container_id(cgroup: &str) -> Option<String> {
+    cgroup
+        .lines()
+        .flat_map(|line| line.rsplit('/'))
+        .map(|segment| {
+            let segment = segment.trim_end_matches(".scope");
+            segment.rsplit('-').next().unwrap_or(segment)
+        })
+        .find(|id| id.len() == 64 && id.chars().all(|c| c.is_ascii_hexdigit()))
+        .map(str::to_string)
+}
+
+/// A state change (a record of the events schema), timestamped when it's been seen by the agent.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+struct Record {
+    timestamp: String,
+    #[serde(flatten)]
+    event: EventRecord,
+}
+
+/// The metrics of a service, as of the report.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct ServiceMetrics {
+    status: ServiceStatus,
+    pid: Option<i32>,
+    restart_attempts: u32,
+    starts: u32,
+    /// Of the last round of healthchecks, if any.
+    health: Option<String>,
+    uptime_secs: Option<u64>,
+}
+
+impl From<&ServiceSnapshot> for ServiceMetrics {
+    fn from(snapshot: &ServiceSnapshot) -> Self {
+        Self {
+            status: snapshot.status.clone(),
+            pid: snapshot.pid.map(|pid| pid.as_raw()),
+            restart_attempts: snapshot.restart_attempts,
+            starts: snapshot.starts,
+            health: snapshot
+                .last_health_check
+                .as_ref()
+                .map(|report| format!("{:?}", report.status)),
+            uptime_secs: snapshot.uptime().map(|uptime| uptime.as_secs()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Report<'a> {
+    #[serde(flatten)]
+    identity: &'a Identity,
+    schema_version: u32,
+    timestamp: String,
+    events: &'a VecDeque<Record>,
+    services: BTreeMap<&'a ServiceName, ServiceMetrics>,
+}
+
+#[derive(Debug)]
+struct Agent {
+    endpoint: Endpoint,
+    identity: Identity,
+    /// Not delivered yet: they're sent again with the next report.
+    pending: VecDeque<Record>,
+    /// Errors are logged once, until a report is delivered again.
+    is_failing: bool,
+}
+
+impl Agent {
+    fn new(endpoint: Endpoint, identity: Identity) -> Self {
+        Self {
+            endpoint,
+            identity,
+            pending: VecDeque::new(),
+            is_failing: false,
+        }
+    }
+
+    fn record(&mut self, ev: Event) {
+        let event = match ev {
+            Event::StatusChanged(_, _) | Event::ShuttingDownInitiated(_) => {
+                EventRecord::from_event(&ev)
+            }
+            _ => None,
+        };
+        let record = match event {
+            Some(event) => Record {
+                timestamp: now_rfc3339(),
+                event,
+            },
+            None => return,
+        };
+        if self.pending.len() == MAX_PENDING_EVENTS {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(record);
+    }
+
+    /// Sends the pending events, and the metrics of every service.
+    fn report(&mut self, services: &[ServiceSnapshot]) {
+        let report = Report {
+            identity: &self.identity,
+            schema_version: EVENTS_SCHEMA_VERSION,
+            timestamp: now_rfc3339(),
+            events: &self.pending,
+            services: services
+                .iter()
+                .map(|snapshot| (&snapshot.name, snapshot.into()))
+                .collect(),
+        };
+        let body = serde_json::to_string(&report).unwrap_or_default();
+        match self.endpoint.post(&body) {
+            Ok(_) => {
+                if self.is_failing {
+                    info!("The collector is reachable again.");
+                }
+                self.pending.clear();
+                self.is_failing = false;
+            }
+            Err(error) => {
+                if !self.is_failing {
+                    error!("Cannot report to the collector: {}", error);
+                }
+                self.is_failing = true;
+            }
+        }
+    }
+}
+
+fn now_rfc3339() -> String {
+    humantime::format_rfc3339_millis(SystemTime::now()).to_string()
+}
+
+pub(crate) fn spawn(
+    subscriber: Subscriber,
+    status_board: StatusBoard,
+    endpoint: Endpoint,
+    identity: Identity,
+    interval: Duration,
+) {
+    thread::Builder::new()
+        .name("collector".into())
+        .spawn(move || {
+            run(
+                subscriber,
+                status_board,
+                Agent::new(endpoint, identity),
+                interval,
+            )
+        })
+        .expect("Failed spawning the collector thread");
+}
+
+/// The state changes are reported as soon as they happen, the metrics at every interval anyway.
+/// While the collector is unreachable, it's only tried again at every interval.
+fn run(subscriber: Subscriber, status_board: StatusBoard, mut agent: Agent, interval: Duration) {
+    let mut last_report: Option<Instant> = None;
+    loop {
+        subscriber
+            .try_get_events()
+            .into_iter()
+            .for_each(|ev| agent.record(ev));
+        let is_due = last_report.is_none_or(|last| last.elapsed() >= interval);
+        if is_due || (!agent.pending.is_empty() && !agent.is_failing) {
+            agent.report(&status_board.services());
+            last_report = Some(Instant::now());
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{container_id, Agent, Endpoint, Identity};
+    use crate::horust::formats::{Event, ServiceStatus, ShutdownReason};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_endpoint() {
+        let endpoint: Endpoint = "http://collector:9000/horust/reports".parse().unwrap();
+        assert_eq!(
+            endpoint,
+            Endpoint {
+                host: "collector".into(),
+                port: 9000,
+                path: "/horust/reports".into(),
+            }
+        );
+        let endpoint: Endpoint = "http://[::1]".parse().unwrap();
+        assert_eq!((endpoint.host.as_str(), endpoint.port), ("::1", 80));
+        assert_eq!(endpoint.path, "/");
+        "https://collector:9000".parse::<Endpoint>().unwrap_err();
+        "http://collector:http".parse::<Endpoint>().unwrap_err();
+        "http:///reports".parse::<Endpoint>().unwrap_err();
+    }
+
+    #[test]
+    fn test_container_id() {
+        let id = "3f2a9c1e5b7d4a6c8e0f2a4b6c8d0e1f3a5b7c9d1e3f5a7b9c1d3e5f7a9b1c3d";
+        let docker = format!("12:memory:/docker/{}\n0::/\n", id);
+        assert_eq!(container_id(&docker).as_deref(), Some(id));
+        let systemd = format!("1:name=systemd:/system.slice/docker-{}.scope\n", id);
+        assert_eq!(container_id(&systemd).as_deref(), Some(id));
+        assert_eq!(container_id("0::/\n"), None);
+    }
+
+    #[test]
+    fn test_report() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/reports", listener.local_addr().unwrap());
+        let identity = Identity {
+            node: "worker-1".into(),
+            hostname: "3f2a9c1e5b7d".into(),
+            container: None,
+            pid: 1,
+        };
+        let mut agent = Agent::new(url.parse().unwrap(), identity);
+        agent.record(Event::StatusChanged("web".into(), ServiceStatus::Running));
+        agent.record(Event::Run("web".into()));
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header == "\r\n" {
+                    break;
+                }
+                if let Some(length) = header.to_lowercase().strip_prefix("content-length:") {
+                    content_length = length.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            (&stream)
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            (request_line, String::from_utf8(body).unwrap())
+        });
+        agent.report(&[]);
+        let (request_line, body) = handle.join().unwrap();
+        assert_eq!(request_line, "POST /reports HTTP/1.1\r\n");
+        let report: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(report["node"], "worker-1");
+        assert_eq!(report["hostname"], "3f2a9c1e5b7d");
+        assert_eq!(report["schema-version"], 1);
+        assert_eq!(report["events"][0]["event"], "status-changed");
+        assert_eq!(report["events"][0]["service"], "web");
+        assert_eq!(report["events"][0]["status"], "Running");
+        assert_eq!(report["events"].as_array().unwrap().len(), 1);
+        assert!(report["services"].as_object().unwrap().is_empty());
+        assert!(agent.pending.is_empty());
+
+        // The collector is gone: the events are kept for the next report.
+        agent.record(Event::ShuttingDownInitiated(ShutdownReason::ControlCommand));
+        agent.report(&[]);
+        assert!(agent.is_failing);
+        assert_eq!(agent.pending.len(), 1);
+    }
+}
@@ -0,0 +1,751 @@
+use crate::horust::bus::{BusConnector, Registrar};
+use crate::horust::formats::{
+    negotiate, Event, EventRecord, Header, HealthinessStatus, RestartMode, Service, ServiceName,
+    ServiceStatus, ShutdownReason,
+};
+use crate::horust::heartbeat::Heartbeat;
+use crate::horust::logging::{self, LogTarget};
+use crate::horust::reload::ReloadPreview;
+use crate::horust::status_board::{format_state, ServiceSnapshot, StatusBoard};
+use selector::LabelSelector;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+mod selector;
+
+/// If the runtime hasn't completed an iteration of its event loop in this time, it's stalled.
+const MAX_HEARTBEAT_AGE: Duration = Duration::from_secs(5);
+/// How long to wait for a ping to go through the bus.
+const BUS_PING_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The control socket: a unix socket used for querying horust itself.
+/// The protocol is line based: the client sends a command, horust answers with a line that
+/// is either `OK` or `ERROR <reason>`, optionally followed by more lines. Then it closes the connection.
+/// Except for `events`: the events keep coming until the client leaves, or horust exits. Using
+/// `events --json [<version>]`, they're json records of the events schema instead of the
+/// internal events, starting with the version in use.
+pub(crate) fn spawn(
+    bus: BusConnector,
+    registrar: Registrar,
+    socket_path: PathBuf,
+    heartbeat: Heartbeat,
+    status_board: StatusBoard,
+    aliases: HashMap<String, ServiceName>,
+    reload_preview: Option<ReloadPreview>,
+) {
+    thread::spawn(move || {
+        let listener = match bind(&socket_path) {
+            Ok(listener) => listener,
+            Err(error) => {
+                error!(
+                    "Cannot create the control socket: {}, error: {}",
+                    socket_path.display(),
+                    error
+                );
+                return;
+            }
+        };
+        Control::new(
+            bus,
+            registrar,
+            heartbeat,
+            status_board,
+            aliases,
+            reload_preview,
+        )
+        .run(listener);
+        if let Err(error) = std::fs::remove_file(&socket_path) {
+            warn!("Cannot remove the control socket: {}", error);
+        }
+    });
+}
+
+/// Maps every alias to the name of its service. The instances of a replicated service share
+/// its aliases, which refer to the service as a whole.
+pub(crate) fn aliases(services: &[Service]) -> HashMap<String, ServiceName> {
+    services
+        .iter()
+        .flat_map(|service| {
+            let name = match &service.replica {
+                Some(replica) => replica.of.clone(),
+                None => service.name.clone(),
+            };
+            service
+                .aliases
+                .iter()
+                .map(move |alias| (alias.clone(), name.clone()))
+        })
+        .collect()
+}
+
+fn bind(socket_path: &Path) -> io::Result<UnixListener> {
+    // Probably left there by a previous run.
+    if socket_path.exists() {
+        std::fs::remove_file(socket_path)?;
+    }
+    let listener = UnixListener::bind(socket_path)?;
+    // Non blocking, so the bus can be consumed as well.
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// The services a command applies to: either a service (by name), or `-l <label selector>`.
+enum Target<'a> {
+    Service(&'a str),
+    Selector(LabelSelector),
+}
+
+impl<'a> Target<'a> {
+    fn parse(first: &'a str, words: &mut impl Iterator<Item = &'a str>) -> Result<Self, String> {
+        if first != "-l" {
+            return Ok(Target::Service(first));
+        }
+        match words.next() {
+            Some(selector) => selector.parse().map(Target::Selector),
+            None => Err("Missing label selector after -l".to_string()),
+        }
+    }
+
+    fn matches(&self, snapshot: &ServiceSnapshot) -> bool {
+        match self {
+            Target::Service(service) => snapshot.name == *service,
+            Target::Selector(selector) => selector.matches(&snapshot.labels),
+        }
+    }
+}
+
+struct Control {
+    bus: BusConnector,
+    /// For the clients following the events.
+    registrar: Registrar,
+    heartbeat: Heartbeat,
+    status_board: StatusBoard,
+    aliases: HashMap<String, ServiceName>,
+    /// None if the services haven't been loaded from directories.
+    reload_preview: Option<ReloadPreview>,
+    is_shutting_down: bool,
+    last_ping: u64,
+}
+
+impl Control {
+    fn new(
+        bus: BusConnector,
+        registrar: Registrar,
+        heartbeat: Heartbeat,
+        status_board: StatusBoard,
+        aliases: HashMap<String, ServiceName>,
+        reload_preview: Option<ReloadPreview>,
+    ) -> Self {
+        Self {
+            bus,
+            registrar,
+            heartbeat,
+            status_board,
+            aliases,
+            reload_preview,
+            is_shutting_down: false,
+            last_ping: 0,
+        }
+    }
+
+    fn run(mut self, listener: UnixListener) {
+        while !self.is_shutting_down {
+            let events = self.bus.try_get_events();
+            events.into_iter().for_each(|ev| self.handle_event(ev));
+            match listener.accept() {
+                Ok((stream, _addr)) => {
+                    if let Err(error) = self.handle_client(stream) {
+                        warn!("Error handling control client: {}", error);
+                    }
+                }
+                Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(100));
+                }
+                Err(error) => error!("Error accepting control client: {}", error),
+            }
+        }
+    }
+
+    fn handle_event(&mut self, ev: Event) {
+        if let Event::ShuttingDownInitiated(_) = ev {
+            self.is_shutting_down = true;
+        }
+    }
+
+    fn handle_client(&mut self, stream: UnixStream) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let mut request = String::new();
+        BufReader::new(&stream).read_line(&mut request)?;
+        let mut words = request.split_whitespace();
+        let response = match (words.next(), words.next()) {
+            (Some("ping"), None) => self.ping(),
+            (Some("status"), None) => self.status(None),
+            (Some("status"), Some(target)) => Target::parse(self.resolve(target), &mut words)
+                .and_then(|target| self.status(Some(&target))),
+            (Some("metrics"), None) => Ok(self.metrics()),
+            (Some("debug"), None) => Ok(self.debug()),
+            (Some("events"), None) => return self.events(stream, None),
+            (Some("events"), Some("--json")) => match negotiate(words.next()) {
+                Ok(version) => return self.events(stream, Some(version)),
+                Err(reason) => Err(reason),
+            },
+            (Some("start"), Some(service)) => self.start(self.resolve(service)),
+            (Some("history"), Some(service)) => self.history(self.resolve(service)),
+            (Some("stop"), Some(target)) => Target::parse(self.resolve(target), &mut words)
+                .and_then(|target| self.stop(&target)),
+            (Some("restart"), Some(target)) => Target::parse(self.resolve(target), &mut words)
+                .and_then(|target| self.restart(&target, RestartMode::All)),
+            (Some("rolling-restart"), Some(target)) => {
+                Target::parse(self.resolve(target), &mut words)
+                    .and_then(|target| self.restart(&target, RestartMode::Rolling))
+            }
+            (Some("mask"), Some(target)) => Target::parse(self.resolve(target), &mut words)
+                .and_then(|target| self.mask(&target, true)),
+            (Some("unmask"), Some(target)) => Target::parse(self.resolve(target), &mut words)
+                .and_then(|target| self.mask(&target, false)),
+            (Some("blue-green-restart"), Some(service)) => {
+                self.blue_green_restart(self.resolve(service))
+            }
+            (Some("shutdown"), None) => Ok(self.shutdown()),
+            (Some("reload"), Some("--dry-run")) => self.reload_dry_run(),
+            (Some("reload"), _) => Err(
+                "Reloading the services isn't supported, only previewing it: use --dry-run"
+                    .to_string(),
+            ),
+            (Some("log-level"), Some(level)) => self.log_level(level, words.next()),
+            (Some("scale"), Some(service)) => match words.next().map(str::parse) {
+                Some(Ok(replicas)) => self.scale(self.resolve(service), replicas),
+                _ => Err("Usage: scale <service> <replicas>".to_string()),
+            },
+            _ => Err(format!("Unknown command: '{}'", request.trim())),
+        };
+        let response = match response {
+            Ok(body) => format!("OK\n{}", body),
+            Err(reason) => format!("ERROR {}\n", reason),
+        };
+        (&stream).write_all(response.as_bytes())
+    }
+
+    /// The name of the service, in case `name` is one of its aliases.
+    fn resolve<'a>(&'a self, name: &'a str) -> &'a str {
+        self.aliases.get(name).map(String::as_str).unwrap_or(name)
+    }
+
+    /// Checks that the runtime is making progress, and that the bus is delivering the events.
+    fn ping(&mut self) -> Result<String, String> {
+        let heartbeat_age = self.heartbeat.elapsed();
+        if heartbeat_age > MAX_HEARTBEAT_AGE {
+            return Err(format!(
+                "Runtime is stalled, last heartbeat: {:?} ago.",
+                heartbeat_age
+            ));
+        }
+        self.last_ping += 1;
+        let ping = Event::Ping(self.last_ping);
+        self.bus.send_event(ping.clone());
+        let start = Instant::now();
+        while start.elapsed() < BUS_PING_TIMEOUT {
+            for ev in self.bus.try_get_events() {
+                if ev == ping {
+                    return Ok(String::new());
+                }
+                self.handle_event(ev);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        Err("Bus is not delivering events.".into())
+    }
+
+    /// One line per service (or just the requested ones).
+    fn status(&self, target: Option<&Target>) -> Result<String, String> {
+        let services: Vec<ServiceSnapshot> = self
+            .status_board
+            .services()
+            .into_iter()
+            .filter(|snapshot| match target {
+                Some(target) => target.matches(snapshot),
+                None => true,
+            })
+            .collect();
+        match target {
+            Some(Target::Service(name)) if services.is_empty() => {
+                Err(format!("Unknown service: '{}'", name))
+            }
+            _ => Ok(services.iter().map(format_status).collect()),
+        }
+    }
+
+    /// The services a command applies to. Replicated services are selected as a whole.
+    fn select(&self, target: &Target) -> Result<Vec<ServiceName>, String> {
+        let mut selected: Vec<ServiceName> = self
+            .status_board
+            .services()
+            .into_iter()
+            .filter(|snapshot| match target {
+                Target::Service(service) => {
+                    snapshot.name == *service || snapshot.replica_of.as_deref() == Some(service)
+                }
+                Target::Selector(selector) => selector.matches(&snapshot.labels),
+            })
+            .map(|snapshot| snapshot.replica_of.unwrap_or(snapshot.name))
+            .collect();
+        selected.sort();
+        selected.dedup();
+        match target {
+            Target::Service(service) if selected.is_empty() => {
+                Err(format!("Unknown service: '{}'", service))
+            }
+            Target::Selector(_) if selected.is_empty() => {
+                Err("No service matches the label selector".to_string())
+            }
+            _ => Ok(selected),
+        }
+    }
+
+    /// Activates the service, if it has been stopped because it was idle.
+    /// Stops all the services, then horust exits: the response is sent right away.
+    fn shutdown(&self) -> String {
+        self.bus
+            .send_event(Event::ShuttingDownInitiated(ShutdownReason::ControlCommand));
+        String::new()
+    }
+
+    fn start(&self, service: &str) -> Result<String, String> {
+        let snapshot = self
+            .status_board
+            .services()
+            .into_iter()
+            .find(|snapshot| snapshot.name == service)
+            .ok_or_else(|| format!("Unknown service: '{}'", service))?;
+        if snapshot.status != ServiceStatus::Idle {
+            return Err(format!(
+                "Service '{}' is not idle, it's: {}",
+                service, snapshot.status
+            ));
+        }
+        self.bus.send_event(Event::Activate(service.to_string()));
+        Ok(String::new())
+    }
+
+    /// One line per run of the service, the oldest first.
+    fn history(&self, service: &str) -> Result<String, String> {
+        let snapshot = self
+            .status_board
+            .services()
+            .into_iter()
+            .find(|snapshot| snapshot.name == service)
+            .ok_or_else(|| format!("Unknown service: '{}'", service))?;
+        Ok(snapshot
+            .run_history
+            .iter()
+            .map(|run| format!("{}\n", run))
+            .collect())
+    }
+
+    /// What reloading the services directories would change, nothing is applied.
+    fn reload_dry_run(&self) -> Result<String, String> {
+        let reload_preview = self.reload_preview.as_ref().ok_or_else(|| {
+            "The services haven't been loaded from a services directory".to_string()
+        })?;
+        reload_preview
+            .plan()
+            .map(|plan| plan.to_string())
+            .map_err(|error| format!("Cannot load the services: {}", error))
+    }
+
+    /// Restarts the services, or all of their instances if they're replicated.
+    fn restart(&self, target: &Target, mode: RestartMode) -> Result<String, String> {
+        for service in self.select(target)? {
+            self.bus.send_event(Event::Restart(service, mode.clone()));
+        }
+        Ok(String::new())
+    }
+
+    /// Stops the services, or all of their instances if they're replicated.
+    /// They won't be started again.
+    fn stop(&self, target: &Target) -> Result<String, String> {
+        for service in self.select(target)? {
+            self.bus.send_event(Event::Stop(service));
+        }
+        Ok(String::new())
+    }
+
+    /// Masks the services (or all the instances of the replicated ones): they're stopped, and
+    /// they're not started again until they're unmasked. Or unmasks them.
+    fn mask(&self, target: &Target, is_masked: bool) -> Result<String, String> {
+        for service in self.select(target)? {
+            let ev = if is_masked {
+                Event::Mask(service)
+            } else {
+                Event::Unmask(service)
+            };
+            self.bus.send_event(ev);
+        }
+        Ok(String::new())
+    }
+
+    /// Restarts the service alongside its running instance, which is stopped afterwards.
+    fn blue_green_restart(&self, service: &str) -> Result<String, String> {
+        let snapshot = self
+            .status_board
+            .services()
+            .into_iter()
+            .find(|snapshot| snapshot.name == service)
+            .ok_or_else(|| format!("Unknown service: '{}'", service))?;
+        if snapshot.replica_of.is_some() {
+            return Err(format!(
+                "Service '{}' is replicated, use a rolling restart instead.",
+                service
+            ));
+        }
+        if snapshot.status != ServiceStatus::Running {
+            return Err(format!(
+                "Service '{}' is not running, it's: {}",
+                service, snapshot.status
+            ));
+        }
+        self.bus
+            .send_event(Event::Restart(service.to_string(), RestartMode::BlueGreen));
+        Ok(String::new())
+    }
+
+    /// Changes the number of instances of a replicated service.
+    fn scale(&self, service: &str, replicas: u32) -> Result<String, String> {
+        let is_replicated = self
+            .status_board
+            .services()
+            .iter()
+            .any(|snapshot| snapshot.replica_of.as_deref() == Some(service));
+        if !is_replicated {
+            return Err(format!(
+                "Service '{}' is not replicated (check `replicas`)",
+                service
+            ));
+        }
+        self.bus
+            .send_event(Event::Scale(service.to_string(), replicas));
+        Ok(String::new())
+    }
+
+    /// Changes horust's own log level, for every record or just for the target.
+    /// `default` removes a level set previously: the HORUST_LOG filter applies again.
+    fn log_level(&self, level: &str, target: Option<&str>) -> Result<String, String> {
+        let level = match level {
+            "default" => None,
+            level => Some(level.parse().map_err(|_| {
+                format!(
+                    "Unknown log level: '{}', expected: off|error|warn|info|debug|trace|default",
+                    level
+                )
+            })?),
+        };
+        let mut target: LogTarget = target.unwrap_or("all").parse()?;
+        if let LogTarget::Service(service) = &mut target {
+            *service = self.resolve(service).to_string();
+            let exists = self
+                .status_board
+                .services()
+                .iter()
+                .any(|snapshot| snapshot.name == *service);
+            if !exists {
+                return Err(format!("Unknown service: '{}'", service));
+            }
+        }
+        logging::set_level(target, level);
+        Ok(String::new())
+    }
+
+    /// Follows the bus: the current state of the services first, then every event as it's
+    /// dispatched, one per line. The client gets its own thread, until it leaves or horust exits.
+    /// With a schema version, only the events of the schema are written, as json.
+    fn events(&self, stream: UnixStream, schema_version: Option<u32>) -> io::Result<()> {
+        let subscriber = match self.registrar.subscribe("control socket client") {
+            Some(subscriber) => subscriber,
+            None => return (&stream).write_all(b"ERROR The bus is over\n"),
+        };
+        // The services which haven't changed status since the boot are not in the snapshot of
+        // the bus: the runtime tells about them.
+        self.bus.send_event(Event::RequestSnapshot);
+        thread::Builder::new()
+            .name("control-events".into())
+            .spawn(move || -> io::Result<()> {
+                let mut stream = stream;
+                stream.write_all(b"OK\n")?;
+                let schema_version = match schema_version {
+                    Some(schema_version) => schema_version,
+                    None => {
+                        for ev in subscriber.iter() {
+                            writeln!(stream, "{:?}", ev)?;
+                        }
+                        return Ok(());
+                    }
+                };
+                writeln!(
+                    stream,
+                    "{}",
+                    serde_json::to_string(&Header { schema_version })?
+                )?;
+                for record in subscriber
+                    .iter()
+                    .filter_map(|ev| EventRecord::from_event(&ev))
+                {
+                    writeln!(stream, "{}", serde_json::to_string(&record)?)?;
+                }
+                Ok(())
+            })?;
+        Ok(())
+    }
+
+    /// Internal state of the runtime and of every service, for troubleshooting.
+    fn debug(&self) -> String {
+        format_state(&self.status_board.runtime(), &self.status_board.services())
+    }
+
+    /// Healthchecks state and uptime of the services, using the prometheus text format.
+    fn metrics(&self) -> String {
+        let services = self.status_board.services();
+        let checked = || {
+            services.iter().filter_map(|snapshot| {
+                snapshot
+                    .last_health_check
+                    .as_ref()
+                    .map(|report| (snapshot, report))
+            })
+        };
+        let mut metrics = String::new();
+        metrics.push_str(
+            "# HELP horust_healthcheck_healthy Whether the last healthcheck has passed.\n\
+             # TYPE horust_healthcheck_healthy gauge\n",
+        );
+        for (snapshot, report) in checked() {
+            let is_healthy = report.status == HealthinessStatus::Healthy;
+            metrics.push_str(&format!(
+                "horust_healthcheck_healthy{{{}}} {}\n",
+                prometheus_labels(snapshot),
+                is_healthy as u8
+            ));
+        }
+        metrics.push_str(
+            "# HELP horust_healthcheck_latency_seconds Time taken by the last healthcheck.\n\
+             # TYPE horust_healthcheck_latency_seconds gauge\n",
+        );
+        for (snapshot, report) in checked() {
+            metrics.push_str(&format!(
+                "horust_healthcheck_latency_seconds{{{}}} {}\n",
+                prometheus_labels(snapshot),
+                report.latency.as_secs_f64()
+            ));
+        }
+        metrics.push_str(
+            "# HELP horust_healthcheck_consecutive_failures Healthchecks failed in a row.\n\
+             # TYPE horust_healthcheck_consecutive_failures gauge\n",
+        );
+        for snapshot in &services {
+            metrics.push_str(&format!(
+                "horust_healthcheck_consecutive_failures{{{}}} {}\n",
+                prometheus_labels(snapshot),
+                snapshot.consecutive_failures
+            ));
+        }
+        metrics.push_str(
+            "# HELP horust_service_starts_total Processes started for the service.\n\
+             # TYPE horust_service_starts_total counter\n",
+        );
+        for snapshot in &services {
+            metrics.push_str(&format!(
+                "horust_service_starts_total{{{}}} {}\n",
+                prometheus_labels(snapshot),
+                snapshot.starts
+            ));
+        }
+        metrics.push_str(
+            "# HELP horust_service_spawn_failures_total Processes which couldn't be spawned, by reason.\n\
+             # TYPE horust_service_spawn_failures_total counter\n",
+        );
+        for snapshot in &services {
+            for (reason, failures) in &snapshot.spawn_failures {
+                metrics.push_str(&format!(
+                    "horust_service_spawn_failures_total{{{},reason=\"{}\"}} {}\n",
+                    prometheus_labels(snapshot),
+                    escape_label_value(reason),
+                    failures
+                ));
+            }
+        }
+        metrics.push_str(
+            "# HELP horust_healthcheck_kills_total Times the service has been killed because of its failing healthchecks.\n\
+             # TYPE horust_healthcheck_kills_total counter\n",
+        );
+        for snapshot in &services {
+            metrics.push_str(&format!(
+                "horust_healthcheck_kills_total{{{}}} {}\n",
+                prometheus_labels(snapshot),
+                snapshot.healthcheck_failures
+            ));
+        }
+        metrics.push_str(
+            "# HELP horust_service_illegal_transitions_total Status changes which have been ignored, because they weren't allowed.\n\
+             # TYPE horust_service_illegal_transitions_total counter\n",
+        );
+        for snapshot in &services {
+            metrics.push_str(&format!(
+                "horust_service_illegal_transitions_total{{{}}} {}\n",
+                prometheus_labels(snapshot),
+                snapshot.illegal_transitions
+            ));
+        }
+        metrics.push_str(
+            "# HELP horust_service_cpu_user_seconds_total User CPU time of the processes which have exited.\n\
+             # TYPE horust_service_cpu_user_seconds_total counter\n",
+        );
+        for snapshot in &services {
+            metrics.push_str(&format!(
+                "horust_service_cpu_user_seconds_total{{{}}} {}\n",
+                prometheus_labels(snapshot),
+                snapshot.resource_usage.user_time.as_secs_f64()
+            ));
+        }
+        metrics.push_str(
+            "# HELP horust_service_cpu_system_seconds_total System CPU time of the processes which have exited.\n\
+             # TYPE horust_service_cpu_system_seconds_total counter\n",
+        );
+        for snapshot in &services {
+            metrics.push_str(&format!(
+                "horust_service_cpu_system_seconds_total{{{}}} {}\n",
+                prometheus_labels(snapshot),
+                snapshot.resource_usage.system_time.as_secs_f64()
+            ));
+        }
+        metrics.push_str(
+            "# HELP horust_service_max_rss_bytes Highest peak resident set size of the processes which have exited.\n\
+             # TYPE horust_service_max_rss_bytes gauge\n",
+        );
+        for snapshot in &services {
+            metrics.push_str(&format!(
+                "horust_service_max_rss_bytes{{{}}} {}\n",
+                prometheus_labels(snapshot),
+                snapshot.resource_usage.max_rss
+            ));
+        }
+        metrics.push_str(
+            "# HELP horust_service_minor_page_faults_total Minor page faults of the processes which have exited.\n\
+             # TYPE horust_service_minor_page_faults_total counter\n",
+        );
+        for snapshot in &services {
+            metrics.push_str(&format!(
+                "horust_service_minor_page_faults_total{{{}}} {}\n",
+                prometheus_labels(snapshot),
+                snapshot.resource_usage.minor_faults
+            ));
+        }
+        metrics.push_str(
+            "# HELP horust_service_major_page_faults_total Major page faults of the processes which have exited.\n\
+             # TYPE horust_service_major_page_faults_total counter\n",
+        );
+        for snapshot in &services {
+            metrics.push_str(&format!(
+                "horust_service_major_page_faults_total{{{}}} {}\n",
+                prometheus_labels(snapshot),
+                snapshot.resource_usage.major_faults
+            ));
+        }
+        metrics.push_str(
+            "# HELP horust_service_uptime_seconds For how long the current process has been running.\n\
+             # TYPE horust_service_uptime_seconds gauge\n",
+        );
+        for snapshot in &services {
+            if let Some(uptime) = snapshot.uptime() {
+                metrics.push_str(&format!(
+                    "horust_service_uptime_seconds{{{}}} {}\n",
+                    prometheus_labels(snapshot),
+                    uptime.as_secs_f64()
+                ));
+            }
+        }
+        metrics.push_str(
+            "# HELP horust_service_start_time_seconds Start time of the current process, since the unix epoch.\n\
+             # TYPE horust_service_start_time_seconds gauge\n",
+        );
+        for snapshot in &services {
+            let since_epoch = snapshot
+                .start_time
+                .and_then(|start_time| start_time.duration_since(UNIX_EPOCH).ok());
+            if let Some(since_epoch) = since_epoch {
+                metrics.push_str(&format!(
+                    "horust_service_start_time_seconds{{{}}} {}\n",
+                    prometheus_labels(snapshot),
+                    since_epoch.as_secs_f64()
+                ));
+            }
+        }
+        metrics
+    }
+}
+
+/// E.g. `service="web",tier="backend"`: the name of the service, followed by its labels.
+fn prometheus_labels(snapshot: &ServiceSnapshot) -> String {
+    std::iter::once(("service", snapshot.name.as_str()))
+        .chain(
+            snapshot
+                .labels
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str())),
+        )
+        .map(|(key, value)| format!("{}=\"{}\"", key, escape_label_value(value)))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// As required by the prometheus text format: e.g. a service name can contain any of them.
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// E.g. `web Running pid=42 restarts=0 starts=1 uptime=3.2s health=unhealthy latency=1.2ms
+/// failures=3 last-error="..." spawn-error="..."`
+fn format_status(snapshot: &ServiceSnapshot) -> String {
+    let pid = snapshot
+        .pid
+        .map_or_else(|| "-".to_string(), |pid| pid.to_string());
+    let (health, latency) = match &snapshot.last_health_check {
+        Some(report) if report.status == HealthinessStatus::Healthy => {
+            ("healthy", format!("{:?}", report.latency))
+        }
+        Some(report) => ("unhealthy", format!("{:?}", report.latency)),
+        None => ("unknown", "-".to_string()),
+    };
+    let last_error = snapshot
+        .last_health_error
+        .as_ref()
+        .map_or_else(|| "-".to_string(), |error| format!("{:?}", error));
+    let spawn_error = snapshot
+        .last_spawn_error
+        .as_ref()
+        .map_or_else(|| "-".to_string(), |error| format!("{:?}", error));
+    let uptime = snapshot
+        .uptime()
+        .map_or_else(|| "-".to_string(), |uptime| format!("{:?}", uptime));
+    format!(
+        "{} {} pid={} restarts={} starts={} uptime={} health={} latency={} failures={} \
+         last-error={} spawn-error={}\n",
+        snapshot.name,
+        snapshot.status,
+        pid,
+        snapshot.restart_attempts,
+        snapshot.starts,
+        uptime,
+        health,
+        latency,
+        snapshot.consecutive_failures,
+        last_error,
+        spawn_error
+    )
+}
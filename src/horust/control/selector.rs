@@ -0,0 +1,62 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+/// Selects the services by their labels, e.g. `tier=backend,team=payments`.
+/// A service is selected if it has all of these labels.
+#[derive(Debug, PartialEq)]
+pub(crate) struct LabelSelector(Vec<(String, String)>);
+
+impl LabelSelector {
+    pub(crate) fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.0
+            .iter()
+            .all(|(key, value)| labels.get(key) == Some(value))
+    }
+}
+
+impl FromStr for LabelSelector {
+    type Err = String;
+
+    fn from_str(selector: &str) -> Result<Self, Self::Err> {
+        selector
+            .split(',')
+            .map(
+                |pair| match pair.splitn(2, '=').collect::<Vec<&str>>()[..] {
+                    [key, value] if !key.trim().is_empty() => {
+                        Ok((key.trim().to_string(), value.trim().to_string()))
+                    }
+                    _ => Err(format!(
+                        "Invalid label selector: '{}', expected: key=value[,key=value]",
+                        selector
+                    )),
+                },
+            )
+            .collect::<Result<Vec<_>, _>>()
+            .map(LabelSelector)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::horust::control::selector::LabelSelector;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_label_selector() {
+        let labels = btreemap! {
+            "tier".to_string() => "backend".to_string(),
+            "team".to_string() => "payments".to_string(),
+        };
+        let selector = LabelSelector::from_str("tier=backend").unwrap();
+        assert!(selector.matches(&labels));
+        let selector = LabelSelector::from_str("tier=backend, team=payments").unwrap();
+        assert!(selector.matches(&labels));
+        let selector = LabelSelector::from_str("tier=backend,team=search").unwrap();
+        assert!(!selector.matches(&labels));
+        let selector = LabelSelector::from_str("zone=eu").unwrap();
+        assert!(!selector.matches(&labels));
+        assert!(LabelSelector::from_str("tier").is_err());
+        assert!(LabelSelector::from_str("=backend").is_err());
+        assert!(LabelSelector::from_str("").is_err());
+    }
+}
@@ -6,8 +6,21 @@ pub type Result<T> = std::result::Result<T, HorustError>;
 pub enum ErrorKind {
     Io(std::io::Error),
     SerDe(toml::de::Error),
+    Yaml(serde_yaml::Error),
+    /// A service (e.g. from a docker-compose file) cannot be translated.
+    Import(String),
+    /// The schema of a service file is not supported.
+    Schema(String),
+    /// The signature of a services directory is missing or invalid.
+    Signature(String),
+    /// A template expression (e.g. `{{ file "/path" }}`) cannot be evaluated.
+    Template(String),
+    /// A service file cannot be loaded: its path, and the reason.
+    ServiceFile(std::path::PathBuf, String),
     NullError(std::ffi::NulError),
     Nix(nix::Error),
+    /// The child process has failed to exec the command, or to set itself up before it.
+    Exec(nix::errno::Errno),
     ValidationError(Vec<ValidationError>),
 }
 
@@ -21,15 +34,46 @@ impl Display for HorustError {
         match &self.kind {
             ErrorKind::Io(error) => write!(f, "IoError: {}", error),
             ErrorKind::Nix(error) => write!(f, "NixError: {}", error),
+            ErrorKind::Exec(errno) => write!(f, "ExecError: {}", errno),
             ErrorKind::NullError(error) => write!(f, "NullError: {}", error),
             ErrorKind::SerDe(error) => write!(f, "Deserialization error(Serde): {}", error),
-            ErrorKind::ValidationError(error) => write!(f, "ValidationErrors: {:?}", error),
+            ErrorKind::Yaml(error) => write!(f, "Deserialization error(Yaml): {}", error),
+            ErrorKind::Import(error) => write!(f, "ImportError: {}", error),
+            ErrorKind::Schema(error) => write!(f, "SchemaError: {}", error),
+            ErrorKind::Signature(error) => write!(f, "SignatureError: {}", error),
+            ErrorKind::Template(error) => write!(f, "TemplateError: {}", error),
+            ErrorKind::ServiceFile(path, error) => write!(f, "{}: {}", path.display(), error),
+            ErrorKind::ValidationError(errors) => {
+                write!(f, "Invalid services:")?;
+                errors
+                    .iter()
+                    .try_for_each(|error| write!(f, "\n * {}", error))
+            }
         }
     }
 }
 
 impl std::error::Error for HorustError {}
 
+impl HorustError {
+    /// The errno of the system call which has failed, if any.
+    pub(crate) fn errno(&self) -> Option<i32> {
+        match &self.kind {
+            ErrorKind::Io(error) => error.raw_os_error(),
+            ErrorKind::Nix(nix::Error::Sys(errno)) | ErrorKind::Exec(errno) => Some(*errno as i32),
+            _ => None,
+        }
+    }
+
+    /// The errno reported by the child process, if it has failed before running the command.
+    pub(crate) fn exec_errno(&self) -> Option<i32> {
+        match &self.kind {
+            ErrorKind::Exec(errno) => Some(*errno as i32),
+            _ => None,
+        }
+    }
+}
+
 impl From<ErrorKind> for HorustError {
     fn from(kind: ErrorKind) -> HorustError {
         HorustError { kind }
@@ -44,6 +88,14 @@ impl From<toml::de::Error> for HorustError {
     }
 }
 
+impl From<serde_yaml::Error> for HorustError {
+    fn from(err: serde_yaml::Error) -> Self {
+        HorustError {
+            kind: ErrorKind::Yaml(err),
+        }
+    }
+}
+
 impl From<std::io::Error> for HorustError {
     fn from(err: std::io::Error) -> Self {
         HorustError {
@@ -86,6 +138,18 @@ pub struct ValidationError {
 pub enum ValidationErrorKind {
     MissingDependency,
     CommandEmpty,
+    InvalidWaitFor,
+    InvalidHealthiness,
+    InvalidReplicas,
+    InvalidListen,
+    InvalidLabels,
+    InvalidSandbox,
+    UnsupportedPlatform,
+    InvalidTarget,
+    InvalidAliases,
+    InvalidFailureThreshold,
+    InvalidTemplate,
+    InvalidSchedule,
 }
 
 impl std::error::Error for ValidationError {}
@@ -0,0 +1,100 @@
+//! A minimal init (`horust exec`): runs a single command without any service, reaps the
+//! zombies, forwards the signals to the command and exits with its exit code.
+use crate::horust::error::Result;
+#[cfg(target_os = "linux")]
+use libc::{prctl, PR_SET_CHILD_SUBREAPER};
+use nix::sys::signal::{kill, sigprocmask, SigSet, SigmaskHow, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{self, fork, ForkResult, Pid};
+use std::ffi::{CStr, CString};
+
+/// Raised by the kernel because of the process itself: they're not forwarded.
+const SYNCHRONOUS_SIGNALS: &[Signal] = &[
+    Signal::SIGFPE,
+    Signal::SIGILL,
+    Signal::SIGSEGV,
+    Signal::SIGBUS,
+    Signal::SIGABRT,
+    Signal::SIGTRAP,
+    Signal::SIGSYS,
+];
+
+/// Blocking call: runs the command and returns its exit code, or 128 + the signal which has
+/// terminated it (like a shell).
+pub fn exec(command: &[String]) -> Result<i32> {
+    let args = command
+        .iter()
+        .map(|arg| CString::new(arg.as_str()))
+        .collect::<std::result::Result<Vec<CString>, _>>()?;
+    // Orphans are reparented to horust instead of init, so that they can be reaped.
+    #[cfg(target_os = "linux")]
+    unsafe {
+        prctl(PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0);
+    }
+    // Blocked before forking, so no signal is missed: they're received using sigwait.
+    let signals = forwarded_signals();
+    let mut previous_mask = SigSet::empty();
+    sigprocmask(
+        SigmaskHow::SIG_BLOCK,
+        Some(&signals),
+        Some(&mut previous_mask),
+    )?;
+    match fork()? {
+        ForkResult::Child => {
+            let error = exec_child(&args, &previous_mask).unwrap_err();
+            eprintln!("Error running {:?}: {}", command, error);
+            unsafe { libc::_exit(127) };
+        }
+        ForkResult::Parent { child } => {
+            debug!("Running {:?}, pid: {}", command, child);
+            supervise(child, &signals)
+        }
+    }
+}
+
+/// Every signal which can be caught, apart from the synchronous ones.
+fn forwarded_signals() -> SigSet {
+    let mut signals = SigSet::empty();
+    Signal::iterator()
+        .filter(|signal| ![Signal::SIGKILL, Signal::SIGSTOP].contains(signal))
+        .filter(|signal| !SYNCHRONOUS_SIGNALS.contains(signal))
+        .for_each(|signal| signals.add(signal));
+    signals
+}
+
+/// The command gets its own process group, in the foreground of the terminal (if any): so the
+/// signals sent by the terminal (e.g. SIGINT on ctrl-c) are received only once.
+/// It returns only in case of error.
+fn exec_child(args: &[CString], previous_mask: &SigSet) -> nix::Result<()> {
+    unistd::setpgid(Pid::from_raw(0), Pid::from_raw(0))?;
+    // SIGTTOU is still blocked, so it's allowed even from the background.
+    let _ = unistd::tcsetpgrp(libc::STDIN_FILENO, unistd::getpid());
+    sigprocmask(SigmaskHow::SIG_SETMASK, Some(previous_mask), None)?;
+    let args: Vec<&CStr> = args.iter().map(|arg| arg.as_c_str()).collect();
+    unistd::execvp(args[0], &args)?;
+    Ok(())
+}
+
+/// Forwards the signals to the child and reaps every process, until the child has exited.
+fn supervise(child: Pid, signals: &SigSet) -> Result<i32> {
+    loop {
+        let signal = signals.wait()?;
+        if signal != Signal::SIGCHLD {
+            debug!("Forwarding {} to {}", signal, child);
+            if let Err(error) = kill(child, signal) {
+                warn!("Cannot forward {} to {}: {}", signal, child, error);
+            }
+            continue;
+        }
+        loop {
+            match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::Exited(pid, code)) if pid == child => return Ok(code),
+                Ok(WaitStatus::Signaled(pid, signal, _)) if pid == child => {
+                    return Ok(128 + signal as i32)
+                }
+                Ok(WaitStatus::StillAlive) | Err(_) => break,
+                Ok(status) => debug!("Reaped: {:?}", status),
+            }
+        }
+    }
+}
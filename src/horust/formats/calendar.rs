@@ -0,0 +1,579 @@
+//! Times of the day (`schedule.at`) in a timezone. The rules of a timezone are the POSIX TZ
+//! string at the end of its zoneinfo file (e.g. `CET-1CEST,M3.5.0,M10.5.0/3`), so the times
+//! are right in every year, across the changes to and from the daylight saving time.
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+/// Used if `TZDIR` isn't set.
+const ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+const LOCALTIME: &str = "/etc/localtime";
+
+/// A time of the day, like "03:00" or "23:59:30".
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct TimeOfDay {
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl TimeOfDay {
+    /// Seconds since midnight.
+    fn seconds(&self) -> i64 {
+        i64::from(self.hour) * 3600 + i64::from(self.minute) * 60 + i64::from(self.second)
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid time: '{}', expected e.g. 03:00 or 23:59:30", s);
+        let parts = s
+            .trim()
+            .split(':')
+            .map(|part| match part.len() {
+                1 | 2 => part.parse::<u8>().map_err(|_| invalid()),
+                _ => Err(invalid()),
+            })
+            .collect::<Result<Vec<u8>, String>>()?;
+        let (hour, minute, second) = match parts.as_slice() {
+            [hour, minute] => (*hour, *minute, 0),
+            [hour, minute, second] => (*hour, *minute, *second),
+            _ => return Err(invalid()),
+        };
+        if hour > 23 || minute > 59 || second > 59 {
+            return Err(invalid());
+        }
+        Ok(TimeOfDay {
+            hour,
+            minute,
+            second,
+        })
+    }
+}
+
+impl std::fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:02}:{:02}", self.hour, self.minute)?;
+        if self.second != 0 {
+            write!(f, ":{:02}", self.second)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for TimeOfDay {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeOfDay {
+    fn deserialize<D>(deserializer: D) -> Result<TimeOfDay, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(TimeOfDayVisitor)
+    }
+}
+
+struct TimeOfDayVisitor;
+impl<'de> Visitor<'de> for TimeOfDayVisitor {
+    type Value = TimeOfDay;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a time of the day like '03:00'")
+    }
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        TimeOfDay::from_str(value).map_err(de::Error::custom)
+    }
+}
+
+/// The day of a change to or from the daylight saving time.
+#[derive(Clone, Debug, PartialEq)]
+enum RuleDay {
+    /// `Jn`: 1 to 365, the 29th of February is never counted.
+    Julian(u16),
+    /// `n`: 0 to 365, the 29th of February is counted.
+    Ordinal(u16),
+    /// `Mm.w.d`: the day `d` (0 is Sunday) of the week `w` (5 is the last one) of the month `m`.
+    MonthWeekDay { month: u8, week: u8, weekday: u8 },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Rule {
+    day: RuleDay,
+    /// Local time of the change, in seconds (it can be negative, or past 24h).
+    time: i64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct DaylightSaving {
+    /// Seconds east of UTC.
+    offset: i64,
+    start: Rule,
+    end: Rule,
+}
+
+/// A timezone, as described by a POSIX TZ string.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timezone {
+    /// Seconds east of UTC.
+    std_offset: i64,
+    dst: Option<DaylightSaving>,
+}
+
+impl Timezone {
+    pub fn utc() -> Self {
+        Timezone {
+            std_offset: 0,
+            dst: None,
+        }
+    }
+
+    /// `name` is either a zoneinfo name (like "Europe/Rome") or a POSIX TZ string. Without a
+    /// name, it's the local timezone: the one in `TZ`, otherwise the one of `/etc/localtime`
+    /// (UTC if it's missing).
+    pub fn load(name: Option<&str>) -> Result<Self, String> {
+        let name = match name {
+            Some(name) => name.to_string(),
+            None => match env::var("TZ") {
+                Ok(tz) => tz.trim_start_matches(':').to_string(),
+                Err(_) if Path::new(LOCALTIME).exists() => {
+                    return Self::from_zoneinfo(Path::new(LOCALTIME))
+                }
+                Err(_) => return Ok(Self::utc()),
+            },
+        };
+        if name.is_empty() || name == "UTC" {
+            return Ok(Self::utc());
+        }
+        let zoneinfo_dir = env::var_os("TZDIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(ZONEINFO_DIR));
+        let path = zoneinfo_dir.join(&name);
+        if !name.starts_with('/') && !name.contains("..") && path.is_file() {
+            return Self::from_zoneinfo(&path);
+        }
+        Self::from_posix(&name).map_err(|error| format!("Unknown timezone: '{}' ({})", name, error))
+    }
+
+    /// The rules of the future are in the footer of the file (TZif version 2 or later).
+    fn from_zoneinfo(path: &Path) -> Result<Self, String> {
+        let invalid = |error: &str| format!("Invalid zoneinfo file {}: {}", path.display(), error);
+        let content = fs::read(path).map_err(|error| invalid(&error.to_string()))?;
+        if content.len() < 5 || &content[..4] != b"TZif" || content[4] < b'2' {
+            return Err(invalid("it should be a TZif file, version 2 or later"));
+        }
+        let content = content
+            .strip_suffix(b"\n")
+            .ok_or_else(|| invalid("missing footer"))?;
+        let footer = content
+            .rsplit(|byte| *byte == b'\n')
+            .next()
+            .and_then(|footer| std::str::from_utf8(footer).ok())
+            .ok_or_else(|| invalid("missing footer"))?;
+        if footer.is_empty() {
+            return Err(invalid("it has no rules for the current times"));
+        }
+        Self::from_posix(footer).map_err(|error| invalid(&error))
+    }
+
+    /// Parses a POSIX TZ string, like `UTC0` or `CET-1CEST,M3.5.0,M10.5.0/3`.
+    pub(crate) fn from_posix(tz: &str) -> Result<Self, String> {
+        let mut parser = PosixParser { rest: tz };
+        parser.name()?;
+        // Positive offsets are west of Greenwich.
+        let std_offset = -parser.offset()?;
+        if parser.rest.is_empty() {
+            return Ok(Timezone {
+                std_offset,
+                dst: None,
+            });
+        }
+        parser.name()?;
+        let dst_offset = match parser.rest.chars().next() {
+            Some(',') | None => std_offset + 3600,
+            _ => -parser.offset()?,
+        };
+        // Without rules, the ones of the US.
+        let rules = if parser.rest.is_empty() {
+            ",M3.2.0,M11.1.0"
+        } else {
+            parser.rest
+        };
+        parser.rest = rules;
+        parser.expect(',')?;
+        let start = parser.rule()?;
+        parser.expect(',')?;
+        let end = parser.rule()?;
+        if !parser.rest.is_empty() {
+            return Err(format!("unexpected '{}'", parser.rest));
+        }
+        Ok(Timezone {
+            std_offset,
+            dst: Some(DaylightSaving {
+                offset: dst_offset,
+                start,
+                end,
+            }),
+        })
+    }
+
+    /// Seconds east of UTC, at the instant `utc` (seconds since the epoch).
+    pub fn offset_at(&self, utc: i64) -> i64 {
+        let dst = match &self.dst {
+            Some(dst) => dst,
+            None => return self.std_offset,
+        };
+        let (year, _, _) = civil_from_days((utc + self.std_offset).div_euclid(SECONDS_PER_DAY));
+        // The start is in standard time, the end in daylight saving time.
+        let start = dst.start.local_seconds(year) - self.std_offset;
+        let end = dst.end.local_seconds(year) - dst.offset;
+        let in_dst = if start < end {
+            start <= utc && utc < end
+        } else {
+            // In the southern hemisphere, it's across the new year.
+            utc < end || start <= utc
+        };
+        if in_dst {
+            dst.offset
+        } else {
+            self.std_offset
+        }
+    }
+
+    /// The first instant after `after` (both in seconds since the epoch) when the local time is
+    /// `time`. When the clocks go back, a time happening twice is taken once, the first time;
+    /// when they go forward, a skipped time is taken as if the clocks hadn't changed yet (e.g.
+    /// 02:30 is 03:30 in the new time).
+    pub fn next(&self, time: &TimeOfDay, after: i64) -> i64 {
+        let offsets = match &self.dst {
+            Some(dst) => vec![self.std_offset, dst.offset],
+            None => vec![self.std_offset],
+        };
+        let today = (after + self.offset_at(after)).div_euclid(SECONDS_PER_DAY);
+        (today - 1..)
+            .map(|day| {
+                let local = day * SECONDS_PER_DAY + time.seconds();
+                offsets
+                    .iter()
+                    .map(|offset| local - offset)
+                    .filter(|utc| utc + self.offset_at(*utc) == local)
+                    .min()
+                    // The clocks go forward: the offset is still the one before, the smaller.
+                    .unwrap_or_else(|| local - offsets.iter().min().unwrap())
+            })
+            .find(|utc| *utc > after)
+            .unwrap()
+    }
+}
+
+impl Rule {
+    /// Local seconds since the epoch of the change, in `year`.
+    fn local_seconds(&self, year: i64) -> i64 {
+        let day = match self.day {
+            RuleDay::Julian(day) => {
+                let day = i64::from(day);
+                let leap_day = if is_leap(year) && day >= 60 { 1 } else { 0 };
+                days_from_civil(year, 1, 1) + day - 1 + leap_day
+            }
+            RuleDay::Ordinal(day) => days_from_civil(year, 1, 1) + i64::from(day),
+            RuleDay::MonthWeekDay {
+                month,
+                week,
+                weekday,
+            } => {
+                let first = days_from_civil(year, month, 1);
+                let mut day = (i64::from(weekday) - weekday_of(first)).rem_euclid(7)
+                    + 7 * (i64::from(week) - 1);
+                while day >= days_in_month(year, month) {
+                    day -= 7;
+                }
+                first + day
+            }
+        };
+        day * SECONDS_PER_DAY + self.time
+    }
+}
+
+struct PosixParser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> PosixParser<'a> {
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        match self.rest.strip_prefix(c) {
+            Some(rest) => {
+                self.rest = rest;
+                Ok(())
+            }
+            None => Err(format!("expected '{}' at '{}'", c, self.rest)),
+        }
+    }
+
+    /// Either 3 or more letters, or anything between '<' and '>' (e.g. `<+03>`).
+    fn name(&mut self) -> Result<&'a str, String> {
+        let (name, rest) = if let Some(quoted) = self.rest.strip_prefix('<') {
+            let end = quoted
+                .find('>')
+                .ok_or_else(|| "unterminated name".to_string())?;
+            (&quoted[..end], &quoted[end + 1..])
+        } else {
+            let end = self
+                .rest
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(self.rest.len());
+            self.rest.split_at(end)
+        };
+        if name.len() < 3 {
+            return Err(format!("invalid timezone name at '{}'", self.rest));
+        }
+        self.rest = rest;
+        Ok(name)
+    }
+
+    fn number(&mut self) -> Result<i64, String> {
+        let end = self
+            .rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.rest.len());
+        let (digits, rest) = self.rest.split_at(end);
+        let number = digits
+            .parse()
+            .map_err(|_| format!("expected a number at '{}'", self.rest))?;
+        self.rest = rest;
+        Ok(number)
+    }
+
+    /// `[+-]hh[:mm[:ss]]`, in seconds.
+    fn offset(&mut self) -> Result<i64, String> {
+        let sign = if let Some(rest) = self.rest.strip_prefix('-') {
+            self.rest = rest;
+            -1
+        } else {
+            self.rest = self.rest.strip_prefix('+').unwrap_or(self.rest);
+            1
+        };
+        let mut seconds = self.number()? * 3600;
+        for unit in &[60, 1] {
+            if self.rest.starts_with(':') {
+                self.expect(':')?;
+                seconds += self.number()? * unit;
+            }
+        }
+        if seconds > 167 * 3600 {
+            return Err("the offset is too big".to_string());
+        }
+        Ok(sign * seconds)
+    }
+
+    /// `Jn`, `n` or `Mm.w.d`, optionally followed by `/time` (02:00 by default).
+    fn rule(&mut self) -> Result<Rule, String> {
+        let day = if let Some(rest) = self.rest.strip_prefix('J') {
+            self.rest = rest;
+            match self.number()? {
+                day @ 1..=365 => RuleDay::Julian(day as u16),
+                _ => return Err("the day of Jn should be between 1 and 365".to_string()),
+            }
+        } else if let Some(rest) = self.rest.strip_prefix('M') {
+            self.rest = rest;
+            let month = self.number()?;
+            self.expect('.')?;
+            let week = self.number()?;
+            self.expect('.')?;
+            let weekday = self.number()?;
+            if !(1..=12).contains(&month) || !(1..=5).contains(&week) || weekday > 6 {
+                return Err("invalid Mm.w.d rule".to_string());
+            }
+            RuleDay::MonthWeekDay {
+                month: month as u8,
+                week: week as u8,
+                weekday: weekday as u8,
+            }
+        } else {
+            match self.number()? {
+                day @ 0..=365 => RuleDay::Ordinal(day as u16),
+                _ => return Err("the day should be between 0 and 365".to_string()),
+            }
+        };
+        let time = if self.rest.starts_with('/') {
+            self.expect('/')?;
+            self.offset()?
+        } else {
+            2 * 3600
+        };
+        Ok(Rule { day, time })
+    }
+}
+
+fn is_leap(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: u8) -> i64 {
+    match month {
+        2 if is_leap(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// 0 is Sunday.
+fn weekday_of(days: i64) -> i64 {
+    // The 1st of January 1970 was a Thursday.
+    (days + 4).rem_euclid(7)
+}
+
+/// Days since the epoch of a date (proleptic gregorian calendar).
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let (month, day) = (i64::from(month), i64::from(day));
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The date of a day since the epoch.
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u8;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{civil_from_days, days_from_civil, TimeOfDay, Timezone};
+    use std::str::FromStr;
+
+    /// Seconds since the epoch of a UTC date and time.
+    fn utc(year: i64, month: u8, day: u8, hour: i64, minute: i64) -> i64 {
+        days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60
+    }
+
+    fn at(time: &str) -> TimeOfDay {
+        TimeOfDay::from_str(time).unwrap()
+    }
+
+    #[test]
+    fn test_time_of_day() {
+        assert_eq!(at("3:00").to_string(), "03:00");
+        assert_eq!(at("23:59:30").to_string(), "23:59:30");
+        vec![
+            "24:00",
+            "12:60",
+            "12",
+            "12:00:00:00",
+            "noon",
+            "-1:00",
+            "012:00",
+        ]
+        .into_iter()
+        .for_each(|time| {
+            TimeOfDay::from_str(time).unwrap_err();
+        });
+    }
+
+    #[test]
+    fn test_civil_days() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(civil_from_days(11017), (2000, 3, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        (-800_000..800_000).step_by(97).for_each(|days| {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        });
+    }
+
+    #[test]
+    fn test_posix_timezones() {
+        Timezone::from_posix("CET").unwrap_err();
+        Timezone::from_posix("CET-1CEST,M3.5.0").unwrap_err();
+        Timezone::from_posix("CET-1CEST,M13.5.0,M10.5.0/3").unwrap_err();
+        Timezone::from_posix("CET-1CEST,M3.5.0,M10.5.0/3x").unwrap_err();
+        assert_eq!(Timezone::from_posix("UTC0").unwrap(), Timezone::utc());
+        assert_eq!(Timezone::load(Some("UTC")).unwrap(), Timezone::utc());
+
+        let india = Timezone::from_posix("IST-5:30").unwrap();
+        assert_eq!(india.offset_at(0), 5 * 3600 + 1800);
+        let quoted = Timezone::from_posix("<-03>3").unwrap();
+        assert_eq!(quoted.offset_at(0), -3 * 3600);
+
+        // The changes are at 01:00 UTC, in both directions.
+        let rome = Timezone::from_posix("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        assert_eq!(rome.offset_at(utc(2021, 3, 28, 0, 59)), 3600);
+        assert_eq!(rome.offset_at(utc(2021, 3, 28, 1, 0)), 7200);
+        assert_eq!(rome.offset_at(utc(2021, 10, 31, 0, 59)), 7200);
+        assert_eq!(rome.offset_at(utc(2021, 10, 31, 1, 0)), 3600);
+
+        // Daylight saving time across the new year, with the times since the first of January.
+        let auckland = Timezone::from_posix("NZST-12NZDT,M9.5.0,M4.1.0/3").unwrap();
+        assert_eq!(auckland.offset_at(utc(2021, 1, 10, 0, 0)), 13 * 3600);
+        assert_eq!(auckland.offset_at(utc(2021, 7, 10, 0, 0)), 12 * 3600);
+        let days = Timezone::from_posix("XST-1XDT,J60,300").unwrap();
+        assert_eq!(days.offset_at(utc(2021, 3, 1, 12, 0)), 7200);
+        assert_eq!(days.offset_at(utc(2020, 2, 29, 12, 0)), 3600);
+        assert_eq!(days.offset_at(utc(2021, 10, 28, 12, 0)), 3600);
+    }
+
+    #[test]
+    fn test_next_across_daylight_saving_changes() {
+        let rome = Timezone::from_posix("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        let three = at("03:00");
+        // Winter, then summer.
+        let next = rome.next(&three, utc(2021, 1, 10, 12, 0));
+        assert_eq!(next, utc(2021, 1, 11, 2, 0));
+        assert_eq!(rome.next(&three, next), utc(2021, 1, 12, 2, 0));
+        assert_eq!(
+            rome.next(&three, utc(2021, 7, 10, 12, 0)),
+            utc(2021, 7, 11, 1, 0)
+        );
+
+        // The clocks go forward at 02:00 to 03:00: 03:00 is the first instant of the new time,
+        // and 02:30 doesn't exist, so it's taken as 03:30.
+        let before_change = utc(2021, 3, 27, 12, 0);
+        assert_eq!(rome.next(&three, before_change), utc(2021, 3, 28, 1, 0));
+        let skipped = rome.next(&at("02:30"), before_change);
+        assert_eq!(skipped, utc(2021, 3, 28, 1, 30));
+        assert_eq!(rome.next(&at("02:30"), skipped), utc(2021, 3, 29, 0, 30));
+
+        // The clocks go back at 03:00 to 02:00: 02:30 happens twice, and it's taken once.
+        let before_change = utc(2021, 10, 30, 12, 0);
+        let twice = rome.next(&at("02:30"), before_change);
+        assert_eq!(twice, utc(2021, 10, 31, 0, 30));
+        assert_eq!(rome.next(&at("02:30"), twice), utc(2021, 11, 1, 1, 30));
+        assert_eq!(rome.next(&three, before_change), utc(2021, 10, 31, 2, 0));
+
+        // One day apart, in UTC too.
+        let utc_tz = Timezone::utc();
+        let next = utc_tz.next(&three, utc(2021, 12, 31, 3, 0));
+        assert_eq!(next, utc(2022, 1, 1, 3, 0));
+    }
+}
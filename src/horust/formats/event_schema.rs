@@ -0,0 +1,438 @@
+//! Stable, machine readable representation of the events, for the consumers outside of horust:
+//! `horustctl events --json`, the plugins and the collector. `Event` is internal and changes
+//! freely: only the records below are part of the schema, and changing them in a way which could
+//! break a consumer requires a new `EVENTS_SCHEMA_VERSION`.
+use crate::horust::formats::{Event, HealthinessStatus, ServiceName, ServiceStatus};
+use serde::{Serialize, Serializer};
+use serde_json::{json, Value};
+
+/// The version of the records produced by this version of horust.
+pub const EVENTS_SCHEMA_VERSION: u32 = 1;
+/// The versions the consumers can ask for.
+const SUPPORTED_VERSIONS: &[u32] = &[1];
+
+/// Sent first to the consumers of a stream of records (e.g. `{"schema-version":1}`).
+#[derive(Debug, Serialize, PartialEq)]
+pub(crate) struct Header {
+    #[serde(rename = "schema-version")]
+    pub(crate) schema_version: u32,
+}
+
+/// The version of the schema to use: the requested one if it's supported, or the latest one.
+pub(crate) fn negotiate(requested: Option<&str>) -> Result<u32, String> {
+    let requested = match requested {
+        Some(requested) => requested,
+        None => return Ok(EVENTS_SCHEMA_VERSION),
+    };
+    requested
+        .parse::<u32>()
+        .ok()
+        .filter(|version| SUPPORTED_VERSIONS.contains(version))
+        .ok_or_else(|| {
+            format!(
+                "Unsupported events schema version: '{}', supported: {:?}",
+                requested, SUPPORTED_VERSIONS
+            )
+        })
+}
+
+/// Statuses are named after their Display, which is part of the schema as well.
+fn status_name<S: Serializer>(status: &ServiceStatus, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(status)
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub(crate) struct ServiceState {
+    service: ServiceName,
+    #[serde(serialize_with = "status_name")]
+    status: ServiceStatus,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub(crate) enum EventRecord {
+    PidChanged {
+        service: ServiceName,
+        pid: i32,
+    },
+    PidAdopted {
+        service: ServiceName,
+        pid: i32,
+    },
+    ServiceStarted {
+        service: ServiceName,
+    },
+    StatusChanged {
+        service: ServiceName,
+        #[serde(serialize_with = "status_name")]
+        status: ServiceStatus,
+    },
+    ServiceExited {
+        service: ServiceName,
+        #[serde(rename = "exit-code")]
+        exit_code: i32,
+    },
+    RunEnded {
+        service: ServiceName,
+        /// Rfc3339, e.g. `2020-05-01T10:00:00Z`.
+        start: Option<String>,
+        #[serde(rename = "duration-ms")]
+        duration_ms: Option<u64>,
+        #[serde(rename = "exit-code")]
+        exit_code: i32,
+        /// E.g. `SIGKILL`.
+        signal: Option<String>,
+    },
+    SpawnFailed {
+        service: ServiceName,
+        /// E.g. `not-found`.
+        reason: String,
+        error: String,
+    },
+    ExecFailed {
+        service: ServiceName,
+        errno: i32,
+    },
+    HealthChecked {
+        service: ServiceName,
+        healthy: bool,
+        #[serde(rename = "latency-ms")]
+        latency_ms: u64,
+        error: Option<String>,
+    },
+    HealthcheckFailed {
+        service: ServiceName,
+        error: Option<String>,
+    },
+    LogPatternMatched {
+        service: ServiceName,
+    },
+    ServiceCreated {
+        service: ServiceName,
+    },
+    FaultInjected {
+        service: ServiceName,
+        fault: String,
+    },
+    ShuttingDown {
+        reason: String,
+    },
+    StateSnapshot {
+        services: Vec<ServiceState>,
+    },
+}
+
+impl EventRecord {
+    /// None for the events which are internal to horust, e.g. the requests between components.
+    /// Every event is listed: a new one has to be either added to the schema or left out
+    /// on purpose.
+    pub(crate) fn from_event(ev: &Event) -> Option<Self> {
+        let record = match ev {
+            Event::PidChanged(service, pid) => EventRecord::PidChanged {
+                service: service.clone(),
+                pid: pid.as_raw(),
+            },
+            Event::PidAdopted(service, pid) => EventRecord::PidAdopted {
+                service: service.clone(),
+                pid: pid.as_raw(),
+            },
+            Event::ServiceStarted(service) => EventRecord::ServiceStarted {
+                service: service.clone(),
+            },
+            Event::StatusChanged(service, status) => EventRecord::StatusChanged {
+                service: service.clone(),
+                status: status.clone(),
+            },
+            Event::ServiceExited(service, exit_code) => EventRecord::ServiceExited {
+                service: service.clone(),
+                exit_code: *exit_code,
+            },
+            Event::RunEnded(service, run) => EventRecord::RunEnded {
+                service: service.clone(),
+                start: run
+                    .start_time
+                    .map(|start| humantime::format_rfc3339_seconds(start).to_string()),
+                duration_ms: run.duration.map(|duration| duration.as_millis() as u64),
+                exit_code: run.exit_code,
+                signal: run.signal().map(|signal| format!("{:?}", signal)),
+            },
+            Event::SpawnFailed(service, error) => EventRecord::SpawnFailed {
+                service: service.clone(),
+                reason: error.key().to_string(),
+                error: error.to_string(),
+            },
+            Event::ExecFailed(service, errno) => EventRecord::ExecFailed {
+                service: service.clone(),
+                errno: *errno,
+            },
+            Event::HealthCheck(service, report) => EventRecord::HealthChecked {
+                service: service.clone(),
+                healthy: report.status == HealthinessStatus::Healthy,
+                latency_ms: report.latency.as_millis() as u64,
+                error: report.error.clone(),
+            },
+            Event::HealthcheckFailed(service, error) => EventRecord::HealthcheckFailed {
+                service: service.clone(),
+                error: error.clone(),
+            },
+            Event::LogPatternMatched(service) => EventRecord::LogPatternMatched {
+                service: service.clone(),
+            },
+            Event::ServiceCreated(service) => EventRecord::ServiceCreated {
+                service: service.name.clone(),
+            },
+            Event::FaultInjected(service, fault) => EventRecord::FaultInjected {
+                service: service.clone(),
+                fault: fault.to_string(),
+            },
+            Event::ShuttingDownInitiated(reason) => EventRecord::ShuttingDown {
+                reason: reason.to_string(),
+            },
+            Event::StateSnapshot(statuses) => EventRecord::StateSnapshot {
+                services: statuses
+                    .iter()
+                    .map(|(service, status)| ServiceState {
+                        service: service.clone(),
+                        status: status.clone(),
+                    })
+                    .collect(),
+            },
+            Event::ForceKill(_)
+            | Event::PidNeverAppeared(_)
+            | Event::Kill(_)
+            | Event::Run(_)
+            | Event::StartRequested(_)
+            | Event::Activate(_)
+            | Event::Ping(_)
+            | Event::Scale(_, _)
+            | Event::CreationDecided(_, _)
+            | Event::Restart(_, _)
+            | Event::Stop(_)
+            | Event::Mask(_)
+            | Event::Unmask(_)
+            | Event::RequestSnapshot => return None,
+        };
+        Some(record)
+    }
+}
+
+const STATUSES: &[ServiceStatus] = &[
+    ServiceStatus::Initial,
+    ServiceStatus::Starting,
+    ServiceStatus::Started,
+    ServiceStatus::Running,
+    ServiceStatus::InKilling,
+    ServiceStatus::Success,
+    ServiceStatus::Finished,
+    ServiceStatus::FinishedFailed,
+    ServiceStatus::PermanentlyFailed,
+    ServiceStatus::Failed,
+    ServiceStatus::Idle,
+    ServiceStatus::Disabled,
+];
+
+/// The schema of a record: all of its properties are always there (null if they're missing).
+fn record_schema(event: &str, properties: Value) -> Value {
+    let mut all = serde_json::Map::new();
+    all.insert("event".into(), json!({ "const": event }));
+    if let Value::Object(properties) = properties {
+        all.extend(properties);
+    }
+    let required: Vec<String> = all.keys().cloned().collect();
+    json!({
+        "type": "object",
+        "properties": all,
+        "required": required,
+        "additionalProperties": false,
+    })
+}
+
+/// The json schema (draft 07) of the records, e.g. for validating them or for generating the
+/// types of a consumer.
+pub fn events_schema() -> String {
+    let service = json!({ "$ref": "#/definitions/service" });
+    let status = json!({ "$ref": "#/definitions/status" });
+    let nullable = |kind: &str| json!({ "type": [kind, "null"] });
+    let integer = json!({ "type": "integer" });
+    let string = json!({ "type": "string" });
+    let statuses: Vec<String> = STATUSES.iter().map(ToString::to_string).collect();
+    let records = vec![
+        record_schema("pid-changed", json!({ "service": service, "pid": integer })),
+        record_schema("pid-adopted", json!({ "service": service, "pid": integer })),
+        record_schema("service-started", json!({ "service": service })),
+        record_schema(
+            "status-changed",
+            json!({ "service": service, "status": status }),
+        ),
+        record_schema(
+            "service-exited",
+            json!({ "service": service, "exit-code": integer }),
+        ),
+        record_schema(
+            "run-ended",
+            json!({
+                "service": service,
+                "start": nullable("string"),
+                "duration-ms": nullable("integer"),
+                "exit-code": integer,
+                "signal": nullable("string"),
+            }),
+        ),
+        record_schema(
+            "spawn-failed",
+            json!({ "service": service, "reason": string, "error": string }),
+        ),
+        record_schema(
+            "exec-failed",
+            json!({ "service": service, "errno": integer }),
+        ),
+        record_schema(
+            "health-checked",
+            json!({
+                "service": service,
+                "healthy": { "type": "boolean" },
+                "latency-ms": integer,
+                "error": nullable("string"),
+            }),
+        ),
+        record_schema(
+            "healthcheck-failed",
+            json!({ "service": service, "error": nullable("string") }),
+        ),
+        record_schema("log-pattern-matched", json!({ "service": service })),
+        record_schema("service-created", json!({ "service": service })),
+        record_schema(
+            "fault-injected",
+            json!({ "service": service, "fault": string }),
+        ),
+        record_schema("shutting-down", json!({ "reason": string })),
+        record_schema(
+            "state-snapshot",
+            json!({
+                "services": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": { "service": service, "status": status },
+                        "required": ["service", "status"],
+                        "additionalProperties": false,
+                    },
+                },
+            }),
+        ),
+    ];
+    let schema = json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": format!("Horust events, schema version {}", EVENTS_SCHEMA_VERSION),
+        "schema-version": EVENTS_SCHEMA_VERSION,
+        "definitions": {
+            "service": { "type": "string" },
+            "status": { "enum": statuses },
+        },
+        "oneOf": records,
+    });
+    serde_json::to_string_pretty(&schema).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{events_schema, negotiate, EventRecord, STATUSES};
+    use crate::horust::formats::{
+        Event, Fault, HealthCheckReport, HealthinessStatus, RunRecord, Service, ServiceStatus,
+        ShutdownReason, SpawnError,
+    };
+    use nix::unistd::Pid;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_event_records() {
+        let web = || "web.toml".to_string();
+        let events = vec![
+            Event::PidChanged(web(), Pid::from_raw(42)),
+            Event::PidAdopted(web(), Pid::from_raw(42)),
+            Event::ServiceStarted(web()),
+            Event::StatusChanged(web(), ServiceStatus::Running),
+            Event::ServiceExited(web(), 1),
+            Event::RunEnded(
+                web(),
+                RunRecord {
+                    start_time: Some(UNIX_EPOCH),
+                    duration: Some(Duration::from_millis(1500)),
+                    exit_code: 137,
+                },
+            ),
+            Event::SpawnFailed(web(), SpawnError::NotFound),
+            Event::ExecFailed(web(), 2),
+            Event::HealthCheck(
+                web(),
+                HealthCheckReport {
+                    status: HealthinessStatus::Unhealthy,
+                    latency: Duration::from_millis(3),
+                    error: Some("http-endpoint: 503".into()),
+                },
+            ),
+            Event::HealthcheckFailed(web(), None),
+            Event::LogPatternMatched(web()),
+            Event::ServiceCreated(Box::new(Service::from_name("web.toml-1"))),
+            Event::FaultInjected(web(), Fault::Killed(Pid::from_raw(42))),
+            Event::ShuttingDownInitiated(ShutdownReason::ControlCommand),
+            Event::StateSnapshot(
+                STATUSES
+                    .iter()
+                    .map(|status| (web(), status.clone()))
+                    .collect(),
+            ),
+        ];
+        let schema: serde_json::Value = serde_json::from_str(&events_schema()).unwrap();
+        let schemas = schema["oneOf"].as_array().unwrap();
+        assert_eq!(schemas.len(), events.len());
+        // Every record matches the schema of its event: all of its properties, and only them.
+        for ev in &events {
+            let record = serde_json::to_value(EventRecord::from_event(ev).unwrap()).unwrap();
+            let record = record.as_object().unwrap();
+            let schema = schemas
+                .iter()
+                .find(|schema| schema["properties"]["event"]["const"] == record["event"])
+                .unwrap_or_else(|| panic!("No schema for {:?}", record));
+            let mut required: Vec<&str> = schema["required"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|key| key.as_str().unwrap())
+                .collect();
+            let mut keys: Vec<&str> = record.keys().map(String::as_str).collect();
+            required.sort_unstable();
+            keys.sort_unstable();
+            assert_eq!(keys, required, "{:?}", record);
+        }
+        let run_ended = EventRecord::from_event(&events[5]).unwrap();
+        assert_eq!(
+            serde_json::to_string(&run_ended).unwrap(),
+            r#"{"event":"run-ended","service":"web.toml","start":"1970-01-01T00:00:00Z","duration-ms":1500,"exit-code":137,"signal":"SIGKILL"}"#
+        );
+        let snapshot = serde_json::to_value(EventRecord::from_event(&events[14]).unwrap()).unwrap();
+        let statuses: Vec<&serde_json::Value> = snapshot["services"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|state| &state["status"])
+            .collect();
+        assert_eq!(
+            statuses,
+            schema["definitions"]["status"]["enum"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>()
+        );
+        assert!(EventRecord::from_event(&Event::Ping(1)).is_none());
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(None), Ok(1));
+        assert_eq!(negotiate(Some("1")), Ok(1));
+        negotiate(Some("2")).unwrap_err();
+        negotiate(Some("latest")).unwrap_err();
+    }
+}
@@ -1,13 +1,128 @@
+use crate::horust::collector::Endpoint;
 use crate::horust::error::Result;
+use crate::horust::formats::{Service, ServiceName};
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 use structopt::StructOpt;
 
-#[derive(Debug, StructOpt, Serialize, Deserialize)]
+const DEFAULT_SPAWN_PARALLELISM: usize = 4;
+const DEFAULT_TICK_INTERVAL: Duration = Duration::from_millis(300);
+const DEFAULT_COLLECTOR_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, StructOpt, Serialize, Deserialize, Default)]
+#[serde(rename_all = "kebab-case", default)]
 pub struct HorustConfig {
     #[structopt(long)]
     /// Exits with an unsuccessful exit code if any process is in FinishedFailed state
+    // Its name before the keys have become kebab-case.
+    #[serde(alias = "unsuccessful_exit_finished_failed")]
     pub unsuccessful_exit_finished_failed: bool,
+    #[structopt(long)]
+    /// Create a control socket in this path, used by `horustctl`. Disabled by default.
+    pub control_socket: Option<PathBuf>,
+    #[structopt(long)]
+    /// What to do when reaping a process which doesn't belong to any service: log|ignore|kill-parent-service.
+    /// Log by default.
+    pub unknown_children: Option<UnknownChildrenPolicy>,
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    /// Once the shutdown has started, SIGKILL everything still running after this time (e.g. `30s`). Disabled by default.
+    #[serde(with = "humantime_serde")]
+    pub shutdown_timeout: Option<Duration>,
+    #[structopt(long = "profile", number_of_values = 1)]
+    /// Also run the services having this profile. Can be repeated.
+    pub profiles: Vec<String>,
+    #[structopt(long)]
+    /// On SIGQUIT, append the state of horust to this file instead of writing it to stderr.
+    pub state_dump_path: Option<PathBuf>,
+    #[structopt(long)]
+    /// Persist the services masked using `horustctl mask` in this directory, so they stay masked
+    /// across restarts of horust. Disabled by default.
+    pub state_directory: Option<PathBuf>,
+    #[structopt(long)]
+    /// Keep a json snapshot of the state of all the services in this file (e.g.
+    /// `/run/horust/state.json`), rewritten whenever it changes. Disabled by default.
+    pub state_file: Option<PathBuf>,
+    #[structopt(long)]
+    /// Create an empty file named after every Running service in this directory (e.g.
+    /// `/run/horust/ready`), removed once it stops. Disabled by default.
+    pub ready_directory: Option<PathBuf>,
+    #[structopt(long)]
+    /// Keep the running services (by name and aliases) mapped to 127.0.0.1 in a block of this
+    /// hosts file, e.g. `/etc/hosts`: its other lines are left alone. Disabled by default.
+    pub hosts_file: Option<PathBuf>,
+    #[structopt(long, default_value = "fail")]
+    /// What to do with the services depending on a disabled (or masked) service: fail|ignore
+    pub disabled_dependencies: DisabledDependencyPolicy,
+    #[structopt(long)]
+    /// Verify the signature of the services directories (`services.minisig`) using this minisign
+    /// public key, before loading them. Only from the command line: the config file isn't signed.
+    pub config_public_key: Option<PathBuf>,
+    #[structopt(long)]
+    /// Refuse to load the services directories which are not signed. Needs config-public-key.
+    /// Only from the command line: the config file isn't signed.
+    pub require_signed_config: bool,
+    #[structopt(long)]
+    /// Append a record of every command run (services and hooks) to this file, one json object
+    /// per line. Disabled by default.
+    pub audit_log: Option<PathBuf>,
+    #[structopt(long)]
+    /// Run this command before creating instances of a service at runtime, and before adopting a
+    /// process: unless it exits successfully, the action is denied. Disabled by default.
+    pub policy_hook: Option<String>,
+    #[structopt(long)]
+    /// How many services can be forked and exec'd at the same time. The others wait in a queue,
+    /// so restarting a lot of services at once doesn't end up in a fork storm. 4 by default.
+    pub spawn_parallelism: Option<usize>,
+    #[structopt(long, parse(try_from_str = parse_tick_interval))]
+    /// How often the runtime checks the services: the resolution of the timers (force-kills,
+    /// start-delays and backoffs, healthchecks). Lower values trade CPU for responsiveness.
+    /// 300ms by default.
+    #[serde(with = "humantime_serde")]
+    pub tick_interval: Option<Duration>,
+    #[structopt(long)]
+    /// Shut down if a core component (the runtime, the healthchecks) stops consuming the events
+    /// of the bus. By default it's only logged.
+    pub shutdown_on_stalled_component: bool,
+    #[structopt(long)]
+    /// Print the progress of the boot on stdout: a line for every service reaching a state, and a
+    /// summary once all of them have settled. Colored, with a spinner, on a terminal.
+    pub boot_progress: bool,
+    #[structopt(long)]
+    /// Report the state changes and the metrics of the services to this http endpoint (e.g.
+    /// `http://collector:9000/horust`), for observing a fleet of containers from one place.
+    /// Disabled by default.
+    pub collector: Option<String>,
+    #[structopt(long)]
+    /// The name of this node in the reports sent to the collector. Default: the hostname.
+    pub collector_node: Option<String>,
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    /// How often the metrics are reported to the collector, even if nothing has changed. 10s by
+    /// default.
+    #[serde(with = "humantime_serde")]
+    pub collector_interval: Option<Duration>,
+    #[structopt(long)]
+    /// Export the spans of horust (the runs of the services, the events) to this OpenTelemetry
+    /// collector, over OTLP/HTTP (e.g. `http://otel-collector:4318`). Needs the `otlp` feature.
+    /// Disabled by default.
+    pub otlp_endpoint: Option<String>,
+    #[structopt(long = "chaos")]
+    #[serde(skip)]
+    /// Inject faults in the services as configured in the `[chaos]` section of the config file,
+    /// for resilience testing. Never use it in production.
+    pub enable_chaos: bool,
+    #[structopt(skip)]
+    /// Fault injection, enabled by `--chaos` (or by its `enabled` key).
+    pub chaos: ChaosConfig,
+    #[structopt(skip)]
+    /// External programs joining the bus as components (`[[plugins]]`), e.g. custom notifiers.
+    pub plugins: Vec<Plugin>,
+    #[structopt(skip)]
+    /// Services defined in the config file itself (`[services.<name>]`), instead of one file per
+    /// service. Named after their table, unless they have a `name`.
+    pub services: BTreeMap<String, Service>,
 }
 
 impl HorustConfig {
@@ -24,16 +139,451 @@ impl HorustConfig {
         let unsuccessful_exit_finished_failed = cmd_line.unsuccessful_exit_finished_failed
             || config_file.unsuccessful_exit_finished_failed;
 
+        let control_socket = cmd_line.control_socket.or(config_file.control_socket);
+        let unknown_children = cmd_line.unknown_children.or(config_file.unknown_children);
+
+        let shutdown_timeout = cmd_line.shutdown_timeout.or(config_file.shutdown_timeout);
+        let profiles = if cmd_line.profiles.is_empty() {
+            config_file.profiles
+        } else {
+            cmd_line.profiles
+        };
+        let state_dump_path = cmd_line.state_dump_path.or(config_file.state_dump_path);
+        let state_directory = cmd_line.state_directory.or(config_file.state_directory);
+        let state_file = cmd_line.state_file.or(config_file.state_file);
+        let ready_directory = cmd_line.ready_directory.or(config_file.ready_directory);
+        let hosts_file = cmd_line.hosts_file.or(config_file.hosts_file);
+        let disabled_dependencies =
+            if cmd_line.disabled_dependencies != DisabledDependencyPolicy::default() {
+                cmd_line.disabled_dependencies
+            } else {
+                config_file.disabled_dependencies
+            };
+
+        // Anyone able to edit the config file could drop the key otherwise.
+        if config_file.config_public_key.is_some() || config_file.require_signed_config {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "config-public-key and require-signed-config can only be passed on the command \
+                 line: the config file isn't signed",
+            )
+            .into());
+        }
+        let config_public_key = cmd_line.config_public_key;
+        let require_signed_config = cmd_line.require_signed_config;
+        if require_signed_config {
+            let unsigned_commands: Vec<&str> = [
+                ("policy-hook", config_file.policy_hook.is_some()),
+                ("audit-log", config_file.audit_log.is_some()),
+                ("plugins", !config_file.plugins.is_empty()),
+            ]
+            .iter()
+            .filter(|(_key, is_set)| *is_set)
+            .map(|(key, _is_set)| *key)
+            .collect();
+            if !unsigned_commands.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "a signed config is required, but the config file (which isn't signed) \
+                         sets: {}",
+                        unsigned_commands.join(", ")
+                    ),
+                )
+                .into());
+            }
+        }
+
+        let audit_log = cmd_line.audit_log.or(config_file.audit_log);
+        let policy_hook = cmd_line.policy_hook.or(config_file.policy_hook);
+        if let Some(policy_hook) = &policy_hook {
+            let argv = shlex::split(policy_hook).unwrap_or_default();
+            if argv.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("invalid policy-hook: '{}'", policy_hook),
+                )
+                .into());
+            }
+        }
+
+        let spawn_parallelism = cmd_line.spawn_parallelism.or(config_file.spawn_parallelism);
+
+        let tick_interval = cmd_line.tick_interval.or(config_file.tick_interval);
+        if tick_interval == Some(Duration::from_secs(0)) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "tick-interval must be greater than zero",
+            )
+            .into());
+        }
+
+        let shutdown_on_stalled_component =
+            cmd_line.shutdown_on_stalled_component || config_file.shutdown_on_stalled_component;
+
+        let boot_progress = cmd_line.boot_progress || config_file.boot_progress;
+
+        let collector = cmd_line.collector.or(config_file.collector);
+        if let Some(collector) = &collector {
+            collector
+                .parse::<Endpoint>()
+                .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidInput, error))?;
+        }
+        let collector_node = cmd_line.collector_node.or(config_file.collector_node);
+        let otlp_endpoint = cmd_line.otlp_endpoint.or(config_file.otlp_endpoint);
+        if let Some(otlp_endpoint) = &otlp_endpoint {
+            otlp_endpoint
+                .parse::<Endpoint>()
+                .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidInput, error))?;
+        }
+        let collector_interval = cmd_line
+            .collector_interval
+            .or(config_file.collector_interval);
+        if collector_interval == Some(Duration::from_secs(0)) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "collector-interval must be greater than zero",
+            )
+            .into());
+        }
+
+        let mut chaos = config_file.chaos;
+        chaos.enabled |= cmd_line.enable_chaos;
+
+        for plugin in &config_file.plugins {
+            let argv = shlex::split(&plugin.command).unwrap_or_default();
+            if argv.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "invalid command of the plugin {}: '{}'",
+                        plugin.name, plugin.command
+                    ),
+                )
+                .into());
+            }
+        }
+
         Ok(HorustConfig {
             unsuccessful_exit_finished_failed,
+            control_socket,
+            unknown_children,
+            shutdown_timeout,
+            profiles,
+            state_dump_path,
+            state_directory,
+            state_file,
+            ready_directory,
+            hosts_file,
+            disabled_dependencies,
+            config_public_key,
+            require_signed_config,
+            audit_log,
+            policy_hook,
+            spawn_parallelism,
+            tick_interval,
+            shutdown_on_stalled_component,
+            boot_progress,
+            collector,
+            collector_node,
+            collector_interval,
+            otlp_endpoint,
+            enable_chaos: chaos.enabled,
+            chaos,
+            plugins: config_file.plugins,
+            services: config_file.services,
         })
     }
+
+    pub fn unknown_children(&self) -> UnknownChildrenPolicy {
+        self.unknown_children.clone().unwrap_or_default()
+    }
+
+    pub fn spawn_parallelism(&self) -> usize {
+        self.spawn_parallelism.unwrap_or(DEFAULT_SPAWN_PARALLELISM)
+    }
+
+    pub fn tick_interval(&self) -> Duration {
+        self.tick_interval.unwrap_or(DEFAULT_TICK_INTERVAL)
+    }
+
+    pub fn collector_interval(&self) -> Duration {
+        self.collector_interval
+            .unwrap_or(DEFAULT_COLLECTOR_INTERVAL)
+    }
+
+    /// The services defined in the config file, named after their table.
+    pub(crate) fn inline_services(&self) -> Vec<Service> {
+        self.services
+            .iter()
+            .map(|(key, service)| {
+                let mut service = service.clone();
+                if service.name.is_empty() {
+                    service.name = key.clone();
+                }
+                service
+            })
+            .collect()
+    }
+}
+
+/// Fault injection, for checking that the restart and failure strategies behave as designed.
+/// At every `interval`, each running service is killed with `kill-probability`. Each healthcheck
+/// result is held back for `healthcheck-delay` with `healthcheck-delay-probability`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct ChaosConfig {
+    pub enabled: bool,
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+    pub kill_probability: f64,
+    pub healthcheck_delay_probability: f64,
+    #[serde(with = "humantime_serde")]
+    pub healthcheck_delay: Duration,
+    /// Only these services are targeted. All of them, if empty.
+    pub services: Vec<ServiceName>,
 }
 
-impl Default for HorustConfig {
+impl Default for ChaosConfig {
     fn default() -> Self {
         Self {
-            unsuccessful_exit_finished_failed: false,
+            enabled: false,
+            interval: Duration::from_secs(10),
+            kill_probability: 0.1,
+            healthcheck_delay_probability: 0.0,
+            healthcheck_delay: Duration::from_secs(5),
+            services: vec![],
+        }
+    }
+}
+
+/// An external program, started along with horust, which talks with the bus over its stdio.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Plugin {
+    /// Used in the logs.
+    pub name: String,
+    pub command: String,
+}
+
+/// Horust, as a subreaper, might reap processes which are not services: e.g. orphaned grandchildren.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnknownChildrenPolicy {
+    /// Log a warning, with the service which has spawned the process (if known).
+    #[default]
+    Log,
+    Ignore,
+    /// Kill the service which has spawned the process.
+    KillParentService,
+}
+
+impl FromStr for UnknownChildrenPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "log" => Ok(UnknownChildrenPolicy::Log),
+            "ignore" => Ok(UnknownChildrenPolicy::Ignore),
+            "kill-parent-service" => Ok(UnknownChildrenPolicy::KillParentService),
+            other => Err(format!("Unknown policy for unknown children: {}", other)),
         }
     }
 }
+
+/// The services depending on a disabled service (through start-after or binds-to) can either
+/// fail right away, or consider it as satisfied.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum DisabledDependencyPolicy {
+    /// The services which haven't been started yet get the FinishedFailed status.
+    #[default]
+    Fail,
+    /// As if it was running.
+    Ignore,
+}
+
+impl FromStr for DisabledDependencyPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fail" => Ok(DisabledDependencyPolicy::Fail),
+            "ignore" => Ok(DisabledDependencyPolicy::Ignore),
+            other => Err(format!(
+                "Unknown policy for disabled dependencies: {}",
+                other
+            )),
+        }
+    }
+}
+
+fn parse_tick_interval(s: &str) -> std::result::Result<Duration, String> {
+    match humantime::parse_duration(s).map_err(|error| error.to_string())? {
+        interval if interval == Duration::from_secs(0) => {
+            Err("the tick interval must be greater than zero".into())
+        }
+        interval => Ok(interval),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::horust::formats::{HorustConfig, UnknownChildrenPolicy};
+    use std::time::Duration;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_inline_services() {
+        let config: HorustConfig = toml::from_str(
+            r#"
+profiles = ["dev"]
+
+[services.web]
+command = "./run.sh"
+start-after = ["db"]
+
+[services.db]
+name = "postgres"
+command = "postgres"
+"#,
+        )
+        .unwrap();
+        let services = config.inline_services();
+        let names: Vec<&str> = services.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["postgres", "web"]);
+        assert_eq!(services[1].command, "./run.sh");
+        assert_eq!(services[1].start_after, vec!["db".into()]);
+        assert!(HorustConfig::default().inline_services().is_empty());
+    }
+
+    #[test]
+    fn test_chaos() {
+        let tempdir = tempdir::TempDir::new("config").unwrap();
+        let path = tempdir.path().join("horust.toml");
+        let config = "[chaos]\ninterval = \"1s\"\nkill-probability = 0.5\nservices = [\"web\"]\n";
+        std::fs::write(&path, config).unwrap();
+        let config = HorustConfig::load_and_merge(HorustConfig::default(), &path).unwrap();
+        assert!(!config.chaos.enabled);
+        assert_eq!(config.chaos.interval, std::time::Duration::from_secs(1));
+        assert_eq!(config.chaos.kill_probability, 0.5);
+        assert_eq!(config.chaos.healthcheck_delay_probability, 0.0);
+        assert_eq!(config.chaos.services, vec!["web".to_string()]);
+        let cmd_line = HorustConfig {
+            enable_chaos: true,
+            ..Default::default()
+        };
+        let config = HorustConfig::load_and_merge(cmd_line, &path).unwrap();
+        assert!(config.chaos.enabled);
+        assert_eq!(config.chaos.kill_probability, 0.5);
+    }
+
+    #[test]
+    fn test_unknown_children() {
+        let tempdir = tempdir::TempDir::new("config").unwrap();
+        let path = tempdir.path().join("horust.toml");
+        let config = HorustConfig::load_and_merge(HorustConfig::default(), &path).unwrap();
+        assert_eq!(config.unknown_children(), UnknownChildrenPolicy::Log);
+        std::fs::write(&path, "unknown-children = \"ignore\"\n").unwrap();
+        let config = HorustConfig::load_and_merge(HorustConfig::default(), &path).unwrap();
+        assert_eq!(config.unknown_children(), UnknownChildrenPolicy::Ignore);
+        // Even if it's the default one.
+        let cmd_line = HorustConfig::from_iter(&["horust", "--unknown-children", "log"]);
+        let config = HorustConfig::load_and_merge(cmd_line, &path).unwrap();
+        assert_eq!(config.unknown_children(), UnknownChildrenPolicy::Log);
+    }
+
+    #[test]
+    fn test_spawn_parallelism() {
+        let tempdir = tempdir::TempDir::new("config").unwrap();
+        let path = tempdir.path().join("horust.toml");
+        let config = HorustConfig::load_and_merge(HorustConfig::default(), &path).unwrap();
+        assert_eq!(config.spawn_parallelism(), 4);
+        std::fs::write(&path, "spawn-parallelism = 1\n").unwrap();
+        let config = HorustConfig::load_and_merge(HorustConfig::default(), &path).unwrap();
+        assert_eq!(config.spawn_parallelism(), 1);
+        let cmd_line = HorustConfig::from_iter(&["horust", "--spawn-parallelism", "4"]);
+        let config = HorustConfig::load_and_merge(cmd_line, &path).unwrap();
+        assert_eq!(config.spawn_parallelism(), 4);
+    }
+
+    #[test]
+    fn test_snake_case_keys() {
+        let tempdir = tempdir::TempDir::new("config").unwrap();
+        let path = tempdir.path().join("horust.toml");
+        for content in &[
+            "unsuccessful_exit_finished_failed = true\n",
+            "unsuccessful-exit-finished-failed = true\n",
+        ] {
+            std::fs::write(&path, content).unwrap();
+            let config = HorustConfig::load_and_merge(HorustConfig::default(), &path).unwrap();
+            assert!(config.unsuccessful_exit_finished_failed);
+        }
+    }
+
+    #[test]
+    fn test_signed_config_keys() {
+        let tempdir = tempdir::TempDir::new("config").unwrap();
+        let path = tempdir.path().join("horust.toml");
+        for content in &[
+            "config-public-key = \"/etc/horust/horust.pub\"\n",
+            "require-signed-config = true\n",
+        ] {
+            std::fs::write(&path, content).unwrap();
+            assert!(HorustConfig::load_and_merge(HorustConfig::default(), &path).is_err());
+        }
+        let required = || HorustConfig::from_iter(&["horust", "--require-signed-config"]);
+        for content in &[
+            "policy-hook = \"/bin/true\"\n",
+            "audit-log = \"/tmp/audit.log\"\n",
+            "[[plugins]]\nname = \"notifier\"\ncommand = \"/bin/true\"\n",
+        ] {
+            std::fs::write(&path, content).unwrap();
+            assert!(HorustConfig::load_and_merge(HorustConfig::default(), &path).is_ok());
+            assert!(HorustConfig::load_and_merge(required(), &path).is_err());
+        }
+        // From the command line, they're trusted.
+        std::fs::write(&path, "").unwrap();
+        let mut cmd_line = required();
+        cmd_line.policy_hook = Some("/bin/true".into());
+        let config = HorustConfig::load_and_merge(cmd_line, &path).unwrap();
+        assert!(config.require_signed_config);
+        assert_eq!(config.policy_hook, Some("/bin/true".into()));
+    }
+
+    #[test]
+    fn test_collector_interval() {
+        let tempdir = tempdir::TempDir::new("config").unwrap();
+        let path = tempdir.path().join("horust.toml");
+        let config = HorustConfig::load_and_merge(HorustConfig::default(), &path).unwrap();
+        assert_eq!(config.collector_interval(), Duration::from_secs(10));
+        std::fs::write(&path, "collector-interval = \"1m\"\n").unwrap();
+        let config = HorustConfig::load_and_merge(HorustConfig::default(), &path).unwrap();
+        assert_eq!(config.collector_interval(), Duration::from_secs(60));
+        let cmd_line = HorustConfig::from_iter(&["horust", "--collector-interval", "10s"]);
+        let config = HorustConfig::load_and_merge(cmd_line, &path).unwrap();
+        assert_eq!(config.collector_interval(), Duration::from_secs(10));
+        std::fs::write(&path, "collector-interval = \"0s\"\n").unwrap();
+        assert!(HorustConfig::load_and_merge(HorustConfig::default(), &path).is_err());
+    }
+
+    #[test]
+    fn test_tick_interval() {
+        let tempdir = tempdir::TempDir::new("config").unwrap();
+        let path = tempdir.path().join("horust.toml");
+        let config = HorustConfig::load_and_merge(HorustConfig::default(), &path).unwrap();
+        assert_eq!(config.tick_interval(), Duration::from_millis(300));
+        std::fs::write(&path, "tick-interval = \"50ms\"\n").unwrap();
+        let config = HorustConfig::load_and_merge(HorustConfig::default(), &path).unwrap();
+        assert_eq!(config.tick_interval(), Duration::from_millis(50));
+        let cmd_line = HorustConfig::from_iter(&["horust", "--tick-interval", "1s"]);
+        let config = HorustConfig::load_and_merge(cmd_line, &path).unwrap();
+        assert_eq!(config.tick_interval(), Duration::from_secs(1));
+        let cmd_line = HorustConfig::from_iter(&["horust", "--tick-interval", "300ms"]);
+        let config = HorustConfig::load_and_merge(cmd_line, &path).unwrap();
+        assert_eq!(config.tick_interval(), Duration::from_millis(300));
+        assert!(HorustConfig::from_iter_safe(&["horust", "--tick-interval", "0s"]).is_err());
+        std::fs::write(&path, "tick-interval = \"0s\"\n").unwrap();
+        assert!(HorustConfig::load_and_merge(HorustConfig::default(), &path).is_err());
+    }
+}
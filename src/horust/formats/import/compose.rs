@@ -0,0 +1,416 @@
+//! Translates the services of a docker-compose file into horust services.
+//! Only the keys which make sense outside of a container engine are translated (e.g. `image`,
+//! `ports` or `volumes` are ignored, with a warning).
+use crate::horust::error::Result;
+use crate::horust::formats::import::{import_error, new_service};
+use crate::horust::formats::{Dependency, RestartStrategy, Service, TerminationSignal, User};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Deserialize, Debug)]
+struct ComposeFile {
+    #[serde(default)]
+    services: BTreeMap<String, ComposeService>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct ComposeService {
+    command: Option<StringOrList>,
+    entrypoint: Option<StringOrList>,
+    depends_on: Option<DependsOn>,
+    environment: Option<ListOrDict>,
+    healthcheck: Option<ComposeHealthcheck>,
+    restart: Option<String>,
+    working_dir: Option<PathBuf>,
+    user: Option<String>,
+    stop_signal: Option<String>,
+    stop_grace_period: Option<String>,
+    labels: Option<ListOrDict>,
+    profiles: Vec<String>,
+    scale: Option<u32>,
+    deploy: Option<Deploy>,
+    /// Everything which is not translated.
+    #[serde(flatten)]
+    ignored: BTreeMap<String, serde_yaml::Value>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct Deploy {
+    replicas: Option<u32>,
+    #[serde(flatten)]
+    ignored: BTreeMap<String, serde_yaml::Value>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct ComposeHealthcheck {
+    test: Option<StringOrList>,
+    timeout: Option<String>,
+    start_period: Option<String>,
+    disable: bool,
+    #[serde(flatten)]
+    ignored: BTreeMap<String, serde_yaml::Value>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum StringOrList {
+    String(String),
+    List(Vec<String>),
+}
+
+impl StringOrList {
+    /// As a command line: the items of a list are quoted.
+    fn to_command(&self) -> String {
+        match self {
+            StringOrList::String(command) => command.clone(),
+            StringOrList::List(words) => words
+                .iter()
+                .map(|word| shlex::quote(word).to_string())
+                .collect::<Vec<String>>()
+                .join(" "),
+        }
+    }
+}
+
+/// E.g. `["KEY=value", "OTHER"]` or `{ KEY: value, OTHER: }`.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum ListOrDict {
+    List(Vec<String>),
+    Dict(BTreeMap<String, Option<serde_yaml::Value>>),
+}
+
+impl ListOrDict {
+    /// The pairs, a missing value means that the key has no value (e.g. `OTHER`).
+    fn pairs(&self) -> Result<Vec<(String, Option<String>)>> {
+        match self {
+            ListOrDict::List(items) => Ok(items
+                .iter()
+                .map(|item| match item.find('=') {
+                    Some(index) => (
+                        item[..index].to_string(),
+                        Some(item[index + 1..].to_string()),
+                    ),
+                    None => (item.clone(), None),
+                })
+                .collect()),
+            ListOrDict::Dict(items) => items
+                .iter()
+                .map(|(key, value)| {
+                    let value = match value {
+                        None | Some(serde_yaml::Value::Null) => None,
+                        Some(serde_yaml::Value::String(value)) => Some(value.clone()),
+                        Some(serde_yaml::Value::Bool(value)) => Some(value.to_string()),
+                        Some(serde_yaml::Value::Number(value)) => Some(value.to_string()),
+                        Some(other) => {
+                            return Err(import_error(format!(
+                                "the value of '{}' should be a string, found: {:?}",
+                                key, other
+                            )))
+                        }
+                    };
+                    Ok((key.clone(), value))
+                })
+                .collect(),
+        }
+    }
+}
+
+/// E.g. `["db"]` or `{ db: { condition: service_healthy } }`.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum DependsOn {
+    List(Vec<String>),
+    Dict(BTreeMap<String, serde_yaml::Value>),
+}
+
+/// Compose durations, e.g. `1m30s`.
+fn parse_duration(service: &str, key: &str, duration: &str) -> Result<Duration> {
+    humantime::parse_duration(duration).map_err(|err| {
+        import_error(format!(
+            "Service '{}', invalid {}: '{}': {}",
+            service, key, duration, err
+        ))
+    })
+}
+
+/// Parses the content of a docker-compose file, and translates its services.
+/// The services keep their name, and they get the same defaults of a service file.
+pub fn from_compose(content: &str) -> Result<Vec<Service>> {
+    let compose: ComposeFile = serde_yaml::from_str(content)?;
+    if compose.services.is_empty() {
+        warn!("No services found in the compose file.");
+    }
+    compose
+        .services
+        .into_iter()
+        .map(|(name, compose_service)| translate(name, compose_service))
+        .collect()
+}
+
+fn translate(name: String, compose: ComposeService) -> Result<Service> {
+    let command = match (&compose.entrypoint, &compose.command) {
+        (Some(entrypoint), Some(command)) => {
+            format!("{} {}", entrypoint.to_command(), command.to_command())
+        }
+        (Some(command), None) | (None, Some(command)) => command.to_command(),
+        (None, None) => {
+            return Err(import_error(format!(
+                "Service '{}' has neither a command nor an entrypoint: the command of the image \
+                 cannot be inferred.",
+                name
+            )))
+        }
+    };
+    let mut service = new_service(name.clone(), command)?;
+    service.start_after = match compose.depends_on {
+        Some(DependsOn::List(services)) => services,
+        Some(DependsOn::Dict(services)) => services.into_keys().collect(),
+        None => vec![],
+    }
+    .into_iter()
+    .map(Dependency::Service)
+    .collect();
+    if let Some(environment) = &compose.environment {
+        for (key, value) in environment.pairs()? {
+            match value {
+                Some(value) => {
+                    service.environment.additional.insert(key, value);
+                }
+                // Compose takes it from the environment of the caller:
+                None => service.environment.re_export.push(key),
+            }
+        }
+    }
+    if let Some(labels) = &compose.labels {
+        for (key, value) in labels.pairs()? {
+            // E.g. `com.example.team` is not a valid label name.
+            let key = key.replace(|c: char| !c.is_ascii_alphanumeric() && c != '_', "_");
+            service.labels.insert(key, value.unwrap_or_default());
+        }
+    }
+    service.profiles = compose.profiles;
+    if let Some(working_dir) = compose.working_dir {
+        service.working_directory = working_dir;
+    }
+    if let Some(user) = &compose.user {
+        // The group (e.g. `1000:1000`) is not supported.
+        let user = user.split(':').next().unwrap_or_default();
+        service.user = match user.parse() {
+            Ok(uid) => User::Uid(uid),
+            Err(_) => User::Name(user.to_string()),
+        };
+    }
+    if let Some(restart) = &compose.restart {
+        let mut policy = restart.splitn(2, ':');
+        // The defaults of a `[restart]` section (e.g. the attempts):
+        service.restart = toml::from_str("")?;
+        service.restart.strategy = match policy.next() {
+            Some("no") => RestartStrategy::Never,
+            Some("always") | Some("unless-stopped") => RestartStrategy::Always,
+            Some("on-failure") => RestartStrategy::OnFailure,
+            _ => {
+                return Err(import_error(format!(
+                    "Service '{}', unknown restart policy: '{}'",
+                    name, restart
+                )))
+            }
+        };
+        if let Some(attempts) = policy.next() {
+            service.restart.attempts = attempts.parse().map_err(|_| {
+                import_error(format!(
+                    "Service '{}', invalid restart policy: '{}'",
+                    name, restart
+                ))
+            })?;
+        }
+    }
+    if let Some(signal) = &compose.stop_signal {
+        let signal = signal.trim_start_matches("SIG");
+        service.termination.signal =
+            serde_yaml::from_str::<TerminationSignal>(signal).map_err(|_| {
+                import_error(format!(
+                    "Service '{}', unsupported stop_signal: '{}'",
+                    name, signal
+                ))
+            })?;
+    }
+    if let Some(wait) = &compose.stop_grace_period {
+        service.termination.wait = parse_duration(&name, "stop_grace_period", wait)?;
+    }
+    if let Some(replicas) = compose
+        .deploy
+        .as_ref()
+        .and_then(|deploy| deploy.replicas)
+        .or(compose.scale)
+    {
+        service.replicas = replicas;
+    }
+    if let Some(healthcheck) = &compose.healthcheck {
+        translate_healthcheck(&mut service, healthcheck)?;
+    }
+    let deploy_ignored = compose
+        .deploy
+        .iter()
+        .flat_map(|deploy| deploy.ignored.keys().map(|key| format!("deploy.{}", key)));
+    let ignored: Vec<String> = compose
+        .ignored
+        .keys()
+        .cloned()
+        .chain(deploy_ignored)
+        .collect();
+    if !ignored.is_empty() {
+        warn!("Service '{}', ignored keys: {:?}", name, ignored);
+    }
+    Ok(service)
+}
+
+/// Horust doesn't run commands as healthchecks: only the http checks (using `curl` or `wget`)
+/// are translated.
+fn translate_healthcheck(service: &mut Service, healthcheck: &ComposeHealthcheck) -> Result<()> {
+    let words = match &healthcheck.test {
+        _ if healthcheck.disable => return Ok(()),
+        None => return Ok(()),
+        Some(StringOrList::String(command)) => shlex::split(command).unwrap_or_default(),
+        Some(StringOrList::List(test)) => match test.split_first() {
+            Some((kind, _)) if kind == "NONE" => return Ok(()),
+            Some((kind, args)) if kind == "CMD" => args.to_vec(),
+            Some((kind, args)) if kind == "CMD-SHELL" => {
+                shlex::split(&args.join(" ")).unwrap_or_default()
+            }
+            _ => test.clone(),
+        },
+    };
+    let is_http_client = words
+        .first()
+        .map(|program| program.ends_with("curl") || program.ends_with("wget"))
+        .unwrap_or(false);
+    let url = words
+        .iter()
+        .find(|word| word.starts_with("http://") || word.starts_with("https://"));
+    match url {
+        Some(url) if is_http_client => service.healthiness.http_endpoint = Some(url.clone()),
+        _ => {
+            warn!(
+                "Service '{}', healthcheck not translated (only http checks using curl or wget are supported): {:?}",
+                service.name, words
+            );
+            return Ok(());
+        }
+    }
+    if let Some(timeout) = &healthcheck.timeout {
+        service.healthiness.timeout =
+            parse_duration(&service.name, "healthcheck.timeout", timeout)?;
+    }
+    if let Some(start_period) = &healthcheck.start_period {
+        service.healthiness.initial_delay =
+            parse_duration(&service.name, "healthcheck.start_period", start_period)?;
+    }
+    if !healthcheck.ignored.is_empty() {
+        warn!(
+            "Service '{}', ignored healthcheck keys: {:?}",
+            service.name,
+            healthcheck.ignored.keys().collect::<Vec<_>>()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::horust::formats::import::compose::from_compose;
+    use crate::horust::formats::import::to_toml;
+    use crate::horust::formats::schema::migrate;
+    use crate::horust::formats::{Dependency, RestartStrategy, Service, TerminationSignal, User};
+    use std::time::Duration;
+
+    const COMPOSE: &str = r#"
+version: "3.8"
+services:
+  db:
+    image: postgres:12
+    command: ["postgres", "-c", "log_statement=all"]
+    user: "999:999"
+    environment:
+      POSTGRES_PASSWORD: secret
+      PGPORT: 5432
+      PGDATA:
+    stop_signal: SIGINT
+    stop_grace_period: 1m30s
+  web:
+    entrypoint: /usr/bin/web
+    command: --port 8080
+    working_dir: /srv
+    depends_on:
+      db:
+        condition: service_healthy
+    environment:
+      - RUST_LOG=info
+      - DATABASE_URL
+    labels:
+      com.example.team: payments
+    profiles: ["dev"]
+    restart: on-failure:3
+    deploy:
+      replicas: 2
+    healthcheck:
+      test: ["CMD", "curl", "-f", "http://localhost:8080/health"]
+      interval: 30s
+      timeout: 5s
+      start_period: 10s
+"#;
+
+    #[test]
+    fn test_from_compose() {
+        let services = from_compose(COMPOSE).unwrap();
+        let (db, web) = (&services[0], &services[1]);
+        assert_eq!(db.name, "db");
+        assert_eq!(db.command, r#"postgres -c "log_statement=all""#);
+        assert_eq!(db.user, User::Uid(999));
+        assert_eq!(db.environment.additional["POSTGRES_PASSWORD"], "secret");
+        assert_eq!(db.environment.additional["PGPORT"], "5432");
+        assert_eq!(db.environment.re_export, vec!["PGDATA"]);
+        assert_eq!(db.termination.signal, TerminationSignal::INT);
+        assert_eq!(db.termination.wait, Duration::from_secs(90));
+        assert_eq!(db.restart.strategy, RestartStrategy::Never);
+
+        assert_eq!(web.command, "/usr/bin/web --port 8080");
+        assert_eq!(web.working_directory.display().to_string(), "/srv");
+        assert_eq!(web.start_after, vec![Dependency::Service("db".into())]);
+        assert_eq!(web.environment.additional["RUST_LOG"], "info");
+        assert_eq!(web.environment.re_export, vec!["DATABASE_URL"]);
+        assert_eq!(web.labels["com_example_team"], "payments");
+        assert_eq!(web.profiles, vec!["dev"]);
+        assert_eq!(web.restart.strategy, RestartStrategy::OnFailure);
+        assert_eq!(web.restart.attempts, 3);
+        assert_eq!(web.replicas, 2);
+        assert_eq!(
+            web.healthiness.http_endpoint.as_deref(),
+            Some("http://localhost:8080/health")
+        );
+        assert_eq!(web.healthiness.timeout, Duration::from_secs(5));
+        assert_eq!(web.healthiness.initial_delay, Duration::from_secs(10));
+
+        let without_command = "services:\n  db:\n    image: postgres\n";
+        assert!(from_compose(without_command).is_err());
+        let unknown_restart = "services:\n  db:\n    command: db\n    restart: sometimes\n";
+        assert!(from_compose(unknown_restart).is_err());
+    }
+
+    #[test]
+    fn test_to_toml() {
+        for service in from_compose(COMPOSE).unwrap() {
+            let content = to_toml(&service).unwrap();
+            assert!(!content.contains("start-delay"));
+            let mut value: toml::Value = toml::from_str(&content).unwrap();
+            assert!(migrate(&mut value).unwrap().is_empty());
+            let parsed: Service = value.try_into().unwrap();
+            assert_eq!(parsed, service);
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Translates the services defined using other formats (docker-compose files, systemd units,
+//! command line definitions) into horust services.
+use crate::horust::error::{ErrorKind, HorustError, Result};
+use crate::horust::formats::{Service, CURRENT_SCHEMA};
+
+mod compose;
+mod spec;
+mod systemd;
+
+pub use compose::from_compose;
+pub use spec::from_spec;
+pub use systemd::from_units;
+
+fn import_error(message: String) -> HorustError {
+    ErrorKind::Import(message).into()
+}
+
+/// A service having the same defaults of a service file.
+fn new_service(name: String, command: String) -> Result<Service> {
+    let mut service: Service = toml::from_str(r#"command = """#)?;
+    service.name = name;
+    service.command = command;
+    Ok(service)
+}
+
+/// The service in the toml format, without the options having their default value.
+pub fn to_toml(service: &Service) -> Result<String> {
+    let mut value = to_value(service)?;
+    let top_defaults = defaults(None)?;
+    if let toml::Value::Table(table) = &mut value {
+        let keys: Vec<String> = table.keys().cloned().collect();
+        for key in keys {
+            if table.get(&key) == top_defaults.get(&key) {
+                table.remove(&key);
+                continue;
+            }
+            // The defaults of the options in a table (e.g. `environment.keep-env`) might differ
+            // from the ones used if the table is missing.
+            if let Some(toml::Value::Table(section)) = table.get_mut(&key) {
+                if let Ok(section_defaults) = defaults(Some(&key)) {
+                    if let Some(toml::Value::Table(section_defaults)) = section_defaults.get(&key) {
+                        remove_defaults(section, section_defaults);
+                    }
+                }
+            }
+        }
+    }
+    let content = toml::to_string(&value).map_err(|err| import_error(err.to_string()))?;
+    Ok(format!("schema = {}\n{}", CURRENT_SCHEMA, content))
+}
+
+fn to_value(service: &Service) -> Result<toml::Value> {
+    toml::Value::try_from(service).map_err(|err| import_error(err.to_string()))
+}
+
+/// The defaults of a service file, optionally having this (empty) table.
+fn defaults(table: Option<&str>) -> Result<toml::Value> {
+    let content = match table {
+        Some(table) => format!("command = \"\"\n[{}]", table),
+        None => r#"command = """#.to_string(),
+    };
+    to_value(&toml::from_str(&content)?)
+}
+
+/// Removes the entries of `table` having their default value.
+fn remove_defaults(
+    table: &mut toml::map::Map<String, toml::Value>,
+    defaults: &toml::map::Map<String, toml::Value>,
+) {
+    let keys: Vec<String> = table
+        .iter()
+        .filter(|(key, value)| defaults.get(*key) == Some(value))
+        .map(|(key, _)| key.clone())
+        .collect();
+    for key in keys {
+        table.remove(&key);
+    }
+}
@@ -0,0 +1,138 @@
+//! Translates the services defined on the command line (`--service`) into horust services.
+//! A definition is a comma separated list of `key=value` options, using the same names of a
+//! service file: e.g. `name=web,command=./run.sh,restart=always,termination.wait=5s`.
+use crate::horust::error::Result;
+use crate::horust::formats::import::import_error;
+use crate::horust::formats::Service;
+use std::ops::Range;
+use std::path::Path;
+
+/// Options which can be used without their section.
+const SHORTHANDS: &[(&str, &str)] = &[("restart", "restart.strategy")];
+
+/// The service defined by `spec`. Without a name, it's named after its program.
+pub fn from_spec(spec: &str) -> Result<Service> {
+    let mut table = toml::value::Table::new();
+    for (key, value) in split_options(spec)? {
+        let key = SHORTHANDS
+            .iter()
+            .find(|(shorthand, _)| *shorthand == key)
+            .map(|(_, key)| *key)
+            .unwrap_or(key);
+        insert(&mut table, key, parse_value(value))?;
+    }
+    let mut service: Service = toml::Value::Table(table).try_into()?;
+    if service.name.is_empty() {
+        service.name = program_name(&service.command)
+            .ok_or_else(|| import_error(format!("{}: either name or command is needed", spec)))?;
+    }
+    Ok(service)
+}
+
+/// The `key=value` options of the definition. A comma is a separator only if it's followed by
+/// another option, so the values (e.g. the command) can contain commas as well.
+fn split_options(spec: &str) -> Result<Vec<(&str, &str)>> {
+    let mut options: Vec<(&str, Range<usize>)> = vec![];
+    let mut start = 0;
+    let ends = spec.match_indices(',').map(|(index, _)| index);
+    for end in ends.chain(Some(spec.len())) {
+        let chunk = &spec[start..end];
+        match (option_key(chunk), options.last_mut()) {
+            (Some(key), _) => options.push((key, start + key.len() + 1..end)),
+            // Part of the value of the previous option.
+            (None, Some((_key, value))) => value.end = end,
+            (None, None) => return Err(import_error(format!("{}: expected key=value", chunk))),
+        }
+        start = end + 1;
+    }
+    Ok(options
+        .into_iter()
+        .map(|(key, value)| (key, &spec[value]))
+        .collect())
+}
+
+/// The key of the option, if `chunk` starts with `key=`: e.g. `restart` or `termination.wait`.
+fn option_key(chunk: &str) -> Option<&str> {
+    let key = &chunk[..chunk.find('=')?];
+    let is_valid = !key.is_empty()
+        && key.split('.').all(|part| {
+            part.starts_with(|c: char| c.is_ascii_lowercase())
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        });
+    if is_valid {
+        Some(key)
+    } else {
+        None
+    }
+}
+
+/// Numbers, booleans, lists and inline tables use the toml syntax (e.g. `replicas=2`,
+/// `start-after=["db"]`), everything else is a string.
+fn parse_value(value: &str) -> toml::Value {
+    toml::from_str::<toml::value::Table>(&format!("value = {}", value))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()))
+}
+
+/// Inserts the value, creating the sections of a dotted key (e.g. `termination.wait`).
+fn insert(table: &mut toml::value::Table, key: &str, value: toml::Value) -> Result<()> {
+    let mut parts: Vec<&str> = key.split('.').collect();
+    let last = parts.pop().unwrap();
+    let mut section = table;
+    for part in parts {
+        let entry = section
+            .entry(part.to_string())
+            .or_insert_with(|| toml::Value::Table(Default::default()));
+        section = match entry {
+            toml::Value::Table(section) => section,
+            _ => return Err(import_error(format!("{} is not a section", part))),
+        };
+    }
+    if section.insert(last.to_string(), value).is_some() {
+        return Err(import_error(format!("{} is defined more than once", key)));
+    }
+    Ok(())
+}
+
+/// The file name of the program run by the command.
+fn program_name(command: &str) -> Option<String> {
+    let program = shlex::split(command)?.into_iter().next()?;
+    Path::new(&program)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::horust::formats::import::spec::from_spec;
+    use crate::horust::formats::{RestartStrategy, TerminationSignal};
+    use std::time::Duration;
+
+    #[test]
+    fn test_from_spec() {
+        let service = from_spec(
+            "name=web,command=./run.sh --hosts a,b,restart=always,termination.wait=5s,\
+             termination.signal=INT,replicas=2,start-after=[\"db\", \"cache\"]",
+        )
+        .unwrap();
+        assert_eq!(service.name, "web");
+        assert_eq!(service.command, "./run.sh --hosts a,b");
+        assert_eq!(service.restart.strategy, RestartStrategy::Always);
+        assert_eq!(service.termination.wait, Duration::from_secs(5));
+        assert_eq!(service.termination.signal, TerminationSignal::INT);
+        assert_eq!(service.replicas, 2);
+        assert_eq!(service.start_after, vec!["db".into(), "cache".into()]);
+
+        let service = from_spec("command=/usr/bin/worker --threads 4").unwrap();
+        assert_eq!(service.name, "worker");
+        assert_eq!(service.restart.strategy, RestartStrategy::Never);
+
+        assert!(from_spec("./run.sh").is_err());
+        assert!(from_spec("command=a,command=b").is_err());
+        assert!(from_spec("command=a,unknown=b").is_err());
+        assert!(from_spec("command=a,restart=sometimes").is_err());
+    }
+}
@@ -0,0 +1,446 @@
+//! Translates simple systemd `.service` units into horust services.
+//! Only the directives which make sense for a supervised process are translated, the others
+//! are ignored with a warning.
+use crate::horust::error::Result;
+use crate::horust::formats::import::{import_error, new_service};
+use crate::horust::formats::{
+    Dependency, ExitCodes, KillScope, RestartStrategy, Service, TerminationSignal, User,
+};
+use std::collections::HashSet;
+use std::time::Duration;
+
+/// Targets which are reached on every boot: the services wanted by them are always run,
+/// while the ones wanted by other targets get a profile named after the target.
+const DEFAULT_TARGETS: &[&str] = &[
+    "default.target",
+    "multi-user.target",
+    "graphical.target",
+    "basic.target",
+    "sysinit.target",
+];
+
+/// The directives of a unit file, in order: e.g. `("Service", "ExecStart", "/usr/bin/app")`.
+struct Unit {
+    directives: Vec<(String, String, String)>,
+}
+
+impl Unit {
+    fn parse(content: &str) -> Result<Self> {
+        let mut directives = vec![];
+        let mut section = String::new();
+        let mut lines = content.lines();
+        while let Some(line) = lines.next() {
+            let mut line = line.trim().to_string();
+            // Continuation lines:
+            while line.ends_with('\\') {
+                line.pop();
+                line = format!("{} ", line.trim_end());
+                line.push_str(lines.next().unwrap_or_default().trim());
+            }
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                section = line[1..line.len() - 1].to_string();
+                continue;
+            }
+            match line.find('=') {
+                Some(index) => directives.push((
+                    section.clone(),
+                    line[..index].trim().to_string(),
+                    line[index + 1..].trim().to_string(),
+                )),
+                None => return Err(import_error(format!("Invalid line: '{}'", line))),
+            }
+        }
+        Ok(Unit { directives })
+    }
+
+    /// All the values of this directive. As in systemd, an empty value resets the previous ones.
+    fn values(&self, section: &str, key: &str) -> Vec<&str> {
+        self.directives
+            .iter()
+            .filter(|(s, k, _)| s == section && k == key)
+            .fold(vec![], |mut values, (_, _, value)| {
+                if value.is_empty() {
+                    values.clear();
+                } else {
+                    values.push(value.as_str());
+                }
+                values
+            })
+    }
+
+    /// The last value of this directive.
+    fn value(&self, section: &str, key: &str) -> Option<&str> {
+        self.values(section, key).last().copied()
+    }
+
+    /// Space separated lists, e.g. `After=network.target db.service`.
+    fn list(&self, section: &str, key: &str) -> Vec<&str> {
+        self.values(section, key)
+            .into_iter()
+            .flat_map(str::split_whitespace)
+            .collect()
+    }
+}
+
+/// Directives which are translated.
+const TRANSLATED: &[(&str, &[&str])] = &[
+    ("Unit", &["Description", "After", "BindsTo"]),
+    (
+        "Service",
+        &[
+            "Type",
+            "ExecStart",
+            "Restart",
+            "RestartSec",
+            "User",
+            "WorkingDirectory",
+            "Environment",
+            "KillSignal",
+            "KillMode",
+            "TimeoutStopSec",
+            "SuccessExitStatus",
+            "RestartPreventExitStatus",
+        ],
+    ),
+    ("Install", &["WantedBy", "RequiredBy"]),
+];
+
+/// Systemd time spans, e.g. `5` (seconds), `500ms` or `1min 30s`.
+fn parse_timespan(service: &str, key: &str, timespan: &str) -> Result<Duration> {
+    if let Ok(secs) = timespan.parse() {
+        return Ok(Duration::from_secs(secs));
+    }
+    humantime::parse_duration(timespan).map_err(|err| {
+        import_error(format!(
+            "Service '{}', invalid {}: '{}': {}",
+            service, key, timespan, err
+        ))
+    })
+}
+
+/// E.g. `db.service` is `db`, while targets, sockets, etc. are not services.
+fn service_name(unit: &str) -> Option<&str> {
+    if unit.ends_with(".service") {
+        Some(unit.trim_end_matches(".service"))
+    } else {
+        None
+    }
+}
+
+/// Translates the units, given as (file name, content): e.g. `("db.service", "[Service]...")`.
+/// The services are named after the units (e.g. `db`). Dependencies on units which are not
+/// among these ones are dropped.
+pub fn from_units(units: &[(String, String)]) -> Result<Vec<Service>> {
+    let services = units
+        .iter()
+        .map(|(file_name, content)| translate(file_name, &Unit::parse(content)?))
+        .collect::<Result<Vec<Service>>>()?;
+    let names: HashSet<String> = services.iter().map(|s| s.name.clone()).collect();
+    Ok(services
+        .into_iter()
+        .map(|mut service| {
+            let service_name = service.name.clone();
+            let is_known = |name: &String| {
+                if !names.contains(name) {
+                    warn!(
+                        "Service '{}', dropped the dependency on '{}': it's not imported.",
+                        service_name, name
+                    );
+                }
+                names.contains(name)
+            };
+            service.start_after = service
+                .start_after
+                .iter()
+                .flat_map(Dependency::services)
+                .filter(|name| is_known(name))
+                .cloned()
+                .map(Dependency::Service)
+                .collect();
+            service.binds_to = service
+                .binds_to
+                .iter()
+                .filter(|name| is_known(name))
+                .cloned()
+                .collect();
+            service
+        })
+        .collect())
+}
+
+fn translate(file_name: &str, unit: &Unit) -> Result<Service> {
+    let name = match service_name(file_name) {
+        Some(name) if !name.ends_with('@') => name.to_string(),
+        _ => {
+            return Err(import_error(format!(
+                "'{}' is not a service unit (template units are not supported).",
+                file_name
+            )))
+        }
+    };
+    let command = match unit.values("Service", "ExecStart")[..] {
+        [command] => command,
+        [] => {
+            return Err(import_error(format!(
+                "Service '{}' has no ExecStart.",
+                name
+            )))
+        }
+        _ => {
+            return Err(import_error(format!(
+                "Service '{}' has more than one ExecStart.",
+                name
+            )))
+        }
+    };
+    // Special prefixes, e.g. `-` for ignoring the failures:
+    let stripped = command.trim_start_matches(|c| "-@:+!".contains(c));
+    if stripped.len() != command.len() {
+        warn!(
+            "Service '{}', ignored the prefixes of ExecStart: '{}'",
+            name,
+            &command[..command.len() - stripped.len()]
+        );
+    }
+    if stripped.contains('%') {
+        warn!(
+            "Service '{}', specifiers (e.g. %i) are not supported: '{}'",
+            name, stripped
+        );
+    }
+    let mut service = new_service(name.clone(), stripped.to_string())?;
+    if unit.value("Service", "Type") == Some("forking") {
+        warn!(
+            "Service '{}' has Type=forking: the command should run in the foreground.",
+            name
+        );
+    }
+    service.start_after = unit
+        .list("Unit", "After")
+        .into_iter()
+        .filter_map(service_name)
+        .map(|name| Dependency::Service(name.to_string()))
+        .collect();
+    service.binds_to = unit
+        .list("Unit", "BindsTo")
+        .into_iter()
+        .filter_map(service_name)
+        .map(str::to_string)
+        .collect();
+    if let Some(restart) = unit.value("Service", "Restart") {
+        // The defaults of a `[restart]` section (e.g. the attempts):
+        service.restart = toml::from_str("")?;
+        service.restart.strategy = match restart {
+            "no" => RestartStrategy::Never,
+            "always" => RestartStrategy::Always,
+            "on-failure" | "on-abnormal" | "on-abort" | "on-watchdog" => RestartStrategy::OnFailure,
+            other => {
+                warn!(
+                    "Service '{}', Restart={} is not supported: it won't be restarted.",
+                    name, other
+                );
+                RestartStrategy::Never
+            }
+        };
+    }
+    if let Some(backoff) = unit.value("Service", "RestartSec") {
+        service.restart.backoff = parse_timespan(&name, "RestartSec", backoff)?;
+    }
+    if let Some(user) = unit.value("Service", "User") {
+        service.user = match user.parse() {
+            Ok(uid) => User::Uid(uid),
+            Err(_) => User::Name(user.to_string()),
+        };
+    }
+    if let Some(working_directory) = unit.value("Service", "WorkingDirectory") {
+        // `-` means that a missing directory is fine.
+        service.working_directory = working_directory.trim_start_matches('-').into();
+    }
+    for environment in unit.values("Service", "Environment") {
+        let assignments = shlex::split(environment).ok_or_else(|| {
+            import_error(format!(
+                "Service '{}', invalid Environment: '{}'",
+                name, environment
+            ))
+        })?;
+        for assignment in assignments {
+            let mut pair = assignment.splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some(key), Some(value)) => {
+                    service
+                        .environment
+                        .additional
+                        .insert(key.to_string(), value.to_string());
+                }
+                _ => {
+                    return Err(import_error(format!(
+                        "Service '{}', invalid Environment: '{}'",
+                        name, assignment
+                    )))
+                }
+            }
+        }
+    }
+    if let Some(signal) = unit.value("Service", "KillSignal") {
+        let signal = signal.trim_start_matches("SIG");
+        service.termination.signal = toml::Value::String(signal.to_string())
+            .try_into::<TerminationSignal>()
+            .map_err(|_| {
+                import_error(format!(
+                    "Service '{}', unsupported KillSignal: '{}'",
+                    name, signal
+                ))
+            })?;
+    }
+    match unit.value("Service", "KillMode") {
+        Some("process") => service.termination.kill_scope = KillScope::Process,
+        // The closest one: the processes which have left its session are not killed.
+        Some("control-group") => service.termination.kill_scope = KillScope::Session,
+        Some(mode) => warn!(
+            "Service '{}', unsupported KillMode: '{}', using the default kill-scope.",
+            name, mode
+        ),
+        None => (),
+    }
+    match unit.value("Service", "TimeoutStopSec") {
+        Some("infinity") | None => (),
+        Some(wait) => service.termination.wait = parse_timespan(&name, "TimeoutStopSec", wait)?,
+    }
+    // Signals (e.g. `SIGKILL`) are not supported.
+    let exit_codes = |key: &str| -> Vec<ExitCodes> {
+        unit.list("Service", key)
+            .into_iter()
+            .filter_map(|status| match status.parse() {
+                Ok(code) => Some(ExitCodes::Code(code)),
+                Err(_) => {
+                    warn!("Service '{}', ignored {}: '{}'", name, key, status);
+                    None
+                }
+            })
+            .collect()
+    };
+    service
+        .failure
+        .successful_exit_code
+        .extend(exit_codes("SuccessExitStatus"));
+    service.failure.permanent_exit_code = exit_codes("RestartPreventExitStatus");
+    service.profiles = unit
+        .list("Install", "WantedBy")
+        .into_iter()
+        .chain(unit.list("Install", "RequiredBy"))
+        .filter(|target| target.ends_with(".target") && !DEFAULT_TARGETS.contains(target))
+        .map(|target| target.trim_end_matches(".target").to_string())
+        .collect();
+    let ignored: Vec<String> = unit
+        .directives
+        .iter()
+        .filter(|(section, key, _)| {
+            !TRANSLATED
+                .iter()
+                .any(|(s, keys)| s == section && keys.contains(&key.as_str()))
+        })
+        .map(|(section, key, _)| format!("{}.{}", section, key))
+        .collect();
+    if !ignored.is_empty() {
+        warn!("Service '{}', ignored directives: {:?}", name, ignored);
+    }
+    Ok(service)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::horust::formats::import::systemd::from_units;
+    use crate::horust::formats::import::to_toml;
+    use crate::horust::formats::schema::migrate;
+    use crate::horust::formats::{
+        Dependency, ExitCodes, KillScope, RestartStrategy, Service, TerminationSignal, User,
+    };
+    use std::time::Duration;
+
+    const WEB: &str = r#"
+[Unit]
+Description=Web server
+After=network.target db.service cache.service
+BindsTo=db.service
+
+[Service]
+Type=simple
+ExecStart=-/usr/bin/web \
+    --port 8080
+Restart=on-failure
+RestartSec=5
+User=www-data
+WorkingDirectory=/srv/web
+Environment="RUST_LOG=info" GREETING=hello
+Environment=PORT=8080
+KillSignal=SIGINT
+KillMode=process
+TimeoutStopSec=1min 30s
+SuccessExitStatus=143 SIGKILL
+RestartPreventExitStatus=78
+LimitNOFILE=65536
+
+[Install]
+WantedBy=multi-user.target debug.target
+"#;
+
+    #[test]
+    fn test_from_units() {
+        let units = vec![
+            ("web.service".to_string(), WEB.to_string()),
+            (
+                "db.service".to_string(),
+                "[Service]\nExecStart=/usr/bin/db\nUser=999\n".to_string(),
+            ),
+        ];
+        let services = from_units(&units).unwrap();
+        let (web, db) = (&services[0], &services[1]);
+        assert_eq!(web.name, "web");
+        assert_eq!(web.command, "/usr/bin/web --port 8080");
+        // cache.service is not imported:
+        assert_eq!(web.start_after, vec![Dependency::Service("db".into())]);
+        assert_eq!(web.binds_to, vec!["db"]);
+        assert_eq!(web.restart.strategy, RestartStrategy::OnFailure);
+        assert_eq!(web.restart.backoff, Duration::from_secs(5));
+        assert_eq!(web.user, User::Name("www-data".into()));
+        assert_eq!(web.working_directory.display().to_string(), "/srv/web");
+        assert_eq!(web.environment.additional["RUST_LOG"], "info");
+        assert_eq!(web.environment.additional["GREETING"], "hello");
+        assert_eq!(web.environment.additional["PORT"], "8080");
+        assert_eq!(web.termination.signal, TerminationSignal::INT);
+        assert_eq!(web.termination.wait, Duration::from_secs(90));
+        assert_eq!(web.termination.kill_scope, KillScope::Process);
+        assert_eq!(
+            web.failure.successful_exit_code,
+            vec![ExitCodes::Code(0), ExitCodes::Code(143)]
+        );
+        assert_eq!(web.failure.permanent_exit_code, vec![ExitCodes::Code(78)]);
+        assert_eq!(web.profiles, vec!["debug"]);
+
+        assert_eq!(db.name, "db");
+        assert_eq!(db.user, User::Uid(999));
+        assert_eq!(db.restart.strategy, RestartStrategy::Never);
+        assert!(db.profiles.is_empty());
+
+        for service in services {
+            let mut value: toml::Value = toml::from_str(&to_toml(&service).unwrap()).unwrap();
+            assert!(migrate(&mut value).unwrap().is_empty());
+            let parsed: Service = value.try_into().unwrap();
+            assert_eq!(parsed, service);
+        }
+
+        let no_exec_start = vec![("a.service".to_string(), "[Service]\n".to_string())];
+        assert!(from_units(&no_exec_start).is_err());
+        let template = vec![(
+            "a@.service".to_string(),
+            "[Service]\nExecStart=/bin/a %i\n".to_string(),
+        )];
+        assert!(from_units(&template).is_err());
+        let reset = "[Service]\nExecStart=/bin/a\nExecStart=\nExecStart=/bin/b\n";
+        let services = from_units(&[("a.service".to_string(), reset.to_string())]).unwrap();
+        assert_eq!(services[0].command, "/bin/b");
+    }
+}
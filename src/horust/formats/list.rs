@@ -0,0 +1,136 @@
+//! `horust list`: the services which would be run, with their main settings.
+use crate::horust::error::Result;
+use crate::horust::formats::{Dependency, RestartStrategy, Service, ServiceName};
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Entry<'a> {
+    name: &'a ServiceName,
+    command: &'a str,
+    restart: &'a RestartStrategy,
+    start_after: &'a [Dependency],
+    binds_to: &'a [ServiceName],
+    enabled: bool,
+}
+
+impl<'a> From<&'a Service> for Entry<'a> {
+    fn from(service: &'a Service) -> Self {
+        Self {
+            name: &service.name,
+            command: &service.command,
+            restart: &service.restart.strategy,
+            start_after: &service.start_after,
+            binds_to: &service.binds_to,
+            enabled: service.enabled,
+        }
+    }
+}
+
+/// A table, one service per line, or a json array if `json`.
+pub fn list_services(services: &[Service], json: bool) -> Result<String> {
+    let entries: Vec<Entry> = services.iter().map(Entry::from).collect();
+    if json {
+        let mut output = serde_json::to_string_pretty(&entries).map_err(std::io::Error::from)?;
+        output.push('\n');
+        return Ok(output);
+    }
+    let header = ["NAME", "RESTART", "START-AFTER", "COMMAND"];
+    let rows: Vec<[String; 4]> = entries
+        .iter()
+        .map(|entry| {
+            [
+                if entry.enabled {
+                    entry.name.clone()
+                } else {
+                    format!("{} (disabled)", entry.name)
+                },
+                match entry.restart {
+                    RestartStrategy::Always => "always",
+                    RestartStrategy::OnFailure => "on-failure",
+                    RestartStrategy::Never => "never",
+                }
+                .to_string(),
+                dependencies(entry.start_after),
+                entry.command.to_string(),
+            ]
+        })
+        .collect();
+    // The command is the last column, so it's not padded.
+    let widths: Vec<usize> = (0..header.len() - 1)
+        .map(|column| {
+            rows.iter()
+                .map(|row| row[column].len())
+                .chain(std::iter::once(header[column].len()))
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    let format_row = |row: &[&str]| -> String {
+        let mut line: String = widths
+            .iter()
+            .zip(row)
+            .map(|(width, cell)| format!("{:width$}  ", cell, width = width))
+            .collect();
+        line.push_str(row[header.len() - 1]);
+        format!("{}\n", line.trim_end())
+    };
+    let mut output = format_row(&header);
+    for row in &rows {
+        let row: Vec<&str> = row.iter().map(String::as_str).collect();
+        output.push_str(&format_row(&row));
+    }
+    Ok(output)
+}
+
+/// The alternatives of an `AnyOf` are separated by `|`, e.g. `db.toml, cache-a|cache-b`.
+fn dependencies(dependencies: &[Dependency]) -> String {
+    let formatted: Vec<String> = dependencies
+        .iter()
+        .map(|dependency| match dependency {
+            Dependency::Service(name) => name.clone(),
+            Dependency::AnyOf(names) => names.join("|"),
+        })
+        .collect();
+    if formatted.is_empty() {
+        "-".to_string()
+    } else {
+        formatted.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::list_services;
+    use crate::horust::formats::{Dependency, RestartStrategy, Service};
+
+    #[test]
+    fn test_list_services() {
+        let mut db = Service::from_name("db.toml");
+        db.command = "/usr/bin/postgres -D /data".into();
+        db.restart.strategy = RestartStrategy::OnFailure;
+        let mut web = Service::from_name("web.toml");
+        web.command = "./run.sh".into();
+        web.start_after = vec![
+            Dependency::Service("db.toml".into()),
+            Dependency::AnyOf(vec!["a.toml".into(), "b.toml".into()]),
+        ];
+        web.enabled = false;
+        let services = vec![db, web];
+
+        let expected = "\
+NAME                 RESTART     START-AFTER             COMMAND
+db.toml              on-failure  -                       /usr/bin/postgres -D /data
+web.toml (disabled)  never       db.toml, a.toml|b.toml  ./run.sh
+";
+        assert_eq!(list_services(&services, false).unwrap(), expected);
+
+        let json: serde_json::Value =
+            serde_json::from_str(&list_services(&services, true).unwrap()).unwrap();
+        assert_eq!(json[0]["name"], "db.toml");
+        assert_eq!(json[0]["restart"], "on-failure");
+        assert_eq!(json[1]["start-after"][0], "db.toml");
+        assert_eq!(json[1]["start-after"][1][1], "b.toml");
+        assert_eq!(json[1]["enabled"], false);
+    }
+}
@@ -1,23 +1,84 @@
+mod calendar;
+mod event_schema;
 mod horust_config;
+mod import;
+mod list;
+mod schema;
 mod service;
-pub use horust_config::HorustConfig;
+mod template;
+pub use calendar::{TimeOfDay, Timezone};
+pub use event_schema::{events_schema, EVENTS_SCHEMA_VERSION};
+pub(crate) use event_schema::{negotiate, EventRecord, Header};
+pub use horust_config::{
+    ChaosConfig, DisabledDependencyPolicy, HorustConfig, Plugin, UnknownChildrenPolicy,
+};
+pub use import::{from_compose, from_spec, from_units, to_toml};
+pub use list::list_services;
+use nix::sys::signal::Signal;
 use nix::unistd::Pid;
+pub use schema::{migrate_file, CURRENT_SCHEMA};
 pub use service::*;
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, SystemTime};
+pub(crate) use template::{render_templates, validate_templates};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Event {
     PidChanged(ServiceName, Pid),
+    /// An already running process has been adopted by the service.
+    PidAdopted(ServiceName, Pid),
     ServiceStarted(ServiceName),
     StatusChanged(ServiceName, ServiceStatus),
     ServiceExited(ServiceName, i32),
+    /// The process of the service has exited: how long it has run, and how it has ended.
+    RunEnded(ServiceName, RunRecord),
     ForceKill(ServiceName),
+    /// The service was killed before its process was spawned, and the process hasn't shown up in
+    /// time: it's given up on.
+    PidNeverAppeared(ServiceName),
     Kill(ServiceName),
-    SpawnFailed(ServiceName),
+    /// The process of the service couldn't be spawned.
+    SpawnFailed(ServiceName, SpawnError),
+    /// The process was forked, but it has failed before running the command (e.g. in exec):
+    /// carries the errno.
+    ExecFailed(ServiceName, i32),
     Run(ServiceName),
-    ShuttingDownInitiated,
-    HealthCheck(ServiceName, HealthinessStatus),
-    // TODO: to allow changes of service at runtime:
-    //ServiceCreated(ServiceHandler)
+    /// The service is going to be spawned (after its start-delay, and once what it waits for is
+    /// there).
+    StartRequested(ServiceName),
+    /// Horust is going to stop all the services, and then to exit.
+    ShuttingDownInitiated(ShutdownReason),
+    HealthCheck(ServiceName, HealthCheckReport),
+    /// The healthchecks of the running service have failed too many times in a row: it's going
+    /// to be killed. Carries the last error.
+    HealthcheckFailed(ServiceName, Option<String>),
+    /// The service has written a line matching the log-pattern of its healthiness section.
+    LogPatternMatched(ServiceName),
+    /// Start again a service stopped because it was idle.
+    Activate(ServiceName),
+    /// Used by the control socket for checking that the bus is delivering events.
+    Ping(u64),
+    /// Change the number of instances of a replicated service.
+    Scale(ServiceName, u32),
+    /// Restart all the instances of the service.
+    Restart(ServiceName, RestartMode),
+    /// Stop all the instances of the service, without starting them again.
+    Stop(ServiceName),
+    /// Don't start the service (all of its instances) anymore, stopping it if needed.
+    Mask(ServiceName),
+    /// Start again a masked service.
+    Unmask(ServiceName),
+    /// A new service has been added at runtime (e.g. a new replica).
+    ServiceCreated(Box<Service>),
+    /// The policy hook has allowed (or denied, with the reason) the creation of a new instance.
+    CreationDecided(Box<Service>, Result<(), String>),
+    /// The chaos component (`--chaos`) has injected a fault in the service.
+    FaultInjected(ServiceName, Fault),
+    /// Ask the runtime for the status of every service (answered with `StateSnapshot`).
+    RequestSnapshot,
+    /// The status of every service (sorted by name), for the components joining after the boot.
+    StateSnapshot(Vec<(ServiceName, ServiceStatus)>),
 }
 
 impl Event {
@@ -35,18 +96,225 @@ impl Event {
     }
 }
 
+/// A process of a service which has exited, kept in the run history of the service.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RunRecord {
+    /// Using the system clock, only for display.
+    pub start_time: Option<SystemTime>,
+    pub duration: Option<Duration>,
+    pub exit_code: i32,
+}
+
+impl RunRecord {
+    /// The signal which has killed the process, if any (its exit code is 128 + the signal).
+    pub fn signal(&self) -> Option<Signal> {
+        if self.exit_code <= 128 {
+            return None;
+        }
+        Signal::try_from(self.exit_code - 128).ok()
+    }
+}
+
+/// E.g. `start=2020-05-01T10:00:00Z duration=1.2s exit-code=137 signal=SIGKILL`
+impl Display for RunRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+        write!(
+            f,
+            "start={} duration={} exit-code={} signal={}",
+            or_dash(
+                self.start_time
+                    .map(|start_time| humantime::format_rfc3339_seconds(start_time).to_string())
+            ),
+            or_dash(self.duration.map(|duration| format!("{:?}", duration))),
+            self.exit_code,
+            or_dash(self.signal().map(|signal| format!("{:?}", signal)))
+        )
+    }
+}
+
+/// A fault injected on purpose, for checking that the restart and failure strategies behave as
+/// designed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    /// The process has been sent a SIGKILL, as if it had crashed.
+    Killed(Pid),
+    /// The result of a healthcheck is going to be delivered this late.
+    HealthcheckDelayed(Duration),
+}
+
+impl Display for Fault {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::Killed(pid) => write!(f, "killed its process ({})", pid),
+            Fault::HealthcheckDelayed(delay) => {
+                write!(f, "delayed a healthcheck by {:?}", delay)
+            }
+        }
+    }
+}
+
+/// Why the process of a service couldn't be spawned.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpawnError {
+    /// The program (or the working directory) doesn't exist.
+    NotFound,
+    /// The program (or the working directory) cannot be accessed or executed.
+    PermissionDenied,
+    /// The program is not in a format which can be executed, e.g. a script without a shebang.
+    NotExecutable,
+    /// Horust is shutting down.
+    Aborted,
+    /// A `wait-for` condition (e.g. `tcp db:5432`) hasn't been met within its timeout.
+    WaitForTimedOut(String),
+    /// Anything else, e.g. an invalid command or an unknown user.
+    Other(String),
+}
+
+impl SpawnError {
+    /// Short identifier of the reason, e.g. for the metrics.
+    pub fn key(&self) -> &'static str {
+        match self {
+            SpawnError::NotFound => "not-found",
+            SpawnError::PermissionDenied => "permission-denied",
+            SpawnError::NotExecutable => "not-executable",
+            SpawnError::Aborted => "aborted",
+            SpawnError::WaitForTimedOut(_) => "wait-for-timed-out",
+            SpawnError::Other(_) => "other",
+        }
+    }
+
+    /// The reasons worth telling apart, out of the errno of the failed system call.
+    pub(crate) fn from_errno(errno: i32) -> Option<Self> {
+        match errno {
+            libc::ENOENT | libc::ENOTDIR => Some(SpawnError::NotFound),
+            libc::EACCES | libc::EPERM => Some(SpawnError::PermissionDenied),
+            libc::ENOEXEC => Some(SpawnError::NotExecutable),
+            _ => None,
+        }
+    }
+
+    /// Trying again won't help, unless something is changed on the system.
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            SpawnError::NotFound | SpawnError::PermissionDenied | SpawnError::NotExecutable
+        )
+    }
+}
+
+impl Display for SpawnError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SpawnError::NotFound => write!(f, "the program or the working directory was not found"),
+            SpawnError::PermissionDenied => write!(f, "permission denied"),
+            SpawnError::NotExecutable => write!(f, "the program is not executable"),
+            SpawnError::Aborted => write!(f, "aborted before spawning the process"),
+            SpawnError::WaitForTimedOut(condition) => {
+                write!(f, "timed out waiting for {}", condition)
+            }
+            SpawnError::Other(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// Why horust has started shutting down.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShutdownReason {
+    /// SIGTERM or SIGINT has been received.
+    Signal(&'static str),
+    /// The service has failed, and its failure strategy is `shutdown`.
+    ServiceFailed(ServiceName),
+    /// The service has run out of restart attempts, and its on-attempts-exhausted is `shutdown`.
+    AttemptsExhausted(ServiceName),
+    /// The healthchecks of the service couldn't be set up.
+    HealthchecksNotReady(ServiceName),
+    /// Requested through the control socket (`horustctl shutdown`).
+    ControlCommand,
+    /// All the services have finished on their own.
+    ServicesFinished,
+    /// Every simulated service has settled (`--simulate`).
+    SimulationOver,
+    /// A core component has stopped consuming the events of the bus (check
+    /// `shutdown-on-stalled-component`).
+    ComponentStalled(String),
+}
+
+impl ShutdownReason {
+    /// The service which has caused the shutdown, if any.
+    pub fn service(&self) -> Option<&ServiceName> {
+        match self {
+            ShutdownReason::ServiceFailed(service)
+            | ShutdownReason::AttemptsExhausted(service)
+            | ShutdownReason::HealthchecksNotReady(service) => Some(service),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ShutdownReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ShutdownReason::Signal(signal) => write!(f, "{} received", signal),
+            ShutdownReason::ServiceFailed(service) => {
+                write!(f, "{} has failed (failure strategy: shutdown)", service)
+            }
+            ShutdownReason::AttemptsExhausted(service) => write!(
+                f,
+                "{} has no restart attempts left (on-attempts-exhausted: shutdown)",
+                service
+            ),
+            ShutdownReason::HealthchecksNotReady(service) => {
+                write!(f, "the healthchecks of {} couldn't be set up", service)
+            }
+            ShutdownReason::ControlCommand => write!(f, "requested using the control socket"),
+            ShutdownReason::ServicesFinished => write!(f, "all the services have finished"),
+            ShutdownReason::SimulationOver => write!(f, "the simulation is over"),
+            ShutdownReason::ComponentStalled(component) => {
+                write!(f, "the {} has stopped consuming its events", component)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RestartMode {
+    /// All the instances at once.
+    All,
+    /// One instance at a time, waiting for each one to be running again.
+    Rolling,
+    /// A new instance is started alongside the old one, which is stopped once the new one is
+    /// running. Only for single-instance services using socket activation.
+    BlueGreen,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum ExitStatus {
     Successful,
     SomeServiceFailed,
+    /// A core component panicked: all the services have been forcefully terminated. Also used if
+    /// a core component has stopped working (`shutdown-on-stalled-component`).
+    Crashed,
+    /// Some services were still running after the shutdown timeout, so they have been killed.
+    ShutdownTimedOut,
 }
 
-#[derive(PartialEq, Clone, Debug)]
+#[derive(PartialEq, Eq, Clone, Debug)]
 pub enum HealthinessStatus {
     Healthy,
     Unhealthy,
 }
 
+/// Outcome of a round of healthchecks.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct HealthCheckReport {
+    pub status: HealthinessStatus,
+    /// Time taken for running all the checks.
+    pub latency: Duration,
+    /// Why the checks have failed.
+    pub error: Option<String>,
+}
+
 impl From<bool> for HealthinessStatus {
     fn from(check: bool) -> Self {
         if check {
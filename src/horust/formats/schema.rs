@@ -0,0 +1,206 @@
+//! Versioning of the service files: `schema = <version>`. The files using an older schema are
+//! still loaded, by translating them to the current one and warning about the deprecated options.
+//! `horust migrate-config` rewrites them using the current schema.
+use crate::horust::error::{ErrorKind, HorustError, Result};
+use std::fs;
+use std::path::Path;
+
+/// Schema of the service files written for this version of horust.
+pub const CURRENT_SCHEMA: i64 = 1;
+/// The top level key holding the schema of the file. Files without it use the schema 0.
+const SCHEMA_KEY: &str = "schema";
+/// Tables whose keys are chosen by the user (e.g. environment variables), so they're never
+/// renamed.
+const FREE_FORM_TABLES: &[&str] = &[
+    "labels",
+    "environment.additional",
+    "healthiness.http-headers",
+    "healthiness.custom",
+];
+
+/// An option of an older schema, replaced by `replacement` in the current one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Deprecation {
+    pub key: String,
+    pub replacement: String,
+    /// The schema which has replaced the option.
+    pub schema: i64,
+}
+
+/// Translates the content of a service file to the current schema, removing the `schema` key.
+/// Returns the options which had to be replaced.
+pub(crate) fn migrate(value: &mut toml::Value) -> Result<Vec<Deprecation>> {
+    let table = match value {
+        toml::Value::Table(table) => table,
+        _ => return Ok(vec![]),
+    };
+    let schema = match table.remove(SCHEMA_KEY) {
+        None => 0,
+        Some(toml::Value::Integer(schema)) if (0..=CURRENT_SCHEMA).contains(&schema) => schema,
+        Some(schema) => {
+            return Err(schema_error(format!(
+                "unsupported schema: {}, the latest one is {}",
+                schema, CURRENT_SCHEMA
+            )))
+        }
+    };
+    let mut deprecations = vec![];
+    if schema < 1 {
+        // Schema 0 also allowed the keys to be spelled using underscores, e.g. `start_after`.
+        dash_keys(table, "", &mut deprecations);
+    }
+    Ok(deprecations)
+}
+
+/// Loads a service file, logging a warning for each of its deprecated options.
+pub(crate) fn load_file(path: &Path) -> Result<toml::Value> {
+    let mut value: toml::Value = toml::from_str(&fs::read_to_string(path)?)?;
+    for deprecation in migrate(&mut value)? {
+        warn!(
+            "{}: `{}` is deprecated since schema {}, use `{}` instead (check `horust migrate-config`)",
+            path.display(),
+            deprecation.key,
+            deprecation.schema,
+            deprecation.replacement
+        );
+    }
+    Ok(value)
+}
+
+/// Rewrites a service file using the current schema, keeping the original one aside
+/// (`<file>.bak`). Returns the options which have been replaced, or None if the file was already
+/// using the current schema.
+pub fn migrate_file(path: &Path) -> Result<Option<Vec<Deprecation>>> {
+    let content = fs::read_to_string(path)?;
+    let mut value: toml::Value = toml::from_str(&content)?;
+    let schema = value.get(SCHEMA_KEY).and_then(toml::Value::as_integer);
+    let deprecations = migrate(&mut value)?;
+    if schema == Some(CURRENT_SCHEMA) {
+        return Ok(None);
+    }
+    let migrated = format!(
+        "{} = {}\n{}",
+        SCHEMA_KEY,
+        CURRENT_SCHEMA,
+        toml::to_string(&value).map_err(|err| schema_error(err.to_string()))?
+    );
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    fs::write(&backup, content)?;
+    fs::write(path, migrated)?;
+    Ok(Some(deprecations))
+}
+
+/// Replaces the underscores in the keys of `table` (and of its sub-tables) with dashes.
+fn dash_keys(table: &mut toml::value::Table, path: &str, deprecations: &mut Vec<Deprecation>) {
+    let keys: Vec<String> = table.keys().cloned().collect();
+    for key in keys {
+        let dashed = key.replace('_', "-");
+        if dashed != key && !table.contains_key(&dashed) {
+            let value = table.remove(&key).unwrap();
+            table.insert(dashed.clone(), value);
+            deprecations.push(Deprecation {
+                key: format!("{}{}", path, key),
+                replacement: format!("{}{}", path, dashed),
+                schema: 1,
+            });
+        }
+        let key_path = format!("{}{}", path, dashed);
+        if FREE_FORM_TABLES.contains(&key_path.as_str()) {
+            continue;
+        }
+        match table.get_mut(&dashed) {
+            Some(toml::Value::Table(section)) => {
+                dash_keys(section, &format!("{}.", key_path), deprecations)
+            }
+            // E.g. the entries of wait-for.
+            Some(toml::Value::Array(items)) => {
+                for item in items {
+                    if let toml::Value::Table(item) = item {
+                        dash_keys(item, &format!("{}[].", key_path), deprecations);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+fn schema_error(message: String) -> HorustError {
+    ErrorKind::Schema(message).into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{migrate, migrate_file, Deprecation, CURRENT_SCHEMA};
+    use crate::horust::formats::Service;
+    use tempdir::TempDir;
+
+    fn deprecation(key: &str, replacement: &str) -> Deprecation {
+        Deprecation {
+            key: key.into(),
+            replacement: replacement.into(),
+            schema: 1,
+        }
+    }
+
+    #[test]
+    fn test_migrate() {
+        let mut value: toml::Value = toml::from_str(
+            r#"
+command = "./run.sh"
+start_after = ["db.toml"]
+labels = { team_name = "payments" }
+wait-for = [{ tcp = "db:5432", timeout = "5s" }]
+[restart]
+retry_permanent_errors = true
+[environment]
+additional = { DB_PASS = "secret" }
+"#,
+        )
+        .unwrap();
+        let deprecations = migrate(&mut value).unwrap();
+        assert_eq!(
+            deprecations,
+            vec![
+                deprecation(
+                    "restart.retry_permanent_errors",
+                    "restart.retry-permanent-errors"
+                ),
+                deprecation("start_after", "start-after"),
+            ]
+        );
+        let service: Service = value.try_into().unwrap();
+        assert_eq!(service.start_after, vec!["db.toml".into()]);
+        assert!(service.restart.retry_permanent_errors);
+        assert_eq!(service.labels["team_name"], "payments");
+        assert_eq!(service.environment.additional["DB_PASS"], "secret");
+
+        let mut value: toml::Value = toml::from_str("schema = 1\nstart_after = []").unwrap();
+        assert!(migrate(&mut value).unwrap().is_empty());
+        assert!(value.get("schema").is_none());
+        let mut value: toml::Value = toml::from_str("schema = 2").unwrap();
+        assert!(migrate(&mut value).is_err());
+    }
+
+    #[test]
+    fn test_migrate_file() {
+        let tempdir = TempDir::new("schema").unwrap();
+        let path = tempdir.path().join("a.toml");
+        let content = "command = \"./run.sh\"\nstart_delay = \"1s\"\n";
+        std::fs::write(&path, content).unwrap();
+        let deprecations = migrate_file(&path).unwrap().unwrap();
+        assert_eq!(
+            deprecations,
+            vec![deprecation("start_delay", "start-delay")]
+        );
+        assert_eq!(
+            std::fs::read_to_string(tempdir.path().join("a.toml.bak")).unwrap(),
+            content
+        );
+        let migrated = std::fs::read_to_string(&path).unwrap();
+        assert!(migrated.starts_with(&format!("schema = {}\n", CURRENT_SCHEMA)));
+        assert!(migrated.contains("start-delay = \"1s\""));
+        assert!(migrate_file(&path).unwrap().is_none());
+    }
+}
@@ -1,20 +1,40 @@
 use crate::horust::error::{HorustError, ValidationError, ValidationErrorKind};
+use crate::horust::formats::{schema, validate_templates, TimeOfDay, Timezone};
 use nix::sys::signal::{Signal, SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGUSR1, SIGUSR2};
 use nix::unistd;
+use rand::distributions::Uniform;
+use rand::Rng;
+use regex::Regex;
 use serde::de::{self, Visitor};
 use serde::export::fmt::Error;
 use serde::export::Formatter;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time::Duration;
 
 pub fn get_sample_service() -> String {
     r#"
 command = "/bin/bash -c 'echo hello world'"
+aliases = ["hello", "greeter"]
 start-delay = "2s"
+start-jitter = "0-10s"
+start-priority = 10
 start-after = ["another.toml", "second.toml"]
+binds-to = ["vpn.toml"]
+wait-for = [{ tcp = "db.internal:5432", timeout = "60s" }, { dns = "api.example.com" }]
+max-runtime = "2h"
+max-runtime-outcome = "success"
+stop-after-idle = "10m"
+replicas = 1
+listen = ["0.0.0.0:{port}"]
+port-range = "8000-8099"
+balance = "0.0.0.0:8080"
+labels = { tier = "backend", team = "payments" }
+profiles = ["dev"]
 stdout = "STDOUT"
 stderr = "/var/logs/hello_world_svc/stderr.log"
 user = "root"
@@ -24,14 +44,46 @@ working-directory = "/tmp/"
 strategy = "never"
 backoff = "0s"
 attempts = 0
+startup-retries = 5
+startup-backoff = "1s"
+on-attempts-exhausted = "exec:/usr/local/bin/notify-oncall"
+fallback-command = "/usr/local/bin/run-safe-mode.sh"
+retry-permanent-errors = true
+window = "10m"
+min-runtime = "10s"
+on-dependency-recovery = true
 
 [healthiness]
 http-endpoint = "http://localhost:8080/healthcheck"
+http-method = "GET"
+http-expected-status = [200, 204]
+http-body-regex = '"status":\s*"up"'
+http-ca-file = "/etc/ssl/internal-ca.pem"
+http-headers = { Authorization = "Bearer token" }
 file-path = "/var/myservice/up"
+unix = "/run/myservice.sock"
+unix-send = "PING\n"
+unix-reply-regex = "PONG"
+delegate = "/run/myservice/horust.sock"
+grpc = { address = "localhost:50051", service = "my.Svc" }
+log-pattern = "Server started on port \\d+"
+timeout = "2s"
+initial-delay = "30s"
+
+[healthiness.custom.redis]
+address = "localhost:6379"
+
+[liveness]
+file-path = "/var/myservice/alive"
+path = "/run/myservice.sock"
 
 [failure]
 successful-exit-code = [ 0, 1, 255]
 strategy = "ignore"
+permanent-exit-code = [78]
+min-runtime = "1s"
+immediate-exits = 5
+threshold = { failures = 3, window = "5m" }
 
 [environment]
 keep-env = false
@@ -42,6 +94,20 @@ additional = { key = "value"}
 signal = "TERM"
 wait = "10s"
 die-if-failed  = [ "db.toml"]
+exec-before = "/usr/local/bin/drain.sh"
+exec-before-timeout = "20s"
+kill-scope = "session"
+
+[adopt]
+pid-file = "/run/legacy.pid"
+
+[sandbox]
+user-namespace = { uid-map = "0 100000 65536", gid-map = "0 100000 65536" }
+
+[schedule]
+every = "1h"
+at = ["03:00", "15:30"]
+timezone = "Europe/Rome"
 "#
     .to_string()
 }
@@ -53,8 +119,20 @@ pub type ServiceName = String;
 pub struct Service {
     #[serde(default)]
     pub name: ServiceName,
-    #[serde()]
+    /// Other names of the service, usable in the dependencies and in horustctl.
+    #[serde(default = "Vec::new")]
+    pub aliases: Vec<String>,
+    /// Empty for targets.
+    #[serde(default)]
     pub command: String,
+    /// Just a group of dependencies, without any command: it's Running as soon as it can be
+    /// started, e.g. for other services to start after all of them at once.
+    #[serde(default)]
+    pub target: bool,
+    /// A disabled service is kept (e.g. other services can still refer to it), but it's not
+    /// started until it's unmasked using horustctl.
+    #[serde(default = "Service::default_enabled")]
+    pub enabled: bool,
     #[serde(default)]
     pub user: User,
     #[serde(default = "Service::default_working_directory")]
@@ -65,20 +143,70 @@ pub struct Service {
     pub stderr: LogOutput,
     #[serde(default, with = "humantime_serde")]
     pub start_delay: Duration,
+    #[serde(default)]
+    pub start_jitter: StartJitter,
+    /// Among the services ready to be started, the ones with a higher priority are started first.
+    #[serde(default)]
+    pub start_priority: i32,
+    #[serde(default = "Vec::new")]
+    pub start_after: Vec<Dependency>,
+    /// Run this service only while all of these services are running.
+    #[serde(default = "Vec::new")]
+    pub binds_to: Vec<ServiceName>,
+    /// Preconditions checked before spawning the command.
+    #[serde(default = "Vec::new")]
+    pub wait_for: Vec<WaitFor>,
+    /// Stop the service once it has been running for this long.
+    #[serde(default, with = "humantime_serde")]
+    pub max_runtime: Option<Duration>,
+    /// Status of a service stopped because of its `max_runtime`.
+    #[serde(default)]
+    pub max_runtime_outcome: MaxRuntimeOutcome,
+    /// Stop the service if it hasn't served any connection for this long.
+    #[serde(default, with = "humantime_serde")]
+    pub stop_after_idle: Option<Duration>,
+    /// Number of identical instances of this service, named `<name>-<index>`.
+    #[serde(default = "Service::default_replicas")]
+    pub replicas: u32,
+    /// Tcp sockets bound by horust and passed to the service (socket activation).
     #[serde(default = "Vec::new")]
-    pub start_after: Vec<ServiceName>,
+    pub listen: Vec<String>,
+    /// A port of this range is allocated to every instance, injected in its environment and in
+    /// its `listen` addresses (`{port}`).
+    #[serde(default)]
+    pub port_range: Option<PortRange>,
+    /// Horust accepts the connections on this address, and forwards them to the Running
+    /// instances in turn (to their first `listen` address).
+    #[serde(default)]
+    pub balance: Option<String>,
     #[serde()]
     pub signal_rewrite: Option<String>,
+    /// Run this service only if one of these profiles is active. If empty, it's always run.
+    #[serde(default = "Vec::new")]
+    pub profiles: Vec<String>,
+    /// Free-form labels, used for selecting services (e.g. in horustctl) and in the metrics.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
     #[serde(default)]
     pub restart: Restart,
     #[serde(default)]
     pub healthiness: Healthiness,
+    /// Checks used once the service is running. If missing, `healthiness` is used.
+    pub liveness: Option<Healthiness>,
     #[serde(default)]
     pub failure: Failure,
     #[serde(default)]
     pub environment: Environment,
     #[serde(default)]
     pub termination: Termination,
+    pub adopt: Option<Adopt>,
+    #[serde(default)]
+    pub sandbox: Sandbox,
+    /// Run the service on a schedule, instead of once: it's Idle between the runs.
+    pub schedule: Option<Schedule>,
+    /// Set if this service is an instance of a replicated service.
+    #[serde(skip)]
+    pub replica: Option<Replica>,
 }
 impl Service {
     fn default_working_directory() -> PathBuf {
@@ -93,9 +221,19 @@ impl Service {
         LogOutput::Stderr
     }
 
-    pub fn from_file(path: &PathBuf) -> crate::horust::error::Result<Self> {
-        let content = std::fs::read_to_string(path)?;
-        toml::from_str::<Service>(content.as_str()).map_err(HorustError::from)
+    fn default_replicas() -> u32 {
+        1
+    }
+
+    fn default_enabled() -> bool {
+        true
+    }
+
+    /// Loads a service file, translating it to the current schema if needed.
+    pub fn from_file(path: &Path) -> crate::horust::error::Result<Self> {
+        schema::load_file(path)?
+            .try_into::<Service>()
+            .map_err(HorustError::from)
     }
 
     /// Create the environment K=V variables, used for exec into the new process.
@@ -107,6 +245,33 @@ impl Service {
         ))
     }
 
+    /// A service without profiles is always active, otherwise at least one of its profiles should
+    /// be active.
+    pub fn is_in_profiles(&self, active_profiles: &[String]) -> bool {
+        self.profiles.is_empty()
+            || self
+                .profiles
+                .iter()
+                .any(|profile| active_profiles.contains(profile))
+    }
+
+    /// The options used by this service which rely on Linux-only features: procfs, pidfds and
+    /// user namespaces.
+    pub fn linux_only_options(&self) -> Vec<&'static str> {
+        vec![
+            ("stop-after-idle", self.stop_after_idle.is_some()),
+            ("adopt", self.adopt.is_some()),
+            (
+                "sandbox.user-namespace",
+                self.sandbox.user_namespace.is_some(),
+            ),
+        ]
+        .into_iter()
+        .filter(|(_option, is_used)| *is_used)
+        .map(|(option, _is_used)| option)
+        .collect()
+    }
+
     /// Wrapper for single command executions
     pub fn from_command(command: String) -> Self {
         Service {
@@ -120,21 +285,262 @@ impl Default for Service {
     fn default() -> Self {
         Self {
             name: "".to_owned(),
+            aliases: Default::default(),
             start_after: Default::default(),
+            binds_to: Default::default(),
+            wait_for: Default::default(),
+            max_runtime: None,
+            max_runtime_outcome: Default::default(),
+            stop_after_idle: None,
+            replicas: Self::default_replicas(),
+            listen: Default::default(),
+            port_range: None,
+            balance: None,
             working_directory: "/".into(),
             stdout: Default::default(),
             stderr: Default::default(),
             user: Default::default(),
             restart: Default::default(),
             start_delay: Duration::from_secs(0),
+            start_jitter: Default::default(),
+            start_priority: 0,
             command: "command".to_string(),
+            target: false,
+            enabled: true,
             healthiness: Default::default(),
+            liveness: None,
             signal_rewrite: None,
+            labels: Default::default(),
+            profiles: Default::default(),
             environment: Default::default(),
             failure: Default::default(),
             termination: Default::default(),
+            adopt: None,
+            sandbox: Default::default(),
+            schedule: None,
+            replica: None,
+        }
+    }
+}
+
+/// Maximum number of sockets passed to a service.
+pub const MAX_LISTENERS: usize = 16;
+
+/// Environment variable holding the index of the instance, in replicated services.
+pub const REPLICA_INDEX_ENV: &str = "HORUST_REPLICA_INDEX";
+/// Environment variable holding the name of the service, shared by its instances if replicated.
+pub const SERVICE_NAME_ENV: &str = "HORUST_SERVICE_NAME";
+/// Environment variable holding the name of the instance (the service itself, if not replicated).
+pub const INSTANCE_ENV: &str = "HORUST_INSTANCE";
+/// Environment variable holding how many times the service has been started before.
+pub const RESTART_COUNT_ENV: &str = "HORUST_RESTART_COUNT";
+/// Environment variable holding the pid of horust.
+pub const SUPERVISOR_PID_ENV: &str = "HORUST_SUPERVISOR_PID";
+/// Environment variables holding the port allocated to the instance (check `port_range`).
+pub const INSTANCE_PORT_ENVS: [&str; 2] = ["PORT", "HORUST_INSTANCE_PORT"];
+/// Replaced with the allocated port in the `listen` addresses, e.g. `0.0.0.0:{port}`.
+pub const PORT_PLACEHOLDER: &str = "{port}";
+
+/// An inclusive range of tcp ports, like "8000-8099".
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    /// How many ports it has.
+    pub fn size(&self) -> u32 {
+        u32::from(self.end - self.start) + 1
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("Invalid port: '{}'", port.trim()))
+        };
+        let (start, end) = match s.find('-') {
+            Some(idx) => (parse(&s[..idx])?, parse(&s[idx + 1..])?),
+            None => {
+                return Err(format!(
+                    "Invalid port range: '{}', expected e.g. 8000-8099",
+                    s
+                ))
+            }
+        };
+        if start == 0 || start > end {
+            return Err(format!(
+                "Invalid port range: '{}', it should start from 1, and the start cannot be bigger than the end",
+                s
+            ));
         }
+        Ok(PortRange { start, end })
+    }
+}
+
+impl std::fmt::Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+impl Serialize for PortRange {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D>(deserializer: D) -> Result<PortRange, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(PortRangeVisitor)
+    }
+}
+
+struct PortRangeVisitor;
+impl<'de> Visitor<'de> for PortRangeVisitor {
+    type Value = PortRange;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a port range like '8000-8099'")
+    }
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        PortRange::from_str(value).map_err(de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Replica {
+    /// Name of the replicated service.
+    pub of: ServiceName,
+    pub index: u32,
+}
+
+impl Service {
+    /// Creates the instance `index` of `replicated`: a service with this same configuration.
+    pub(crate) fn new_replica(&self, replicated: &str, index: u32) -> Self {
+        let mut service = self.clone();
+        service.name = format!("{}-{}", replicated, index);
+        service
+            .environment
+            .additional
+            .insert(REPLICA_INDEX_ENV.to_string(), index.to_string());
+        service.replica = Some(Replica {
+            of: replicated.to_string(),
+            index,
+        });
+        service
+    }
+}
+
+/// Replaces the services having more than one replica with their instances.
+/// References to them (e.g. in start-after) are replaced with references to all the instances.
+pub fn expand_replicas(services: Vec<Service>) -> Vec<Service> {
+    let instances: HashMap<ServiceName, Vec<ServiceName>> = services
+        .iter()
+        .filter(|service| service.replicas > 1)
+        .map(|service| {
+            let names = (0..service.replicas)
+                .map(|index| format!("{}-{}", service.name, index))
+                .collect();
+            (service.name.clone(), names)
+        })
+        .collect();
+    if instances.is_empty() {
+        return services;
+    }
+    let expand = |names: &[ServiceName]| -> Vec<ServiceName> {
+        names
+            .iter()
+            .flat_map(|name| {
+                instances
+                    .get(name)
+                    .cloned()
+                    .unwrap_or_else(|| vec![name.clone()])
+            })
+            .collect()
+    };
+    services
+        .into_iter()
+        .map(|mut service| {
+            service.start_after = service
+                .start_after
+                .iter()
+                .flat_map(|dependency| match dependency {
+                    Dependency::Service(name) => expand(std::slice::from_ref(name))
+                        .into_iter()
+                        .map(Dependency::Service)
+                        .collect(),
+                    Dependency::AnyOf(names) => vec![Dependency::AnyOf(expand(names))],
+                })
+                .collect();
+            service.binds_to = expand(&service.binds_to);
+            service.termination.die_if_failed = expand(&service.termination.die_if_failed);
+            service
+        })
+        .flat_map(|service| {
+            if service.replicas > 1 {
+                (0..service.replicas)
+                    .map(|index| service.new_replica(&service.name, index))
+                    .collect()
+            } else {
+                vec![service]
+            }
+        })
+        .collect()
+}
+
+/// Replaces the references to the aliases of the services (e.g. in start-after) with their names.
+pub fn resolve_aliases(services: Vec<Service>) -> Vec<Service> {
+    let names: HashMap<String, ServiceName> = services
+        .iter()
+        .flat_map(|service| {
+            service
+                .aliases
+                .iter()
+                .map(move |alias| (alias.clone(), service.name.clone()))
+        })
+        .collect();
+    if names.is_empty() {
+        return services;
     }
+    let resolve = |names_or_aliases: &[String]| -> Vec<ServiceName> {
+        names_or_aliases
+            .iter()
+            .map(|name| names.get(name).unwrap_or(name).clone())
+            .collect()
+    };
+    services
+        .into_iter()
+        .map(|mut service| {
+            service.start_after = service
+                .start_after
+                .iter()
+                .map(|dependency| match dependency {
+                    Dependency::Service(name) => {
+                        Dependency::Service(names.get(name).unwrap_or(name).clone())
+                    }
+                    Dependency::AnyOf(any_of) => Dependency::AnyOf(resolve(any_of)),
+                })
+                .collect();
+            service.binds_to = resolve(&service.binds_to);
+            service.termination.die_if_failed = resolve(&service.termination.die_if_failed);
+            service
+        })
+        .collect()
 }
 
 impl FromStr for Service {
@@ -220,6 +626,97 @@ impl From<&str> for LogOutput {
     }
 }
 
+/// A random delay in the range `min-max`, added to the start delay.
+/// E.g. `"0-10s"`, or `"10s"` which is the same.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StartJitter {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl StartJitter {
+    /// Picks a random duration in the range.
+    pub fn pick(&self) -> Duration {
+        if self.max <= self.min {
+            return self.min;
+        }
+        rand::thread_rng().sample(Uniform::new_inclusive(self.min, self.max))
+    }
+}
+
+impl FromStr for StartJitter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |s: &str| match s.trim() {
+            // Humantime requires a unit, even for zero.
+            "0" => Ok(Duration::from_secs(0)),
+            s => humantime::parse_duration(s)
+                .map_err(|error| format!("Invalid start-jitter: '{}', error: {}", s, error)),
+        };
+        let (min, max) = match s.find('-') {
+            Some(idx) => {
+                let (min, max) = (s[..idx].trim(), &s[idx + 1..]);
+                // The unit of max applies to a bare min as well: `5-10s` is `5s-10s`.
+                let unit = max.trim().trim_start_matches(|c: char| c.is_ascii_digit());
+                let is_bare = !min.is_empty() && min.chars().all(|c| c.is_ascii_digit());
+                let min = if is_bare && !unit.is_empty() {
+                    parse(&format!("{}{}", min, unit))?
+                } else {
+                    parse(min)?
+                };
+                (min, parse(max)?)
+            }
+            None => (Duration::from_secs(0), parse(s)?),
+        };
+        if min > max {
+            return Err(format!(
+                "Invalid start-jitter: '{}', min is bigger than max",
+                s
+            ));
+        }
+        Ok(Self { min, max })
+    }
+}
+
+impl Serialize for StartJitter {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let as_string = format!(
+            "{}-{}",
+            humantime::format_duration(self.min),
+            humantime::format_duration(self.max)
+        );
+        serializer.serialize_str(as_string.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for StartJitter {
+    fn deserialize<D>(deserializer: D) -> Result<StartJitter, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(StartJitterVisitor)
+    }
+}
+
+struct StartJitterVisitor;
+impl<'de> Visitor<'de> for StartJitterVisitor {
+    type Value = StartJitter;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a range of durations like '0-10s', or a single duration")
+    }
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        StartJitter::from_str(value).map_err(de::Error::custom)
+    }
+}
+
 #[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub struct Environment {
@@ -246,7 +743,7 @@ impl Environment {
         true
     }
 
-    fn get_hostname_val() -> String {
+    pub(crate) fn get_hostname_val() -> String {
         let hostname_path = "/etc/hostname";
         let localhost = "localhost".to_string();
         if std::path::PathBuf::from(hostname_path).is_file() {
@@ -323,18 +820,112 @@ impl Environment {
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 // TODO: Add a retry instead of instantly giving up.
 pub struct Healthiness {
+    /// Every probe should return within this time, otherwise it's considered failed.
+    /// Kept before the tables (`http-headers`, `custom`), for serializing into toml.
+    #[serde(default = "Healthiness::default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Grace period after the service is spawned, before running the first checks.
+    #[serde(default, with = "humantime_serde")]
+    pub initial_delay: Duration,
     pub http_endpoint: Option<String>,
+    /// Defaults to HEAD, or GET if `http_body_regex` is set.
+    pub http_method: Option<String>,
+    /// If empty, any 2xx status is fine.
+    #[serde(default)]
+    pub http_expected_status: Vec<u16>,
+    pub http_body_regex: Option<String>,
+    /// Accept any certificate from an https endpoint, e.g. a self-signed one.
+    #[serde(default)]
+    pub http_insecure: bool,
+    /// PEM file with additional certificate authorities, for an https endpoint.
+    pub http_ca_file: Option<PathBuf>,
+    #[serde(default)]
+    pub http_headers: HashMap<String, String>,
     pub file_path: Option<PathBuf>,
+    /// Should keep existing (e.g. a unix socket, or a pid file). Unlike `file_path`, it's never
+    /// removed by horust.
+    pub path: Option<PathBuf>,
+    /// Unix socket accepting connections.
+    pub unix: Option<PathBuf>,
+    /// Sent after connecting to `unix`.
+    pub unix_send: Option<String>,
+    /// The reply read from `unix` should match this regex.
+    pub unix_reply_regex: Option<String>,
+    /// Control socket of a nested supervisor (e.g. another horust, as a service group): healthy
+    /// while all of its services are up.
+    pub delegate: Option<PathBuf>,
+    pub grpc: Option<GrpcHealthCheck>,
+    /// Regex matched against the output of the service. It's only used for deciding when the
+    /// service is running (so it's ignored in the liveness section).
+    pub log_pattern: Option<String>,
+    /// Configuration of the custom health probes, keyed by the probe's key.
+    #[serde(default)]
+    pub custom: HashMap<String, HashMap<String, String>>,
+}
+
+/// Uses the standard grpc health checking protocol (grpc.health.v1.Health/Check).
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct GrpcHealthCheck {
+    /// E.g. `localhost:50051`.
+    pub address: String,
+    /// Name of the grpc service to check. If empty, the overall health of the server is checked.
+    #[serde(default)]
+    pub service: String,
 }
 
 impl Default for Healthiness {
     fn default() -> Self {
         Self {
             http_endpoint: None,
+            http_method: None,
+            http_expected_status: Default::default(),
+            http_body_regex: None,
+            http_insecure: false,
+            http_ca_file: None,
+            http_headers: Default::default(),
             file_path: None,
+            path: None,
+            unix: None,
+            unix_send: None,
+            unix_reply_regex: None,
+            delegate: None,
+            grpc: None,
+            log_pattern: None,
+            custom: Default::default(),
+            timeout: Self::default_timeout(),
+            initial_delay: Duration::from_secs(0),
+        }
+    }
+}
+impl Healthiness {
+    fn default_timeout() -> Duration {
+        Duration::from_secs(1)
+    }
+    fn validate(&self) -> Result<(), String> {
+        if let Some(body_regex) = &self.http_body_regex {
+            Regex::new(body_regex).map_err(|err| format!("invalid http-body-regex: {}", err))?;
+        }
+        if let Some(log_pattern) = &self.log_pattern {
+            Regex::new(log_pattern).map_err(|err| format!("invalid log-pattern: {}", err))?;
+        }
+        if let Some(reply_regex) = &self.unix_reply_regex {
+            Regex::new(reply_regex).map_err(|err| format!("invalid unix-reply-regex: {}", err))?;
+        }
+        if self.timeout == Duration::from_secs(0) {
+            return Err("timeout must be greater than zero".into());
+        }
+        if let Some(method) = &self.http_method {
+            let is_valid = ["GET", "HEAD", "POST", "PUT", "DELETE", "OPTIONS", "PATCH"]
+                .contains(&method.to_uppercase().as_str());
+            if !is_valid {
+                return Err(format!("invalid http-method: {}", method));
+            }
         }
+        Ok(())
     }
 }
+
 /// A user in the system.
 /// It can be either a uuid or a username (available in passwd)
 #[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
@@ -407,11 +998,18 @@ pub enum ServiceStatus {
     Finished,
     /// A failed, finished service won't be restarted.
     FinishedFailed,
+    /// Like FinishedFailed, but it has been given up on before running out of attempts: trying
+    /// again is pointless (e.g. its program doesn't exist).
+    PermanentlyFailed,
     /// A Failed service might be restarted if the restart policy demands so.
     Failed,
     /// This is the initial state: A service in Initial state is marked to be runnable:
     /// it will be run as soon as possible.
     Initial,
+    /// Stopped after being idle, it will be started again on the next activation.
+    Idle,
+    /// Disabled (`enabled = false`) or masked: it won't be started until it's unmasked.
+    Disabled,
 }
 
 impl std::fmt::Display for ServiceStatus {
@@ -420,12 +1018,15 @@ impl std::fmt::Display for ServiceStatus {
             ServiceStatus::Failed => "Failed",
             ServiceStatus::Finished => "Finished",
             ServiceStatus::FinishedFailed => "FinishedFailed",
+            ServiceStatus::PermanentlyFailed => "PermanentlyFailed",
             ServiceStatus::InKilling => "InKilling",
             ServiceStatus::Initial => "Initial",
             ServiceStatus::Running => "Running",
             ServiceStatus::Started => "Started",
             ServiceStatus::Starting => "Starting",
             ServiceStatus::Success => "Success",
+            ServiceStatus::Idle => "Idle",
+            ServiceStatus::Disabled => "Disabled",
         })
     }
 }
@@ -439,6 +1040,34 @@ pub struct Restart {
     pub backoff: Duration,
     #[serde(default = "default_attempts")]
     pub attempts: u32,
+    /// If set, the failures before the service has ever been running are counted against these
+    /// retries instead of `attempts`, which are left for the failures after having been running.
+    #[serde(default)]
+    pub startup_retries: Option<u32>,
+    /// The backoff of the startup retries, `backoff` by default.
+    #[serde(default, with = "humantime_serde")]
+    pub startup_backoff: Option<Duration>,
+    #[serde(default)]
+    pub on_attempts_exhausted: AttemptsExhaustedAction,
+    /// Once the attempts are exhausted, the service gets another round of attempts running this
+    /// command instead (e.g. a degraded mode). `on_attempts_exhausted` applies after those.
+    pub fallback_command: Option<String>,
+    /// By default, if the command cannot be run because of a permanent error (e.g. the program
+    /// doesn't exist), the service becomes FinishedFailed without using the remaining attempts.
+    #[serde(default)]
+    pub retry_permanent_errors: bool,
+    /// If set, the failures are counted as attempts even after the service has been running, and
+    /// the attempts are reset only once the service has been up for this long.
+    #[serde(default, with = "humantime_serde")]
+    pub window: Option<Duration>,
+    /// Exiting before being up for this long counts as a failed start, even with a successful exit
+    /// code: e.g. a daemon which exits right away because of its configuration.
+    #[serde(default, with = "humantime_serde")]
+    pub min_runtime: Option<Duration>,
+    /// Restarted once one of its dependencies is running again after having failed, e.g. for
+    /// refreshing its connections to it.
+    #[serde(default)]
+    pub on_dependency_recovery: bool,
 }
 fn default_attempts() -> u32 {
     10
@@ -450,10 +1079,86 @@ impl Default for Restart {
             strategy: RestartStrategy::Never,
             backoff: Duration::from_secs(0),
             attempts: 0,
+            startup_retries: None,
+            startup_backoff: None,
+            on_attempts_exhausted: Default::default(),
+            fallback_command: None,
+            retry_permanent_errors: false,
+            window: None,
+            min_runtime: None,
+            on_dependency_recovery: false,
+        }
+    }
+}
+
+/// What to do once the service has run out of restart attempts.
+#[derive(Clone, Debug, Eq, PartialEq, Default)]
+pub enum AttemptsExhaustedAction {
+    /// The service is just considered as FinishedFailed.
+    #[default]
+    Ignore,
+    /// Shut down the whole system.
+    Shutdown,
+    /// Run this command, e.g. for remediation or alerting.
+    Exec(String),
+}
+
+impl FromStr for AttemptsExhaustedAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(AttemptsExhaustedAction::Ignore),
+            "shutdown" => Ok(AttemptsExhaustedAction::Shutdown),
+            s if s.starts_with("exec:") && s.len() > "exec:".len() => {
+                Ok(AttemptsExhaustedAction::Exec(s["exec:".len()..].into()))
+            }
+            s => Err(format!(
+                "Invalid on-attempts-exhausted: '{}', expected: 'ignore', 'shutdown' or 'exec:<command>'",
+                s
+            )),
         }
     }
 }
 
+impl Serialize for AttemptsExhaustedAction {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let as_string = match self {
+            AttemptsExhaustedAction::Ignore => "ignore".to_string(),
+            AttemptsExhaustedAction::Shutdown => "shutdown".to_string(),
+            AttemptsExhaustedAction::Exec(command) => format!("exec:{}", command),
+        };
+        serializer.serialize_str(as_string.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for AttemptsExhaustedAction {
+    fn deserialize<D>(deserializer: D) -> Result<AttemptsExhaustedAction, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(AttemptsExhaustedActionVisitor)
+    }
+}
+
+struct AttemptsExhaustedActionVisitor;
+impl<'de> Visitor<'de> for AttemptsExhaustedActionVisitor {
+    type Value = AttemptsExhaustedAction;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("'ignore', 'shutdown' or 'exec:<command>'")
+    }
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        AttemptsExhaustedAction::from_str(value).map_err(de::Error::custom)
+    }
+}
+
 #[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub enum RestartStrategy {
@@ -485,55 +1190,322 @@ impl From<&str> for RestartStrategy {
     }
 }
 
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum MaxRuntimeOutcome {
+    #[default]
+    Failed,
+    Success,
+}
+
 #[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct Failure {
-    #[serde(default = "Failure::default_successful_exit_code")]
-    pub successful_exit_code: Vec<i32>,
+    #[serde(
+        default = "Failure::default_successful_exit_code",
+        serialize_with = "Failure::serialize_exit_codes"
+    )]
+    pub successful_exit_code: Vec<ExitCodes>,
     pub strategy: FailureStrategy,
+    /// Exiting with one of these codes means that restarting the service is pointless (e.g. 78,
+    /// EX_CONFIG, for an invalid configuration): it becomes PermanentlyFailed.
+    #[serde(default, serialize_with = "Failure::serialize_exit_codes")]
+    pub permanent_exit_code: Vec<ExitCodes>,
+    /// Failing before being up for this long counts as an immediate exit.
+    #[serde(default, with = "humantime_serde")]
+    pub min_runtime: Option<Duration>,
+    /// After this many immediate exits in a row, the service becomes PermanentlyFailed.
+    #[serde(default = "Failure::default_immediate_exits")]
+    pub immediate_exits: u32,
+    /// If set, the strategy applies only once the service has failed this often, instead of at
+    /// every failure.
+    #[serde(default)]
+    pub threshold: Option<FailureThreshold>,
 }
 
-impl Failure {
-    fn default_successful_exit_code() -> Vec<i32> {
-        vec![0]
-    }
-}
-
+/// The failure strategy applies only to a service failing at least `failures` times within
+/// `window`.
 #[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
-pub enum FailureStrategy {
-    Shutdown,
-    KillDependents,
-    Ignore,
+pub struct FailureThreshold {
+    pub failures: u32,
+    #[serde(with = "humantime_serde")]
+    pub window: Duration,
 }
 
-impl Default for Failure {
-    fn default() -> Self {
-        Failure {
-            successful_exit_code: Self::default_successful_exit_code(),
-            strategy: FailureStrategy::Ignore,
+impl Failure {
+    fn default_successful_exit_code() -> Vec<ExitCodes> {
+        vec![ExitCodes::Code(0)]
+    }
+
+    fn default_immediate_exits() -> u32 {
+        3
+    }
+
+    /// TOML arrays cannot mix integers and strings: unless they're all plain codes, the exit
+    /// codes are written as strings.
+    fn serialize_exit_codes<S>(codes: &[ExitCodes], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        if codes
+            .iter()
+            .all(|codes| matches!(codes, ExitCodes::Code(_)))
+        {
+            codes.serialize(serializer)
+        } else {
+            codes
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .serialize(serializer)
         }
     }
-}
 
-impl From<String> for FailureStrategy {
-    fn from(strategy: String) -> Self {
-        strategy.as_str().into()
+    pub fn is_successful(&self, exit_code: i32) -> bool {
+        self.successful_exit_code
+            .iter()
+            .any(|codes| codes.contains(exit_code))
+    }
+
+    pub fn is_permanent(&self, exit_code: i32) -> bool {
+        self.permanent_exit_code
+            .iter()
+            .any(|codes| codes.contains(exit_code))
     }
 }
 
-impl From<&str> for FailureStrategy {
-    fn from(strategy: &str) -> Self {
-        match strategy.to_lowercase().as_str() {
-            "kill-dependents" => FailureStrategy::KillDependents,
-            "kill-all" => FailureStrategy::Shutdown,
-            "ignore" => FailureStrategy::Ignore,
-            _ => FailureStrategy::Ignore,
+/// Sets of exit codes available by name.
+const NAMED_EXIT_CODES: &[(&str, &[i32])] = &[
+    // diff, cmp and grep exit with 1 if the inputs differ or nothing matches, and with 2 on errors.
+    ("diff", &[0, 1]),
+    // Exit codes used by shells and by the JVM when terminated by SIGINT or SIGTERM.
+    ("terminated", &[130, 143]),
+];
+
+/// Some exit codes of a service: a single one, an inclusive range like "0-3", a named set (check
+/// `NAMED_EXIT_CODES`), or "any".
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ExitCodes {
+    Code(i32),
+    Range(i32, i32),
+    Named(&'static str, &'static [i32]),
+    Any,
+}
+
+impl ExitCodes {
+    pub fn contains(&self, exit_code: i32) -> bool {
+        match self {
+            ExitCodes::Code(code) => *code == exit_code,
+            ExitCodes::Range(min, max) => (*min..=*max).contains(&exit_code),
+            ExitCodes::Named(_name, codes) => codes.contains(&exit_code),
+            ExitCodes::Any => true,
         }
     }
 }
 
-#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+impl From<i32> for ExitCodes {
+    fn from(code: i32) -> Self {
+        ExitCodes::Code(code)
+    }
+}
+
+impl FromStr for ExitCodes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s == "any" {
+            return Ok(ExitCodes::Any);
+        }
+        if let Some((name, codes)) = NAMED_EXIT_CODES.iter().find(|(name, _codes)| *name == s) {
+            return Ok(ExitCodes::Named(name, codes));
+        }
+        let parse = |code: &str| {
+            code.trim()
+                .parse::<i32>()
+                .map_err(|_| format!("Invalid exit code: '{}'", code.trim()))
+        };
+        match s.find('-') {
+            Some(idx) => {
+                let (min, max) = (parse(&s[..idx])?, parse(&s[idx + 1..])?);
+                if min > max {
+                    return Err(format!(
+                        "Invalid exit codes range: '{}', min is bigger than max",
+                        s
+                    ));
+                }
+                Ok(ExitCodes::Range(min, max))
+            }
+            None => parse(s).map(ExitCodes::Code),
+        }
+    }
+}
+
+impl std::fmt::Display for ExitCodes {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ExitCodes::Code(code) => write!(f, "{}", code),
+            ExitCodes::Range(min, max) => write!(f, "{}-{}", min, max),
+            ExitCodes::Named(name, _codes) => f.write_str(name),
+            ExitCodes::Any => f.write_str("any"),
+        }
+    }
+}
+
+impl Serialize for ExitCodes {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            ExitCodes::Code(code) => serializer.serialize_i32(*code),
+            other => serializer.serialize_str(&other.to_string()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ExitCodes {
+    fn deserialize<D>(deserializer: D) -> Result<ExitCodes, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(ExitCodesVisitor)
+    }
+}
+
+struct ExitCodesVisitor;
+impl<'de> Visitor<'de> for ExitCodesVisitor {
+    type Value = ExitCodes;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("an exit code, a range like '0-3', a named set or 'any'")
+    }
+    fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        i32::try_from(value)
+            .map(ExitCodes::Code)
+            .map_err(|_| de::Error::custom(format!("Invalid exit code: {}", value)))
+    }
+    fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        i32::try_from(value)
+            .map(ExitCodes::Code)
+            .map_err(|_| de::Error::custom(format!("Invalid exit code: {}", value)))
+    }
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        ExitCodes::from_str(value).map_err(de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FailureStrategy {
+    Shutdown,
+    KillDependents,
+    /// Stops the services of the group (having this `group` label), and the ones bound to them.
+    ShutdownGroup(String),
+    Ignore,
+}
+
+/// The label naming the group of a service, for `FailureStrategy::ShutdownGroup`.
+pub const GROUP_LABEL: &str = "group";
+
+impl Default for Failure {
+    fn default() -> Self {
+        Failure {
+            successful_exit_code: Self::default_successful_exit_code(),
+            strategy: FailureStrategy::Ignore,
+            permanent_exit_code: vec![],
+            min_runtime: None,
+            immediate_exits: Self::default_immediate_exits(),
+            threshold: None,
+        }
+    }
+}
+
+impl From<String> for FailureStrategy {
+    fn from(strategy: String) -> Self {
+        strategy.as_str().into()
+    }
+}
+
+impl FromStr for FailureStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shutdown" => Ok(FailureStrategy::Shutdown),
+            "kill-dependents" => Ok(FailureStrategy::KillDependents),
+            "ignore" => Ok(FailureStrategy::Ignore),
+            s if s.starts_with("shutdown-group:") && s.len() > "shutdown-group:".len() => Ok(
+                FailureStrategy::ShutdownGroup(s["shutdown-group:".len()..].into()),
+            ),
+            s => Err(format!(
+                "Invalid failure strategy: '{}', expected: 'shutdown', 'kill-dependents', 'ignore' or 'shutdown-group:<group>'",
+                s
+            )),
+        }
+    }
+}
+
+impl Serialize for FailureStrategy {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let as_string = match self {
+            FailureStrategy::Shutdown => "shutdown".to_string(),
+            FailureStrategy::KillDependents => "kill-dependents".to_string(),
+            FailureStrategy::ShutdownGroup(group) => format!("shutdown-group:{}", group),
+            FailureStrategy::Ignore => "ignore".to_string(),
+        };
+        serializer.serialize_str(as_string.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for FailureStrategy {
+    fn deserialize<D>(deserializer: D) -> Result<FailureStrategy, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(FailureStrategyVisitor)
+    }
+}
+
+struct FailureStrategyVisitor;
+impl<'de> Visitor<'de> for FailureStrategyVisitor {
+    type Value = FailureStrategy;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("'shutdown', 'kill-dependents', 'ignore' or 'shutdown-group:<group>'")
+    }
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        FailureStrategy::from_str(value).map_err(de::Error::custom)
+    }
+}
+
+impl From<&str> for FailureStrategy {
+    fn from(strategy: &str) -> Self {
+        match strategy.to_lowercase().as_str() {
+            "kill-dependents" => FailureStrategy::KillDependents,
+            "kill-all" => FailureStrategy::Shutdown,
+            "ignore" => FailureStrategy::Ignore,
+            _ => FailureStrategy::Ignore,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct Termination {
     #[serde(default)]
@@ -545,12 +1517,26 @@ pub struct Termination {
     #[serde(default = "Vec::new")]
     // Will kill this service if any of the services in Vec are failed
     pub die_if_failed: Vec<ServiceName>,
+    /// Run (e.g. for draining the connections) before sending the signal.
+    pub exec_before: Option<String>,
+    #[serde(
+        default = "Termination::default_exec_before_timeout",
+        with = "humantime_serde"
+    )]
+    /// After this time, `exec_before` is killed and the signal is sent anyway.
+    pub exec_before_timeout: Duration,
+    #[serde(default)]
+    /// Which processes receive the signal, and SIGKILL.
+    pub kill_scope: KillScope,
 }
 
 impl Termination {
     fn default_wait() -> Duration {
         Duration::from_secs(5)
     }
+    fn default_exec_before_timeout() -> Duration {
+        Duration::from_secs(30)
+    }
 }
 
 impl Default for Termination {
@@ -559,10 +1545,24 @@ impl Default for Termination {
             signal: Default::default(),
             wait: Self::default_wait(),
             die_if_failed: Vec::new(),
+            exec_before: None,
+            exec_before_timeout: Self::default_exec_before_timeout(),
+            kill_scope: Default::default(),
         }
     }
 }
 
+/// The processes of the service which are killed when it's stopped.
+#[derive(Serialize, Copy, Clone, Deserialize, Debug, Eq, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum KillScope {
+    /// Only its main process: the processes it has spawned are left running.
+    #[default]
+    Process,
+    /// Every process in its session.
+    Session,
+}
+
 #[derive(Serialize, Copy, Clone, Deserialize, Debug, Eq, PartialEq)]
 pub enum TerminationSignal {
     TERM,
@@ -592,12 +1592,244 @@ impl Default for TerminationSignal {
     }
 }
 
+/// A precondition for spawning the service: exactly one among `tcp` and `dns` should be set.
+/// If it's not met within `timeout`, the service fails.
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct WaitFor {
+    /// `host:port` accepting tcp connections.
+    pub tcp: Option<String>,
+    /// Hostname which should be resolvable.
+    pub dns: Option<String>,
+    #[serde(default = "WaitFor::default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+impl WaitFor {
+    fn default_timeout() -> Duration {
+        Duration::from_secs(30)
+    }
+}
+
+impl std::fmt::Display for WaitFor {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (&self.tcp, &self.dns) {
+            (Some(address), _) => write!(f, "tcp {}", address),
+            (None, Some(hostname)) => write!(f, "dns {}", hostname),
+            (None, None) => write!(f, "nothing"),
+        }
+    }
+}
+
+/// An entry of `start-after`.
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(untagged)]
+pub enum Dependency {
+    /// This service must be running (or finished).
+    Service(ServiceName),
+    /// At least one of these services must be running (or finished).
+    AnyOf(Vec<ServiceName>),
+}
+
+impl Dependency {
+    /// All the services mentioned by this dependency.
+    pub fn services(&self) -> Vec<&ServiceName> {
+        match self {
+            Dependency::Service(name) => vec![name],
+            Dependency::AnyOf(names) => names.iter().collect(),
+        }
+    }
+
+    /// Checks if the dependency is satisfied, given a check for the single services.
+    pub fn is_satisfied<F>(&self, is_started: F) -> bool
+    where
+        F: Fn(&ServiceName) -> bool,
+    {
+        match self {
+            Dependency::Service(name) => is_started(name),
+            Dependency::AnyOf(names) => names.iter().any(is_started),
+        }
+    }
+}
+
+impl From<&str> for Dependency {
+    fn from(name: &str) -> Self {
+        Dependency::Service(name.into())
+    }
+}
+
+/// Attach an already running process to the service, instead of spawning `command`.
+/// The command is still used if the process cannot be adopted, or for restarting the service after
+/// the adopted process has exited.
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Adopt {
+    /// File containing the pid of the process to adopt.
+    pub pid_file: PathBuf,
+}
+
+/// The timers starting the service. It's started at every firing of one of them, unless it's
+/// still running: then that firing is skipped.
+#[derive(Serialize, Clone, Deserialize, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Schedule {
+    /// Interval since horust has started, on a monotonic clock (which counts the time spent
+    /// suspended too): the firings keep their pace regardless of the changes of the wall clock.
+    #[serde(default, with = "humantime_serde")]
+    pub every: Option<Duration>,
+    /// Times of the day, in `timezone`, e.g. "03:00".
+    #[serde(default = "Vec::new")]
+    pub at: Vec<TimeOfDay>,
+    /// Timezone of the `at` times: either a zoneinfo name (e.g. "Europe/Rome") or a POSIX TZ
+    /// string. If missing, the local timezone.
+    #[serde(default)]
+    pub timezone: Option<String>,
+}
+
+/// Isolation of the service from the rest of the system.
+#[derive(Serialize, Clone, Deserialize, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Sandbox {
+    /// Run the service in a new user namespace.
+    pub user_namespace: Option<UserNamespace>,
+}
+
+/// The uids and gids of the namespace, mapped to the ones of the host. Each map has one or more
+/// ranges (separated by newlines or commas) like `<first id inside> <first id outside> <count>`,
+/// e.g. `0 100000 65536`: the same format of `/proc/<pid>/uid_map`.
+/// The service runs with the `user` of the namespace, and with its group 0.
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct UserNamespace {
+    pub uid_map: String,
+    pub gid_map: String,
+}
+
+/// Maximum number of ranges in a uid or gid map.
+const MAX_ID_MAP_RANGES: usize = 340;
+
+/// A range of a uid or gid map: (first id inside, first id outside, count).
+pub(crate) type IdMapRange = (u32, u32, u32);
+
+impl UserNamespace {
+    pub(crate) fn parse_map(map: &str) -> Result<Vec<IdMapRange>, String> {
+        let ranges = map
+            .split(['\n', ','])
+            .map(str::trim)
+            .filter(|range| !range.is_empty())
+            .map(|range| {
+                let ids = range
+                    .split_whitespace()
+                    .map(str::parse)
+                    .collect::<Result<Vec<u32>, _>>()
+                    .map_err(|err| format!("invalid range '{}': {}", range, err))?;
+                match ids[..] {
+                    [inside, outside, count] if count > 0 => Ok((inside, outside, count)),
+                    _ => Err(format!(
+                        "invalid range '{}', expected: '<inside> <outside> <count>'",
+                        range
+                    )),
+                }
+            })
+            .collect::<Result<Vec<IdMapRange>, String>>()?;
+        match ranges.len() {
+            0 => Err("the map is empty".into()),
+            len if len > MAX_ID_MAP_RANGES => Err(format!(
+                "the map has more than {} ranges",
+                MAX_ID_MAP_RANGES
+            )),
+            _ => Ok(ranges),
+        }
+    }
+
+    /// The map, as written to `/proc/<pid>/uid_map` or `/proc/<pid>/gid_map`.
+    pub(crate) fn format_map(ranges: &[IdMapRange]) -> String {
+        ranges
+            .iter()
+            .map(|(inside, outside, count)| format!("{} {} {}\n", inside, outside, count))
+            .collect()
+    }
+
+    fn is_mapped(ranges: &[IdMapRange], id: u32) -> bool {
+        ranges
+            .iter()
+            .any(|(inside, _, count)| id >= *inside && (id - inside) < *count)
+    }
+
+    fn validate(&self, user: &User) -> Result<(), String> {
+        let uid_map = Self::parse_map(&self.uid_map).map_err(|err| format!("uid-map: {}", err))?;
+        let gid_map = Self::parse_map(&self.gid_map).map_err(|err| format!("gid-map: {}", err))?;
+        if let User::Uid(uid) = user {
+            if !Self::is_mapped(&uid_map, *uid) {
+                return Err(format!("the uid of its user ({}) is not mapped", uid));
+            }
+        }
+        if !Self::is_mapped(&gid_map, 0) {
+            return Err("the gid 0 is not mapped".into());
+        }
+        Ok(())
+    }
+}
+
+/// Labels are exported as prometheus labels, so they follow the same rules.
+/// `service` is already used for the service name.
+/// Labels added by horust to some of the metrics.
+const RESERVED_LABELS: &[&str] = &["service", "reason"];
+
+fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    let is_first_valid = match chars.next() {
+        Some(first) => first.is_ascii_alphabetic() || first == '_',
+        None => false,
+    };
+    is_first_valid
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with("__")
+        && !RESERVED_LABELS.contains(&name)
+}
+
+/// The name of the service which was most likely meant, in case of a typo: the service names
+/// include the extension of their file (e.g. `db.toml`), which is easy to forget.
+fn closest_service_name<'a>(name: &str, services: &'a [Service]) -> Option<&'a str> {
+    let with_extension = format!("{}.toml", name);
+    services
+        .iter()
+        .map(|service| service.name.as_str())
+        .find(|candidate| *candidate == with_extension)
+        .or_else(|| {
+            services
+                .iter()
+                .map(|service| (edit_distance(name, &service.name), service.name.as_str()))
+                .filter(|(distance, _candidate)| *distance <= 2)
+                .min()
+                .map(|(_distance, candidate)| candidate)
+        })
+}
+
+/// Levenshtein distance between the two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + (a_char != *b_char) as usize;
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
 /// Runs some validation checks on the services.
 /// TODO: if redirect output is file, check it exists and permissions.
 pub fn validate(services: Vec<Service>) -> Result<Vec<Service>, Vec<ValidationError>> {
     let mut errors = vec![];
     services.iter().for_each(|service| {
-        if service.command.is_empty() {
+        if service.target && !service.command.is_empty() {
+            let err = format!("Service '{}', a target cannot have a command.", service.name);
+            errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::InvalidTarget));
+        } else if !service.target && service.command.is_empty() {
             let err = format!("Command is defined, but it is empty for service: {}", service.name);
             errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::CommandEmpty));
         }
@@ -607,16 +1839,166 @@ pub fn validate(services: Vec<Service>) -> Result<Vec<Service>, Vec<ValidationEr
                 service.name, service.start_after
             );
         }
+        let start_after = service.start_after.iter().flat_map(Dependency::services);
+        let binds_to = service.binds_to.iter();
+        let die_if_failed = service.termination.die_if_failed.iter();
+        start_after
+            .map(|name| ("start-after", name))
+            .chain(binds_to.map(|name| ("binds-to", name)))
+            .chain(die_if_failed.map(|name| ("termination.die-if-failed", name)))
+            .filter(|(_field, name)| {
+                !services
+                    .iter()
+                    .any(|s| s.name == **name || s.aliases.contains(name))
+            })
+            .for_each(|(field, name)| {
+                let suggestion = closest_service_name(name, &services)
+                    .map(|closest| format!(" Did you mean '{}'?", closest))
+                    .unwrap_or_default();
+                let err = format!("Service '{}', {} refers to '{}', but there is no service with such name.{}", service.name, field, name, suggestion);
+                errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::MissingDependency));
+            });
+        vec![Some(&service.healthiness), service.liveness.as_ref()]
+            .into_iter()
+            .flatten()
+            .for_each(|healthiness| {
+                if let Err(err) = healthiness.validate() {
+                    let err = format!("Service '{}', {}", service.name, err);
+                    errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::InvalidHealthiness));
+                }
+            });
         service
-            .start_after
+            .wait_for
             .iter()
-            .for_each(|name| {
-                let passed = services.iter().any(|s| s.name == *name);
-                if !passed {
-                    let err = format!("Service '{}', should start after '{}', but there is no service with such name.", service.name, name);
-                    errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::MissingDependency));
-                }
+            .filter(|wait_for| wait_for.tcp.is_some() == wait_for.dns.is_some())
+            .for_each(|wait_for| {
+                let err = format!("Service '{}', every wait-for should have either tcp or dns, found: {:?}", service.name, wait_for);
+                errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::InvalidWaitFor));
+            });
+        std::iter::once(("command", &service.command))
+            .chain(service.environment.additional.values().map(|value| ("environment", value)))
+            .filter_map(|(option, value)| validate_templates(value).err().map(|error| (option, error)))
+            .for_each(|(option, error)| {
+                let err = format!("Service '{}', invalid template in its {}: {}", service.name, option, error);
+                errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::InvalidTemplate));
+            });
+        if service.listen.len() > MAX_LISTENERS {
+            let err = format!("Service '{}', it cannot listen on more than {} sockets.", service.name, MAX_LISTENERS);
+            errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::InvalidListen));
+        }
+        // Otherwise the instances would bind the same address.
+        let (with_port, without_port): (Vec<&String>, Vec<&String>) = service.listen.iter().partition(|address| address.contains(PORT_PLACEHOLDER));
+        match service.port_range {
+            Some(_) if !without_port.is_empty() => {
+                let err = format!("Service '{}', using port-range, every listen address should use the allocated port ({}), found: {:?}", service.name, PORT_PLACEHOLDER, without_port);
+                errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::InvalidListen));
+            }
+            None if !with_port.is_empty() => {
+                let err = format!("Service '{}', the listen addresses {:?} use the allocated port ({}), but there is no port-range.", service.name, with_port, PORT_PLACEHOLDER);
+                errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::InvalidListen));
+            }
+            Some(port_range) if port_range.size() < service.replicas => {
+                let err = format!("Service '{}', its port-range {} is too small for {} replicas.", service.name, port_range, service.replicas);
+                errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::InvalidReplicas));
+            }
+            _ => (),
+        }
+        if let Some(balance) = &service.balance {
+            if balance.parse::<SocketAddr>().is_err() {
+                let err = format!("Service '{}', balance should be a tcp address (e.g. 0.0.0.0:8080), found: {}", service.name, balance);
+                errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::InvalidListen));
+            }
+            // The instances cannot be told apart otherwise.
+            if service.port_range.is_none() || service.listen.is_empty() {
+                let err = format!("Service '{}', using balance, it should listen on the allocated port (check port-range).", service.name);
+                errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::InvalidListen));
+            }
+        }
+        service
+            .labels
+            .keys()
+            .filter(|key| !is_valid_label_name(key))
+            .for_each(|key| {
+                let err = format!("Service '{}', invalid label name: '{}'. It should match [a-zA-Z_][a-zA-Z0-9_]*, and it cannot be 'service' or 'reason'.", service.name, key);
+                errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::InvalidLabels));
+            });
+        if let Some(user_namespace) = &service.sandbox.user_namespace {
+            if let Err(err) = user_namespace.validate(&service.user) {
+                let err = format!("Service '{}', invalid sandbox.user-namespace: {}", service.name, err);
+                errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::InvalidSandbox));
+            }
+        }
+        if !cfg!(target_os = "linux") {
+            service.linux_only_options().into_iter().for_each(|option| {
+                let err = format!("Service '{}', {} is supported only on Linux.", service.name, option);
+                errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::UnsupportedPlatform));
             });
+        }
+        service
+            .aliases
+            .iter()
+            .filter(|alias| {
+                services.iter().any(|s| s.name == **alias)
+                    || services
+                        .iter()
+                        .flat_map(|s| s.aliases.iter())
+                        .filter(|other| other == alias)
+                        .count()
+                        > 1
+            })
+            .for_each(|alias| {
+                let err = format!("Service '{}', the alias '{}' is already used by another service.", service.name, alias);
+                errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::InvalidAliases));
+            });
+        if let FailureStrategy::ShutdownGroup(group) = &service.failure.strategy {
+            let is_group = |s: &Service| s.labels.get(GROUP_LABEL) == Some(group);
+            if !services.iter().any(is_group) {
+                let err = format!("Service '{}', failure.strategy shuts down the group '{}', but there is no service with the label {} = \"{}\".", service.name, group, GROUP_LABEL, group);
+                errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::MissingDependency));
+            }
+        }
+        if let Some(threshold) = &service.failure.threshold {
+            if threshold.failures == 0 || threshold.window == Duration::from_secs(0) {
+                let err = format!("Service '{}', failure.threshold needs at least 1 failure, within a window longer than 0s.", service.name);
+                errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::InvalidFailureThreshold));
+            }
+        }
+        if let Some(schedule) = &service.schedule {
+            if schedule.every.is_none() && schedule.at.is_empty() {
+                let err = format!("Service '{}', its schedule needs either every or at.", service.name);
+                errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::InvalidSchedule));
+            }
+            if schedule.every.is_some_and(|every| every < Duration::from_secs(1)) {
+                let err = format!("Service '{}', schedule.every should be at least 1s.", service.name);
+                errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::InvalidSchedule));
+            }
+            if let Err(error) = Timezone::load(schedule.timezone.as_deref()) {
+                let err = format!("Service '{}', invalid schedule.timezone: {}", service.name, error);
+                errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::InvalidSchedule));
+            }
+            // It would never be Idle, waiting for the schedule.
+            if service.restart.strategy == RestartStrategy::Always {
+                let err = format!("Service '{}', a scheduled service cannot have the always restart strategy.", service.name);
+                errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::InvalidSchedule));
+            }
+        }
+        if service.replicas == 0 {
+            let err = format!("Service '{}', replicas should be at least 1.", service.name);
+            errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::InvalidReplicas));
+        }
+        if service.replicas > 1 && service.adopt.is_some() {
+            let err = format!("Service '{}', a replicated service cannot adopt a process.", service.name);
+            errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::InvalidReplicas));
+        }
+        if service.replicas > 1 {
+            (0..service.replicas)
+                .map(|index| format!("{}-{}", service.name, index))
+                .filter(|instance| services.iter().any(|s| s.name == *instance))
+                .for_each(|instance| {
+                    let err = format!("Service '{}', the name of its replica '{}' is already used by another service.", service.name, instance);
+                    errors.push(ValidationError::new(err.as_str(), ValidationErrorKind::InvalidReplicas));
+                });
+        }
     });
     if errors.is_empty() {
         Ok(services)
@@ -628,10 +2010,13 @@ pub fn validate(services: Vec<Service>) -> Result<Vec<Service>, Vec<ValidationEr
 #[cfg(test)]
 mod test {
     use crate::horust::formats::TerminationSignal::TERM;
-    use crate::horust::formats::User::Name;
+    use crate::horust::formats::User::{Name, Uid};
     use crate::horust::formats::{
-        validate, Environment, Failure, FailureStrategy, Healthiness, Restart, RestartStrategy,
-        Service, Termination,
+        expand_replicas, resolve_aliases, validate, Adopt, AttemptsExhaustedAction, Dependency,
+        Environment, ExitCodes, Failure, FailureStrategy, FailureThreshold, GrpcHealthCheck,
+        Healthiness, KillScope, MaxRuntimeOutcome, PortRange, Replica, Restart, RestartStrategy,
+        Sandbox, Schedule, Service, StartJitter, Termination, TimeOfDay, UserNamespace, WaitFor,
+        REPLICA_INDEX_ENV,
     };
     use crate::horust::get_sample_service;
     use std::str::FromStr;
@@ -655,7 +2040,10 @@ mod test {
     fn test_should_correctly_deserialize_sample() {
         let expected = Service {
             name: "".to_string(),
+            aliases: vec!["hello".into(), "greeter".into()],
             command: "/bin/bash -c \'echo hello world\'".to_string(),
+            target: false,
+            enabled: true,
             user: Name("root".into()),
             environment: Environment {
                 keep_env: false,
@@ -668,41 +2056,340 @@ mod test {
             stdout: "STDOUT".into(),
             stderr: "/var/logs/hello_world_svc/stderr.log".into(),
             start_delay: Duration::from_secs(2),
+            start_jitter: StartJitter {
+                min: Duration::from_secs(0),
+                max: Duration::from_secs(10),
+            },
+            start_priority: 10,
             start_after: vec!["another.toml".into(), "second.toml".into()],
+            binds_to: vec!["vpn.toml".into()],
+            wait_for: vec![
+                WaitFor {
+                    tcp: Some("db.internal:5432".into()),
+                    dns: None,
+                    timeout: Duration::from_secs(60),
+                },
+                WaitFor {
+                    tcp: None,
+                    dns: Some("api.example.com".into()),
+                    timeout: Duration::from_secs(30),
+                },
+            ],
+            max_runtime: Some(Duration::from_secs(2 * 60 * 60)),
+            max_runtime_outcome: MaxRuntimeOutcome::Success,
+            stop_after_idle: Some(Duration::from_secs(10 * 60)),
             restart: Restart {
                 strategy: RestartStrategy::Never,
                 backoff: Duration::from_millis(0),
                 attempts: 0,
+                startup_retries: Some(5),
+                startup_backoff: Some(Duration::from_secs(1)),
+                on_attempts_exhausted: AttemptsExhaustedAction::Exec(
+                    "/usr/local/bin/notify-oncall".into(),
+                ),
+                fallback_command: Some("/usr/local/bin/run-safe-mode.sh".into()),
+                retry_permanent_errors: true,
+                window: Some(Duration::from_secs(10 * 60)),
+                min_runtime: Some(Duration::from_secs(10)),
+                on_dependency_recovery: true,
             },
             healthiness: Healthiness {
                 http_endpoint: Some("http://localhost:8080/healthcheck".into()),
+                http_method: Some("GET".into()),
+                http_expected_status: vec![200, 204],
+                http_body_regex: Some(r#""status":\s*"up""#.into()),
+                http_insecure: false,
+                http_ca_file: Some("/etc/ssl/internal-ca.pem".into()),
+                http_headers: vec![("Authorization".to_string(), "Bearer token".to_string())]
+                    .into_iter()
+                    .collect(),
                 file_path: Some("/var/myservice/up".into()),
+                path: None,
+                unix: Some("/run/myservice.sock".into()),
+                unix_send: Some("PING\n".into()),
+                unix_reply_regex: Some("PONG".into()),
+                delegate: Some("/run/myservice/horust.sock".into()),
+                grpc: Some(GrpcHealthCheck {
+                    address: "localhost:50051".into(),
+                    service: "my.Svc".into(),
+                }),
+                log_pattern: Some(r#"Server started on port \d+"#.into()),
+                custom: hashmap! {
+                    "redis".to_string() => hashmap! {
+                        "address".to_string() => "localhost:6379".to_string()
+                    }
+                },
+                timeout: Duration::from_secs(2),
+                initial_delay: Duration::from_secs(30),
             },
+            liveness: Some(Healthiness {
+                file_path: Some("/var/myservice/alive".into()),
+                path: Some("/run/myservice.sock".into()),
+                ..Default::default()
+            }),
             signal_rewrite: None,
+            labels: btreemap! {
+                "tier".to_string() => "backend".to_string(),
+                "team".to_string() => "payments".to_string(),
+            },
+            profiles: vec!["dev".into()],
             failure: Failure {
-                successful_exit_code: vec![0, 1, 255],
+                successful_exit_code: vec![
+                    ExitCodes::Code(0),
+                    ExitCodes::Code(1),
+                    ExitCodes::Code(255),
+                ],
                 strategy: FailureStrategy::Ignore,
+                permanent_exit_code: vec![ExitCodes::Code(78)],
+                min_runtime: Some(Duration::from_secs(1)),
+                immediate_exits: 5,
+                threshold: Some(FailureThreshold {
+                    failures: 3,
+                    window: Duration::from_secs(5 * 60),
+                }),
             },
             termination: Termination {
                 signal: TERM,
                 wait: Duration::from_secs(10),
                 die_if_failed: vec!["db.toml".into()],
+                exec_before: Some("/usr/local/bin/drain.sh".into()),
+                exec_before_timeout: Duration::from_secs(20),
+                kill_scope: KillScope::Session,
+            },
+            adopt: Some(Adopt {
+                pid_file: "/run/legacy.pid".into(),
+            }),
+            sandbox: Sandbox {
+                user_namespace: Some(UserNamespace {
+                    uid_map: "0 100000 65536".into(),
+                    gid_map: "0 100000 65536".into(),
+                }),
             },
+            replicas: 1,
+            listen: vec!["0.0.0.0:{port}".into()],
+            port_range: Some(PortRange {
+                start: 8000,
+                end: 8099,
+            }),
+            balance: Some("0.0.0.0:8080".into()),
+            schedule: Some(Schedule {
+                every: Some(Duration::from_secs(3600)),
+                at: vec![
+                    TimeOfDay::from_str("03:00").unwrap(),
+                    TimeOfDay::from_str("15:30").unwrap(),
+                ],
+                timezone: Some("Europe/Rome".into()),
+            }),
+            replica: None,
         };
         let service = Service::from_str(get_sample_service().as_str())
             .expect("error on deserializing the manifest");
         assert_eq!(expected, service);
     }
+    #[test]
+    fn test_should_deserialize_any_of_dependencies() {
+        let service = Service::from_str(
+            r#"command = "cmd"
+start-after = [["redis-primary.toml", "redis-replica.toml"], ["db.toml"]]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            service.start_after,
+            vec![
+                Dependency::AnyOf(vec![
+                    "redis-primary.toml".into(),
+                    "redis-replica.toml".into()
+                ]),
+                Dependency::AnyOf(vec!["db.toml".into()]),
+            ]
+        );
+        let is_started = |name: &String| name == "redis-replica.toml";
+        assert!(service.start_after[0].is_satisfied(is_started));
+        assert!(!service.start_after[1].is_satisfied(is_started));
+    }
+
+    #[test]
+    fn test_start_jitter() {
+        let jitter = StartJitter::from_str("10s").unwrap();
+        assert_eq!(jitter.min, Duration::from_secs(0));
+        assert_eq!(jitter.max, Duration::from_secs(10));
+        let jitter = StartJitter::from_str("5-10s").unwrap();
+        assert_eq!(jitter.min, Duration::from_secs(5));
+        assert_eq!(jitter.max, Duration::from_secs(10));
+        let jitter = StartJitter::from_str("500-1500ms").unwrap();
+        assert_eq!(jitter.min, Duration::from_millis(500));
+        let jitter = StartJitter::from_str("1m-2h").unwrap();
+        assert_eq!(jitter.min, Duration::from_secs(60));
+        let jitter = StartJitter::from_str("1s-2s").unwrap();
+        (0..100).map(|_| jitter.pick()).for_each(|delay| {
+            assert!(delay >= jitter.min && delay <= jitter.max);
+        });
+        StartJitter::from_str("2s-1s").unwrap_err();
+        StartJitter::from_str("hello").unwrap_err();
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        let failure: Failure = toml::from_str(
+            r#"successful-exit-code = ["2", "10-12", "diff"]
+strategy = "ignore"
+"#,
+        )
+        .unwrap();
+        [0, 1, 2, 10, 11, 12]
+            .iter()
+            .for_each(|code| assert!(failure.is_successful(*code), "{}", code));
+        [3, 9, 13, 255]
+            .iter()
+            .for_each(|code| assert!(!failure.is_successful(*code), "{}", code));
+        assert!(ExitCodes::from_str("any").unwrap().contains(255));
+        assert_eq!(ExitCodes::from_str("7"), Ok(ExitCodes::Code(7)));
+        ExitCodes::from_str("12-10").unwrap_err();
+        ExitCodes::from_str("robocopy").unwrap_err();
+        let serialized = toml::to_string(&failure).unwrap();
+        assert_eq!(toml::from_str::<Failure>(&serialized).unwrap(), failure);
+    }
+
+    #[test]
+    fn test_failure_strategy() {
+        let failure: Failure = toml::from_str(r#"strategy = "shutdown-group:web""#).unwrap();
+        assert_eq!(
+            failure.strategy,
+            FailureStrategy::ShutdownGroup("web".into())
+        );
+        assert!(toml::to_string(&failure)
+            .unwrap()
+            .contains(r#"strategy = "shutdown-group:web""#));
+        assert!(toml::from_str::<Failure>(r#"strategy = "shutdown-group:""#).is_err());
+        assert!(toml::from_str::<Failure>(r#"strategy = "kill-everything""#).is_err());
+    }
+
     #[test]
     fn test_validate() {
         // Service does not exists:
         let services = vec![Service::start_after("a", vec!["b"])];
         validate(services).unwrap_err();
 
+        // Bound service does not exists:
+        let mut bound = Service::from_name("a");
+        bound.binds_to = vec!["b".into()];
+        validate(vec![bound]).unwrap_err();
+
+        // Typos, in every field referring to other services:
+        let mut dependent = Service::start_after("a.toml", vec!["db"]);
+        dependent.binds_to = vec!["vpn.tml".into()];
+        dependent.termination.die_if_failed = vec!["cache.toml".into()];
+        let services = vec![
+            dependent,
+            Service::from_name("db.toml"),
+            Service::from_name("vpn.toml"),
+        ];
+        let errors: Vec<String> = validate(services)
+            .unwrap_err()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "Service 'a.toml', start-after refers to 'db', but there is no service with such name. Did you mean 'db.toml'?",
+                "Service 'a.toml', binds-to refers to 'vpn.tml', but there is no service with such name. Did you mean 'vpn.toml'?",
+                "Service 'a.toml', termination.die-if-failed refers to 'cache.toml', but there is no service with such name.",
+            ]
+        );
+
+        // The group to shut down has no service:
+        let mut failing = Service::from_name("a");
+        failing.failure.strategy = FailureStrategy::ShutdownGroup("web".into());
+        validate(vec![failing.clone()]).unwrap_err();
+        failing.labels.insert("group".into(), "web".into());
+        validate(vec![failing]).unwrap();
+
+        // A threshold which is always reached:
+        let mut failing = Service::from_name("a");
+        failing.failure.threshold = Some(FailureThreshold {
+            failures: 0,
+            window: Duration::from_secs(60),
+        });
+        validate(vec![failing.clone()]).unwrap_err();
+        failing.failure.threshold = Some(FailureThreshold {
+            failures: 3,
+            window: Duration::from_secs(60),
+        });
+        validate(vec![failing]).unwrap();
+
+        // Wait-for without any condition:
+        let mut waiting = Service::from_name("a");
+        waiting.wait_for = vec![WaitFor {
+            tcp: None,
+            dns: None,
+            timeout: Duration::from_secs(1),
+        }];
+        validate(vec![waiting]).unwrap_err();
+
+        // A target with a command, and a service without:
+        let mut target = Service::from_name("a");
+        target.target = true;
+        validate(vec![target.clone()]).unwrap_err();
+        target.command = "".into();
+        validate(vec![target.clone()]).unwrap();
+        target.target = false;
+        validate(vec![target]).unwrap_err();
+
+        // Invalid body regex:
+        let mut service = Service::from_name("a");
+        service.healthiness.http_body_regex = Some("(".into());
+        validate(vec![service]).unwrap_err();
+
+        // Invalid user namespaces:
+        let user_namespace = |uid_map: &str, gid_map: &str| Sandbox {
+            user_namespace: Some(UserNamespace {
+                uid_map: uid_map.into(),
+                gid_map: gid_map.into(),
+            }),
+        };
+        for (uid_map, gid_map) in vec![
+            ("", "0 100000 65536"),
+            ("0 100000", "0 100000 65536"),
+            ("0 100000 0", "0 100000 65536"),
+            ("0 100000 a", "0 100000 65536"),
+            // The user (root) is not mapped:
+            ("1000 100000 1", "0 100000 65536"),
+            // The group 0 is not mapped:
+            ("0 100000 65536", "1 100000 65536"),
+        ] {
+            let mut service = Service::from_name("a");
+            service.user = Uid(0);
+            service.sandbox = user_namespace(uid_map, gid_map);
+            validate(vec![service]).unwrap_err();
+        }
+        let mut service = Service::from_name("a");
+        service.sandbox = user_namespace("0 100000 1\n1000 200000 1000", "0 100000 1, 1 1 1");
+        validate(vec![service]).unwrap();
+
+        // Invalid label names:
+        for name in vec!["", "1tier", "tier-name", "__tier", "service", "reason"] {
+            let mut service = Service::from_name("a");
+            service.labels.insert(name.into(), "backend".into());
+            validate(vec![service]).unwrap_err();
+        }
+        let mut service = Service::from_name("a");
+        service.labels.insert("_tier_2".into(), "backend".into());
+        validate(vec![service]).unwrap();
+
         // Command is empty:
         let services = vec![Service::from_command("".into())];
         validate(services).unwrap_err();
 
+        // The name of a replica is already taken:
+        let mut replicated = Service::from_name("a");
+        replicated.replicas = 2;
+        validate(vec![replicated.clone(), Service::from_name("a-1")]).unwrap_err();
+
+        // No replicas:
+        replicated.replicas = 0;
+        validate(vec![replicated]).unwrap_err();
+
         // Should pass validation:
         let services = vec![
             Service::from_name("b"),
@@ -710,4 +2397,154 @@ mod test {
         ];
         validate(services).expect("Validation failed");
     }
+
+    #[test]
+    fn test_is_in_profiles() {
+        let service = Service::from_name("a");
+        assert!(service.is_in_profiles(&[]));
+        let mut mock = Service::from_name("mock");
+        mock.profiles = vec!["dev".into(), "test".into()];
+        assert!(!mock.is_in_profiles(&[]));
+        assert!(!mock.is_in_profiles(&["prod".into()]));
+        assert!(mock.is_in_profiles(&["prod".into(), "test".into()]));
+    }
+
+    #[test]
+    fn test_linux_only_options() {
+        let mut service = Service::from_name("a");
+        assert!(service.linux_only_options().is_empty());
+        service.stop_after_idle = Some(Duration::from_secs(60));
+        service.sandbox.user_namespace = Some(UserNamespace {
+            uid_map: "0 1000 1".into(),
+            gid_map: "0 1000 1".into(),
+        });
+        assert_eq!(
+            service.linux_only_options(),
+            vec!["stop-after-idle", "sandbox.user-namespace"]
+        );
+    }
+
+    #[test]
+    fn test_aliases() {
+        let mut db = Service::from_name("db.toml");
+        db.aliases = vec!["db".into(), "postgres".into()];
+        let mut web = Service::start_after("web.toml", vec!["postgres"]);
+        web.binds_to = vec!["db".into()];
+        let services = validate(vec![db.clone(), web]).unwrap();
+        let services = resolve_aliases(services);
+        assert_eq!(services[1].start_after, vec!["db.toml".into()]);
+        assert_eq!(services[1].binds_to, vec!["db.toml"]);
+
+        let mut clashing = Service::from_name("cache.toml");
+        clashing.aliases = vec!["db".into(), "web.toml".into()];
+        let web = Service::from_name("web.toml");
+        let errors = validate(vec![db, clashing, web]).unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert!(errors.iter().all(|error| error
+            .to_string()
+            .contains("is already used by another service")));
+    }
+
+    #[test]
+    fn test_expand_replicas() {
+        let mut worker = Service::from_name("worker");
+        worker.replicas = 2;
+        let mut web = Service::start_after("web", vec!["worker"]);
+        web.binds_to = vec!["worker".into()];
+        let services = expand_replicas(vec![worker, web]);
+        let names: Vec<&str> = services.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["worker-0", "worker-1", "web"]);
+        assert_eq!(
+            services[1].environment.additional.get(REPLICA_INDEX_ENV),
+            Some(&"1".to_string())
+        );
+        assert_eq!(
+            services[1].replica,
+            Some(Replica {
+                of: "worker".into(),
+                index: 1
+            })
+        );
+        assert_eq!(
+            services[2].start_after,
+            vec!["worker-0".into(), "worker-1".into()]
+        );
+        assert_eq!(services[2].binds_to, vec!["worker-0", "worker-1"]);
+    }
+
+    #[test]
+    fn test_port_range() {
+        let range = PortRange::from_str("8000-8002").unwrap();
+        assert_eq!(
+            range,
+            PortRange {
+                start: 8000,
+                end: 8002
+            }
+        );
+        assert_eq!(range.size(), 3);
+        assert_eq!(range.to_string(), "8000-8002");
+        PortRange::from_str("8002-8000").unwrap_err();
+        PortRange::from_str("0-10").unwrap_err();
+        PortRange::from_str("8000").unwrap_err();
+        PortRange::from_str("8000-70000").unwrap_err();
+
+        let mut web = Service::from_name("web");
+        web.replicas = 3;
+        web.port_range = Some(range);
+        web.listen = vec!["0.0.0.0:{port}".into()];
+        validate(vec![web.clone()]).unwrap();
+        web.balance = Some("0.0.0.0:8080".into());
+        validate(vec![web.clone()]).unwrap();
+        web.balance = Some("web:8080".into());
+        validate(vec![web.clone()]).unwrap_err();
+        web.balance = None;
+        web.replicas = 4;
+        validate(vec![web.clone()]).unwrap_err();
+        web.replicas = 3;
+        web.listen = vec!["0.0.0.0:80".into()];
+        validate(vec![web.clone()]).unwrap_err();
+        web.port_range = None;
+        web.listen = vec!["0.0.0.0:{port}".into()];
+        validate(vec![web.clone()]).unwrap_err();
+        web.listen = vec![];
+        web.balance = Some("0.0.0.0:8080".into());
+        validate(vec![web]).unwrap_err();
+    }
+
+    #[test]
+    fn test_schedule() {
+        let service = Service::from_str(
+            r#"command = "backup.sh"
+[schedule]
+at = ["3:00"]
+timezone = "CET-1CEST,M3.5.0,M10.5.0/3""#,
+        )
+        .unwrap();
+        let schedule = service.schedule.clone().unwrap();
+        assert_eq!(schedule.every, None);
+        assert_eq!(schedule.at, vec![TimeOfDay::from_str("03:00").unwrap()]);
+        validate(vec![service.clone()]).unwrap();
+        Service::from_str("command = \"a\"\n[schedule]\nat = [\"25:00\"]").unwrap_err();
+
+        let with_schedule = |schedule: Schedule| Service {
+            schedule: Some(schedule),
+            ..service.clone()
+        };
+        validate(vec![with_schedule(Schedule::default())]).unwrap_err();
+        let every = |secs| Schedule {
+            every: Some(Duration::from_millis(secs)),
+            ..Default::default()
+        };
+        validate(vec![with_schedule(every(1000))]).unwrap();
+        validate(vec![with_schedule(every(500))]).unwrap_err();
+        let wrong_timezone = Schedule {
+            timezone: Some("Mars/Olympus".into()),
+            ..schedule
+        };
+        validate(vec![with_schedule(wrong_timezone)]).unwrap_err();
+        let mut always = service;
+        always.restart.strategy = RestartStrategy::Always;
+        validate(vec![always]).unwrap_err();
+    }
 }
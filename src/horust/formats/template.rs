@@ -0,0 +1,213 @@
+//! Template expressions in the command and in the environment of the services, evaluated at every
+//! spawn: `{{ hostname }}`, `{{ random_port }}`, `{{ uuid }}` and `{{ file "/path" }}`.
+//! Any other `{{ ... }}` is left as it is (e.g. docker's `--format '{{.Names}}'`).
+use crate::horust::error::{ErrorKind, Result};
+use crate::horust::formats::{Environment, Service};
+use std::fs;
+use std::io::Read;
+use std::net::TcpListener;
+use std::path::PathBuf;
+
+const OPEN: &str = "{{";
+const CLOSE: &str = "}}";
+
+#[derive(Debug, PartialEq)]
+enum Function {
+    Hostname,
+    RandomPort,
+    Uuid,
+    File(PathBuf),
+}
+
+impl Function {
+    /// None if it's not a template expression at all, e.g. `.Names`.
+    fn parse(expression: &str) -> Option<std::result::Result<Self, String>> {
+        let expression = expression.trim();
+        let (name, argument) = match expression.find(char::is_whitespace) {
+            Some(idx) => (&expression[..idx], expression[idx..].trim()),
+            None => (expression, ""),
+        };
+        let function = match (name, argument) {
+            ("hostname", "") => Ok(Function::Hostname),
+            ("random_port", "") => Ok(Function::RandomPort),
+            ("uuid", "") => Ok(Function::Uuid),
+            ("file", argument) => match argument
+                .strip_prefix('"')
+                .and_then(|path| path.strip_suffix('"'))
+            {
+                Some(path) if !path.is_empty() => Ok(Function::File(PathBuf::from(path))),
+                _ => Err(format!(
+                    "file expects a quoted path, e.g. {{{{ file \"/run/secret\" }}}}, found: {}",
+                    expression
+                )),
+            },
+            ("hostname", _) | ("random_port", _) | ("uuid", _) => Err(format!(
+                "{} takes no arguments, found: {}",
+                name, expression
+            )),
+            _ => return None,
+        };
+        Some(function)
+    }
+}
+
+/// The text, with the template expressions found in it.
+enum Part<'a> {
+    Text(&'a str),
+    Function(Function),
+}
+
+fn parse(value: &str) -> std::result::Result<Vec<Part<'_>>, String> {
+    let mut parts = vec![];
+    let mut rest = value;
+    while let Some(start) = rest.find(OPEN) {
+        let end = match rest[start..].find(CLOSE) {
+            Some(end) => start + end,
+            None => break,
+        };
+        match Function::parse(&rest[start + OPEN.len()..end]) {
+            Some(function) => {
+                parts.push(Part::Text(&rest[..start]));
+                parts.push(Part::Function(function?));
+            }
+            None => parts.push(Part::Text(&rest[..end + CLOSE.len()])),
+        }
+        rest = &rest[end + CLOSE.len()..];
+    }
+    parts.push(Part::Text(rest));
+    Ok(parts)
+}
+
+/// Checks the template expressions of the value, without evaluating them.
+pub(crate) fn validate_templates(value: &str) -> std::result::Result<(), String> {
+    parse(value).map(|_| ())
+}
+
+/// The values of a spawn: `random_port` and `uuid` are evaluated once, so they're the same in
+/// the command and in the environment.
+#[derive(Debug, Default)]
+struct Evaluation {
+    random_port: Option<u16>,
+    uuid: Option<String>,
+}
+
+impl Evaluation {
+    fn render(&mut self, value: &str) -> Result<String> {
+        let parts = parse(value).map_err(ErrorKind::Template)?;
+        let mut rendered = String::new();
+        for part in parts {
+            match part {
+                Part::Text(text) => rendered.push_str(text),
+                Part::Function(function) => rendered.push_str(&self.evaluate(&function)?),
+            }
+        }
+        Ok(rendered)
+    }
+
+    fn evaluate(&mut self, function: &Function) -> Result<String> {
+        let value = match function {
+            Function::Hostname => Environment::get_hostname_val().trim().to_string(),
+            Function::RandomPort => match self.random_port {
+                Some(port) => port.to_string(),
+                None => {
+                    let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+                    self.random_port = Some(port);
+                    port.to_string()
+                }
+            },
+            Function::Uuid => match &self.uuid {
+                Some(uuid) => uuid.clone(),
+                None => {
+                    let uuid = uuid_v4()?;
+                    self.uuid = Some(uuid.clone());
+                    uuid
+                }
+            },
+            Function::File(path) => fs::read_to_string(path)
+                .map_err(|error| {
+                    ErrorKind::Template(format!("cannot read {}: {}", path.display(), error))
+                })?
+                .trim_end_matches(&['\n', '\r'][..])
+                .to_string(),
+        };
+        Ok(value)
+    }
+}
+
+/// A random (version 4) uuid, e.g. `0b7c8b5e-4a1f-4c3e-9d2a-6f1e2d3c4b5a`.
+fn uuid_v4() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    ))
+}
+
+/// The service to spawn, with the template expressions of its command and of its additional
+/// environment evaluated.
+pub(crate) fn render_templates(service: &Service) -> Result<Service> {
+    let mut evaluation = Evaluation::default();
+    let mut rendered = service.clone();
+    rendered.command = evaluation.render(&service.command)?;
+    for value in rendered.environment.additional.values_mut() {
+        *value = evaluation.render(value)?;
+    }
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{render_templates, validate_templates};
+    use crate::horust::formats::Service;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_templates() {
+        let tempdir = TempDir::new("templates").unwrap();
+        let secret = tempdir.path().join("secret");
+        std::fs::write(&secret, "s3cr3t\n").unwrap();
+        let mut service = Service::from_name("web");
+        service.command = format!(
+            "./run.sh --port {{{{random_port}}}} --format '{{{{.Names}}}}' --token {{{{ file \"{}\" }}}}",
+            secret.display()
+        );
+        service
+            .environment
+            .additional
+            .insert("PORT".into(), "{{ random_port }}".into());
+        service
+            .environment
+            .additional
+            .insert("ID".into(), "{{ uuid }}".into());
+        let rendered = render_templates(&service).unwrap();
+        let port = &rendered.environment.additional["PORT"];
+        assert!(port.parse::<u16>().is_ok());
+        assert_eq!(
+            rendered.command,
+            format!(
+                "./run.sh --port {} --format '{{{{.Names}}}}' --token s3cr3t",
+                port
+            )
+        );
+        let id = &rendered.environment.additional["ID"];
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "4");
+        // Evaluated again at every spawn:
+        let rendered_again = render_templates(&service).unwrap();
+        assert_ne!(&rendered_again.environment.additional["ID"], id);
+
+        validate_templates("{{ hostname }} {{ docker }} {{").unwrap();
+        validate_templates("{{ file }}").unwrap_err();
+        validate_templates("{{ uuid 4 }}").unwrap_err();
+        service.command = "cat {{ file \"/missing\" }}".into();
+        render_templates(&service).unwrap_err();
+    }
+}
@@ -1,56 +1,136 @@
+#[cfg(feature = "http-healthcheck")]
+use crate::horust::formats::GrpcHealthCheck;
 use crate::horust::formats::Healthiness;
+use crate::horust::healthcheck::HealthProbe;
+#[cfg(feature = "http-healthcheck")]
+use hyper::body::HttpBody;
+use regex::Regex;
 #[cfg(feature = "http-healthcheck")]
 use reqwest::blocking::Client;
+#[cfg(feature = "http-healthcheck")]
+use reqwest::{Certificate, Method};
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+#[cfg(feature = "http-healthcheck")]
 use std::time::Duration;
 
-static FILE_CHECK: FilePathCheck = FilePathCheck {};
-static HTTP_CHECK: HttpCheck = HttpCheck {};
-
-pub(crate) fn get_checks() -> Vec<&'static dyn Check> {
-    let checks: Vec<&dyn Check> = vec![&FILE_CHECK, &HTTP_CHECK];
-    checks
-}
-
-pub(crate) trait Check {
-    fn run(&self, healthiness: &Healthiness) -> bool;
-    fn prepare(&self, _healtiness: &Healthiness) -> Result<(), std::io::Error> {
-        Ok(())
-    }
-}
-
 pub(crate) struct HttpCheck;
 
-impl Check for HttpCheck {
-    fn run(&self, healthiness: &Healthiness) -> bool {
+impl HealthProbe for HttpCheck {
+    fn key(&self) -> &str {
+        "http-endpoint"
+    }
+    fn is_configured(&self, healthiness: &Healthiness) -> bool {
+        healthiness.http_endpoint.is_some()
+    }
+    fn check(&self, healthiness: &Healthiness) -> Result<(), String> {
         healthiness
             .http_endpoint.as_ref()
             .map(|endpoint| {
                 if cfg!(not(feature = "http-healthcheck")){
                     error!("There is an http based healthcheck, but horust was built without the http-healthcheck feature (thus it will never pass these checks).");
-                    return false;
+                    return Err("horust was built without the http-healthcheck feature".into());
                 }
                 #[cfg(feature = "http-healthcheck")]
                     {
-                        let client = Client::builder()
-                            .timeout(Duration::from_secs(1))
-                            .build().expect("Http client");
-                        let resp: Result<reqwest::blocking::Response, reqwest::Error> = client.head(endpoint).send();
-                        resp.map(|resp| resp.status().is_success()).unwrap_or(false)
+                        let client = http_client(healthiness)?;
+                        let resp: Result<reqwest::blocking::Response, reqwest::Error> = build_request(&client, endpoint, healthiness).send();
+                        resp.map_err(|error| error.to_string()).and_then(|resp| check_response(resp, healthiness))
                     }
             })
-            .unwrap_or(true)
+            .unwrap_or(Ok(()))
+    }
+}
+
+/// The certificates of an https endpoint are checked against `http-ca-file` too, unless
+/// `http-insecure` is set.
+#[cfg(feature = "http-healthcheck")]
+fn http_client(healthiness: &Healthiness) -> Result<Client, String> {
+    let mut builder = Client::builder()
+        .timeout(healthiness.timeout)
+        .danger_accept_invalid_certs(healthiness.http_insecure);
+    if let Some(ca_file) = &healthiness.http_ca_file {
+        let pem = std::fs::read(ca_file)
+            .map_err(|error| format!("cannot read {}: {}", ca_file.display(), error))?;
+        let certificate = Certificate::from_pem(&pem)
+            .map_err(|error| format!("invalid http-ca-file {}: {}", ca_file.display(), error))?;
+        builder = builder.add_root_certificate(certificate);
+    }
+    builder.build().map_err(|error| error.to_string())
+}
+
+#[cfg(feature = "http-healthcheck")]
+fn build_request(
+    client: &Client,
+    endpoint: &str,
+    healthiness: &Healthiness,
+) -> reqwest::blocking::RequestBuilder {
+    // The body is needed for matching the regex, so HEAD is not enough.
+    let default_method = if healthiness.http_body_regex.is_some() {
+        "GET"
+    } else {
+        "HEAD"
+    };
+    let method = healthiness
+        .http_method
+        .as_deref()
+        .unwrap_or(default_method)
+        .to_uppercase();
+    // Already validated.
+    let method = Method::from_bytes(method.as_bytes()).unwrap_or(Method::HEAD);
+    healthiness
+        .http_headers
+        .iter()
+        .fold(client.request(method, endpoint), |request, (key, value)| {
+            request.header(key.as_str(), value.as_str())
+        })
+}
+
+#[cfg(feature = "http-healthcheck")]
+fn check_response(
+    resp: reqwest::blocking::Response,
+    healthiness: &Healthiness,
+) -> Result<(), String> {
+    let status = resp.status();
+    let is_status_valid = if healthiness.http_expected_status.is_empty() {
+        status.is_success()
+    } else {
+        healthiness.http_expected_status.contains(&status.as_u16())
+    };
+    if !is_status_valid {
+        return Err(format!("unexpected status: {}", status));
+    }
+    match &healthiness.http_body_regex {
+        Some(body_regex) => {
+            let body = resp.text().unwrap_or_default();
+            let regex = Regex::new(body_regex).map_err(|error| error.to_string())?;
+            if regex.is_match(body.as_str()) {
+                Ok(())
+            } else {
+                Err("the body doesn't match http-body-regex".into())
+            }
+        }
+        None => Ok(()),
     }
 }
 
 pub(crate) struct FilePathCheck;
 
-impl Check for FilePathCheck {
-    fn run(&self, healthiness: &Healthiness) -> bool {
-        healthiness
-            .file_path
-            .as_ref()
-            .map(|file_path| file_path.exists())
-            .unwrap_or(true)
+impl HealthProbe for FilePathCheck {
+    fn key(&self) -> &str {
+        "file-path"
+    }
+    fn is_configured(&self, healthiness: &Healthiness) -> bool {
+        healthiness.file_path.is_some()
+    }
+    fn check(&self, healthiness: &Healthiness) -> Result<(), String> {
+        match &healthiness.file_path {
+            Some(file_path) if !file_path.exists() => {
+                Err(format!("{} doesn't exist", file_path.display()))
+            }
+            _ => Ok(()),
+        }
     }
     fn prepare(&self, healthiness: &Healthiness) -> Result<(), std::io::Error> {
         //TODO: check if user has permissions to remove this file.
@@ -66,3 +146,310 @@ impl Check for FilePathCheck {
         }
     }
 }
+
+pub(crate) struct PathCheck;
+
+impl HealthProbe for PathCheck {
+    fn key(&self) -> &str {
+        "path"
+    }
+    fn is_configured(&self, healthiness: &Healthiness) -> bool {
+        healthiness.path.is_some()
+    }
+    fn check(&self, healthiness: &Healthiness) -> Result<(), String> {
+        match &healthiness.path {
+            Some(path) if !path.exists() => Err(format!("{} has vanished", path.display())),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub(crate) struct UnixSocketCheck;
+
+impl HealthProbe for UnixSocketCheck {
+    fn key(&self) -> &str {
+        "unix"
+    }
+    fn is_configured(&self, healthiness: &Healthiness) -> bool {
+        healthiness.unix.is_some()
+    }
+    fn check(&self, healthiness: &Healthiness) -> Result<(), String> {
+        healthiness
+            .unix
+            .as_ref()
+            .map(
+                |socket_path| match check_unix_socket(socket_path, healthiness) {
+                    Ok(true) => Ok(()),
+                    Ok(false) => Err("the reply doesn't match unix-reply-regex".into()),
+                    Err(error) => Err(format!("{}: {}", socket_path.display(), error)),
+                },
+            )
+            .unwrap_or(Ok(()))
+    }
+}
+
+fn check_unix_socket(socket_path: &PathBuf, healthiness: &Healthiness) -> io::Result<bool> {
+    let mut stream = UnixStream::connect(socket_path)?;
+    stream.set_read_timeout(Some(healthiness.timeout))?;
+    stream.set_write_timeout(Some(healthiness.timeout))?;
+    if let Some(payload) = &healthiness.unix_send {
+        stream.write_all(payload.as_bytes())?;
+    }
+    let reply_regex = match &healthiness.unix_reply_regex {
+        Some(reply_regex) => reply_regex,
+        None => return Ok(true),
+    };
+    let regex = Regex::new(reply_regex)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error.to_string()))?;
+    // Read until the reply matches, the peer closes the connection, or the timeout is hit.
+    let mut reply = vec![];
+    let mut buffer = [0; 512];
+    loop {
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(false);
+        }
+        reply.extend_from_slice(&buffer[..read]);
+        if regex.is_match(String::from_utf8_lossy(&reply).as_ref()) {
+            return Ok(true);
+        }
+    }
+}
+
+/// The statuses of the services of a nested supervisor which count as up.
+const DELEGATE_UP_STATUSES: [&str; 5] = ["Running", "Success", "Finished", "Idle", "Disabled"];
+
+/// A nested supervisor (e.g. another horust) is healthy while all of its services are up.
+pub(crate) struct DelegateCheck;
+
+impl HealthProbe for DelegateCheck {
+    fn key(&self) -> &str {
+        "delegate"
+    }
+    fn is_configured(&self, healthiness: &Healthiness) -> bool {
+        healthiness.delegate.is_some()
+    }
+    fn check(&self, healthiness: &Healthiness) -> Result<(), String> {
+        let socket_path = match &healthiness.delegate {
+            Some(socket_path) => socket_path,
+            None => return Ok(()),
+        };
+        let reply = query_status(socket_path, healthiness)
+            .map_err(|error| format!("{}: {}", socket_path.display(), error))?;
+        let mut lines = reply.lines();
+        match lines.next() {
+            Some("OK") => (),
+            Some(error) => return Err(format!("{}: {}", socket_path.display(), error)),
+            None => return Err(format!("{}: empty reply", socket_path.display())),
+        }
+        // E.g. `web.toml Running pid=42 restarts=0 ...`
+        let not_ready: Vec<String> = lines
+            .filter_map(|line| {
+                let mut words = line.split_whitespace();
+                match (words.next(), words.next()) {
+                    (Some(name), Some(status)) if !DELEGATE_UP_STATUSES.contains(&status) => {
+                        Some(format!("{} {}", name, status))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        if not_ready.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("not ready: {}", not_ready.join(", ")))
+        }
+    }
+}
+
+/// The reply to `status`, read until the control socket closes the connection.
+fn query_status(socket_path: &PathBuf, healthiness: &Healthiness) -> io::Result<String> {
+    let mut stream = UnixStream::connect(socket_path)?;
+    stream.set_read_timeout(Some(healthiness.timeout))?;
+    stream.set_write_timeout(Some(healthiness.timeout))?;
+    stream.write_all(b"status\n")?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
+
+/// `SERVING` in grpc.health.v1.HealthCheckResponse.ServingStatus
+const GRPC_SERVING: u64 = 1;
+
+pub(crate) struct GrpcCheck;
+
+impl HealthProbe for GrpcCheck {
+    fn key(&self) -> &str {
+        "grpc"
+    }
+    fn is_configured(&self, healthiness: &Healthiness) -> bool {
+        healthiness.grpc.is_some()
+    }
+    fn check(&self, healthiness: &Healthiness) -> Result<(), String> {
+        healthiness
+            .grpc
+            .as_ref()
+            .map(|grpc| {
+                if cfg!(not(feature = "http-healthcheck")) {
+                    error!("There is a grpc based healthcheck, but horust was built without the http-healthcheck feature (thus it will never pass these checks).");
+                    return Err("horust was built without the http-healthcheck feature".into());
+                }
+                #[cfg(feature = "http-healthcheck")]
+                check_grpc(grpc, healthiness.timeout)
+            })
+            .unwrap_or(Ok(()))
+    }
+}
+
+/// Calls grpc.health.v1.Health/Check over h2c: both the `grpc-status` of the call and the status
+/// in the response should be fine.
+#[cfg(feature = "http-healthcheck")]
+fn check_grpc(grpc: &GrpcHealthCheck, timeout: Duration) -> Result<(), String> {
+    let address = if grpc.address.contains("://") {
+        grpc.address.clone()
+    } else {
+        format!("http://{}", grpc.address)
+    };
+    let request = hyper::Request::post(format!("{}/grpc.health.v1.Health/Check", address))
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .body(hyper::Body::from(encode_grpc_health_request(&grpc.service)))
+        .map_err(|error| error.to_string())?;
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .map_err(|error| error.to_string())?;
+    let client = hyper::Client::builder()
+        .http2_only(true)
+        .build_http::<hyper::Body>();
+    let call = async {
+        let response = client
+            .request(request)
+            .await
+            .map_err(|error| error.to_string())?;
+        // Trailers-Only: the call has failed right away, without any message.
+        if let Some(Err(error)) = grpc_status(response.headers()) {
+            return Err(error);
+        }
+        if response.status() != hyper::StatusCode::OK {
+            return Err(format!("unexpected status: {}", response.status()));
+        }
+        let mut body = response.into_body();
+        let mut message = vec![];
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|error| format!("cannot read the response: {}", error))?;
+            message.extend_from_slice(&chunk);
+        }
+        let trailers = body
+            .trailers()
+            .await
+            .map_err(|error| format!("cannot read the trailers: {}", error))?
+            .unwrap_or_default();
+        grpc_status(&trailers).unwrap_or_else(|| Err("missing grpc-status".into()))?;
+        match decode_grpc_health_response(&message) {
+            Some(GRPC_SERVING) => Ok(()),
+            Some(status) => Err(format!("not serving, status: {}", serving_status(status))),
+            None => Err("invalid response".into()),
+        }
+    };
+    runtime.block_on(async {
+        tokio::time::timeout(timeout, call)
+            .await
+            .unwrap_or_else(|_| Err("timed out".into()))
+    })
+}
+
+/// None if there is no `grpc-status`, otherwise whether it's OK (with `grpc-message` if not).
+#[cfg(feature = "http-healthcheck")]
+fn grpc_status(headers: &hyper::HeaderMap) -> Option<Result<(), String>> {
+    let status = headers.get("grpc-status")?.to_str().unwrap_or_default();
+    if status == "0" {
+        return Some(Ok(()));
+    }
+    let message = headers
+        .get("grpc-message")
+        .and_then(|message| message.to_str().ok())
+        .map(|message| format!(" ({})", message))
+        .unwrap_or_default();
+    Some(Err(format!("grpc-status: {}{}", status, message)))
+}
+
+/// Name of a grpc.health.v1.HealthCheckResponse.ServingStatus.
+#[cfg(feature = "http-healthcheck")]
+fn serving_status(status: u64) -> String {
+    match status {
+        0 => "UNKNOWN".into(),
+        1 => "SERVING".into(),
+        2 => "NOT_SERVING".into(),
+        3 => "SERVICE_UNKNOWN".into(),
+        status => status.to_string(),
+    }
+}
+
+/// A length prefixed grpc message, holding a grpc.health.v1.HealthCheckRequest.
+pub(crate) fn encode_grpc_health_request(service: &str) -> Vec<u8> {
+    // Field 1 (service), wire type 2 (length delimited). Empty strings are omitted in proto3.
+    let mut message = vec![];
+    if !service.is_empty() {
+        message.push(0x0a);
+        encode_varint(service.len() as u64, &mut message);
+        message.extend_from_slice(service.as_bytes());
+    }
+    // Not compressed, followed by the length of the message (big endian).
+    let mut frame = vec![0];
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend(message);
+    frame
+}
+
+/// Returns the status of a grpc.health.v1.HealthCheckResponse, wrapped in a grpc message.
+pub(crate) fn decode_grpc_health_response(body: &[u8]) -> Option<u64> {
+    if body.len() < 5 || body[0] != 0 {
+        return None;
+    }
+    let length = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+    let mut message = body.get(5..5 + length)?;
+    // Zero is the default value (UNKNOWN), and proto3 doesn't send default values.
+    let mut status = 0;
+    while !message.is_empty() {
+        let key = decode_varint(&mut message)?;
+        match key & 0x7 {
+            // Varint
+            0 => {
+                let value = decode_varint(&mut message)?;
+                if key >> 3 == 1 {
+                    status = value;
+                }
+            }
+            // Length delimited
+            2 => {
+                let length = decode_varint(&mut message)? as usize;
+                message = message.get(length..)?;
+            }
+            _ => return None,
+        }
+    }
+    Some(status)
+}
+
+fn encode_varint(mut value: u64, buffer: &mut Vec<u8>) {
+    while value >= 0x80 {
+        buffer.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn decode_varint(buffer: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = buffer.split_first()?;
+        *buffer = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
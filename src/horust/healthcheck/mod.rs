@@ -1,42 +1,114 @@
-use crate::horust::bus::BusConnector;
+use crate::horust::bus::{BusConnector, Publisher};
+use crate::horust::chaos::FaultInjector;
 use crate::horust::formats::{
-    Event, Healthiness, HealthinessStatus, Service, ServiceName, ServiceStatus,
+    Event, Fault, HealthCheckReport, Healthiness, Service, ServiceName, ServiceStatus,
 };
-use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError};
-use std::time::Duration;
+use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 mod checks;
-use checks::*;
+mod registry;
+pub use registry::HealthProbe;
+pub(crate) use registry::{ProbeRegistry, TimedOutProbes};
 use std::thread;
 use std::thread::JoinHandle;
 
+/// How often the health of each service is checked, unless the tick interval is longer.
+const HEALTHCHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Stops the worker, waits for it, and tells it when the service is running.
+type WorkerHandle = (Sender<()>, JoinHandle<()>, Arc<AtomicBool>);
+
 struct Worker {
     service: Service,
-    bus: BusConnector<Event>,
+    bus: Publisher,
     work_done_notifier: Receiver<()>,
+    probes: ProbeRegistry,
+    timed_out: TimedOutProbes,
+    /// Once the service is running, the liveness checks are used.
+    is_running: Arc<AtomicBool>,
+    chaos: Option<FaultInjector>,
+    /// Between two checks.
+    interval: Duration,
 }
 impl Worker {
-    fn new(service: Service, bus: BusConnector<Event>, work_done_notifier: Receiver<()>) -> Self {
+    fn new(
+        service: Service,
+        bus: Publisher,
+        work_done_notifier: Receiver<()>,
+        probes: ProbeRegistry,
+        is_running: Arc<AtomicBool>,
+        chaos: Option<FaultInjector>,
+        interval: Duration,
+    ) -> Self {
         Worker {
             service,
             bus,
             work_done_notifier,
+            probes,
+            timed_out: Default::default(),
+            is_running,
+            chaos,
+            interval,
         }
     }
     pub fn spawn_thread(self) -> JoinHandle<()> {
         thread::spawn(move || self.run())
     }
-    fn run(self) {
+    /// Waits for the initial delay of the section, false if the worker has been stopped meanwhile.
+    fn wait_initial_delay(&self, healthiness: &Healthiness) -> bool {
+        let initial_delay = healthiness.initial_delay;
+        if initial_delay == Duration::from_secs(0) {
+            return true;
+        }
+        debug!(
+            "Waiting {:?} before checking the health of: {}",
+            initial_delay, self.service.name
+        );
+        matches!(
+            self.work_done_notifier.recv_timeout(initial_delay),
+            Err(RecvTimeoutError::Timeout)
+        )
+    }
+
+    fn run(mut self) {
+        if !self.wait_initial_delay(&self.service.healthiness) {
+            return;
+        }
+        let mut is_live = false;
         loop {
-            let status = check_health(&self.service.healthiness);
-            self.bus.send_event(Event::HealthCheck(
-                self.service.name.clone(),
-                status.clone(),
-            ));
-            match self
-                .work_done_notifier
-                .recv_timeout(Duration::from_millis(1000))
-            {
+            let liveness = self.service.liveness.as_ref();
+            let healthiness = match liveness {
+                Some(liveness) if self.is_running.load(Ordering::SeqCst) => {
+                    // Its own grace period, once the service is running.
+                    if !is_live && !self.wait_initial_delay(liveness) {
+                        break;
+                    }
+                    is_live = true;
+                    liveness
+                }
+                _ => &self.service.healthiness,
+            };
+            let report = check_health(&self.probes, healthiness, &mut self.timed_out);
+            let delay = self
+                .chaos
+                .as_ref()
+                .and_then(|chaos| chaos.healthcheck_delay(&self.service.name));
+            if let Some(delay) = delay {
+                let fault = Fault::HealthcheckDelayed(delay);
+                self.bus
+                    .send_event(Event::FaultInjected(self.service.name.clone(), fault));
+                match self.work_done_notifier.recv_timeout(delay) {
+                    Ok(()) | Err(RecvTimeoutError::Disconnected) => break,
+                    _ => (),
+                };
+            }
+            self.bus
+                .send_event(Event::HealthCheck(self.service.name.clone(), report));
+            match self.work_done_notifier.recv_timeout(self.interval) {
                 Ok(()) | Err(RecvTimeoutError::Disconnected) => break,
                 _ => (),
             };
@@ -47,25 +119,52 @@ impl Worker {
 // TODO:
 // * Tunable healthchecks timing in horust's config
 // * If there are no checks to run, just exit the thread. or go sleep until an "service created" event is received.
-pub fn spawn(bus: BusConnector<Event>, services: Vec<Service>) {
+pub(crate) fn spawn(
+    bus: BusConnector,
+    services: Vec<Service>,
+    probes: ProbeRegistry,
+    chaos: Option<FaultInjector>,
+    tick_interval: Duration,
+) {
     std::thread::spawn(move || {
-        run(bus, services);
+        run(bus, services, probes, chaos, tick_interval);
     });
 }
 
 /// Returns true if the service is healthy and all checks are passed.
-fn check_health(healthiness: &Healthiness) -> HealthinessStatus {
-    let failed_checks = get_checks()
-        .into_iter()
-        .filter(|check| !check.run(healthiness))
-        .count();
-    let is_healthy = failed_checks == 0;
-    is_healthy.into()
+fn check_health(
+    probes: &ProbeRegistry,
+    healthiness: &Healthiness,
+    timed_out: &mut TimedOutProbes,
+) -> HealthCheckReport {
+    let start = Instant::now();
+    let result = probes.check(healthiness, timed_out);
+    HealthCheckReport {
+        status: result.is_ok().into(),
+        latency: start.elapsed(),
+        error: result.err(),
+    }
+}
+
+/// The liveness section (if any) and the healthiness one.
+fn healthiness_sections(service: &Service) -> impl Iterator<Item = &Healthiness> {
+    service
+        .liveness
+        .iter()
+        .chain(std::iter::once(&service.healthiness))
 }
 
-fn run(bus: BusConnector<Event>, services: Vec<Service>) {
-    let mut workers = hashmap! {};
-    let get_service = |s_name: &ServiceName| {
+fn run(
+    bus: BusConnector,
+    mut services: Vec<Service>,
+    probes: ProbeRegistry,
+    chaos: Option<FaultInjector>,
+    tick_interval: Duration,
+) {
+    // Nothing is scheduled more often than the runtime's tick.
+    let interval = HEALTHCHECK_INTERVAL.max(tick_interval);
+    let mut workers: HashMap<ServiceName, WorkerHandle> = HashMap::new();
+    let get_service = |services: &[Service], s_name: &ServiceName| {
         services
             .iter()
             .filter(|sh| sh.name == *s_name)
@@ -78,31 +177,44 @@ fn run(bus: BusConnector<Event>, services: Vec<Service>) {
         match ev {
             Event::StatusChanged(s_name, ServiceStatus::Started) => {
                 let (worker_notifier, work_done_rcv) = unbounded();
-                let service = get_service(&s_name);
-                let w = Worker::new(service, bus.clone(), work_done_rcv);
+                let service = get_service(&services, &s_name);
+                let is_running = Arc::new(AtomicBool::new(false));
+                let w = Worker::new(
+                    service,
+                    bus.publisher(),
+                    work_done_rcv,
+                    probes.clone(),
+                    is_running.clone(),
+                    chaos.clone(),
+                    interval,
+                );
                 let handle = w.spawn_thread();
-                workers.insert(s_name, (worker_notifier, handle));
+                workers.insert(s_name, (worker_notifier, handle, is_running));
             }
-            Event::ServiceExited(s_name, _exit_code) => {
-                if let Some((sender, handler)) = workers.remove(&s_name) {
-                    if sender.send(()).is_err() {
-                        error!("Cannot send msg to sender - channel closed.");
-                    }
-                    if let Err(error) = handler.join() {
-                        error!("Error joining thread: {:?}", error);
-                    }
-                } else {
-                    warn!("Worker thread for {} not found.", s_name);
+            Event::StatusChanged(s_name, ServiceStatus::Running) => {
+                if let Some((_ws, _wh, is_running)) = workers.get(&s_name) {
+                    is_running.store(true, Ordering::SeqCst);
                 }
             }
-            Event::ShuttingDownInitiated => {
+            // Probing a dying process would only produce spurious failures, racing with its
+            // termination.
+            Event::StatusChanged(s_name, ServiceStatus::InKilling) => {
+                stop_worker(&mut workers, &s_name);
+            }
+            Event::ServiceExited(s_name, _exit_code) => {
+                stop_worker(&mut workers, &s_name);
+                healthiness_sections(&get_service(&services, &s_name))
+                    .for_each(|healthiness| probes.teardown(healthiness));
+            }
+            Event::ServiceCreated(service) => services.push(*service),
+            Event::ShuttingDownInitiated(_) => {
                 // Stop all the workers:
-                for (ws, _wh) in workers.values() {
+                for (ws, _wh, _is_running) in workers.values() {
                     // TODO: handle these
                     ws.send(()).unwrap();
                 }
                 // Actually wait for them
-                for (_s_name, (_ws, wh)) in workers {
+                for (_s_name, (_ws, wh, _is_running)) in workers {
                     wh.join().unwrap();
                 }
                 break;
@@ -112,28 +224,62 @@ fn run(bus: BusConnector<Event>, services: Vec<Service>) {
     }
 }
 
+/// Stops the healthchecks of the service, if they're still running.
+fn stop_worker(workers: &mut HashMap<ServiceName, WorkerHandle>, s_name: &str) {
+    if let Some((sender, handler, _is_running)) = workers.remove(s_name) {
+        if sender.send(()).is_err() {
+            error!("Cannot send msg to sender - channel closed.");
+        }
+        if let Err(error) = handler.join() {
+            error!("Error joining thread: {:?}", error);
+        }
+    } else {
+        debug!(
+            "Worker thread for {} not found, or already stopped.",
+            s_name
+        );
+    }
+}
+
 /// Setup require for the service, before running the healthchecks and starting the service
-pub fn prepare_service(healthiness: &Healthiness) -> Result<Vec<()>, std::io::Error> {
-    get_checks()
-        .into_iter()
-        .map(|check| check.prepare(healthiness))
-        .collect()
+pub(crate) fn prepare_service(
+    probes: &ProbeRegistry,
+    service: &Service,
+) -> Result<(), std::io::Error> {
+    healthiness_sections(service).try_for_each(|healthiness| probes.prepare(healthiness))
 }
 
 #[cfg(test)]
 mod test {
     use crate::horust::error::Result;
-    use crate::horust::formats::{Healthiness, HealthinessStatus};
-    use crate::horust::healthcheck::check_health;
-    use std::io::{Read, Write};
+    use crate::horust::formats::{GrpcHealthCheck, Healthiness, HealthinessStatus};
+    use crate::horust::healthcheck::checks::{
+        decode_grpc_health_response, encode_grpc_health_request, GrpcCheck,
+    };
+    use crate::horust::healthcheck::{check_health, HealthProbe, ProbeRegistry, TimedOutProbes};
+    use hyper::body::{Bytes, HttpBody};
+    use hyper::header::{HeaderMap, HeaderValue};
+    use hyper::service::{make_service_fn, service_fn};
+    use std::convert::Infallible;
+    use std::io::{BufRead, BufReader, Read, Write};
     use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
-    use std::sync::mpsc;
+    use std::os::unix::net::UnixListener;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::task::{Context, Poll};
     use std::thread;
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
     use tempdir::TempDir;
 
     fn check_health_w(healthiness: &Healthiness) -> bool {
-        check_health(healthiness) == HealthinessStatus::Healthy
+        check_health(
+            &ProbeRegistry::default(),
+            healthiness,
+            &mut TimedOutProbes::default(),
+        )
+        .status
+            == HealthinessStatus::Healthy
     }
     #[test]
     fn test_healthiness_check_file() -> Result<()> {
@@ -141,7 +287,7 @@ mod test {
         let file_path = tempdir.path().join("file.txt");
         let healthiness = Healthiness {
             file_path: Some(file_path.clone()),
-            http_endpoint: None,
+            ..Default::default()
         };
         assert!(!check_health_w(&healthiness));
         std::fs::write(file_path, "Hello world!")?;
@@ -150,6 +296,22 @@ mod test {
         assert!(check_health_w(&healthiness));
         Ok(())
     }
+    #[test]
+    fn test_healthiness_check_path() -> Result<()> {
+        let tempdir = TempDir::new("health")?;
+        let path = tempdir.path().join("app.sock");
+        std::fs::write(&path, "")?;
+        let healthiness = Healthiness {
+            path: Some(path.clone()),
+            ..Default::default()
+        };
+        // Unlike file-path, it's left alone before spawning the service.
+        ProbeRegistry::default().prepare(&healthiness)?;
+        assert!(check_health_w(&healthiness));
+        std::fs::remove_file(path)?;
+        assert!(!check_health_w(&healthiness));
+        Ok(())
+    }
     fn handle_request(listener: TcpListener) -> std::io::Result<()> {
         for stream in listener.incoming() {
             println!("Received request");
@@ -166,8 +328,8 @@ mod test {
     #[test]
     fn test_healthiness_http() -> Result<()> {
         let healthiness = Healthiness {
-            file_path: None,
             http_endpoint: Some("http://localhost:123/".into()),
+            ..Default::default()
         };
         assert!(!check_health_w(&healthiness));
         let loopback = Ipv4Addr::new(127, 0, 0, 1);
@@ -176,8 +338,8 @@ mod test {
         let port = listener.local_addr()?.port();
         let endpoint = format!("http://localhost:{}", port);
         let healthiness = Healthiness {
-            file_path: None,
             http_endpoint: Some(endpoint),
+            ..Default::default()
         };
         let (sender, receiver) = mpsc::sync_channel(0);
         thread::spawn(move || {
@@ -191,4 +353,359 @@ mod test {
         assert!(!check_health_w(&healthiness));
         Ok(())
     }
+
+    /// Answers `requests` requests, returns the received requests.
+    fn serve(listener: TcpListener, response: &'static [u8], requests: usize) -> Vec<String> {
+        listener
+            .incoming()
+            .take(requests)
+            .map(|stream| {
+                let mut stream = stream.unwrap();
+                let mut buffer = [0; 512];
+                let read = stream.read(&mut buffer).unwrap();
+                stream.write_all(response).expect("Stream write");
+                String::from_utf8_lossy(&buffer[..read]).to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_healthiness_http_response_validation() -> Result<()> {
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let endpoint = format!("http://{}/health", listener.local_addr()?);
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 17\r\n\r\n{\"status\":\"down\"}";
+        let handle = thread::spawn(move || serve(listener, response, 3));
+        let healthiness = Healthiness {
+            http_endpoint: Some(endpoint),
+            http_headers: vec![("X-Probe".to_string(), "horust".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        assert!(check_health_w(&healthiness));
+        let body_regex = Healthiness {
+            http_body_regex: Some(r#""status":\s*"up""#.into()),
+            ..healthiness.clone()
+        };
+        assert!(!check_health_w(&body_regex));
+        let expected_status = Healthiness {
+            http_expected_status: vec![204],
+            ..healthiness
+        };
+        assert!(!check_health_w(&expected_status));
+
+        let requests = handle.join().unwrap();
+        assert!(requests[0].starts_with("HEAD /health"));
+        assert!(requests[0].to_lowercase().contains("x-probe: horust"));
+        assert!(requests[1].starts_with("GET /health"));
+        Ok(())
+    }
+
+    /// Answers `requests` requests over tls, with a certificate for localhost signed by a test CA.
+    fn serve_tls(listener: TcpListener, requests: usize) {
+        use rustls::internal::pemfile::{certs, pkcs8_private_keys};
+        let tls = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls");
+        let read =
+            |file: &str| BufReader::new(std::fs::File::open(format!("{}/{}", tls, file)).unwrap());
+        let mut config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+        let certificates = certs(&mut read("localhost.pem")).unwrap();
+        let key = pkcs8_private_keys(&mut read("localhost.key"))
+            .unwrap()
+            .remove(0);
+        config.set_single_cert(certificates, key).unwrap();
+        let config = Arc::new(config);
+        for stream in listener.incoming().take(requests) {
+            let mut session = rustls::ServerSession::new(&config);
+            let mut stream = stream.unwrap();
+            let mut tls_stream = rustls::Stream::new(&mut session, &mut stream);
+            let mut buffer = [0; 512];
+            // The handshake fails if the client doesn't trust the certificate.
+            if tls_stream.read(&mut buffer).is_ok() {
+                let _ = tls_stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+            }
+        }
+    }
+
+    #[test]
+    fn test_healthiness_https() -> Result<()> {
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))?;
+        let endpoint = format!("https://localhost:{}/health", listener.local_addr()?.port());
+        let handle = thread::spawn(move || serve_tls(listener, 3));
+        let healthiness = Healthiness {
+            http_endpoint: Some(endpoint),
+            ..Default::default()
+        };
+        // Signed by an unknown CA.
+        assert!(!check_health_w(&healthiness));
+        let ca_file = Healthiness {
+            http_ca_file: Some(
+                concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls/ca.pem").into(),
+            ),
+            ..healthiness.clone()
+        };
+        assert!(check_health_w(&ca_file));
+        let insecure = Healthiness {
+            http_insecure: true,
+            ..healthiness.clone()
+        };
+        assert!(check_health_w(&insecure));
+        handle.join().unwrap();
+
+        let missing_ca_file = Healthiness {
+            http_ca_file: Some("/does/not/exist.pem".into()),
+            ..healthiness
+        };
+        assert!(!check_health_w(&missing_ca_file));
+        Ok(())
+    }
+
+    #[test]
+    fn test_healthiness_unix_socket() -> Result<()> {
+        let tempdir = TempDir::new("health")?;
+        let socket_path = tempdir.path().join("service.sock");
+        let healthiness = Healthiness {
+            unix: Some(socket_path.clone()),
+            unix_send: Some("PING\n".into()),
+            unix_reply_regex: Some("^PONG".into()),
+            ..Default::default()
+        };
+        assert!(!check_health_w(&healthiness));
+        let listener = UnixListener::bind(&socket_path)?;
+        let handle = thread::spawn(move || {
+            for stream in listener.incoming().take(2) {
+                let mut stream = stream.unwrap();
+                let mut request = String::new();
+                BufReader::new(&stream).read_line(&mut request).unwrap();
+                let reply = if request == "PING\n" {
+                    "PONG\n"
+                } else {
+                    "WHAT?\n"
+                };
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+        assert!(check_health_w(&healthiness));
+        let unexpected_reply = Healthiness {
+            unix_send: Some("HELLO\n".into()),
+            ..healthiness
+        };
+        assert!(!check_health_w(&unexpected_reply));
+        handle.join().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_healthiness_delegate() -> Result<()> {
+        let tempdir = TempDir::new("health")?;
+        let socket_path = tempdir.path().join("horust.sock");
+        let healthiness = Healthiness {
+            delegate: Some(socket_path.clone()),
+            ..Default::default()
+        };
+        assert!(!check_health_w(&healthiness));
+        let listener = UnixListener::bind(&socket_path)?;
+        let handle = thread::spawn(move || {
+            let replies = [
+                "OK\nweb.toml Running pid=10\ncron.toml Finished pid=-\n",
+                "OK\nweb.toml Running pid=10\ndb.toml Starting pid=-\n",
+                "ERROR Runtime is stalled\n",
+            ];
+            for (reply, stream) in replies.iter().zip(listener.incoming()) {
+                let mut stream = stream.unwrap();
+                let mut request = String::new();
+                BufReader::new(&stream).read_line(&mut request).unwrap();
+                assert_eq!(request, "status\n");
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+        assert!(check_health_w(&healthiness));
+        assert!(!check_health_w(&healthiness));
+        assert!(!check_health_w(&healthiness));
+        handle.join().unwrap();
+        Ok(())
+    }
+
+    /// Healthy if the configured value is "up".
+    struct StateProbe;
+    impl HealthProbe for StateProbe {
+        fn key(&self) -> &str {
+            "state"
+        }
+        fn check(&self, healthiness: &Healthiness) -> std::result::Result<(), String> {
+            match healthiness.custom["state"].get("value") {
+                Some(value) if value == "up" => Ok(()),
+                value => Err(format!("state is {:?}", value)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_healthiness_custom_probe() {
+        let custom = |value: &str| Healthiness {
+            custom: hashmap! {
+                "state".to_string() => hashmap! {"value".to_string() => value.to_string()}
+            },
+            ..Default::default()
+        };
+        let mut probes = ProbeRegistry::default();
+        // Not registered yet:
+        assert!(probes
+            .check(&custom("up"), &mut TimedOutProbes::default())
+            .is_err());
+        probes.register(StateProbe);
+        assert!(probes
+            .check(&custom("up"), &mut TimedOutProbes::default())
+            .is_ok());
+        assert!(probes
+            .check(&custom("down"), &mut TimedOutProbes::default())
+            .is_err());
+        // Not configured:
+        assert!(probes
+            .check(&Healthiness::default(), &mut TimedOutProbes::default())
+            .is_ok());
+    }
+
+    /// Hangs as long as `sleep`, counting how many times it was run.
+    struct SlowProbe {
+        sleep: Duration,
+        runs: Arc<AtomicUsize>,
+    }
+    impl HealthProbe for SlowProbe {
+        fn key(&self) -> &str {
+            "slow"
+        }
+        fn check(&self, _healthiness: &Healthiness) -> std::result::Result<(), String> {
+            self.runs.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(self.sleep);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_healthiness_probe_timeout() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let mut probes = ProbeRegistry::default();
+        probes.register(SlowProbe {
+            sleep: Duration::from_millis(1500),
+            runs: runs.clone(),
+        });
+        let healthiness = Healthiness {
+            custom: hashmap! {"slow".to_string() => hashmap! {}},
+            timeout: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let mut timed_out = TimedOutProbes::default();
+        let start = Instant::now();
+        assert_eq!(
+            probes.check(&healthiness, &mut timed_out),
+            Err("slow: timed out after 500ms".to_string())
+        );
+        assert!(start.elapsed() < Duration::from_millis(1000));
+        // Still running: it's not started again.
+        assert!(probes.check(&healthiness, &mut timed_out).is_err());
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        // Once it has returned, it's run again.
+        thread::sleep(Duration::from_millis(1000));
+        assert!(probes.check(&healthiness, &mut timed_out).is_err());
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+        let healthiness = Healthiness {
+            timeout: Duration::from_secs(3),
+            ..healthiness
+        };
+        assert!(probes
+            .check(&healthiness, &mut TimedOutProbes::default())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_grpc_health_messages() {
+        assert_eq!(encode_grpc_health_request(""), vec![0, 0, 0, 0, 0]);
+        assert_eq!(
+            encode_grpc_health_request("my.Svc"),
+            vec![0, 0, 0, 0, 8, 0x0a, 6, b'm', b'y', b'.', b'S', b'v', b'c']
+        );
+        // SERVING
+        assert_eq!(
+            decode_grpc_health_response(&[0, 0, 0, 0, 2, 0x08, 1]),
+            Some(1)
+        );
+        // UNKNOWN is the default value, so it's not sent.
+        assert_eq!(decode_grpc_health_response(&[0, 0, 0, 0, 0]), Some(0));
+        // Truncated.
+        assert_eq!(decode_grpc_health_response(&[0, 0, 0, 0, 2, 0x08]), None);
+        assert_eq!(decode_grpc_health_response(&[]), None);
+    }
+
+    /// A HealthCheckResponse, followed by the trailers.
+    struct GrpcResponse {
+        message: Option<Bytes>,
+        trailers: Option<HeaderMap>,
+    }
+
+    impl HttpBody for GrpcResponse {
+        type Data = Bytes;
+        type Error = Infallible;
+        fn poll_data(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<std::result::Result<Bytes, Infallible>>> {
+            Poll::Ready(self.message.take().map(Ok))
+        }
+        fn poll_trailers(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<std::result::Result<Option<HeaderMap>, Infallible>> {
+            Poll::Ready(Ok(self.trailers.take()))
+        }
+    }
+
+    /// Answers every health check with `status`, and ends the call with `grpc_status`.
+    fn serve_grpc(listener: TcpListener, status: u8, grpc_status: &'static str) {
+        thread::spawn(move || {
+            let mut runtime = tokio::runtime::Builder::new()
+                .basic_scheduler()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let make_service = make_service_fn(move |_| async move {
+                    Ok::<_, Infallible>(service_fn(move |_request| async move {
+                        let mut trailers = HeaderMap::new();
+                        trailers.insert("grpc-status", HeaderValue::from_static(grpc_status));
+                        let message = vec![0, 0, 0, 0, 2, 0x08, status];
+                        Ok::<_, Infallible>(hyper::Response::new(GrpcResponse {
+                            message: Some(message.into()),
+                            trailers: Some(trailers),
+                        }))
+                    }))
+                });
+                let server = hyper::Server::from_tcp(listener).unwrap();
+                server.http2_only(true).serve(make_service).await.unwrap();
+            });
+        });
+    }
+
+    #[test]
+    fn test_healthiness_grpc() {
+        let check = |status, grpc_status| {
+            let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).unwrap();
+            let healthiness = Healthiness {
+                grpc: Some(GrpcHealthCheck {
+                    address: listener.local_addr().unwrap().to_string(),
+                    service: "my.Svc".into(),
+                }),
+                ..Default::default()
+            };
+            serve_grpc(listener, status, grpc_status);
+            GrpcCheck.check(&healthiness)
+        };
+        assert_eq!(check(1, "0"), Ok(()));
+        assert_eq!(
+            check(2, "0"),
+            Err("not serving, status: NOT_SERVING".into())
+        );
+        // The call itself has failed.
+        assert_eq!(check(1, "14"), Err("grpc-status: 14".into()));
+    }
 }
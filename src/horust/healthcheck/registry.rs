@@ -0,0 +1,206 @@
+use crate::horust::formats::Healthiness;
+use crate::horust::healthcheck::checks::{
+    DelegateCheck, FilePathCheck, GrpcCheck, HttpCheck, PathCheck, UnixSocketCheck,
+};
+use crossbeam::channel::{bounded, Receiver, RecvTimeoutError, TryRecvError};
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+/// A kind of healthcheck.
+/// Built-in probes are configured using their own field in the healthiness section, custom ones
+/// using a table in `healthiness.custom`, e.g. `[healthiness.custom.my-probe]`.
+pub trait HealthProbe: Send + Sync {
+    /// Key of the configuration of this probe: the registry holds one probe per key.
+    fn key(&self) -> &str;
+
+    /// If false, the probe is not used for this healthiness section.
+    fn is_configured(&self, healthiness: &Healthiness) -> bool {
+        healthiness.custom.contains_key(self.key())
+    }
+
+    /// Called before spawning the service.
+    fn prepare(&self, _healthiness: &Healthiness) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    /// Returns Ok if the service is healthy, otherwise the reason why it's not.
+    /// It should give up after `healthiness.timeout`, otherwise the result is ignored and
+    /// the check is considered failed.
+    fn check(&self, healthiness: &Healthiness) -> Result<(), String>;
+
+    /// Called after the service has exited.
+    fn teardown(&self, _healthiness: &Healthiness) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+type ProbeResult = Result<(), String>;
+
+/// All the available health probes, keyed by their configuration key.
+#[derive(Clone)]
+pub(crate) struct ProbeRegistry {
+    probes: HashMap<String, Arc<dyn HealthProbe>>,
+}
+
+impl Default for ProbeRegistry {
+    fn default() -> Self {
+        let mut registry = Self {
+            probes: HashMap::new(),
+        };
+        registry.register(FilePathCheck);
+        registry.register(PathCheck);
+        registry.register(HttpCheck);
+        registry.register(UnixSocketCheck);
+        registry.register(GrpcCheck);
+        registry.register(DelegateCheck);
+        registry
+    }
+}
+
+impl Debug for ProbeRegistry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.probes.keys()).finish()
+    }
+}
+
+impl ProbeRegistry {
+    /// Adds the probe, replacing the one already registered with the same key (if any).
+    pub(crate) fn register<P>(&mut self, probe: P)
+    where
+        P: HealthProbe + 'static,
+    {
+        self.probes.insert(probe.key().to_string(), Arc::new(probe));
+    }
+
+    fn configured<'a>(
+        &'a self,
+        healthiness: &'a Healthiness,
+    ) -> impl Iterator<Item = &'a Arc<dyn HealthProbe>> + 'a {
+        self.probes
+            .values()
+            .filter(move |probe| probe.is_configured(healthiness))
+    }
+
+    /// Returns Ok if all the configured probes have passed within `healthiness.timeout`,
+    /// otherwise the errors of the failed ones.
+    /// The probes are run concurrently, each one in its own thread.
+    pub(crate) fn check(
+        &self,
+        healthiness: &Healthiness,
+        timed_out: &mut TimedOutProbes,
+    ) -> ProbeResult {
+        let unknown: Vec<&String> = healthiness
+            .custom
+            .keys()
+            .filter(|key| !self.probes.contains_key(key.as_str()))
+            .collect();
+        if !unknown.is_empty() {
+            error!(
+                "There is no health probe registered for: {:?} (thus it will never pass these checks).",
+                unknown
+            );
+            return Err(format!("no health probe registered for: {:?}", unknown));
+        }
+        let deadline = Instant::now() + healthiness.timeout;
+        let running: Vec<(String, Option<Receiver<ProbeResult>>)> =
+            self.configured(healthiness)
+                .map(|probe| {
+                    let key = probe.key().to_string();
+                    let receiver = timed_out.resume(&key).or_else(|| {
+                        let probe = probe.clone();
+                        let healthiness = healthiness.clone();
+                        let (sender, receiver) = bounded(1);
+                        let spawned = thread::Builder::new().name(format!("probe-{}", key)).spawn(
+                            move || {
+                                // If the probe has timed out, nobody is listening anymore.
+                                let _ = sender.send(probe.check(&healthiness));
+                            },
+                        );
+                        match spawned {
+                            Ok(_handle) => Some(receiver),
+                            Err(error) => {
+                                error!(
+                                    "Cannot spawn the thread for health probe {}: {}",
+                                    key, error
+                                );
+                                None
+                            }
+                        }
+                    });
+                    (key, receiver)
+                })
+                .collect();
+        // Wait for all of them, so no result is left behind.
+        let errors: Vec<String> = running
+            .into_iter()
+            .filter_map(|(key, receiver)| {
+                let receiver = match receiver {
+                    Some(receiver) => receiver,
+                    None => return Some(format!("{}: cannot be started", key)),
+                };
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                let error = match receiver.recv_timeout(timeout) {
+                    Ok(result) => result.err()?,
+                    Err(RecvTimeoutError::Timeout) => {
+                        warn!(
+                            "Health probe {} has timed out after {:?}.",
+                            key, healthiness.timeout
+                        );
+                        timed_out.0.insert(key.clone(), receiver);
+                        format!("timed out after {:?}", healthiness.timeout)
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        error!("Health probe {} has panicked.", key);
+                        "panicked".to_string()
+                    }
+                };
+                Some(format!("{}: {}", key, error))
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+
+    pub(crate) fn prepare(&self, healthiness: &Healthiness) -> Result<(), std::io::Error> {
+        self.configured(healthiness)
+            .try_for_each(|probe| probe.prepare(healthiness))
+    }
+
+    pub(crate) fn teardown(&self, healthiness: &Healthiness) {
+        self.configured(healthiness).for_each(|probe| {
+            if let Err(error) = probe.teardown(healthiness) {
+                warn!("Teardown of health probe {} failed: {}", probe.key(), error);
+            }
+        })
+    }
+}
+
+/// Probes which have timed out, but are still running: threads cannot be interrupted.
+/// Until they return, they are not run again (so hanging probes don't pile up), and every check
+/// waiting for them is considered failed.
+#[derive(Debug, Default)]
+pub(crate) struct TimedOutProbes(HashMap<String, Receiver<ProbeResult>>);
+
+impl TimedOutProbes {
+    /// Returns the receiver of the probe if it's still running.
+    /// If it has returned in the meantime, its (late) result is discarded.
+    fn resume(&mut self, key: &str) -> Option<Receiver<ProbeResult>> {
+        let receiver = self.0.remove(key)?;
+        match receiver.try_recv() {
+            Err(TryRecvError::Empty) => {
+                debug!(
+                    "Health probe {} is still running, not starting it again.",
+                    key
+                );
+                Some(receiver)
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,24 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Shared timestamp, updated by the runtime at every iteration of its event loop.
+/// Used for detecting if the runtime is stalled.
+#[derive(Debug, Clone)]
+pub(crate) struct Heartbeat(Arc<Mutex<Instant>>);
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(Instant::now())))
+    }
+}
+
+impl Heartbeat {
+    pub(crate) fn beat(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+
+    /// Time passed since the last beat
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.0.lock().unwrap().elapsed()
+    }
+}
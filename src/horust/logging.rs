@@ -0,0 +1,430 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Format of horust's own logs. The output of the services is not affected.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LogFormat {
+    Text,
+    /// One json object per line: timestamp, level, target, message, and service and event when the
+    /// record is about a service.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("Unknown log format: {}", other)),
+        }
+    }
+}
+
+/// Service and event of the record being logged, see `service_event`.
+struct Context {
+    service: String,
+    event: &'static str,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Option<Context>> = const { RefCell::new(None) };
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    timestamp: String,
+    level: String,
+    target: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    service: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<&'a str>,
+    message: String,
+}
+
+/// What a log level set at runtime applies to (check `horustctl log-level`).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum LogTarget {
+    /// Every record.
+    All,
+    /// The records logged by this module, or by its submodules.
+    Module(&'static str),
+    /// The records about this service (see `service_event`).
+    Service(String),
+}
+
+impl FromStr for LogTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(LogTarget::All),
+            "runtime" => Ok(LogTarget::Module("horust::horust::runtime")),
+            "healthcheck" => Ok(LogTarget::Module("horust::horust::healthcheck")),
+            other => match other.strip_prefix("service:") {
+                Some(service) if !service.is_empty() => Ok(LogTarget::Service(service.into())),
+                _ => Err(format!(
+                    "Unknown log target: '{}', expected: all|runtime|healthcheck|service:<name>",
+                    other
+                )),
+            },
+        }
+    }
+}
+
+/// Log levels set at runtime. They take precedence over the HORUST_LOG filter: the one of the
+/// service first, then the one of the module (the most specific), then the one for every record.
+struct Overrides {
+    all: Option<LevelFilter>,
+    modules: BTreeMap<&'static str, LevelFilter>,
+    services: BTreeMap<String, LevelFilter>,
+}
+
+static OVERRIDES: RwLock<Overrides> = RwLock::new(Overrides {
+    all: None,
+    modules: BTreeMap::new(),
+    services: BTreeMap::new(),
+});
+
+/// The most verbose level allowed by the HORUST_LOG filter.
+static FILTER_LEVEL: RwLock<LevelFilter> = RwLock::new(LevelFilter::Off);
+
+impl Overrides {
+    fn level(&self, target: &str, service: Option<&str>) -> Option<LevelFilter> {
+        let service_level = service.and_then(|service| self.services.get(service));
+        let module_level = self
+            .modules
+            .iter()
+            .filter(|(module, _level)| is_in_module(target, module))
+            .max_by_key(|(module, _level)| module.len())
+            .map(|(_module, level)| level);
+        service_level
+            .or(module_level)
+            .or(self.all.as_ref())
+            .cloned()
+    }
+
+    fn max_level(&self) -> Option<LevelFilter> {
+        self.all
+            .iter()
+            .chain(self.modules.values())
+            .chain(self.services.values())
+            .max()
+            .cloned()
+    }
+}
+
+fn is_in_module(target: &str, module: &str) -> bool {
+    target == module || (target.starts_with(module) && target[module.len()..].starts_with("::"))
+}
+
+/// Sets the log level of the target, or removes it if `level` is None: the HORUST_LOG filter
+/// applies again.
+pub(crate) fn set_level(target: LogTarget, level: Option<LevelFilter>) {
+    let mut overrides = OVERRIDES.write().unwrap_or_else(|error| error.into_inner());
+    match (target, level) {
+        (LogTarget::All, level) => overrides.all = level,
+        (LogTarget::Module(module), Some(level)) => {
+            overrides.modules.insert(module, level);
+        }
+        (LogTarget::Module(module), None) => {
+            overrides.modules.remove(module);
+        }
+        (LogTarget::Service(service), Some(level)) => {
+            overrides.services.insert(service, level);
+        }
+        (LogTarget::Service(service), None) => {
+            overrides.services.remove(&service);
+        }
+    }
+    let filter_level = *FILTER_LEVEL
+        .read()
+        .unwrap_or_else(|error| error.into_inner());
+    log::set_max_level(
+        overrides
+            .max_level()
+            .map_or(filter_level, |max| max.max(filter_level)),
+    );
+}
+
+/// Filters the records using the overrides and the HORUST_LOG filter, then hands them to
+/// env_logger, which is only used for writing them.
+struct HorustLogger {
+    filter: env_logger::filter::Filter,
+    writer: env_logger::Logger,
+}
+
+impl HorustLogger {
+    fn is_enabled(&self, metadata: &Metadata) -> bool {
+        let level = CONTEXT.with(|context| {
+            let context = context.borrow();
+            let service = context.as_ref().map(|context| context.service.as_str());
+            OVERRIDES
+                .read()
+                .ok()
+                .and_then(|overrides| overrides.level(metadata.target(), service))
+        });
+        match level {
+            Some(level) => metadata.level() <= level,
+            None => self.filter.enabled(metadata),
+        }
+    }
+}
+
+impl Log for HorustLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.is_enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.is_enabled(record.metadata()) {
+            self.writer.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.writer.flush();
+    }
+}
+
+/// Rate limits a log line, e.g. one logged at every iteration of the event loop.
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    interval: Duration,
+    last: Option<Instant>,
+    suppressed: u64,
+}
+
+impl Throttle {
+    pub(crate) fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: None,
+            suppressed: 0,
+        }
+    }
+
+    /// Returns how many times the line hasn't been logged since the last time, if it's time to
+    /// log it again.
+    pub(crate) fn check(&mut self) -> Option<u64> {
+        let now = Instant::now();
+        match self.last {
+            Some(last) if now.duration_since(last) < self.interval => {
+                self.suppressed += 1;
+                None
+            }
+            _ => {
+                self.last = Some(now);
+                Some(std::mem::replace(&mut self.suppressed, 0))
+            }
+        }
+    }
+}
+
+/// Sets up the logger. The filter is read from HORUST_LOG, and the style from HORUST_LOG_STYLE.
+/// The log levels can be changed later on, using `set_level`.
+pub fn init_logging(format: LogFormat) {
+    let filter = env_logger::filter::Builder::from_env("HORUST_LOG").build();
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(LevelFilter::Trace);
+    if let Ok(write_style) = std::env::var("HORUST_LOG_STYLE") {
+        builder.parse_write_style(&write_style);
+    }
+    if format == LogFormat::Json {
+        builder.format(|buf, record| writeln!(buf, "{}", to_json(record)));
+    }
+    if let Ok(mut filter_level) = FILTER_LEVEL.write() {
+        *filter_level = filter.filter();
+    }
+    let max_level = filter.filter();
+    let logger = HorustLogger {
+        filter,
+        writer: builder.build(),
+    };
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+/// Logs something which happened to a service. With the json format, the service and the event
+/// become fields of the record, so that they can be filtered on. The message should be
+/// self-explanatory anyway, as it's the only thing shown by the text format.
+/// `target` is the module logging the event (i.e. `module_path!()`), used for filtering.
+pub(crate) fn service_event(
+    target: &str,
+    level: tracing::Level,
+    service: &str,
+    event: &'static str,
+    message: &str,
+) {
+    CONTEXT.with(|context| {
+        *context.borrow_mut() = Some(Context {
+            service: service.to_string(),
+            event,
+        })
+    });
+    let level = log_level(level);
+    if level <= log::max_level() {
+        // Built by hand (the target of tracing's macros must be a constant), so that the module
+        // path is the one of the caller as well.
+        log::logger().log(
+            &Record::builder()
+                .level(level)
+                .target(target)
+                .module_path(Some(target))
+                .args(format_args!("{}", message))
+                .build(),
+        );
+    }
+    CONTEXT.with(|context| *context.borrow_mut() = None);
+}
+
+/// Tracing's events end up in the log records written by `HorustLogger`: the same goes for the
+/// service events.
+fn log_level(level: tracing::Level) -> Level {
+    match level {
+        tracing::Level::ERROR => Level::Error,
+        tracing::Level::WARN => Level::Warn,
+        tracing::Level::INFO => Level::Info,
+        tracing::Level::DEBUG => Level::Debug,
+        _ => Level::Trace,
+    }
+}
+
+/// The logger writes the record on the same thread which has logged it, so the context set by
+/// `service_event` (if any) is still there.
+fn to_json(record: &Record) -> String {
+    CONTEXT.with(|context| {
+        let context = context.borrow();
+        let json_record = JsonRecord {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            level: record.level().to_string(),
+            target: record.target(),
+            service: context.as_ref().map(|context| context.service.as_str()),
+            event: context.as_ref().map(|context| context.event),
+            message: record.args().to_string(),
+        };
+        serde_json::to_string(&json_record).unwrap_or_else(|error| {
+            format!(
+                r#"{{"level":"ERROR","message":"Cannot serialize log record: {}"}}"#,
+                error
+            )
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn json_of(level: Level, message: &str, context: Option<Context>) -> serde_json::Value {
+        CONTEXT.with(|c| *c.borrow_mut() = context);
+        let json = to_json(
+            &Record::builder()
+                .level(level)
+                .target("horust::runtime")
+                .args(format_args!("{}", message))
+                .build(),
+        );
+        CONTEXT.with(|c| *c.borrow_mut() = None);
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn test_to_json() {
+        let json = json_of(Level::Warn, "quoted \"message\"", None);
+        assert_eq!(json["level"], "WARN");
+        assert_eq!(json["target"], "horust::runtime");
+        assert_eq!(json["message"], "quoted \"message\"");
+        assert!(json["timestamp"].as_str().unwrap().ends_with('Z'));
+        assert!(json.get("service").is_none());
+        assert!(json.get("event").is_none());
+
+        let context = Context {
+            service: "web".into(),
+            event: "exited",
+        };
+        let json = json_of(Level::Info, "Service web has exited", Some(context));
+        assert_eq!(json["service"], "web");
+        assert_eq!(json["event"], "exited");
+    }
+
+    #[test]
+    fn test_overrides_level() {
+        let mut overrides = Overrides {
+            all: None,
+            modules: BTreeMap::new(),
+            services: BTreeMap::new(),
+        };
+        assert_eq!(overrides.level("horust::horust::runtime", None), None);
+        assert_eq!(overrides.max_level(), None);
+        overrides.all = Some(LevelFilter::Warn);
+        overrides
+            .modules
+            .insert("horust::horust::runtime", LevelFilter::Debug);
+        overrides.services.insert("web".into(), LevelFilter::Trace);
+        let level = |target, service| overrides.level(target, service);
+        assert_eq!(
+            level("horust::horust::healthcheck", None),
+            Some(LevelFilter::Warn)
+        );
+        assert_eq!(
+            level("horust::horust::runtime", None),
+            Some(LevelFilter::Debug)
+        );
+        assert_eq!(
+            level("horust::horust::runtime::reaper", Some("db")),
+            Some(LevelFilter::Debug)
+        );
+        assert_eq!(
+            level("horust::horust::runtimes", None),
+            Some(LevelFilter::Warn)
+        );
+        assert_eq!(
+            level("horust::horust::healthcheck", Some("web")),
+            Some(LevelFilter::Trace)
+        );
+        assert_eq!(overrides.max_level(), Some(LevelFilter::Trace));
+    }
+
+    #[test]
+    fn test_log_target_from_str() {
+        assert_eq!("all".parse::<LogTarget>(), Ok(LogTarget::All));
+        assert_eq!(
+            "runtime".parse::<LogTarget>(),
+            Ok(LogTarget::Module("horust::horust::runtime"))
+        );
+        assert_eq!(
+            "service:web".parse::<LogTarget>(),
+            Ok(LogTarget::Service("web".into()))
+        );
+        assert!("service:".parse::<LogTarget>().is_err());
+        assert!("bus".parse::<LogTarget>().is_err());
+    }
+
+    #[test]
+    fn test_throttle() {
+        let mut throttle = Throttle::new(Duration::from_millis(200));
+        assert_eq!(throttle.check(), Some(0));
+        assert_eq!(throttle.check(), None);
+        assert_eq!(throttle.check(), None);
+        std::thread::sleep(Duration::from_millis(250));
+        assert_eq!(throttle.check(), Some(2));
+        assert_eq!(throttle.check(), None);
+    }
+
+    #[test]
+    fn test_log_format_from_str() {
+        assert_eq!("json".parse::<LogFormat>(), Ok(LogFormat::Json));
+        assert_eq!("text".parse::<LogFormat>(), Ok(LogFormat::Text));
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+}
@@ -1,43 +1,121 @@
+mod boot_progress;
 mod bus;
+mod chaos;
+mod collector;
+mod control;
 mod error;
+mod exec;
 mod formats;
 mod healthcheck;
+mod heartbeat;
+mod logging;
+#[cfg(feature = "otlp")]
+mod otlp;
+mod plugins;
+mod reload;
 mod runtime;
+mod scheduler;
 mod signal_safe;
+mod signature;
+mod status_board;
 
 pub use self::error::HorustError;
-pub use self::formats::{get_sample_service, ExitStatus, HorustConfig};
-use crate::horust::bus::Bus;
-use crate::horust::error::Result;
-use crate::horust::formats::{validate, Service};
+pub use self::exec::exec;
+pub use self::formats::{
+    events_schema, get_sample_service, list_services, migrate_file, ExitStatus, Healthiness,
+    HorustConfig,
+};
+pub use self::healthcheck::HealthProbe;
+pub use self::logging::{init_logging, LogFormat};
+pub use self::signature::signing_payload;
+use crate::horust::bus::{EventBus, Supervision};
+use crate::horust::chaos::FaultInjector;
+use crate::horust::error::{ErrorKind, Result};
+use crate::horust::formats::{
+    expand_replicas, from_compose, from_spec, from_units, resolve_aliases, to_toml, validate,
+    Service,
+};
+use crate::horust::healthcheck::ProbeRegistry;
+use crate::horust::heartbeat::Heartbeat;
+use crate::horust::reload::ReloadPreview;
+use crate::horust::runtime::audit::AuditLog;
+use crate::horust::runtime::simulation::{self, Simulation};
+use crate::horust::runtime::zygote::Zygote;
+use crate::horust::runtime::SpawnMode;
+use crate::horust::status_board::StatusBoard;
 pub use formats::Event;
+#[cfg(target_os = "linux")]
 use libc::{prctl, PR_SET_CHILD_SUBREAPER};
 use std::ffi::OsStr;
 use std::fmt::Debug;
 use std::fs;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub struct Horust {
     services: Vec<Service>,
-    services_dir: Option<PathBuf>,
+    services_dirs: Vec<PathBuf>,
+    /// The active profiles the services have been loaded with.
+    profiles: Vec<String>,
+    config: HorustConfig,
+    probes: ProbeRegistry,
+    simulation: Option<Simulation>,
 }
 
 impl Horust {
-    fn new(services: Vec<Service>, services_dir: Option<PathBuf>) -> Self {
+    fn new(services: Vec<Service>, services_dirs: Vec<PathBuf>) -> Self {
         Horust {
             services,
-            services_dir,
+            services_dirs,
+            profiles: vec![],
+            config: Default::default(),
+            probes: Default::default(),
+            simulation: None,
         }
     }
 
+    /// Use this configuration instead of the default one.
+    pub fn set_config(&mut self, config: HorustConfig) {
+        self.config = config;
+    }
+
+    /// Makes a custom health probe available to the services, configured using
+    /// `[healthiness.custom.<key>]`. A built-in probe with the same key is replaced.
+    pub fn register_probe<P>(&mut self, probe: P)
+    where
+        P: HealthProbe + 'static,
+    {
+        self.probes.register(probe);
+    }
+
+    /// Don't run anything, but simulate the boot instead (printing the events on stdout): each
+    /// service starts and becomes healthy after `delay`, except the `failing` ones which exit with
+    /// an error. Once every service has settled, the simulated services are shut down.
+    pub fn simulate(&mut self, delay: Duration, failing: Vec<String>) {
+        self.simulation = Some(Simulation { delay, failing });
+    }
+
     pub fn get_services(&self) -> &Vec<Service> {
         &self.services
     }
     /// Creates a new Horust instance from a command.
     /// The command will be wrapped in a service and run with sane defaults
     pub fn from_command(command: String) -> Self {
-        Self::new(vec![Service::from_command(command)], None)
+        Self::new(vec![Service::from_command(command)], vec![])
+    }
+
+    /// Creates a new Horust instance from the services defined on the command line (e.g.
+    /// `name=web,command=./run.sh,restart=always`), plus the command if any.
+    pub fn from_service_specs(specs: &[String], command: Option<String>) -> Result<Self> {
+        let mut services = specs
+            .iter()
+            .map(|spec| from_spec(spec))
+            .collect::<Result<Vec<Service>>>()?;
+        services.extend(command.map(Service::from_command));
+        Self::from_services(services, &[], vec![])
     }
 
     /// Create a new horust instance from a path of services.
@@ -45,31 +123,358 @@ impl Horust {
     where
         P: AsRef<Path> + ?Sized + AsRef<OsStr> + Debug,
     {
-        let services = fetch_services(&path)?;
-        validate(services)
-            .map_err(Into::into)
-            .map(|services| Horust::new(services, Some(PathBuf::from(path))))
+        Self::from_services_dir_with_profiles(path, &[])
+    }
+
+    /// Create a new horust instance from a path of services, skipping the services which don't
+    /// belong to any of the active profiles. Services without profiles are always included.
+    pub fn from_services_dir_with_profiles<P>(path: &P, profiles: &[String]) -> Result<Self>
+    where
+        P: AsRef<Path> + ?Sized + AsRef<OsStr> + Debug,
+    {
+        Self::from_services_dirs_with_profiles(&[path], profiles)
+    }
+
+    /// Create a new horust instance from several paths of services, e.g. the defaults shipped
+    /// with an image and the ones added at deploy time. A service replaces the one with the same
+    /// name found in any of the previous paths.
+    pub fn from_services_dirs_with_profiles<P>(paths: &[P], profiles: &[String]) -> Result<Self>
+    where
+        P: AsRef<Path> + AsRef<OsStr> + Debug,
+    {
+        Self::from_verified_services_dirs(paths, profiles, None, false)
+    }
+
+    /// Like `from_services_dirs_with_profiles`, but the directories are verified first using
+    /// their detached signature (`services.minisig`) and the minisign `public_key`. An unsigned
+    /// directory is loaded only if the signature is not `required`.
+    pub fn from_verified_services_dirs<P>(
+        paths: &[P],
+        profiles: &[String],
+        public_key: Option<&Path>,
+        required: bool,
+    ) -> Result<Self>
+    where
+        P: AsRef<Path> + AsRef<OsStr> + Debug,
+    {
+        let services = load_services_dirs(paths, profiles, public_key, required, false)?;
+        let mut horust = Horust::new(services, paths.iter().map(PathBuf::from).collect());
+        horust.profiles = profiles.to_vec();
+        Ok(horust)
+    }
+
+    /// Create a new horust instance from the services defined in the config file
+    /// (`[services.<name>]`), skipping the services which don't belong to any of its profiles.
+    pub fn from_inline_services(config: &HorustConfig) -> Result<Self> {
+        if config.require_signed_config {
+            let err = "a signed config is required, but the config file cannot be signed";
+            return Err(ErrorKind::Signature(err.to_string()).into());
+        }
+        Self::from_services(config.inline_services(), &config.profiles, vec![])
+    }
+
+    /// Create a new horust instance from the services of a docker-compose file, skipping the
+    /// services which don't belong to any of the active profiles.
+    pub fn from_compose_file<P>(path: &P, profiles: &[String]) -> Result<Self>
+    where
+        P: AsRef<Path> + ?Sized + Debug,
+    {
+        debug!("Loading services from compose file: {:?}", path);
+        let content = fs::read_to_string(path)?;
+        Self::from_services(from_compose(&content)?, profiles, vec![])
+    }
+
+    fn from_services(
+        services: Vec<Service>,
+        profiles: &[String],
+        services_dirs: Vec<PathBuf>,
+    ) -> Result<Self> {
+        let mut horust = Horust::new(prepare_services(services, profiles)?, services_dirs);
+        horust.profiles = profiles.to_vec();
+        Ok(horust)
     }
 
     /// Blocking call, will setup the event loop and the threads and run all the available services.
     pub fn run(&mut self) -> ExitStatus {
+        // Orphans are reparented to horust instead of init, so that they can be reaped.
+        // There is no such thing on macOS: only the direct children are reaped there.
+        #[cfg(target_os = "linux")]
         unsafe {
             prctl(PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0);
         }
+        // Forked before starting any thread, and before installing the signal handlers.
+        let is_sandboxed = self
+            .services
+            .iter()
+            .any(|service| service.sandbox.user_namespace.is_some());
+        let zygote = if is_sandboxed && self.simulation.is_none() {
+            Zygote::start()
+                .map_err(|error| warn!("Cannot start the zygote: {}", error))
+                .ok()
+        } else {
+            None
+        };
         runtime::signal_handling::init();
+        install_panic_hook();
+        #[cfg(feature = "otlp")]
+        let otlp = self.install_otlp();
+        #[cfg(not(feature = "otlp"))]
+        self.install_otlp();
 
-        let mut dispatcher = Bus::new();
+        let mut dispatcher = EventBus::new();
+        dispatcher.supervise(Supervision::new(self.config.shutdown_on_stalled_component));
         debug!("Services: {:?}", self.services);
+        let heartbeat = Heartbeat::default();
+        let status_board = StatusBoard::default();
+        // Shared by the runtime and the plugins.
+        let audit = AuditLog::open(self.config.audit_log.as_deref()).unwrap_or_else(|error| {
+            error!("Cannot open the audit log: {}", error);
+            AuditLog::default()
+        });
         // Spawn helper threads:
-        healthcheck::spawn(dispatcher.join_bus(), self.services.clone());
-        let handle = runtime::spawn(dispatcher.join_bus(), self.services.clone());
-        dispatcher.run();
-        handle.join().unwrap()
+        if let Some(simulation) = self.simulation.clone() {
+            // The simulation plays the part of the healthchecks as well.
+            simulation::spawn(
+                dispatcher.join_bus_as("simulation", true),
+                self.services.clone(),
+                simulation,
+            );
+        } else {
+            let chaos = FaultInjector::new(&self.config.chaos);
+            healthcheck::spawn(
+                dispatcher.join_bus_as("healthcheck", true),
+                self.services.clone(),
+                self.probes.clone(),
+                chaos.clone(),
+                self.config.tick_interval(),
+            );
+            if let Some(chaos) = chaos {
+                chaos::spawn(dispatcher.join_bus_as("chaos", false), chaos);
+            }
+        }
+        let is_scheduled = self.services.iter().any(|s| s.schedule.is_some());
+        if let (true, None) = (is_scheduled, &self.simulation) {
+            scheduler::spawn(
+                dispatcher.join_bus_as("scheduler", false),
+                self.services.clone(),
+            );
+        }
+        if self.simulation.is_none() {
+            for plugin in &self.config.plugins {
+                match plugins::start(plugin, &audit) {
+                    Ok(child) => plugins::spawn(
+                        dispatcher.join_bus_as(&format!("plugin {}", plugin.name), false),
+                        plugin.clone(),
+                        child,
+                        &self.services,
+                    ),
+                    Err(error) => error!("Cannot start plugin {}: {}", plugin.name, error),
+                }
+            }
+        }
+        if let (Some(socket_path), None) = (self.config.control_socket.clone(), &self.simulation) {
+            control::spawn(
+                dispatcher.join_bus_as("control socket", false),
+                dispatcher.registrar(),
+                socket_path,
+                heartbeat.clone(),
+                status_board.clone(),
+                control::aliases(&self.services),
+                self.reload_preview(),
+            );
+        }
+        if let (true, None) = (self.config.boot_progress, &self.simulation) {
+            if let Some(subscriber) = dispatcher.registrar().subscribe("boot progress") {
+                let services = self.services.iter().map(|s| s.name.clone()).collect();
+                boot_progress::spawn(subscriber, services);
+            }
+        }
+        if let (Some(collector), None) = (&self.config.collector, &self.simulation) {
+            let subscriber = dispatcher.registrar().subscribe("collector");
+            if let (Some(subscriber), Ok(endpoint)) = (subscriber, collector.parse()) {
+                collector::spawn(
+                    subscriber,
+                    status_board.clone(),
+                    endpoint,
+                    collector::Identity::new(self.config.collector_node.clone()),
+                    self.config.collector_interval(),
+                );
+            }
+        }
+        let handle = runtime::spawn(
+            dispatcher.join_bus_as("runtime", true),
+            self.services.clone(),
+            self.config.clone(),
+            heartbeat,
+            self.probes.clone(),
+            status_board,
+            match self.simulation {
+                Some(_) => SpawnMode::Simulated,
+                None => SpawnMode::Processes(zygote, audit),
+            },
+        );
+        let bus = thread::Builder::new()
+            .name("bus".into())
+            .spawn(move || {
+                if panic::catch_unwind(AssertUnwindSafe(|| dispatcher.run())).is_err() {
+                    runtime::bus_crashed();
+                }
+            })
+            .expect("Failed spawning the bus thread");
+        let exit_status = handle.join().unwrap_or(ExitStatus::Crashed);
+        #[cfg(feature = "otlp")]
+        if let Some(otlp) = otlp {
+            otlp.flush();
+        }
+        // Components might be stuck (e.g. a spawner waiting for a start-delay): don't wait for them.
+        // The simulation never leaves the bus.
+        if exit_status != ExitStatus::ShutdownTimedOut && self.simulation.is_none() {
+            let _ = bus.join();
+        }
+        exit_status
     }
+
+    /// Installs the exporter of the spans, if `--otlp-endpoint` is set.
+    #[cfg(feature = "otlp")]
+    fn install_otlp(&self) -> Option<otlp::Flush> {
+        let endpoint = self.config.otlp_endpoint.as_ref()?;
+        if self.simulation.is_some() {
+            return None;
+        }
+        let identity = collector::Identity::new(self.config.collector_node.clone());
+        otlp::install(endpoint, identity)
+            .map_err(|error| error!("Cannot export the spans to {}: {}", endpoint, error))
+            .ok()
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    fn install_otlp(&self) {
+        if self.config.otlp_endpoint.is_some() {
+            error!("Cannot export the spans: horust has been built without the otlp feature.");
+        }
+    }
+
+    /// Only the services loaded from directories can be loaded again.
+    fn reload_preview(&self) -> Option<ReloadPreview> {
+        if self.services_dirs.is_empty() {
+            return None;
+        }
+        Some(ReloadPreview {
+            services_dirs: self.services_dirs.clone(),
+            profiles: self.profiles.clone(),
+            public_key: self.config.config_public_key.clone(),
+            required: self.config.require_signed_config,
+            current: self.services.clone(),
+        })
+    }
+}
+
+/// Loads the services of the directories, verifying their signature first if there is a
+/// `public_key`. If `strict`, a file which cannot be loaded fails the whole load.
+fn load_services_dirs<P>(
+    paths: &[P],
+    profiles: &[String],
+    public_key: Option<&Path>,
+    required: bool,
+    strict: bool,
+) -> Result<Vec<Service>>
+where
+    P: AsRef<Path> + AsRef<OsStr> + Debug,
+{
+    if required && public_key.is_none() {
+        let err = "a signed config is required, but there is no config-public-key".to_string();
+        return Err(ErrorKind::Signature(err).into());
+    }
+    if let Some(public_key) = public_key {
+        for path in paths {
+            signature::verify_dir(path.as_ref(), public_key, required)?;
+        }
+    }
+    prepare_services(fetch_layered_services(paths, strict)?, profiles)
+}
+
+/// Skips the services which don't belong to any of the active profiles, validates the others
+/// and expands their replicas.
+fn prepare_services(services: Vec<Service>, profiles: &[String]) -> Result<Vec<Service>> {
+    let services = services
+        .into_iter()
+        .filter(|service| {
+            let is_active = service.is_in_profiles(profiles);
+            if !is_active {
+                debug!(
+                    "Skipping service {}, profiles: {:?}",
+                    service.name, service.profiles
+                );
+            }
+            is_active
+        })
+        .collect();
+    validate(services)
+        .map_err(Into::into)
+        .map(resolve_aliases)
+        .map(expand_replicas)
+}
+
+/// Translates the services defined in docker-compose files or systemd units (`.service` files)
+/// into service files, written in `output_dir` and named after the services.
+/// Existing files are not overwritten. Returns the paths of the written files.
+pub fn import_services<P, Q>(paths: &[P], output_dir: &Q) -> Result<Vec<PathBuf>>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path> + ?Sized,
+{
+    let mut units = vec![];
+    let mut services = vec![];
+    for path in paths {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)?;
+        if path.extension() == Some("service".as_ref()) {
+            let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+            units.push((file_name, content));
+        } else {
+            services.extend(from_compose(&content)?);
+        }
+    }
+    services.extend(from_units(&units)?);
+    // Fail before writing anything:
+    validate(services.clone())?;
+    let files = services
+        .iter()
+        .map(|service| {
+            let path = output_dir.as_ref().join(format!("{}.toml", service.name));
+            if path.exists() {
+                let err = format!("{} already exists", path.display());
+                return Err(ErrorKind::Import(err).into());
+            }
+            Ok((path, to_toml(service)?))
+        })
+        .collect::<Result<Vec<(PathBuf, String)>>>()?;
+    files
+        .into_iter()
+        .map(|(path, content)| {
+            fs::write(&path, content)?;
+            Ok(path)
+        })
+        .collect()
+}
+
+/// Report panics using the logger as well, including the name of the thread which has panicked.
+fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |panic_info| {
+        let thread = std::thread::current();
+        error!(
+            "Thread '{}' has panicked: {}",
+            thread.name().unwrap_or("<unnamed>"),
+            panic_info
+        );
+        default_hook(panic_info);
+    }));
 }
 
 /// Search for *.toml files in path, and deserialize them into Service.
-fn fetch_services<P>(path: &P) -> Result<Vec<Service>>
+/// The files which cannot be deserialized are skipped, unless `strict`.
+fn fetch_services<P>(path: &P, strict: bool) -> Result<Vec<Service>>
 where
     P: AsRef<Path> + ?Sized + AsRef<OsStr> + Debug,
 {
@@ -84,7 +489,6 @@ where
     let is_toml_file = |path: &PathBuf| path.is_file() && has_toml_extension(path);
     let dir = fs::read_dir(path)?;
 
-    //TODO: option to decide to not start if the deserialization of any service failed.
     let services = dir
         .filter_map(std::result::Result::ok)
         .map(|dir_entry| dir_entry.path())
@@ -99,22 +503,48 @@ where
                 service
             })
             .map_err(|error| {
-                error!("Error loading toml file: {}", error);
-                error
+                error!("Error loading toml file {}: {}", file.display(), error);
+                ErrorKind::ServiceFile(file, error.to_string()).into()
             })
-        })
-        .filter_map(Result::ok)
-        .collect::<Vec<Service>>();
+        });
+    if strict {
+        services.collect()
+    } else {
+        Ok(services.filter_map(Result::ok).collect())
+    }
+}
+
+/// The services of every path: the ones of a path replace the services with the same name
+/// found in the previous paths.
+fn fetch_layered_services<P>(paths: &[P], strict: bool) -> Result<Vec<Service>>
+where
+    P: AsRef<Path> + AsRef<OsStr> + Debug,
+{
+    let mut services: Vec<Service> = vec![];
+    for path in paths {
+        for service in fetch_services(path, strict)? {
+            match services.iter_mut().find(|other| other.name == service.name) {
+                Some(other) => {
+                    info!(
+                        "Service {} is overridden by the one in: {:?}",
+                        service.name, path
+                    );
+                    *other = service;
+                }
+                None => services.push(service),
+            }
+        }
+    }
     if services.is_empty() {
-        error!("Horust: No services found in: {:?}", path);
+        error!("Horust: No services found in: {:?}", paths);
     }
     Ok(services)
 }
 
 #[cfg(test)]
 mod test {
-    use crate::horust::fetch_services;
     use crate::horust::formats::Service;
+    use crate::horust::{fetch_layered_services, fetch_services};
     use std::fs;
     use std::io;
     use std::path::{Path, PathBuf};
@@ -149,12 +579,40 @@ mod test {
     fn test_fetch_services() -> io::Result<()> {
         let tempdir = create_test_dir()?;
         std::fs::write(tempdir.path().join("not-a-service"), "Hello world")?;
-        let res = fetch_services(tempdir.path()).unwrap();
+        let res = fetch_services(tempdir.path(), true).unwrap();
         assert_eq!(res.len(), 2);
         let mut names: Vec<String> = res.into_iter().map(|serv| serv.name).collect();
         names.sort();
         assert_eq!(vec!["a", "b"], names);
 
+        // The invalid files are skipped, unless strict.
+        std::fs::write(tempdir.path().join("broken.toml"), "command = [")?;
+        assert_eq!(fetch_services(tempdir.path(), false).unwrap().len(), 2);
+        let error = fetch_services(tempdir.path(), true).unwrap_err();
+        assert!(error.to_string().contains("broken.toml"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_layered_services() -> io::Result<()> {
+        let base = create_test_dir()?;
+        let layer = TempDir::new("horust").unwrap();
+        let mut b = Service::from_name("b");
+        b.command = "/bin/true".into();
+        std::fs::write(layer.path().join("b.toml"), toml::to_string(&b).unwrap())?;
+        std::fs::write(
+            layer.path().join("c.toml"),
+            toml::to_string(&Service::from_name("c")).unwrap(),
+        )?;
+        let res = fetch_layered_services(&[base.path(), layer.path()], true).unwrap();
+        let mut names: Vec<&str> = res.iter().map(|serv| serv.name.as_str()).collect();
+        names.sort();
+        assert_eq!(vec!["a", "b", "c"], names);
+        let b = res.iter().find(|serv| serv.name == "b").unwrap();
+        assert_eq!(b.command, "/bin/true");
+        assert!(b.start_after.is_empty());
+
         Ok(())
     }
 
@@ -0,0 +1,397 @@
+//! OpenTelemetry exporter (`--otlp-endpoint`, built with the `otlp` feature): the spans of horust
+//! (a `service` span for every run of a service, an `event` span for every event applied by the
+//! runtime) are posted in batches to an OTLP/HTTP collector, encoded as json. The logs are not
+//! affected: the log records are still emitted, even if a tracing subscriber is set.
+use crate::horust::collector::{Endpoint, Identity};
+use crossbeam::channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender};
+use rand::Rng;
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+/// The finished spans are exported at least this often...
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// ...or as soon as there are this many of them.
+const MAX_BATCH_SIZE: usize = 512;
+/// How long the exit of horust waits for the last spans to be exported.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(3);
+
+thread_local! {
+    /// The spans entered by this thread, the innermost last.
+    static CURRENT_SPANS: RefCell<Vec<u64>> = const { RefCell::new(vec![]) };
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct SpanData {
+    trace_id: u128,
+    span_id: u64,
+    parent_span_id: Option<u64>,
+    name: &'static str,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+}
+
+enum Message {
+    Finished(SpanData),
+    /// Export the pending spans right away, and acknowledge it.
+    Flush(Sender<()>),
+}
+
+struct OpenSpan {
+    data: SpanData,
+    /// The handles of the span: it's finished once all of them are dropped.
+    handles: usize,
+}
+
+/// Keeps track of the spans of horust, and hands them over to the exporter once they're closed.
+struct OtlpSubscriber {
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, OpenSpan>>,
+    exporter: Sender<Message>,
+}
+
+impl OtlpSubscriber {
+    fn new(exporter: Sender<Message>) -> Self {
+        Self {
+            // Zero isn't a valid span id.
+            next_id: AtomicU64::new(1),
+            spans: Default::default(),
+            exporter,
+        }
+    }
+}
+
+/// Random trace and span ids: zero means invalid in OTLP.
+fn random_id<T: Default + PartialEq>() -> T
+where
+    rand::distributions::Standard: rand::distributions::Distribution<T>,
+{
+    let mut rng = rand::thread_rng();
+    loop {
+        let id: T = rng.gen();
+        if id != T::default() {
+            return id;
+        }
+    }
+}
+
+impl Subscriber for OtlpSubscriber {
+    /// Only the spans of horust: not the ones of its dependencies, nor the events.
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.is_span() && metadata.target().starts_with("horust")
+    }
+
+    fn new_span(&self, attributes: &Attributes<'_>) -> Id {
+        let parent = match attributes.parent() {
+            Some(parent) => Some(parent.into_u64()),
+            None if attributes.is_contextual() => {
+                CURRENT_SPANS.with(|current| current.borrow().last().copied())
+            }
+            None => None,
+        };
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut spans = self.spans.lock().unwrap_or_else(|error| error.into_inner());
+        let parent = parent
+            .and_then(|parent| spans.get(&parent))
+            .map(|parent| (parent.data.trace_id, parent.data.span_id));
+        let trace_id = match parent {
+            Some((trace_id, _span_id)) => trace_id,
+            None => random_id(),
+        };
+        let mut data = SpanData {
+            trace_id,
+            span_id: random_id(),
+            parent_span_id: parent.map(|(_trace_id, span_id)| span_id),
+            name: attributes.metadata().name(),
+            start: SystemTime::now(),
+            end: UNIX_EPOCH,
+            attributes: vec![],
+        };
+        attributes.record(&mut Fields(&mut data.attributes));
+        spans.insert(id, OpenSpan { data, handles: 1 });
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut spans = self.spans.lock().unwrap_or_else(|error| error.into_inner());
+        if let Some(open) = spans.get_mut(&span.into_u64()) {
+            values.record(&mut Fields(&mut open.data.attributes));
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, _event: &Event<'_>) {}
+
+    fn enter(&self, span: &Id) {
+        CURRENT_SPANS.with(|current| current.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &Id) {
+        CURRENT_SPANS.with(|current| {
+            let mut current = current.borrow_mut();
+            if let Some(idx) = current.iter().rposition(|id| *id == span.into_u64()) {
+                current.remove(idx);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        let mut spans = self.spans.lock().unwrap_or_else(|error| error.into_inner());
+        if let Some(open) = spans.get_mut(&span.into_u64()) {
+            open.handles += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut spans = self.spans.lock().unwrap_or_else(|error| error.into_inner());
+        let id = span.into_u64();
+        match spans.get_mut(&id) {
+            Some(open) if open.handles > 1 => {
+                open.handles -= 1;
+                false
+            }
+            Some(_) => {
+                let mut data = spans.remove(&id).unwrap().data;
+                data.end = SystemTime::now();
+                let _ = self.exporter.send(Message::Finished(data));
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Collects the fields of a span as its attributes.
+struct Fields<'a>(&'a mut Vec<(&'static str, String)>);
+
+impl Visit for Fields<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name(), value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.push((field.name(), format!("{:?}", value)));
+    }
+}
+
+/// Exports the spans of horust to the OTLP/HTTP collector at `endpoint` (e.g.
+/// `http://otel-collector:4318`), until horust exits. Returns a handle for flushing the last
+/// spans. It fails if another subscriber has been set already.
+pub(crate) fn install(endpoint: &str, identity: Identity) -> Result<Flush, String> {
+    let traces = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+    let traces: Endpoint = traces.parse()?;
+    let (sender, receiver) = unbounded();
+    tracing::subscriber::set_global_default(OtlpSubscriber::new(sender.clone()))
+        .map_err(|error| error.to_string())?;
+    let resource = resource(&identity);
+    thread::Builder::new()
+        .name("otlp".into())
+        .spawn(move || export(receiver, &traces, &resource))
+        .map_err(|error| error.to_string())?;
+    Ok(Flush(sender))
+}
+
+/// Exports the pending spans, e.g. before exiting.
+pub(crate) struct Flush(Sender<Message>);
+
+impl Flush {
+    pub(crate) fn flush(&self) {
+        let (sender, receiver) = bounded(1);
+        if self.0.send(Message::Flush(sender)).is_ok() {
+            let _ = receiver.recv_timeout(FLUSH_TIMEOUT);
+        }
+    }
+}
+
+fn export(receiver: Receiver<Message>, endpoint: &Endpoint, resource: &Value) {
+    let mut batch = vec![];
+    let mut last_export = Instant::now();
+    loop {
+        let flushed = match receiver.recv_timeout(EXPORT_INTERVAL) {
+            Ok(Message::Finished(span)) => {
+                batch.push(span);
+                None
+            }
+            Ok(Message::Flush(flushed)) => Some(flushed),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        let is_due = batch.len() >= MAX_BATCH_SIZE || last_export.elapsed() >= EXPORT_INTERVAL;
+        if !batch.is_empty() && (is_due || flushed.is_some()) {
+            let body = encode(&batch, resource).to_string();
+            // They're dropped anyway: the memory stays bounded while the collector is down.
+            if let Err(error) = endpoint.post(&body) {
+                debug!("Cannot export {} spans: {}", batch.len(), error);
+            }
+            batch.clear();
+            last_export = Instant::now();
+        }
+        if let Some(flushed) = flushed {
+            let _ = flushed.send(());
+        }
+    }
+}
+
+/// Who is exporting the spans, following the semantic conventions of OpenTelemetry.
+fn resource(identity: &Identity) -> Value {
+    let mut attributes = vec![
+        ("service.name", "horust".to_string()),
+        ("service.version", env!("CARGO_PKG_VERSION").to_string()),
+        ("host.name", identity.node.clone()),
+        ("process.pid", identity.pid.to_string()),
+    ];
+    if let Some(container) = &identity.container {
+        attributes.push(("container.id", container.clone()));
+    }
+    json!({ "attributes": encode_attributes(&attributes) })
+}
+
+/// An ExportTraceServiceRequest, in the json encoding of OTLP.
+fn encode(spans: &[SpanData], resource: &Value) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": resource,
+            "scopeSpans": [{
+                "scope": { "name": "horust", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans.iter().map(encode_span).collect::<Vec<_>>(),
+            }],
+        }],
+    })
+}
+
+fn encode_span(span: &SpanData) -> Value {
+    let unix_nanos = |time: SystemTime| {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        since_epoch.as_nanos().to_string()
+    };
+    let mut encoded = json!({
+        "traceId": format!("{:032x}", span.trace_id),
+        "spanId": format!("{:016x}", span.span_id),
+        "name": span.name,
+        // SPAN_KIND_INTERNAL
+        "kind": 1,
+        "startTimeUnixNano": unix_nanos(span.start),
+        "endTimeUnixNano": unix_nanos(span.end),
+        "attributes": encode_attributes(&span.attributes),
+    });
+    if let Some(parent_span_id) = span.parent_span_id {
+        encoded["parentSpanId"] = json!(format!("{:016x}", parent_span_id));
+    }
+    encoded
+}
+
+fn encode_attributes(attributes: &[(&str, String)]) -> Value {
+    attributes
+        .iter()
+        .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode, export, Message, OtlpSubscriber, SpanData};
+    use crossbeam::channel::{bounded, unbounded};
+    use serde_json::json;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn span() -> SpanData {
+        SpanData {
+            trace_id: 0x0af7651916cd43dd8448eb211c80319c,
+            span_id: 0xb7ad6b7169203331,
+            parent_span_id: Some(1),
+            name: "service",
+            start: UNIX_EPOCH + Duration::from_secs(1),
+            end: UNIX_EPOCH + Duration::from_millis(1500),
+            attributes: vec![("pid", "42".into())],
+        }
+    }
+
+    #[test]
+    fn test_spans() {
+        let (sender, receiver) = unbounded();
+        tracing::subscriber::with_default(OtlpSubscriber::new(sender), || {
+            let service = info_span!(target: "horust::runtime", "service", name = "web.toml");
+            let event = service.in_scope(|| trace_span!(target: "horust::runtime", "event"));
+            drop(event);
+            assert_eq!(receiver.len(), 1);
+            // Still open: a handle is around.
+            let handle = service.clone();
+            drop(service);
+            assert_eq!(receiver.len(), 1);
+            drop(handle);
+            // Not a span of horust.
+            drop(info_span!(target: "hyper::client", "request"));
+        });
+        let spans: Vec<SpanData> = receiver
+            .try_iter()
+            .map(|message| match message {
+                Message::Finished(span) => span,
+                Message::Flush(_) => panic!("Unexpected flush"),
+            })
+            .collect();
+        assert_eq!(spans.len(), 2);
+        let (event, service) = (&spans[0], &spans[1]);
+        assert_eq!(event.name, "event");
+        assert_eq!(event.trace_id, service.trace_id);
+        assert_eq!(event.parent_span_id, Some(service.span_id));
+        assert_eq!(service.parent_span_id, None);
+        assert_eq!(service.attributes, vec![("name", "web.toml".to_string())]);
+        assert!(service.start <= event.start && event.end <= service.end);
+    }
+
+    #[test]
+    fn test_encode() {
+        let encoded = encode(&[span()], &json!({ "attributes": [] }));
+        assert_eq!(
+            encoded["resourceSpans"][0]["scopeSpans"][0]["spans"][0],
+            json!({
+                "traceId": "0af7651916cd43dd8448eb211c80319c",
+                "spanId": "b7ad6b7169203331",
+                "parentSpanId": "0000000000000001",
+                "name": "service",
+                "kind": 1,
+                "startTimeUnixNano": "1000000000",
+                "endTimeUnixNano": "1500000000",
+                "attributes": [{ "key": "pid", "value": { "stringValue": "42" } }],
+            })
+        );
+    }
+
+    #[test]
+    fn test_export() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let endpoint = endpoint.parse().unwrap();
+        let (sender, receiver) = unbounded();
+        thread::spawn(move || export(receiver, &endpoint, &json!({ "attributes": [] })));
+        sender.send(Message::Finished(span())).unwrap();
+        // Not before the interval: unless flushed.
+        let (flushed, is_flushed) = bounded(1);
+        sender.send(Message::Flush(flushed)).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = vec![0; 8192];
+        let len = stream.read(&mut request).unwrap();
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        drop(stream);
+        is_flushed.recv_timeout(Duration::from_secs(5)).unwrap();
+        let request = String::from_utf8_lossy(&request[..len]);
+        assert!(request.starts_with("POST /v1/traces HTTP/1.1\r\n"));
+        assert!(request.contains("\"spanId\":\"b7ad6b7169203331\""));
+    }
+}
@@ -0,0 +1,290 @@
+//! Plugins (`[[plugins]]` in the config file): external programs joining the bus as components,
+//! e.g. custom healthcheckers, notifiers or schedulers. A plugin is told about the events on its
+//! stdin (the records of the events schema, after its version), and it sends its requests on its
+//! stdout. Both ways, every message is a json object prefixed by its length in bytes (4 bytes,
+//! big endian).
+use crate::horust::bus::{BusConnector, Publisher};
+use crate::horust::formats::{
+    Event, EventRecord, Header, HealthCheckReport, Plugin, RestartMode, Service, ServiceName,
+    EVENTS_SCHEMA_VERSION,
+};
+use crate::horust::runtime::audit::{AuditEntry, AuditLog};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// A longer message is taken as a broken plugin.
+const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+/// The names the requests can refer to: the services, and the replicated ones.
+type KnownServices = Arc<Mutex<BTreeSet<ServiceName>>>;
+
+/// What the plugins can ask for: the same as the control socket, and the results of their own
+/// healthchecks.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "command", rename_all = "kebab-case", deny_unknown_fields)]
+enum Request {
+    Stop {
+        service: ServiceName,
+    },
+    Restart {
+        service: ServiceName,
+    },
+    Scale {
+        service: ServiceName,
+        instances: u32,
+    },
+    Mask {
+        service: ServiceName,
+    },
+    Unmask {
+        service: ServiceName,
+    },
+    /// Counted like the results of the checks of the healthiness section.
+    Health {
+        service: ServiceName,
+        healthy: bool,
+        #[serde(default)]
+        error: Option<String>,
+    },
+}
+
+impl Request {
+    fn service(&self) -> &ServiceName {
+        match self {
+            Request::Stop { service }
+            | Request::Restart { service }
+            | Request::Scale { service, .. }
+            | Request::Mask { service }
+            | Request::Unmask { service }
+            | Request::Health { service, .. } => service,
+        }
+    }
+
+    fn into_event(self) -> Event {
+        match self {
+            Request::Stop { service } => Event::Stop(service),
+            Request::Restart { service } => Event::Restart(service, RestartMode::All),
+            Request::Scale { service, instances } => Event::Scale(service, instances),
+            Request::Mask { service } => Event::Mask(service),
+            Request::Unmask { service } => Event::Unmask(service),
+            Request::Health {
+                service,
+                healthy,
+                error,
+            } => Event::HealthCheck(
+                service,
+                HealthCheckReport {
+                    status: healthy.into(),
+                    latency: Duration::from_secs(0),
+                    error,
+                },
+            ),
+        }
+    }
+}
+
+fn write_message<T: Serialize>(writer: &mut impl Write, message: &T) -> io::Result<()> {
+    let body = serde_json::to_vec(message)?;
+    writer.write_all(&(body.len() as u32).to_be_bytes())?;
+    writer.write_all(&body)?;
+    writer.flush()
+}
+
+/// None once the other side has closed the stream.
+fn read_message(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("the message is too long: {} bytes", len),
+        ));
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+/// Starts the program of the plugin, recording it in the audit log. Its stderr is horust's one.
+pub(crate) fn start(plugin: &Plugin, audit: &AuditLog) -> io::Result<Child> {
+    let argv = shlex::split(&plugin.command).unwrap_or_default();
+    if argv.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid command: '{}'", plugin.command),
+        ));
+    }
+    let entry = AuditEntry::inherited("plugin", &plugin.name, &argv);
+    let child = Command::new(&argv[0])
+        .args(&argv[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn();
+    let child = match child {
+        Ok(child) => {
+            audit.record(entry, Ok(Pid::from_raw(child.id() as i32)));
+            child
+        }
+        Err(error) => {
+            audit.record(entry, Err(error.to_string()));
+            return Err(error);
+        }
+    };
+    info!("Plugin {} started, pid: {}", plugin.name, child.id());
+    Ok(child)
+}
+
+/// The process isn't waited for: once it has exited, it's reaped by the runtime.
+pub(crate) fn spawn(bus: BusConnector, plugin: Plugin, mut child: Child, services: &[Service]) {
+    let known: KnownServices = Arc::new(Mutex::new(
+        services
+            .iter()
+            .flat_map(|service| {
+                std::iter::once(service.name.clone())
+                    .chain(service.replica.as_ref().map(|replica| replica.of.clone()))
+            })
+            .collect(),
+    ));
+    let stdin = child
+        .stdin
+        .take()
+        .expect("The stdin of the plugin is piped");
+    let stdout = child
+        .stdout
+        .take()
+        .expect("The stdout of the plugin is piped");
+    let publisher = bus.publisher();
+    let name = plugin.name.clone();
+    let known_by_reader = known.clone();
+    thread::Builder::new()
+        .name(format!("plugin {} requests", plugin.name))
+        .spawn(move || read_requests(&name, stdout, publisher, known_by_reader))
+        .expect("Failed spawning the plugin thread");
+    thread::Builder::new()
+        .name(format!("plugin {}", plugin.name))
+        .spawn(move || run(&plugin.name, bus, stdin, known))
+        .expect("Failed spawning the plugin thread");
+}
+
+/// Forwards the requests of the plugin to the bus, until it closes its stdout.
+fn read_requests(name: &str, mut stdout: ChildStdout, publisher: Publisher, known: KnownServices) {
+    loop {
+        let body = match read_message(&mut stdout) {
+            Ok(Some(body)) => body,
+            Ok(None) => {
+                info!("Plugin {} has closed its stdout", name);
+                return;
+            }
+            Err(error) => {
+                error!("Cannot read the requests of plugin {}: {}", name, error);
+                return;
+            }
+        };
+        let request: Request = match serde_json::from_slice(&body) {
+            Ok(request) => request,
+            Err(error) => {
+                warn!("Plugin {} has sent an invalid request: {}", name, error);
+                continue;
+            }
+        };
+        if !known.lock().unwrap().contains(request.service()) {
+            warn!(
+                "Plugin {} has sent a request for an unknown service: {:?}",
+                name, request
+            );
+            continue;
+        }
+        debug!("Request of plugin {}: {:?}", name, request);
+        publisher.send_event(request.into_event());
+    }
+}
+
+/// Notifies the plugin, until the shutdown: then its stdin is closed, and it's expected to exit.
+fn run(name: &str, bus: BusConnector, mut stdin: ChildStdin, known: KnownServices) {
+    let header = Header {
+        schema_version: EVENTS_SCHEMA_VERSION,
+    };
+    let mut stdin = match write_message(&mut stdin, &header) {
+        Ok(()) => Some(stdin),
+        Err(error) => {
+            warn!("Cannot notify plugin {}: {}", name, error);
+            None
+        }
+    };
+    for ev in bus.iter() {
+        if let Event::ServiceCreated(service) = &ev {
+            known.lock().unwrap().insert(service.name.clone());
+        }
+        if let (Some(writer), Some(record)) = (stdin.as_mut(), EventRecord::from_event(&ev)) {
+            if let Err(error) = write_message(writer, &record) {
+                warn!(
+                    "Cannot notify plugin {}, it won't be notified anymore: {}",
+                    name, error
+                );
+                stdin = None;
+            }
+        }
+        if let Event::ShuttingDownInitiated(_) = ev {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_message, write_message, Request};
+    use crate::horust::formats::{Event, EventRecord, ServiceStatus};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_plugin_protocol() {
+        let mut stream = vec![];
+        let record = EventRecord::from_event(&Event::StatusChanged(
+            "web.toml".into(),
+            ServiceStatus::Running,
+        ))
+        .unwrap();
+        write_message(&mut stream, &record).unwrap();
+        let record = EventRecord::from_event(&Event::ServiceExited("web.toml".into(), 1)).unwrap();
+        write_message(&mut stream, &record).unwrap();
+        assert_eq!(&stream[..4], &[0, 0, 0, 66]);
+
+        let mut reader = Cursor::new(stream);
+        let first = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(
+            String::from_utf8(first).unwrap(),
+            r#"{"event":"status-changed","service":"web.toml","status":"Running"}"#
+        );
+        let second = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(
+            String::from_utf8(second).unwrap(),
+            r#"{"event":"service-exited","service":"web.toml","exit-code":1}"#
+        );
+        assert!(read_message(&mut reader).unwrap().is_none());
+        let mut too_long = Cursor::new(vec![0xff, 0xff, 0xff, 0xff]);
+        read_message(&mut too_long).unwrap_err();
+
+        let request: Request =
+            serde_json::from_str(r#"{"command":"scale","service":"web.toml","instances":3}"#)
+                .unwrap();
+        assert_eq!(request.into_event(), Event::Scale("web.toml".into(), 3));
+        let request: Request =
+            serde_json::from_str(r#"{"command":"health","service":"web.toml","healthy":false}"#)
+                .unwrap();
+        assert!(matches!(request.into_event(), Event::HealthCheck(s, report)
+            if s == "web.toml" && report.error.is_none()));
+        serde_json::from_str::<Request>(r#"{"command":"kill","service":"web.toml"}"#).unwrap_err();
+        serde_json::from_str::<Request>(r#"{"command":"stop","name":"web.toml"}"#).unwrap_err();
+    }
+}
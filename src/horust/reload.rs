@@ -0,0 +1,132 @@
+//! `horustctl reload --dry-run`: what reloading the services directories would change, compared
+//! to the services horust has been started with.
+use crate::horust::error::Result;
+use crate::horust::formats::{Service, ServiceName};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::PathBuf;
+
+/// Everything needed for loading the services again, the same way as at startup.
+#[derive(Debug, Clone)]
+pub(crate) struct ReloadPreview {
+    pub(crate) services_dirs: Vec<PathBuf>,
+    pub(crate) profiles: Vec<String>,
+    pub(crate) public_key: Option<PathBuf>,
+    pub(crate) required: bool,
+    /// The services loaded at startup, with their replicas expanded.
+    pub(crate) current: Vec<Service>,
+}
+
+impl ReloadPreview {
+    /// Reads the services directories again, without changing anything. Unlike at startup, the
+    /// files which cannot be loaded aren't skipped: a reload is taken as a whole or not at all,
+    /// so a broken file never shows up as a service to remove.
+    pub(crate) fn plan(&self) -> Result<ReloadPlan> {
+        let reloaded = super::load_services_dirs(
+            &self.services_dirs,
+            &self.profiles,
+            self.public_key.as_deref(),
+            self.required,
+            true,
+        )?;
+        Ok(ReloadPlan::new(&self.current, &reloaded))
+    }
+}
+
+/// The services which would be added, removed, restarted (with the changed settings) or kept.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct ReloadPlan {
+    pub(crate) added: Vec<ServiceName>,
+    pub(crate) removed: Vec<ServiceName>,
+    pub(crate) restarted: BTreeMap<ServiceName, Vec<String>>,
+    pub(crate) kept: Vec<ServiceName>,
+}
+
+impl ReloadPlan {
+    pub(crate) fn new(current: &[Service], reloaded: &[Service]) -> Self {
+        let current: BTreeMap<&ServiceName, &Service> = current
+            .iter()
+            .map(|service| (&service.name, service))
+            .collect();
+        let reloaded: BTreeMap<&ServiceName, &Service> = reloaded
+            .iter()
+            .map(|service| (&service.name, service))
+            .collect();
+        let mut plan = ReloadPlan::default();
+        for (name, service) in &reloaded {
+            match current.get(name) {
+                None => plan.added.push((*name).clone()),
+                Some(other) if other == service => plan.kept.push((*name).clone()),
+                Some(other) => {
+                    plan.restarted
+                        .insert((*name).clone(), changed_settings(other, service));
+                }
+            }
+        }
+        plan.removed = current
+            .keys()
+            .filter(|name| !reloaded.contains_key(*name))
+            .map(|name| (*name).clone())
+            .collect();
+        plan
+    }
+}
+
+/// The top level settings (e.g. `command`, or `restart` for any of its options) which differ.
+fn changed_settings(current: &Service, reloaded: &Service) -> Vec<String> {
+    let table = |service: &Service| match toml::Value::try_from(service) {
+        Ok(toml::Value::Table(table)) => table,
+        _ => Default::default(),
+    };
+    let (current, reloaded) = (table(current), table(reloaded));
+    let keys: BTreeSet<&String> = current.keys().chain(reloaded.keys()).collect();
+    keys.into_iter()
+        .filter(|key| current.get(*key) != reloaded.get(*key))
+        .cloned()
+        .collect()
+}
+
+/// One line per service, e.g. `restart web.toml: command, environment`.
+impl fmt::Display for ReloadPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for name in &self.added {
+            writeln!(f, "add {}", name)?;
+        }
+        for name in &self.removed {
+            writeln!(f, "remove {}", name)?;
+        }
+        for (name, settings) in &self.restarted {
+            writeln!(f, "restart {}: {}", name, settings.join(", "))?;
+        }
+        for name in &self.kept {
+            writeln!(f, "keep {}", name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ReloadPlan;
+    use crate::horust::formats::{RestartStrategy, Service};
+
+    #[test]
+    fn test_reload_plan() {
+        let db = Service::from_name("db.toml");
+        let web = Service::from_name("web.toml");
+        let cron = Service::from_name("cron.toml");
+        let mut changed_web = web.clone();
+        changed_web.command = "./run.sh --port 80".into();
+        changed_web.restart.strategy = RestartStrategy::Always;
+        let api = Service::from_name("api.toml");
+
+        let plan = ReloadPlan::new(&[db.clone(), web, cron], &[db, changed_web, api]);
+        assert_eq!(plan.added, vec!["api.toml"]);
+        assert_eq!(plan.removed, vec!["cron.toml"]);
+        assert_eq!(plan.kept, vec!["db.toml"]);
+        assert_eq!(
+            plan.to_string(),
+            "add api.toml\nremove cron.toml\nrestart web.toml: command, restart\nkeep db.toml\n"
+        );
+    }
+}
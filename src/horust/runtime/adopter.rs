@@ -0,0 +1,127 @@
+use crate::horust::formats::Adopt;
+use crate::horust::runtime::repo::Repo;
+use crate::horust::Event;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::unistd::{self, Pid};
+use std::os::unix::io::RawFd;
+
+/// The exit code of a process which is not a child of horust cannot be collected.
+/// Using an invalid exit code, the exit of an adopted process is always considered as failed.
+const UNKNOWN_EXIT_CODE: i32 = -1;
+
+/// Not exposed by libc yet. Same number on every architecture supported by horust.
+const SYS_PIDFD_OPEN: libc::c_long = 434;
+
+/// Reads the pid-file, and returns the pid if there is a process alive with such pid.
+pub(crate) fn get_adoptable_pid(adopt: &Adopt) -> Option<Pid> {
+    let content = std::fs::read_to_string(&adopt.pid_file)
+        .map_err(|err| {
+            warn!(
+                "Cannot read pid file: {}, error: {}",
+                adopt.pid_file.display(),
+                err
+            )
+        })
+        .ok()?;
+    let pid = content
+        .trim()
+        .parse::<libc::pid_t>()
+        .map_err(|err| {
+            warn!(
+                "Invalid pid in pid file: {}, error: {}",
+                adopt.pid_file.display(),
+                err
+            )
+        })
+        .ok()
+        .map(Pid::from_raw)?;
+    match read_stat(pid) {
+        Some(stat) if !stat.is_zombie() => Some(pid),
+        _ => {
+            warn!(
+                "Process with pid {} (from {}) is not alive.",
+                pid,
+                adopt.pid_file.display()
+            );
+            None
+        }
+    }
+}
+
+/// Uses a pidfd (if supported by the kernel) for getting notified about the exit of the process.
+pub(crate) fn open_pidfd(pid: Pid) -> Option<RawFd> {
+    let fd = unsafe { libc::syscall(SYS_PIDFD_OPEN, pid.as_raw(), 0) };
+    if fd < 0 {
+        debug!("pidfd_open not available, falling back to procfs polling.");
+        None
+    } else {
+        Some(fd as RawFd)
+    }
+}
+
+/// Checks for the exit of adopted processes, and generates the ServiceExited events.
+/// If an adopted process is now a child of horust (e.g. it was reparented), it is left to the reaper.
+pub(crate) fn run(repo: &mut Repo) -> Vec<Event> {
+    let exited: Vec<Pid> = repo
+        .adopted
+        .iter()
+        .filter_map(|(pid, pidfd)| match read_stat(*pid) {
+            Some(stat) if stat.ppid == unistd::getpid() => Some(*pid),
+            Some(ref stat) if !stat.is_zombie() && !is_pidfd_ready(*pidfd) => None,
+            _ => Some(*pid),
+        })
+        .collect();
+    exited
+        .into_iter()
+        .filter_map(|pid| {
+            if let Some(Some(fd)) = repo.adopted.remove(&pid) {
+                let _ = unistd::close(fd);
+            }
+            let is_child = read_stat(pid)
+                .map(|stat| stat.ppid == unistd::getpid())
+                .unwrap_or(false);
+            if is_child {
+                debug!("Adopted pid {} is now a child, it will be reaped.", pid);
+                return None;
+            }
+            repo.get_service_by_pid(pid).map(|s_name| {
+                debug!("Adopted process of service '{}' has exited.", s_name);
+                Event::new_service_exited(s_name.into(), UNKNOWN_EXIT_CODE)
+            })
+        })
+        .collect()
+}
+
+fn is_pidfd_ready(pidfd: Option<RawFd>) -> bool {
+    pidfd
+        .map(|fd| {
+            let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+            poll(&mut fds, 0).map(|ready| ready > 0).unwrap_or(false)
+        })
+        .unwrap_or(false)
+}
+
+struct ProcStat {
+    state: char,
+    ppid: Pid,
+}
+
+impl ProcStat {
+    fn is_zombie(&self) -> bool {
+        self.state == 'Z' || self.state == 'X'
+    }
+}
+
+/// Parses /proc/<pid>/stat. Returns None if the process doesn't exist.
+fn read_stat(pid: Pid) -> Option<ProcStat> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The process name is wrapped in parenthesis, and might contain spaces.
+    let after_name = &stat[stat.rfind(')')? + 1..];
+    let mut fields = after_name.split_whitespace();
+    let state = fields.next()?.chars().next()?;
+    let ppid = fields.next()?.parse::<libc::pid_t>().ok()?;
+    Some(ProcStat {
+        state,
+        ppid: Pid::from_raw(ppid),
+    })
+}
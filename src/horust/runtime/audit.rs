@@ -0,0 +1,135 @@
+//! Append-only record of every command run by horust (services and hooks), for compliance
+//! environments: one json object per line.
+use nix::unistd::{self, Pid};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Without a file, nothing is recorded.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AuditLog {
+    file: Option<Arc<Mutex<File>>>,
+}
+
+/// A command run by horust. Only the names of the environment variables are recorded, since
+/// their values might be secrets.
+#[derive(Debug, Serialize, PartialEq)]
+pub(crate) struct AuditEntry {
+    pub(crate) timestamp: String,
+    /// `service`, the hook (e.g. `exec-before`, `policy-hook`) or `plugin`.
+    pub(crate) kind: String,
+    pub(crate) service: String,
+    pub(crate) argv: Vec<String>,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) cwd: String,
+    pub(crate) env: Vec<String>,
+    /// None if the command couldn't be run.
+    pub(crate) pid: Option<i32>,
+    pub(crate) error: Option<String>,
+}
+
+impl AuditEntry {
+    /// A command run by horust itself (e.g. a hook): it has the same user, working directory and
+    /// environment of horust.
+    pub(crate) fn inherited(kind: &str, service: &str, argv: &[String]) -> Self {
+        Self {
+            timestamp: String::new(),
+            kind: kind.to_string(),
+            service: service.to_string(),
+            argv: argv.to_vec(),
+            uid: unistd::getuid().as_raw(),
+            gid: unistd::getgid().as_raw(),
+            cwd: std::env::current_dir()
+                .map(|cwd| cwd.display().to_string())
+                .unwrap_or_default(),
+            env: std::env::vars_os()
+                .map(|(name, _)| name.to_string_lossy().to_string())
+                .collect(),
+            pid: None,
+            error: None,
+        }
+    }
+}
+
+impl AuditLog {
+    /// Appends to the file, creating it if needed.
+    pub(crate) fn open(path: Option<&Path>) -> io::Result<Self> {
+        let file = match path {
+            Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+            None => None,
+        };
+        Ok(Self {
+            file: file.map(|file| Arc::new(Mutex::new(file))),
+        })
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// Records the outcome of running the command: its pid, or the error.
+    pub(crate) fn record(&self, mut entry: AuditEntry, outcome: Result<Pid, String>) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+        entry.timestamp = humantime::format_rfc3339_millis(SystemTime::now()).to_string();
+        match outcome {
+            Ok(pid) => entry.pid = Some(pid.as_raw()),
+            Err(error) => entry.error = Some(error),
+        }
+        let mut line = serde_json::to_string(&entry).unwrap_or_default();
+        line.push('\n');
+        // A single write, so the lines are not interleaved.
+        let res = file
+            .lock()
+            .map_err(|_| io::Error::other("poisoned lock"))
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+        if let Err(error) = res {
+            error!("Cannot write the audit log: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AuditEntry, AuditLog};
+    use nix::unistd::Pid;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_record() {
+        let tempdir = TempDir::new("audit").unwrap();
+        let path = tempdir.path().join("audit.log");
+        AuditLog::default().record(AuditEntry::inherited("hook", "a", &[]), Ok(Pid::this()));
+        let audit = AuditLog::open(Some(&path)).unwrap();
+        let argv = vec!["/bin/echo".to_string(), "hi".to_string()];
+        audit.record(
+            AuditEntry::inherited("exec-before", "a.toml", &argv),
+            Ok(Pid::from_raw(42)),
+        );
+        // Appended, even after reopening.
+        let audit = AuditLog::open(Some(&path)).unwrap();
+        audit.record(
+            AuditEntry::inherited("service", "b.toml", &[]),
+            Err("not found".into()),
+        );
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["kind"], "exec-before");
+        assert_eq!(lines[0]["service"], "a.toml");
+        assert_eq!(lines[0]["argv"], serde_json::json!(["/bin/echo", "hi"]));
+        assert_eq!(lines[0]["pid"], 42);
+        assert!(lines[0]["env"].as_array().unwrap().contains(&"PATH".into()));
+        assert_eq!(lines[1]["pid"], serde_json::Value::Null);
+        assert_eq!(lines[1]["error"], "not found");
+    }
+}
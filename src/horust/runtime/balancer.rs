@@ -0,0 +1,233 @@
+//! Round-robin tcp balancer (`balance`): horust accepts the connections on the public address,
+//! and forwards every one of them to the next Running instance of the replicated service. It's
+//! meant for small setups, where running a proxy as another service would be too much.
+use crate::horust::formats::ServiceName;
+use crate::horust::runtime::service_handler::ServiceHandler;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// For connecting to an instance: the next one is tried afterwards.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The addresses of the Running instances, used in turn.
+#[derive(Debug, Default)]
+struct Backends {
+    addresses: Vec<SocketAddr>,
+    next: usize,
+}
+
+impl Backends {
+    /// The instances to try for the next connection, starting from the next one in turn.
+    fn candidates(&mut self) -> Vec<SocketAddr> {
+        if self.addresses.is_empty() {
+            return vec![];
+        }
+        let start = self.next % self.addresses.len();
+        self.next = start + 1;
+        self.addresses[start..]
+            .iter()
+            .chain(self.addresses[..start].iter())
+            .cloned()
+            .collect()
+    }
+}
+
+/// A balancer for every replicated service using `balance`.
+#[derive(Debug, Default)]
+pub(crate) struct Balancers(HashMap<ServiceName, Arc<Mutex<Backends>>>);
+
+impl Balancers {
+    /// Binds the public addresses, and starts accepting the connections on them.
+    pub(crate) fn new<'a>(services: impl Iterator<Item = &'a ServiceHandler>) -> Self {
+        let mut balancers = HashMap::new();
+        for sh in services {
+            let service = sh.service();
+            let (key, address) = match (&service.replica, &service.balance) {
+                (Some(replica), Some(address)) => (&replica.of, address),
+                (None, Some(address)) => (&service.name, address),
+                _ => continue,
+            };
+            if balancers.contains_key(key) {
+                continue;
+            }
+            let listener = match TcpListener::bind(address) {
+                Ok(listener) => listener,
+                Err(error) => {
+                    error!("Cannot balance {} on {}: {}", key, address, error);
+                    continue;
+                }
+            };
+            let backends = Arc::new(Mutex::new(Backends::default()));
+            let accepting = backends.clone();
+            let spawned = thread::Builder::new()
+                .name(format!("balancer-{}", key))
+                .spawn(move || accept(listener, accepting));
+            match spawned {
+                Ok(_) => {
+                    debug!("Balancing {} on {}.", key, address);
+                    balancers.insert(key.clone(), backends);
+                }
+                Err(error) => error!("Cannot balance {}: {}", key, error),
+            }
+        }
+        Self(balancers)
+    }
+
+    /// Keeps the backends up to date: only the Running instances get the connections.
+    pub(crate) fn update<'a>(&self, services: impl Iterator<Item = &'a ServiceHandler>) {
+        if self.0.is_empty() {
+            return;
+        }
+        let mut running: HashMap<&ServiceName, Vec<(&ServiceName, SocketAddr)>> = HashMap::new();
+        for sh in services.filter(|sh| sh.is_running()) {
+            let key = match &sh.service().replica {
+                Some(replica) => &replica.of,
+                None => sh.name(),
+            };
+            if !self.0.contains_key(key) {
+                continue;
+            }
+            if let Some(address) = backend_address(sh) {
+                running.entry(key).or_default().push((sh.name(), address));
+            }
+        }
+        for (key, backends) in &self.0 {
+            let mut addresses = running.remove(key).unwrap_or_default();
+            addresses.sort();
+            let addresses: Vec<SocketAddr> = addresses.into_iter().map(|(_, a)| a).collect();
+            let mut backends = backends.lock().unwrap();
+            if backends.addresses != addresses {
+                debug!("Balancing {} across {:?}", key, addresses);
+                backends.addresses = addresses;
+            }
+        }
+    }
+}
+
+/// The first listen address of the instance, with its allocated port. An unspecified address
+/// (e.g. 0.0.0.0) is reached on the loopback.
+fn backend_address(sh: &ServiceHandler) -> Option<SocketAddr> {
+    let mut address: SocketAddr = sh.spawned_service().listen.first()?.parse().ok()?;
+    if address.ip().is_unspecified() {
+        address.set_ip(match address.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        });
+    }
+    Some(address)
+}
+
+fn accept(listener: TcpListener, backends: Arc<Mutex<Backends>>) {
+    for client in listener.incoming() {
+        let client = match client {
+            Ok(client) => client,
+            Err(error) => {
+                warn!("Balancer, cannot accept a connection: {}", error);
+                continue;
+            }
+        };
+        let candidates = backends.lock().unwrap().candidates();
+        let spawned = thread::Builder::new()
+            .name("balancer-conn".into())
+            .spawn(move || forward(client, candidates));
+        if let Err(error) = spawned {
+            warn!("Balancer, cannot forward a connection: {}", error);
+        }
+    }
+}
+
+/// Connects to the first instance accepting the connection, and copies the data both ways until
+/// either side closes it. Without any instance, the connection is closed right away.
+fn forward(client: TcpStream, candidates: Vec<SocketAddr>) {
+    let backend = candidates
+        .iter()
+        .find_map(|address| TcpStream::connect_timeout(address, CONNECT_TIMEOUT).ok());
+    let backend = match backend {
+        Some(backend) => backend,
+        None => {
+            debug!(
+                "Balancer, no instance available for {:?}",
+                client.peer_addr()
+            );
+            return;
+        }
+    };
+    let copy = |mut from: TcpStream, mut to: TcpStream| -> io::Result<()> {
+        io::copy(&mut from, &mut to)?;
+        to.shutdown(Shutdown::Write)
+    };
+    let (client_read, backend_write) = match (client.try_clone(), backend.try_clone()) {
+        (Ok(client_read), Ok(backend_write)) => (client_read, backend_write),
+        _ => return,
+    };
+    let upstream = thread::spawn(move || copy(client_read, backend_write));
+    let _ = copy(backend, client);
+    let _ = upstream.join();
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Backends, Balancers};
+    use crate::horust::formats::{PortRange, Service, ServiceStatus};
+    use crate::horust::runtime::service_handler::ServiceHandler;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn test_round_robin() {
+        let mut backends = Backends::default();
+        assert!(backends.candidates().is_empty());
+        backends.addresses = vec![
+            "127.0.0.1:8001".parse().unwrap(),
+            "127.0.0.1:8002".parse().unwrap(),
+        ];
+        assert_eq!(backends.candidates(), backends.addresses);
+        assert_eq!(
+            backends.candidates(),
+            vec![backends.addresses[1], backends.addresses[0]]
+        );
+        assert_eq!(backends.candidates(), backends.addresses);
+    }
+
+    #[test]
+    fn test_balancer() {
+        let public = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let instance = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = instance.local_addr().unwrap().port();
+        let mut web = Service::from_name("web");
+        web.balance = Some(public.to_string());
+        web.listen = vec!["0.0.0.0:{port}".into()];
+        web.port_range = Some(PortRange {
+            start: port,
+            end: port,
+        });
+        let mut web: ServiceHandler = web.into();
+        web.port = Some(port);
+        let balancers = Balancers::new(std::iter::once(&web));
+        // Not running yet: the connection is closed.
+        let mut client = TcpStream::connect(public).unwrap();
+        let mut received = String::new();
+        client.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "");
+
+        web.status = ServiceStatus::Running;
+        balancers.update(std::iter::once(&web));
+        let mut client = TcpStream::connect(public).unwrap();
+        client.write_all(b"ping").unwrap();
+        let (mut server, _) = instance.accept().unwrap();
+        let mut request = [0; 4];
+        server.read_exact(&mut request).unwrap();
+        assert_eq!(&request, b"ping");
+        server.write_all(b"pong").unwrap();
+        drop(server);
+        client.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "pong");
+    }
+}
@@ -0,0 +1,42 @@
+use crate::horust::runtime::audit::{AuditEntry, AuditLog};
+use crate::horust::runtime::repo::Repo;
+use nix::unistd::Pid;
+use std::process::Command;
+
+/// Spawns a command on behalf of a service (e.g. its `exec-before` hook), without waiting for it.
+/// The pid is tracked in the repo (until it's reaped), so the reaper won't consider it as an
+/// unknown child.
+pub(crate) fn run_hook(
+    repo: &mut Repo,
+    audit: &AuditLog,
+    hook: &str,
+    service_name: &str,
+    command: &str,
+) -> Option<Pid> {
+    let description = format!("{} of {}", hook, service_name);
+    let chunks = match shlex::split(command) {
+        Some(chunks) if !chunks.is_empty() => chunks,
+        _ => {
+            error!("Invalid command for {}: '{}'", description, command);
+            return None;
+        }
+    };
+    let entry = AuditEntry::inherited(hook, service_name, &chunks);
+    match Command::new(&chunks[0]).args(&chunks[1..]).spawn() {
+        Ok(child) => {
+            let pid = Pid::from_raw(child.id() as i32);
+            info!("Running {}: '{}', pid: {}", description, command, pid);
+            audit.record(entry, Ok(pid));
+            repo.hooks.insert(pid, description);
+            Some(pid)
+        }
+        Err(error) => {
+            error!(
+                "Cannot run {}: '{}', error: {}",
+                description, command, error
+            );
+            audit.record(entry, Err(error.to_string()));
+            None
+        }
+    }
+}
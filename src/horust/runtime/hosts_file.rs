@@ -0,0 +1,198 @@
+//! Hosts file of the running services (`--hosts-file`): every service is reachable at 127.0.0.1
+//! by its name (without `.toml`) and by its aliases, like the services of a compose network.
+use crate::horust::runtime::service_handler::ServiceHandler;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// The lines of horust are kept between these markers: the rest of the file isn't touched.
+const BEGIN: &str = "# BEGIN horust: the running services.";
+const END: &str = "# END horust";
+
+/// Rewrites the block of horust in the file whenever the running services change.
+#[derive(Debug)]
+pub(crate) struct HostsFile {
+    path: PathBuf,
+    /// The last block written, so the same hosts aren't written again at every iteration.
+    last: Option<String>,
+}
+
+impl HostsFile {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Self { path, last: None }
+    }
+
+    pub(crate) fn update<'a>(&mut self, services: impl Iterator<Item = &'a ServiceHandler>) {
+        let content = hosts(services);
+        if self.last.as_ref() == Some(&content) {
+            return;
+        }
+        if let Err(error) = self.write(&content) {
+            error!(
+                "Cannot write the hosts file {}: {}",
+                self.path.display(),
+                error
+            );
+        }
+        // Tried again at the next change.
+        self.last = Some(content);
+    }
+
+    /// Horust is exiting: no service is reachable anymore, the block is removed.
+    pub(crate) fn clear(&self) {
+        if let Err(error) = self.write("") {
+            error!(
+                "Cannot write the hosts file {}: {}",
+                self.path.display(),
+                error
+            );
+        }
+    }
+
+    /// Edited in place, since it can't be replaced when it's a mount point (e.g. the /etc/hosts
+    /// of a docker container).
+    fn write(&self, block: &str) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let current = match fs::read_to_string(&self.path) {
+            Ok(current) => current,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error),
+        };
+        let content = replace_block(&current, block);
+        if content == current {
+            return Ok(());
+        }
+        fs::write(&self.path, content)
+    }
+}
+
+/// Replaces the block of horust with `block`, keeping every other line where it was. The block is
+/// appended if there isn't one yet.
+fn replace_block(content: &str, block: &str) -> String {
+    let mut lines: Vec<&str> = vec![];
+    let mut position = None;
+    let mut in_block = false;
+    for line in content.lines() {
+        match (in_block, line) {
+            (false, BEGIN) => {
+                in_block = true;
+                position.get_or_insert(lines.len());
+            }
+            (true, END) => in_block = false,
+            (true, _) => (),
+            (false, line) => lines.push(line),
+        }
+    }
+    let position = position.unwrap_or(lines.len());
+    lines.splice(position..position, block.lines());
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+/// The block with a line for every running service, e.g.
+/// `127.0.0.1 db postgres # db.toml, ports: 5432`.
+fn hosts<'a>(services: impl Iterator<Item = &'a ServiceHandler>) -> String {
+    let mut lines: Vec<String> = services
+        .filter(|sh| sh.is_running())
+        .map(|sh| {
+            let service = sh.spawned_service();
+            let names: Vec<String> = std::iter::once(sh.name().replacen(".toml", "", 1))
+                .chain(service.aliases.iter().cloned())
+                .collect();
+            let ports: Vec<String> = service
+                .listen
+                .iter()
+                .filter_map(|address| address.parse::<SocketAddr>().ok())
+                .map(|address| address.port().to_string())
+                .collect();
+            let ports = if ports.is_empty() {
+                String::new()
+            } else {
+                format!(", ports: {}", ports.join(" "))
+            };
+            format!("127.0.0.1 {} # {}{}\n", names.join(" "), sh.name(), ports)
+        })
+        .collect();
+    lines.sort();
+    format!("{}\n{}{}\n", BEGIN, lines.concat(), END)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hosts, HostsFile};
+    use crate::horust::formats::{Service, ServiceStatus};
+    use crate::horust::runtime::service_handler::ServiceHandler;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_hosts_file() {
+        let mut db = Service::from_name("db.toml");
+        db.aliases = vec!["postgres".into()];
+        db.listen = vec!["0.0.0.0:5432".into()];
+        let mut db: ServiceHandler = db.into();
+        db.status = ServiceStatus::Running;
+        let mut web: ServiceHandler = Service::from_name("web.toml").into();
+        web.status = ServiceStatus::Running;
+        let cron: ServiceHandler = Service::from_name("cron.toml").into();
+        let services = [web.clone(), db, cron];
+        assert_eq!(
+            hosts(services.iter()),
+            "# BEGIN horust: the running services.\n\
+             127.0.0.1 db postgres # db.toml, ports: 5432\n\
+             127.0.0.1 web # web.toml\n\
+             # END horust\n"
+        );
+
+        let tempdir = TempDir::new("hosts_file").unwrap();
+        let path = tempdir.path().join("run").join("hosts");
+        let mut hosts_file = HostsFile::new(path.clone());
+        hosts_file.update(std::iter::once(&web));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            hosts(std::iter::once(&web))
+        );
+        hosts_file.clear();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+    }
+
+    /// The other lines are kept, and the file is edited in place.
+    #[test]
+    fn test_hosts_file_block() {
+        use std::os::unix::fs::MetadataExt;
+        let mut web: ServiceHandler = Service::from_name("web.toml").into();
+        web.status = ServiceStatus::Running;
+        let tempdir = TempDir::new("hosts_file").unwrap();
+        let path = tempdir.path().join("hosts");
+        let original = "127.0.0.1 localhost\n::1 localhost\n";
+        std::fs::write(&path, original).unwrap();
+        let inode = std::fs::metadata(&path).unwrap().ino();
+        let mut hosts_file = HostsFile::new(path.clone());
+        hosts_file.update(std::iter::once(&web));
+        let block = hosts(std::iter::once(&web));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}{}", original, block)
+        );
+        // Lines added by others after the block are kept as well.
+        let mut content = std::fs::read_to_string(&path).unwrap();
+        content.push_str("10.0.0.1 other\n");
+        std::fs::write(&path, content).unwrap();
+        web.status = ServiceStatus::Finished;
+        hosts_file.update(std::iter::once(&web));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!(
+                "{}# BEGIN horust: the running services.\n# END horust\n10.0.0.1 other\n",
+                original
+            )
+        );
+        hosts_file.clear();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}10.0.0.1 other\n", original)
+        );
+        assert_eq!(std::fs::metadata(&path).unwrap().ino(), inode);
+    }
+}
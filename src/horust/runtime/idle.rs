@@ -0,0 +1,94 @@
+use crate::horust::runtime::session;
+use nix::unistd::Pid;
+use std::collections::{HashMap, HashSet};
+
+/// State of an established connection, in /proc/net/tcp.
+const TCP_ESTABLISHED: &str = "01";
+
+/// Inodes of the established tcp connections, read from /proc/net/tcp and /proc/net/tcp6.
+fn established_connections() -> HashSet<String> {
+    ["/proc/net/tcp", "/proc/net/tcp6"]
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .flat_map(|table| {
+            table
+                .lines()
+                // Skip the header.
+                .skip(1)
+                .filter_map(|line| {
+                    // Fields: sl, local_address, rem_address, st, ..., uid, timeout, inode.
+                    let fields: Vec<&str> = line.split_whitespace().collect();
+                    match (fields.get(3), fields.get(9)) {
+                        (Some(&state), Some(inode)) if state == TCP_ESTABLISHED => {
+                            Some(inode.to_string())
+                        }
+                        _ => None,
+                    }
+                })
+                .collect::<Vec<String>>()
+        })
+        .collect()
+}
+
+/// Inodes of the sockets opened by the process.
+fn sockets(pid: Pid) -> Vec<String> {
+    let entries = match std::fs::read_dir(format!("/proc/{}/fd", pid)) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    entries
+        .filter_map(|entry| std::fs::read_link(entry.ok()?.path()).ok())
+        .filter_map(|target| {
+            // E.g. socket:[12345]
+            let target = target.to_str()?;
+            let inode = target.strip_prefix("socket:[")?.strip_suffix(']')?;
+            Some(inode.to_string())
+        })
+        .collect()
+}
+
+/// Any of these processes has one of the established tcp connections.
+fn have_connections(pids: &[Pid], established: &HashSet<String>) -> bool {
+    pids.iter()
+        .flat_map(|pid| sockets(*pid))
+        .any(|inode| established.contains(&inode))
+}
+
+/// The services (by the pid of their main process) which, or whose children, are serving a tcp
+/// connection. /proc and the connection tables are read once for all of them.
+pub(crate) fn active_services(pids: &[Pid]) -> HashSet<Pid> {
+    let established = established_connections();
+    let sessions: HashMap<Pid, Vec<Pid>> = session::members_by_session();
+    pids.iter()
+        .filter(|pid| {
+            sessions
+                .get(pid)
+                .is_some_and(|members| have_connections(members, &established))
+        })
+        .copied()
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::horust::runtime::idle::{
+        active_services, established_connections, have_connections,
+    };
+    use nix::unistd::Pid;
+    use std::net::{TcpListener, TcpStream};
+    use std::process::Command;
+
+    #[test]
+    fn test_have_connections() {
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let child_pid = Pid::from_raw(child.id() as i32);
+        assert!(!have_connections(&[child_pid], &established_connections()));
+        assert!(active_services(&[child_pid]).is_empty());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        assert!(have_connections(&[Pid::this()], &established_connections()));
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
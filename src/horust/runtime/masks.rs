@@ -0,0 +1,53 @@
+use crate::horust::formats::ServiceName;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The masked services are stored in this file of the state directory, one name per line.
+const MASKED_FILE: &str = "masked";
+
+/// The services masked in a previous run. A missing file means that none of them is masked.
+pub(crate) fn load(state_directory: &Path) -> io::Result<BTreeSet<ServiceName>> {
+    match fs::read_to_string(state_directory.join(MASKED_FILE)) {
+        Ok(content) => Ok(content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect()),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(error) => Err(error),
+    }
+}
+
+/// Replaces the stored masked services, creating the state directory if needed.
+pub(crate) fn store(state_directory: &Path, masked: &BTreeSet<ServiceName>) -> io::Result<()> {
+    fs::create_dir_all(state_directory)?;
+    let content: String = masked.iter().map(|name| format!("{}\n", name)).collect();
+    // Written aside and then renamed, so a crash cannot leave a truncated file behind.
+    let tmp_path = state_directory.join(format!("{}.tmp", MASKED_FILE));
+    fs::write(&tmp_path, content)?;
+    fs::rename(tmp_path, state_directory.join(MASKED_FILE))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{load, store};
+    use std::collections::BTreeSet;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_load_and_store() {
+        let tempdir = TempDir::new("masks").unwrap();
+        let state_directory = tempdir.path().join("state");
+        assert!(load(&state_directory).unwrap().is_empty());
+        let masked: BTreeSet<String> = vec!["db.toml".to_string(), "web.toml".to_string()]
+            .into_iter()
+            .collect();
+        store(&state_directory, &masked).unwrap();
+        assert_eq!(load(&state_directory).unwrap(), masked);
+        store(&state_directory, &BTreeSet::new()).unwrap();
+        assert!(load(&state_directory).unwrap().is_empty());
+    }
+}
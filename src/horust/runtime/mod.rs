@@ -1,86 +1,433 @@
 use crate::horust::bus::BusConnector;
 use crate::horust::formats::{
-    Event, ExitStatus, FailureStrategy, HealthinessStatus, RestartStrategy, Service, ServiceName,
-    ServiceStatus,
+    AttemptsExhaustedAction, Event, ExitStatus, FailureStrategy, HealthinessStatus, HorustConfig,
+    KillScope, RestartMode, RestartStrategy, Service, ServiceName, ServiceStatus, ShutdownReason,
+    SpawnError,
 };
-use crate::horust::healthcheck;
+use crate::horust::healthcheck::{self, ProbeRegistry};
+use crate::horust::heartbeat::Heartbeat;
+use crate::horust::logging;
+use crate::horust::status_board::{format_state, RuntimeStats, ServiceSnapshot, StatusBoard};
+use audit::AuditLog;
+use balancer::Balancers;
+use hosts_file::HostsFile;
+use monitor::Monitor;
+use nix::errno::Errno;
 use nix::sys::signal;
 use nix::unistd;
+use policy::Policy;
+use process_spawner::SpawnQueue;
+use ready_files::ReadyFiles;
 use repo::Repo;
-use service_handler::ServiceHandler;
+use rollout::{Progress, Rollout};
+use service_handler::{Drain, ServiceHandler, StopReason};
+use socket_activation::Listeners;
+use state_file::StateFile;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt::Debug;
-use std::ops::Mul;
+use std::fs;
+use std::io::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{Level, Span};
+use zygote::Zygote;
 
+mod adopter;
+pub(crate) mod audit;
+mod balancer;
+mod hooks;
+mod hosts_file;
+mod idle;
+mod masks;
+mod monitor;
+mod output_capture;
+mod policy;
 mod process_spawner;
+mod ready_files;
 mod reaper;
 mod repo;
-mod service_handler;
+pub(crate) mod resource_usage;
+mod rollout;
+pub(crate) mod service_handler;
+mod session;
+pub(crate) mod simulation;
+mod socket_activation;
+mod state_file;
+mod user_namespace;
+#[cfg(all(target_os = "linux", feature = "vfork-spawn"))]
+mod vfork;
+mod wait_for;
+pub(crate) mod zygote;
 
 pub(crate) mod signal_handling;
 
 const MAX_PROCESS_REAPS_ITERS: u32 = 20;
+/// The events applied by the event loop are logged at most this often.
+const EVENTS_LOG_INTERVAL: Duration = Duration::from_secs(1);
+/// The connections of the services using stop-after-idle are checked at most this often.
+const ACTIVITY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait for the pid of a service killed before being spawned, on top of its
+/// termination wait. The spawner might be waiting for its start-delay or for its wait-for.
+const MISSING_PID_GRACE: Duration = Duration::from_secs(5);
+
+/// Set if the bus has died: the runtime cannot communicate anymore so it should bring everything down.
+static BUS_CRASHED: AtomicBool = AtomicBool::new(false);
+
+/// Notifies the runtime that the bus thread has panicked.
+pub(crate) fn bus_crashed() {
+    BUS_CRASHED.store(true, Ordering::SeqCst);
+}
+
+/// How the runtime spawns the processes of the services.
+pub(crate) enum SpawnMode {
+    /// Nothing is actually spawned (`--simulate`).
+    Simulated,
+    /// The sandboxed services are spawned by the zygote, if any. Every command run is recorded
+    /// in the audit log.
+    Processes(Option<Zygote>, AuditLog),
+}
 
 // Spawns and runs this component in a new thread.
-pub fn spawn(
-    bus: BusConnector<Event>,
+// If the runtime panics, every known child is terminated before returning.
+pub(crate) fn spawn(
+    bus: BusConnector,
     services: Vec<Service>,
+    config: HorustConfig,
+    heartbeat: Heartbeat,
+    probes: ProbeRegistry,
+    status_board: StatusBoard,
+    spawn_mode: SpawnMode,
 ) -> std::thread::JoinHandle<ExitStatus> {
-    thread::spawn(move || Runtime::new(bus, services).run())
+    thread::Builder::new()
+        .name("runtime".into())
+        .spawn(move || {
+            let mut runtime = Runtime::new(
+                bus,
+                services,
+                config,
+                heartbeat,
+                probes,
+                status_board,
+                spawn_mode,
+            );
+            match panic::catch_unwind(AssertUnwindSafe(|| runtime.run())) {
+                Ok(exit_status) => exit_status,
+                Err(_panic) => {
+                    error!("Runtime has panicked, terminating all the services.");
+                    runtime.kill_all_children();
+                    ExitStatus::Crashed
+                }
+            }
+        })
+        .expect("Failed spawning the runtime thread")
 }
 
 #[derive(Debug)]
 pub struct Runtime {
     /// The system is shutting down, no more services will be spawned.
     is_shutting_down: bool,
+    /// Don't wait for the services to gracefully exit, kill them right away.
+    is_force_killing: bool,
+    /// Set once shutting down, if there is a shutdown timeout.
+    shutdown_deadline: Option<Instant>,
+    /// Why the shutdown has started: only the first reason is kept.
+    shutdown_reason: Option<ShutdownReason>,
     repo: Repo,
+    config: HorustConfig,
+    heartbeat: Heartbeat,
+    /// Used for preparing the healthchecks, before spawning a service.
+    probes: ProbeRegistry,
+    status_board: StatusBoard,
+    /// Rolling restarts in progress.
+    rollouts: Vec<Rollout>,
+    /// Sockets bound on behalf of the services using socket activation.
+    listeners: Listeners,
+    /// Forwarding the connections to the instances of the services using `balance`.
+    balancers: Balancers,
+    /// Previous instances of blue/green restarts which have been sent the termination signal,
+    /// with the deadline for killing them.
+    retired: Vec<(unistd::Pid, Instant, KillScope)>,
+    /// Waits between the iterations of the event loop.
+    monitor: Monitor,
+    /// A span for every running service, from its spawn to its exit.
+    lifecycles: HashMap<ServiceName, Span>,
+    /// For the events applied at every iteration, which might be a lot of them.
+    events_log: logging::Throttle,
+    /// When the connections of the services using stop-after-idle have been checked last.
+    last_activity_check: Option<Instant>,
+    /// Services masked using horustctl, persisted in the state directory (if any).
+    masked: BTreeSet<ServiceName>,
+    /// Records every command run, if enabled.
+    audit: AuditLog,
+    /// Asked before creating instances at runtime, and before adopting processes.
+    policy: Policy,
+    /// Forks and execs the services, a few at a time.
+    spawn_queue: SpawnQueue,
+    /// Nothing is actually run (`--simulate`): the processes are played by the simulation.
+    is_simulated: bool,
+    /// Kept up to date with the state of the services, if enabled.
+    state_file: Option<StateFile>,
+    /// A marker file for every Running service, if enabled.
+    ready_files: Option<ReadyFiles>,
+    /// The running services by hostname, if enabled.
+    hosts_file: Option<HostsFile>,
 }
 
 impl Runtime {
-    fn new(bus: BusConnector<Event>, services: Vec<Service>) -> Self {
-        let repo = Repo::new(bus, services);
+    fn new(
+        bus: BusConnector,
+        services: Vec<Service>,
+        config: HorustConfig,
+        heartbeat: Heartbeat,
+        probes: ProbeRegistry,
+        status_board: StatusBoard,
+        spawn_mode: SpawnMode,
+    ) -> Self {
+        let (is_simulated, zygote, audit) = match spawn_mode {
+            SpawnMode::Simulated => (true, None, AuditLog::default()),
+            SpawnMode::Processes(zygote, audit) => (false, zygote, audit),
+        };
+        let mut repo = Repo::new(bus, services, config.disabled_dependencies.clone());
+        let masked = match &config.state_directory {
+            Some(state_directory) => masks::load(state_directory).unwrap_or_else(|error| {
+                error!("Cannot load the masked services: {}", error);
+                BTreeSet::new()
+            }),
+            None => BTreeSet::new(),
+        };
+        let state_file = config.state_file.clone().map(StateFile::new);
+        let ready_files = config.ready_directory.clone().map(ReadyFiles::new);
+        let hosts_file = config.hosts_file.clone().map(HostsFile::new);
+        let balancers = Balancers::new(repo.services.values());
+        let policy = Policy::new(config.policy_hook.as_deref(), audit.clone());
+        repo.policy_hooks = policy.running_hooks();
+        let spawn_queue = SpawnQueue::new(
+            config.spawn_parallelism(),
+            config.tick_interval(),
+            repo.bus.publisher(),
+            audit.clone(),
+            zygote,
+            policy.clone(),
+        );
+        for sh in repo.services.values_mut() {
+            // Replicated services are masked as a whole.
+            let masked_name = match &sh.service().replica {
+                Some(replica) => &replica.of,
+                None => sh.name(),
+            };
+            if masked.contains(masked_name) {
+                info!("Service {} is masked.", sh.name());
+                sh.stop_for(StopReason::Masked);
+            }
+        }
         Self {
             repo,
             is_shutting_down: false,
+            is_force_killing: false,
+            shutdown_deadline: None,
+            shutdown_reason: None,
+            config,
+            heartbeat,
+            probes,
+            status_board,
+            rollouts: vec![],
+            listeners: Default::default(),
+            balancers,
+            retired: vec![],
+            monitor: Monitor::new(),
+            lifecycles: HashMap::new(),
+            events_log: logging::Throttle::new(EVENTS_LOG_INTERVAL),
+            last_activity_check: None,
+            masked,
+            audit,
+            policy,
+            spawn_queue,
+            is_simulated,
+            state_file,
+            ready_files,
+            hosts_file,
         }
     }
 
     /// Generates events that, if applied, will make service_handler FSM progress
     fn next(&self, service_handler: &ServiceHandler) -> Vec<Event> {
+        if self.is_drain_over(service_handler) {
+            // Time to actually send the termination signal.
+            return vec![Event::Kill(service_handler.name().clone())];
+        }
         if self.is_shutting_down {
-            next_events_shutting_down(service_handler)
+            next_events_shutting_down(service_handler, self.is_force_killing)
         } else {
             self.next_events(service_handler)
         }
     }
 
+    /// The exec-before hook has either exited or timed out, but the signal hasn't been sent yet.
+    fn is_drain_over(&self, service_handler: &ServiceHandler) -> bool {
+        match &service_handler.drain {
+            Some(drain)
+                if service_handler.is_in_killing()
+                    && service_handler.shutting_down_start.is_none() =>
+            {
+                !self.repo.hooks.contains_key(&drain.pid) || Instant::now() > drain.deadline
+            }
+            _ => false,
+        }
+    }
+
+    /// Runs a hook of the service, unless it's a simulation.
+    fn run_hook(&mut self, hook: &str, service_name: &str, command: &str) -> Option<unistd::Pid> {
+        if self.is_simulated {
+            info!(
+                "Simulation: not running {} of {}: '{}'",
+                hook, service_name, command
+            );
+            return None;
+        }
+        hooks::run_hook(&mut self.repo, &self.audit, hook, service_name, command)
+    }
+
+    /// Runs the exec-before hook of the service, if it has one which hasn't been run yet.
+    /// Returns true if it's running: the signal will be sent once it's over.
+    fn start_drain(&mut self, service_name: &str) -> bool {
+        let service_handler = self.repo.get_sh(service_name);
+        if service_handler.drain.is_some() || service_handler.shutting_down_start.is_some() {
+            return false;
+        }
+        let termination = &service_handler.service().termination;
+        let (command, timeout) = match &termination.exec_before {
+            Some(command) => (command.clone(), termination.exec_before_timeout),
+            None => return false,
+        };
+        match self.run_hook("exec-before", service_name, command.as_str()) {
+            Some(pid) => {
+                self.repo.get_mut_sh(service_name).drain = Some(Drain {
+                    pid,
+                    deadline: Instant::now() + timeout,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
     /// Generate the events needed for moving forward the FSM for the service handler
     /// If the system is shutting down, it will call next_shutting_down.
     fn next_events(&self, service_handler: &ServiceHandler) -> Vec<Event> {
         let ev_status =
             |status: ServiceStatus| Event::new_status_changed(service_handler.name(), status);
         let vev_status = |status: ServiceStatus| vec![ev_status(status)];
+        let kill_evs = || {
+            vec![
+                ev_status(ServiceStatus::InKilling),
+                Event::Kill(service_handler.name().clone()),
+            ]
+        };
+        if service_handler.is_starting_or_up() && !self.repo.are_bindings_running(service_handler) {
+            debug!(
+                "A service bound to {} has stopped, going to stop it.",
+                service_handler.name()
+            );
+            return kill_evs();
+        }
+        match service_handler.stop_reason() {
+            Some(StopReason::Restart) if service_handler.is_starting_or_up() => {
+                return kill_evs();
+            }
+            // Stopped for good (until unmasked or scaled up again).
+            Some(reason @ StopReason::Masked)
+            | Some(reason @ StopReason::Requested)
+            | Some(reason @ StopReason::ScaledDown) => match service_handler.status {
+                ServiceStatus::Starting | ServiceStatus::Started | ServiceStatus::Running => {
+                    return kill_evs();
+                }
+                ServiceStatus::Initial
+                | ServiceStatus::Success
+                | ServiceStatus::Failed
+                | ServiceStatus::Idle => return vev_status(reason.stopped_status()),
+                // Once killed, it gets its status.
+                _ => (),
+            },
+            _ => (),
+        }
+        if [ServiceStatus::Started, ServiceStatus::Running].contains(&service_handler.status)
+            && service_handler.has_exceeded_max_runtime()
+        {
+            info!(
+                "Service {} has exceeded its max-runtime, going to stop it.",
+                service_handler.name()
+            );
+            return kill_evs();
+        }
+        if service_handler.is_running() && service_handler.is_idle() {
+            info!(
+                "Service {} has been idle for {:?}, going to stop it.",
+                service_handler.name(),
+                service_handler
+                    .service()
+                    .stop_after_idle
+                    .unwrap_or_default()
+            );
+            return kill_evs();
+        }
         match service_handler.status {
-            ServiceStatus::Initial if self.repo.is_service_runnable(&service_handler) => {
+            ServiceStatus::Initial if self.repo.is_any_binding_finished(service_handler) => {
+                vev_status(ServiceStatus::Finished)
+            }
+            ServiceStatus::Initial if self.repo.has_disabled_dependency(service_handler) => {
+                logging::service_event(
+                    module_path!(),
+                    Level::WARN,
+                    service_handler.name(),
+                    "disabled-dependency",
+                    &format!(
+                        "Service: {} depends on a disabled service, it won't be started.",
+                        service_handler.name()
+                    ),
+                );
+                vev_status(ServiceStatus::FinishedFailed)
+            }
+            ServiceStatus::Initial
+                if !service_handler.is_run_requested
+                    && self.repo.is_service_runnable(service_handler) =>
+            {
                 vec![Event::Run(service_handler.name().clone())]
             }
-            ServiceStatus::Started if service_handler.healthiness_checks_failed == 0 => {
+            ServiceStatus::Started
+                if service_handler.healthiness_checks_failed == 0
+                    && service_handler.is_log_pattern_satisfied() =>
+            {
                 vev_status(ServiceStatus::Running)
             }
             // If 2 healthcheks are failed, then kill the service. Maybe this should be parametrized
-            ServiceStatus::Running if service_handler.healthiness_checks_failed > 2 => vec![
-                ev_status(ServiceStatus::InKilling),
-                Event::Kill(service_handler.name().clone()),
-            ],
+            ServiceStatus::Running if service_handler.healthiness_checks_failed > 2 => {
+                let mut evs = vec![Event::HealthcheckFailed(
+                    service_handler.name().clone(),
+                    service_handler.last_health_error.clone(),
+                )];
+                evs.extend(kill_evs());
+                evs
+            }
             ServiceStatus::Success => {
                 vec![handle_restart_strategy(service_handler.service(), false)]
             }
             ServiceStatus::Failed => {
-                let mut failure_evs = handle_failed_service(
-                    self.repo.get_dependents(service_handler.name()),
-                    service_handler.service(),
-                );
+                let mut failure_evs = if service_handler
+                    .is_failure_threshold_reached(Instant::now())
+                {
+                    handle_failed_service(
+                        self.repo.get_failure_targets(service_handler.service()),
+                        service_handler.service(),
+                    )
+                } else {
+                    debug!(
+                        "{} hasn't reached its failure threshold yet, its failure strategy doesn't apply.",
+                        service_handler.name()
+                    );
+                    vec![]
+                };
                 let other_services_termination = self
                     .repo
                     .get_die_if_failed(service_handler.name())
@@ -99,38 +446,605 @@ impl Runtime {
                 failure_evs.extend(other_services_termination);
                 failure_evs
             }
-            ServiceStatus::InKilling if should_force_kill(service_handler) => vec![
-                Event::new_force_kill(service_handler.name()),
-                Event::new_status_changed(service_handler.name(), ServiceStatus::Failed),
-            ],
+            ServiceStatus::InKilling if has_pid_never_appeared(service_handler) => {
+                vec![Event::PidNeverAppeared(service_handler.name().clone())]
+            }
+            ServiceStatus::InKilling
+                if should_force_kill(service_handler, self.is_force_killing) =>
+            {
+                // The status is changed once the kill is done, by the ForceKill handler.
+                vec![Event::new_force_kill(service_handler.name())]
+            }
 
             _ => vec![],
         }
     }
 
+    /// Targets have no process to wait for: once asked to stop, they're stopped right away.
+    fn stop_target(&mut self, service_name: &str) -> Vec<Event> {
+        let service_handler = self.repo.get_mut_sh(service_name);
+        if !service_handler.is_in_killing() {
+            return vec![];
+        }
+        let status = service_handler
+            .planned_stop_status()
+            .unwrap_or(ServiceStatus::Success);
+        service_handler.end_restart();
+        service_handler.started_at = None;
+        service_handler.start_time = None;
+        service_handler.status = status.clone();
+        vec![Event::new_status_changed(service_name, status)]
+    }
+
+    /// The service has failed, and it has no more restart attempts left: every path leading to
+    /// FinishedFailed ends here, except the shutdown and the disabled dependencies.
+    fn handle_attempts_exhausted(&mut self, service_name: &str) -> Vec<Event> {
+        if self.is_shutting_down {
+            return vec![];
+        }
+        let action = self
+            .repo
+            .get_sh(service_name)
+            .service()
+            .restart
+            .on_attempts_exhausted
+            .clone();
+        logging::service_event(
+            module_path!(),
+            Level::WARN,
+            service_name,
+            "attempts-exhausted",
+            &format!("Service: {} has no restart attempts left.", service_name),
+        );
+        match action {
+            AttemptsExhaustedAction::Ignore => vec![],
+            AttemptsExhaustedAction::Shutdown => vec![Event::ShuttingDownInitiated(
+                ShutdownReason::AttemptsExhausted(service_name.to_string()),
+            )],
+            AttemptsExhaustedAction::Exec(command) => {
+                self.run_hook("on-attempts-exhausted", service_name, command.as_str());
+                vec![]
+            }
+        }
+    }
+
+    /// The process of the service is gone (or it has never been spawned), and its new status is
+    /// set: once its attempts are exhausted, it either falls back or it's finished failed.
+    fn settle_exit(
+        &mut self,
+        service_name: &str,
+        mut is_exhausted_after_running: bool,
+    ) -> Vec<Event> {
+        let service_handler = self.repo.get_mut_sh(service_name);
+        if service_handler.is_finished_failed() && service_handler.can_fall_back() {
+            logging::service_event(
+                module_path!(),
+                Level::WARN,
+                service_handler.name(),
+                "fallback",
+                &format!(
+                    "Service: {} has no restart attempts left, going to run its \
+                     fallback-command.",
+                    service_handler.name()
+                ),
+            );
+            service_handler.is_fallback = true;
+            service_handler.restart_attempts = 0;
+            service_handler.immediate_exits = 0;
+            service_handler.status = ServiceStatus::Initial;
+            is_exhausted_after_running = false;
+        }
+        let mut evs = vec![Event::new_status_changed(
+            service_name,
+            service_handler.status.clone(),
+        )];
+        let service_handler = &self.repo.services[service_name];
+        if is_exhausted_after_running
+            && service_handler.is_failure_threshold_reached(Instant::now())
+        {
+            // Its failure strategy applies, as it would have if it had just failed.
+            let service = service_handler.service();
+            evs.extend(handle_failed_service(
+                self.repo.get_failure_targets(service),
+                service,
+            ));
+        }
+        if self.repo.get_sh(service_name).is_finished_failed() {
+            evs.extend(self.handle_attempts_exhausted(service_name));
+        }
+        evs
+    }
+
+    /// The process has been reaped after the service had finished (e.g. it was force killed): it's
+    /// not running anymore, but its status stays the same.
+    fn exited_after_finishing(&mut self, service_name: &str, exit_code: i32) -> Vec<Event> {
+        let service_handler = self.repo.get_mut_sh(service_name);
+        debug!(
+            "Service {} has exited with {}, after it had finished: it stays {}.",
+            service_name, exit_code, service_handler.status
+        );
+        service_handler.pid = None;
+        service_handler.started_at = None;
+        service_handler.start_time = None;
+        service_handler.shutting_down_start = None;
+        service_handler.last_active = None;
+        service_handler.drain = None;
+        debug_assert!(service_handler.is_terminal());
+        vec![]
+    }
+
+    /// The process of the service couldn't be spawned: `error` is the underlying error, if it's
+    /// more detailed than the reason.
+    fn spawn_failed(
+        &mut self,
+        service_name: &str,
+        reason: SpawnError,
+        error: Option<String>,
+    ) -> Vec<Event> {
+        if let Some(evs) = self.roll_back_blue_green(service_name) {
+            return evs;
+        }
+        let service_handler = self.repo.get_mut_sh(service_name);
+        *service_handler
+            .spawn_failures
+            .entry(reason.key())
+            .or_default() += 1;
+        if reason == SpawnError::Aborted {
+            service_handler.status = ServiceStatus::Failed;
+            return vec![Event::new_status_changed(
+                service_name,
+                ServiceStatus::Failed,
+            )];
+        }
+        service_handler.last_spawn_error = Some(error.unwrap_or_else(|| reason.to_string()));
+        // Like a process exiting right away, it counts as an attempt.
+        service_handler.restart_attempts += 1;
+        let is_given_up = reason.is_permanent()
+            && !service_handler.service().restart.retry_permanent_errors
+            && !service_handler.restart_attempts_are_over();
+        if is_given_up {
+            logging::service_event(
+                module_path!(),
+                Level::WARN,
+                service_name,
+                "permanent-error",
+                &format!(
+                    "Service: {} cannot be run ({}), not retrying.",
+                    service_name, reason
+                ),
+            );
+        }
+        service_handler.status = if is_given_up {
+            ServiceStatus::PermanentlyFailed
+        } else if service_handler.restart_attempts_are_over() {
+            ServiceStatus::FinishedFailed
+        } else {
+            ServiceStatus::Initial
+        };
+        self.settle_exit(service_name, false)
+    }
+
+    /// Starts or stops instances of the replicated service, until `replicas` of them are left.
+    /// Scaled down instances are kept around (as finished), so scaling up can start them again.
+    fn scale(&mut self, replicated: &str, replicas: u32) -> Vec<Event> {
+        let mut instances: Vec<(u32, ServiceName)> = self
+            .repo
+            .services
+            .values()
+            .filter_map(|sh| match &sh.service().replica {
+                Some(replica) if replica.of == replicated => {
+                    Some((replica.index, sh.name().clone()))
+                }
+                _ => None,
+            })
+            .collect();
+        instances.sort();
+        let template = match instances.first() {
+            Some((_index, name)) => self.repo.get_sh(name).service().clone(),
+            None => {
+                warn!("Service {} has no replicas, cannot scale it.", replicated);
+                return vec![];
+            }
+        };
+        info!("Scaling {} to {} replicas.", replicated, replicas);
+        let is_masked = self.masked.contains(replicated);
+        let mut evs = vec![];
+        for (index, name) in &instances {
+            let service_handler = self.repo.get_mut_sh(name);
+            if *index >= replicas {
+                service_handler.stop_for(StopReason::ScaledDown);
+            } else if service_handler.is_stopping_for(StopReason::ScaledDown) {
+                service_handler.cancel_stop(StopReason::ScaledDown);
+                // Scaling down has overridden the mask.
+                if is_masked {
+                    service_handler.stop_for(StopReason::Masked);
+                }
+                if service_handler.is_finished() {
+                    service_handler.status = ServiceStatus::Initial;
+                    evs.push(Event::new_status_changed(name, ServiceStatus::Initial));
+                }
+            }
+        }
+        let created: Vec<Service> = (instances.len() as u32..replicas)
+            .map(|index| template.new_replica(replicated, index))
+            .collect();
+        // Nothing is run in a simulation.
+        if self.is_simulated || !self.policy.is_configured() {
+            for service in created {
+                evs.extend(self.create(service));
+            }
+        } else if !created.is_empty() {
+            // They're created once the policy hook allows them (CreationDecided).
+            self.policy.ask_create(created, self.repo.bus.publisher());
+        }
+        self.repo.allocate_ports();
+        evs
+    }
+
+    /// Adds a new instance of a replicated service.
+    fn create(&mut self, service: Service) -> Vec<Event> {
+        self.repo
+            .services
+            .insert(service.name.clone(), service.clone().into());
+        self.repo.allocate_ports();
+        vec![Event::ServiceCreated(Box::new(service))]
+    }
+
+    /// The service itself, or all of its instances if it's replicated.
+    fn instances(&self, service_name: &str) -> Vec<ServiceName> {
+        if self.repo.services.contains_key(service_name) {
+            return vec![service_name.to_string()];
+        }
+        let mut instances: Vec<(u32, ServiceName)> = self
+            .repo
+            .services
+            .values()
+            .filter_map(|sh| match &sh.service().replica {
+                Some(replica)
+                    if replica.of == service_name
+                        && !sh.is_stopping_for(StopReason::ScaledDown) =>
+                {
+                    Some((replica.index, sh.name().clone()))
+                }
+                _ => None,
+            })
+            .collect();
+        instances.sort();
+        instances.into_iter().map(|(_index, name)| name).collect()
+    }
+
+    /// Masks (or unmasks) all the instances of the service, and persists it. Masked services are
+    /// stopped, and they're not started again until they're unmasked.
+    fn set_masked(&mut self, service_name: ServiceName, is_masked: bool) -> Vec<Event> {
+        let instances = self.instances(&service_name);
+        info!(
+            "{} {}: {:?}",
+            if is_masked { "Masking" } else { "Unmasking" },
+            service_name,
+            instances
+        );
+        let mut evs = vec![];
+        for name in &instances {
+            let service_handler = self.repo.get_mut_sh(name);
+            if is_masked {
+                service_handler.stop_for(StopReason::Masked);
+            } else {
+                service_handler.cancel_stop(StopReason::Masked);
+            }
+            if !is_masked && service_handler.is_disabled() && !self.is_shutting_down {
+                service_handler.status = ServiceStatus::Initial;
+                evs.push(Event::new_status_changed(name, ServiceStatus::Initial));
+            }
+        }
+        let has_changed = if is_masked {
+            self.masked.insert(service_name)
+        } else {
+            self.masked.remove(&service_name)
+        };
+        if let (true, Some(state_directory)) = (has_changed, &self.config.state_directory) {
+            if let Err(error) = masks::store(state_directory, &self.masked) {
+                error!("Cannot store the masked services: {}", error);
+            }
+        }
+        evs
+    }
+
+    /// Restarts all the instances of the service.
+    fn restart(&mut self, service_name: ServiceName, mode: RestartMode) -> Vec<Event> {
+        let instances = self.instances(&service_name);
+        match mode {
+            RestartMode::Rolling => {
+                if self.rollouts.iter().any(|r| r.service == service_name) {
+                    warn!(
+                        "Rolling restart of {} is already in progress.",
+                        service_name
+                    );
+                    return vec![];
+                }
+                info!("Rolling restart of {}: {:?}", service_name, instances);
+                self.rollouts.push(Rollout::new(service_name, instances));
+            }
+            RestartMode::All => {
+                info!("Restarting {}: {:?}", service_name, instances);
+                instances.iter().for_each(|name| {
+                    let service_handler = self.repo.get_mut_sh(name);
+                    if service_handler.is_starting_or_up() {
+                        service_handler.stop_for(StopReason::Restart);
+                        service_handler.is_fallback = false;
+                    }
+                });
+            }
+            RestartMode::BlueGreen => return self.start_blue_green(&service_name),
+        }
+        vec![]
+    }
+
+    /// Starts a new instance of the service, while the previous one keeps running (and serving
+    /// the shared sockets) until the new one is running.
+    /// The previous instance is tracked as a hook, so the reaper won't consider it as unknown.
+    fn start_blue_green(&mut self, service_name: &str) -> Vec<Event> {
+        let previous = match self.repo.services.get(service_name) {
+            Some(sh)
+                if sh.is_running()
+                    && sh.retiring.is_none()
+                    && sh.service().replica.is_none()
+                    && !sh.service().listen.is_empty() =>
+            {
+                sh.pid
+            }
+            _ => None,
+        };
+        let previous = match previous {
+            Some(pid) if !self.repo.adopted.contains_key(&pid) => pid,
+            _ => {
+                warn!(
+                    "Cannot restart {} blue/green: it should be a running, not replicated \
+                     service, spawned by horust and using socket activation.",
+                    service_name
+                );
+                return vec![];
+            }
+        };
+        info!(
+            "Blue/green restart of {}, previous instance: {}",
+            service_name, previous
+        );
+        self.repo.remove_pid(previous);
+        self.repo
+            .hooks
+            .insert(previous, format!("previous instance of {}", service_name));
+        let service_handler = self.repo.get_mut_sh(service_name);
+        service_handler.retiring = Some(previous);
+        service_handler.is_fallback = false;
+        service_handler.pid = None;
+        service_handler.started_at = None;
+        service_handler.start_time = None;
+        service_handler.last_active = None;
+        service_handler.status = ServiceStatus::Initial;
+        vec![Event::new_status_changed(
+            service_name,
+            ServiceStatus::Initial,
+        )]
+    }
+
+    /// Stops the previous instances of the blue/green restarts, once the new ones are running
+    /// (or right away, if shutting down). They're killed if they outlive termination.wait.
+    fn retire_previous_instances(&mut self) {
+        let now = Instant::now();
+        for sh in self.repo.services.values_mut() {
+            let previous = match sh.retiring {
+                Some(pid) => pid,
+                None => continue,
+            };
+            if !self.repo.hooks.contains_key(&previous) {
+                // It has exited on its own.
+                sh.retiring = None;
+            } else if sh.is_running() || self.is_shutting_down {
+                info!(
+                    "Stopping the previous instance of {}: {}",
+                    sh.name(),
+                    previous
+                );
+                let termination = &sh.service().termination;
+                let signal: signal::Signal = termination.signal.into();
+                let _res = session::signal_all(previous, termination.kill_scope, signal);
+                self.retired
+                    .push((previous, now + termination.wait, termination.kill_scope));
+                sh.retiring = None;
+            }
+        }
+        let hooks = &self.repo.hooks;
+        self.retired.retain(|(pid, deadline, kill_scope)| {
+            if !hooks.contains_key(pid) {
+                return false;
+            }
+            if now > *deadline {
+                warn!(
+                    "Previous instance {} has outlived termination.wait, killing it.",
+                    pid
+                );
+                session::kill_all_detached(*pid, *kill_scope, format!("previous instance {}", pid));
+                return false;
+            }
+            true
+        });
+    }
+
+    /// The new instance of a blue/green restart has exited: the previous one, still running,
+    /// takes its place again.
+    fn roll_back_blue_green(&mut self, service_name: &str) -> Option<Vec<Event>> {
+        let previous = self.repo.get_sh(service_name).retiring?;
+        if self.is_shutting_down || self.repo.hooks.remove(&previous).is_none() {
+            return None;
+        }
+        error!(
+            "The new instance of {} has failed, keeping the previous one: {}",
+            service_name, previous
+        );
+        self.repo.add_pid(previous, service_name.to_string());
+        let service_handler = self.repo.get_mut_sh(service_name);
+        service_handler.retiring = None;
+        service_handler.cancel_stop(StopReason::Restart);
+        service_handler.shutting_down_start = None;
+        service_handler.drain = None;
+        service_handler.pid = Some(previous);
+        service_handler.started_at = Some(Instant::now());
+        service_handler.start_time = Some(SystemTime::now());
+        service_handler.last_active = Some(Instant::now());
+        service_handler.healthiness_checks_failed = 0;
+        service_handler.status = ServiceStatus::Running;
+        // Started is needed for getting the healthchecks going again.
+        Some(vec![
+            Event::new_status_changed(service_name, ServiceStatus::Started),
+            Event::new_status_changed(service_name, ServiceStatus::Running),
+        ])
+    }
+
+    /// Moves the rolling restarts forward, dropping the ones which are over.
+    fn advance_rollouts(&mut self) {
+        let repo = &mut self.repo;
+        self.rollouts
+            .retain_mut(|rollout| match rollout.advance(repo) {
+                Progress::Waiting => true,
+                Progress::Restart(name) => {
+                    info!(
+                        "Rolling restart of {}: restarting {}.",
+                        rollout.service, name
+                    );
+                    let service_handler = repo.get_mut_sh(&name);
+                    service_handler.stop_for(StopReason::Restart);
+                    service_handler.is_fallback = false;
+                    true
+                }
+                Progress::Done => {
+                    info!("Rolling restart of {} is done.", rollout.service);
+                    false
+                }
+                Progress::Aborted(name) => {
+                    error!(
+                        "Rolling restart of {} aborted: {} has failed after being restarted.",
+                        rollout.service, name
+                    );
+                    false
+                }
+            });
+    }
+
+    /// Restarts the dependents opting in for it (restart.on-dependency-recovery) of the services
+    /// which are running again after having failed. The restarted dependents are recovering in
+    /// turn, so theirs follow once they're running: the restarts go in dependency order.
+    fn restart_recovered_dependents(&mut self) {
+        let recovered: Vec<ServiceName> = self
+            .repo
+            .services
+            .iter()
+            .filter(|(_, sh)| sh.is_recovering && sh.is_running())
+            .map(|(s_name, _)| s_name.clone())
+            .collect();
+        for s_name in recovered {
+            self.repo.get_mut_sh(&s_name).is_recovering = false;
+            for dependent in self.repo.get_dependents(&s_name) {
+                let service_handler = self.repo.get_mut_sh(&dependent);
+                if service_handler.service().restart.on_dependency_recovery
+                    && service_handler.is_starting_or_up()
+                    && !service_handler.is_stopping_for(StopReason::Restart)
+                {
+                    info!(
+                        "{} has recovered, restarting its dependent {}.",
+                        s_name, dependent
+                    );
+                    service_handler.stop_for(StopReason::Restart);
+                    service_handler.is_recovering = true;
+                }
+            }
+        }
+    }
+
     /// Handle the events, returns Events (state changes) to be dispatched.
     fn handle_event(&mut self, ev: Event) -> Vec<Event> {
+        let _event_span = trace_span!("event", event = ?ev).entered();
+        if let Event::Run(service_name) = &ev {
+            if let Some(service_handler) = self.repo.services.get_mut(service_name) {
+                service_handler.is_run_requested = false;
+            }
+        }
         match ev {
             Event::ServiceExited(service_name, exit_code) => {
-                let pid = self.repo.get_sh(&service_name).pid.unwrap();
+                let pid = match self.repo.get_sh(&service_name).pid {
+                    Some(pid) => pid,
+                    None => {
+                        // Adopted processes might be notified by both the reaper and the adopter.
+                        debug!("Service {} has already exited, ignoring.", service_name);
+                        return vec![];
+                    }
+                };
                 self.repo.remove_pid(pid);
+                let run = self.repo.get_mut_sh(&service_name).record_run(exit_code);
+                self.repo
+                    .send_ev(Event::RunEnded(service_name.clone(), run));
+                if let Some(lifecycle) = self.lifecycles.remove(&service_name) {
+                    let service_handler = self.repo.get_sh(&service_name);
+                    let uptime = service_handler.started_at.map(|start| start.elapsed());
+                    let since_termination = service_handler
+                        .shutting_down_start
+                        .map(|start| start.elapsed());
+                    lifecycle.in_scope(|| {
+                        debug!(
+                            exit_code,
+                            ?uptime,
+                            ?since_termination,
+                            "Process of {} has exited.",
+                            service_name
+                        )
+                    });
+                }
+                if self.repo.get_sh(&service_name).is_terminal() {
+                    return self.exited_after_finishing(&service_name, exit_code);
+                }
+                if let Some(evs) = self.roll_back_blue_green(&service_name) {
+                    return evs;
+                }
                 let service_handler = self.repo.get_mut_sh(&service_name);
+                let planned_stop_status = service_handler.planned_stop_status();
+                let uptime = service_handler.started_at.map(|start| start.elapsed());
+                service_handler.end_restart();
                 service_handler.shutting_down_start = None;
+                service_handler.drain = None;
                 service_handler.pid = None;
+                service_handler.started_at = None;
+                service_handler.start_time = None;
+                service_handler.last_active = None;
+                let restart_window = service_handler.service().restart.window;
+                let min_runtime = service_handler.service().restart.min_runtime;
+                if let (Some(up_enough), Some(uptime)) = (restart_window.or(min_runtime), uptime) {
+                    // It has been up long enough: the failures before don't count anymore.
+                    if uptime >= up_enough {
+                        service_handler.restart_attempts = 0;
+                    }
+                }
+                let mut is_exhausted_after_running = false;
 
-                let has_failed = !service_handler
-                    .service()
-                    .failure
-                    .successful_exit_code
-                    .contains(&exit_code);
+                let has_exited_too_soon = service_handler.has_exited_too_soon(uptime);
+                let has_failed = has_exited_too_soon
+                    || !service_handler.service().failure.is_successful(exit_code);
                 let healthcheck_failed = service_handler.healthiness_checks_failed > 0
                     && service_handler.status == ServiceStatus::Running;
-                service_handler.status = if has_failed || healthcheck_failed {
+                service_handler.status = if let Some(status) = planned_stop_status {
+                    info!(
+                        "Service: {} has been stopped by horust, exit code: {}",
+                        service_handler.name(),
+                        exit_code
+                    );
+                    status
+                } else if has_failed || healthcheck_failed {
+                    service_handler.is_recovering = true;
+                    service_handler.record_failure(Instant::now());
                     warn!(
-                        "Service: {} has failed, exit code: {}, healthchecks: {}",
+                        "Service: {} has failed, exit code: {}, healthchecks: {}, uptime: {:?}",
                         service_handler.name(),
                         exit_code,
-                        healthcheck_failed
+                        healthcheck_failed,
+                        uptime
                     );
 
                     // If it has failed too quickly, increase service_handler's restart attempts
@@ -140,7 +1054,25 @@ impl Runtime {
                         ServiceStatus::Starting,
                         ServiceStatus::Started,
                     ];
-                    if early_states.contains(&service_handler.status) {
+                    let permanent_failure = service_handler.classify_failure(exit_code, uptime);
+                    if let Some(reason) = permanent_failure {
+                        logging::service_event(
+                            module_path!(),
+                            Level::WARN,
+                            service_handler.name(),
+                            "permanently-failed",
+                            &format!(
+                                "Service: {} has failed permanently ({}), not restarting it.",
+                                service_handler.name(),
+                                reason
+                            ),
+                        );
+                        // Its failure strategy applies, as it would have if it had just failed.
+                        is_exhausted_after_running =
+                            !early_states.contains(&service_handler.status);
+                        ServiceStatus::PermanentlyFailed
+                    } else if early_states.contains(&service_handler.status) || has_exited_too_soon
+                    {
                         service_handler.restart_attempts += 1;
                         if service_handler.restart_attempts_are_over() {
                             //Game over!
@@ -148,6 +1080,15 @@ impl Runtime {
                         } else {
                             ServiceStatus::Initial
                         }
+                    } else if restart_window.is_some() {
+                        // Within the restart window, every failure counts as an attempt.
+                        service_handler.restart_attempts += 1;
+                        if service_handler.restart_attempts_are_over() {
+                            is_exhausted_after_running = true;
+                            ServiceStatus::FinishedFailed
+                        } else {
+                            ServiceStatus::Failed
+                        }
                     } else {
                         // If wasn't starting, then it's just failed in a usual way:
                         ServiceStatus::Failed
@@ -158,20 +1099,36 @@ impl Runtime {
                         service_handler.name(),
                         exit_code
                     );
+                    service_handler.immediate_exits = 0;
                     ServiceStatus::Success
                 };
                 debug!("New state for exited service: {:?}", service_handler.status);
-                vec![Event::StatusChanged(
-                    service_name.clone(),
-                    service_handler.status.clone(),
-                )]
+                self.settle_exit(&service_name, is_exhausted_after_running)
+            }
+            Event::Run(service_name)
+                if self.repo.get_sh(&service_name).is_initial()
+                    && self.repo.get_sh(&service_name).service().target =>
+            {
+                // Nothing to spawn: it's up as soon as it can be started.
+                let service_handler = self.repo.get_mut_sh(&service_name);
+                service_handler.status = ServiceStatus::Running;
+                service_handler.healthiness_checks_failed = 0;
+                service_handler.started_at = Some(Instant::now());
+                service_handler.start_time = Some(SystemTime::now());
+                service_handler.starts += 1;
+                vec![Event::StatusChanged(service_name, ServiceStatus::Running)]
             }
             Event::Run(service_name) if self.repo.get_sh(&service_name).is_initial() => {
-                let mut evs = vec![];
+                let mut evs = vec![Event::StartRequested(service_name.clone())];
                 let service_handler = self.repo.get_mut_sh(&service_name);
                 evs.push(Event::StatusChanged(service_name, ServiceStatus::Starting));
                 service_handler.status = ServiceStatus::Starting;
-                let res = healthcheck::prepare_service(&service_handler.service().healthiness);
+                service_handler.log_pattern_matched = false;
+                if self.is_simulated {
+                    // The simulation spawns it, once StartRequested is received.
+                    return evs;
+                }
+                let res = healthcheck::prepare_service(&self.probes, service_handler.service());
                 if res.is_err() {
                     //TODO: maybe this is a bit too aggressive.
                     error!(
@@ -180,33 +1137,83 @@ impl Runtime {
                     );
                     service_handler.status = ServiceStatus::FinishedFailed;
                     self.is_shutting_down = true;
+                    self.spawn_queue.shut_down();
                     return vec![
                         Event::StatusChanged(
                             service_handler.name().clone(),
                             ServiceStatus::FinishedFailed,
                         ),
-                        Event::ShuttingDownInitiated,
+                        Event::ShuttingDownInitiated(ShutdownReason::HealthchecksNotReady(
+                            service_handler.name().clone(),
+                        )),
                     ];
                 }
-                let backoff = service_handler
-                    .service()
-                    .restart
-                    .backoff
-                    .mul(service_handler.restart_attempts);
-                process_spawner::spawn_fork_exec_handler(
-                    service_handler.service().clone(),
-                    backoff,
-                    self.repo.bus.clone(),
-                );
+                if service_handler.service().port_range.is_some() && service_handler.port.is_none()
+                {
+                    error!(
+                        "Service {} has no port: its port-range is exhausted.",
+                        service_handler.name()
+                    );
+                    service_handler.status = ServiceStatus::Failed;
+                    return vec![Event::new_status_changed(
+                        service_handler.name(),
+                        ServiceStatus::Failed,
+                    )];
+                }
+                let service = service_handler.spawned_service();
+                let listen_fds = match self.listeners.get_or_bind(&service) {
+                    Ok(listen_fds) => listen_fds,
+                    Err(error) => {
+                        error!(
+                            "Cannot bind the sockets of service {}: {}",
+                            service_handler.name(),
+                            error
+                        );
+                        service_handler.status = ServiceStatus::Failed;
+                        return vec![Event::new_status_changed(
+                            service_handler.name(),
+                            ServiceStatus::Failed,
+                        )];
+                    }
+                };
+                let backoff = service_handler.restart_backoff();
+                self.spawn_queue.push(service, backoff, listen_fds);
                 evs
             }
-            Event::SpawnFailed(s_name) => {
-                let service_handler = self.repo.get_mut_sh(&s_name);
-                service_handler.status = ServiceStatus::Failed;
-                vec![Event::StatusChanged(s_name, ServiceStatus::Failed)]
+            Event::SpawnFailed(s_name, reason) => self.spawn_failed(&s_name, reason, None),
+            Event::ExecFailed(s_name, errno) => {
+                let error = Errno::from_i32(errno).to_string();
+                let reason = SpawnError::from_errno(errno)
+                    .unwrap_or_else(|| SpawnError::Other(error.clone()));
+                self.spawn_failed(&s_name, reason, Some(error))
+            }
+            Event::Kill(service_name) | Event::ForceKill(service_name)
+                if self.repo.get_sh(&service_name).is_terminal() =>
+            {
+                // E.g. it has exited on its own while being stopped.
+                debug!(
+                    "Service {} has already finished ({}), nothing to kill.",
+                    service_name,
+                    self.repo.get_sh(&service_name).status
+                );
+                vec![]
+            }
+            Event::Kill(service_name) if self.repo.get_sh(&service_name).service().target => {
+                self.stop_target(&service_name)
             }
             Event::Kill(service_name) => {
                 debug!("Received kill request");
+                if self.repo.get_sh(&service_name).is_in_killing()
+                    && self.start_drain(&service_name)
+                {
+                    return vec![];
+                }
+                if let Some(drain) = self.repo.get_sh(&service_name).drain.clone() {
+                    if self.repo.hooks.contains_key(&drain.pid) {
+                        warn!("exec-before of {} has timed out, killing it.", service_name);
+                        let _res = signal::kill(drain.pid, signal::SIGKILL);
+                    }
+                }
                 let service_handler = self.repo.get_mut_sh(&service_name);
                 if service_handler.is_in_killing() {
                     service_handler.shutting_down_started();
@@ -219,18 +1226,56 @@ impl Runtime {
                 }
                 vec![]
             }
+            Event::PidNeverAppeared(service_name)
+                if has_pid_never_appeared(self.repo.get_sh(&service_name)) =>
+            {
+                logging::service_event(
+                    module_path!(),
+                    Level::WARN,
+                    &service_name,
+                    "pid-never-appeared",
+                    &format!(
+                        "Service: {} was killed before being spawned, and its process hasn't appeared \
+                         within termination wait + {:?}. Giving up on it.",
+                        service_name, MISSING_PID_GRACE
+                    ),
+                );
+                self.repo.get_mut_sh(&service_name).status = ServiceStatus::FinishedFailed;
+                let mut evs = vec![Event::new_status_changed(
+                    &service_name,
+                    ServiceStatus::FinishedFailed,
+                )];
+                evs.extend(self.handle_attempts_exhausted(&service_name));
+                evs
+            }
             Event::ForceKill(service_name) if self.repo.get_sh(&service_name).is_in_killing() => {
                 debug!("Going to forcekill {}", service_name);
                 let service_handler = self.repo.get_mut_sh(&service_name);
                 kill(&service_handler, Some(signal::SIGKILL));
-                service_handler.status = ServiceStatus::Failed;
+                // Its children might still be around (e.g. keeping a port bound).
+                if let Some(pid) = service_handler.pid {
+                    let kill_scope = service_handler.service().termination.kill_scope;
+                    session::kill_all_detached(
+                        pid,
+                        kill_scope,
+                        format!("service {}", service_name),
+                    );
+                }
+                service_handler.status = service_handler
+                    .planned_stop_status()
+                    .unwrap_or(ServiceStatus::Failed);
+                service_handler.end_restart();
                 vec![Event::new_status_changed(
                     service_handler.name(),
-                    ServiceStatus::Failed,
+                    service_handler.status.clone(),
                 )]
             }
             Event::PidChanged(service_name, pid) => {
                 self.repo.add_pid(pid, service_name.clone());
+                self.lifecycles.insert(
+                    service_name.clone(),
+                    info_span!("service", name = %service_name, pid = %pid),
+                );
 
                 let service_handler = self.repo.get_mut_sh(&service_name);
                 service_handler.pid = Some(pid);
@@ -240,32 +1285,137 @@ impl Runtime {
                     kill(service_handler, None)
                 } else {
                     service_handler.status = ServiceStatus::Started;
+                    service_handler.started_at = Some(Instant::now());
+                    service_handler.start_time = Some(SystemTime::now());
+                    service_handler.starts += 1;
+                    service_handler.last_active = Some(Instant::now());
                     return vec![Event::StatusChanged(service_name, ServiceStatus::Started)];
                 }
 
                 vec![]
             }
-            Event::HealthCheck(s_name, health) => {
+            Event::PidAdopted(service_name, pid) => {
+                self.repo.adopted.insert(pid, adopter::open_pidfd(pid));
+                self.handle_event(Event::new_pid_changed(service_name, pid))
+            }
+            Event::HealthCheck(s_name, report) => {
                 let sh = self.repo.get_mut_sh(&s_name);
                 // Count the failed healthiness checks. The state change producer wll handle states
-                // changes (if they're needed)
-                if vec![
-                    ServiceStatus::Running,
-                    ServiceStatus::Started,
-                    ServiceStatus::Starting,
-                ]
-                .contains(&sh.status)
+                // changes (if they're needed). Once shutting down, the late results are ignored:
+                // they would race with the termination.
+                if !self.is_shutting_down
+                    && [
+                        ServiceStatus::Running,
+                        ServiceStatus::Started,
+                        ServiceStatus::Starting,
+                    ]
+                    .contains(&sh.status)
                 {
-                    if let HealthinessStatus::Healthy = health {
+                    if let HealthinessStatus::Healthy = report.status {
                         sh.healthiness_checks_failed = 0;
                     } else {
                         sh.healthiness_checks_failed += 1;
+                        sh.last_health_error = report.error.clone();
                     }
+                    sh.last_health_check = Some(report);
                 };
                 vec![]
             }
-            Event::ShuttingDownInitiated => {
+            Event::HealthcheckFailed(s_name, _error) => {
+                self.repo.get_mut_sh(&s_name).healthcheck_failures += 1;
+                vec![]
+            }
+            Event::FaultInjected(s_name, fault) => {
+                warn!("Chaos: injected a fault in {}: {}", s_name, fault);
+                vec![]
+            }
+            Event::LogPatternMatched(s_name) => {
+                let sh = self.repo.get_mut_sh(&s_name);
+                if sh.is_starting_or_up() {
+                    debug!("Service: {} has matched its log-pattern.", s_name);
+                    sh.log_pattern_matched = true;
+                }
+                vec![]
+            }
+            Event::Activate(service_name) => {
+                let service_handler = self.repo.get_mut_sh(&service_name);
+                if service_handler.is_idle_stopped() && !self.is_shutting_down {
+                    info!("Service {} has been activated.", service_name);
+                    service_handler.status = ServiceStatus::Initial;
+                    vec![Event::StatusChanged(service_name, ServiceStatus::Initial)]
+                } else {
+                    debug!(
+                        "Service {} is not idle, ignoring the activation: {}",
+                        service_name, service_handler.status
+                    );
+                    vec![]
+                }
+            }
+            Event::Scale(service_name, replicas) if !self.is_shutting_down => {
+                self.scale(&service_name, replicas)
+            }
+            Event::CreationDecided(service, decision) => match decision {
+                // Two scalings in a row might ask for the same instance.
+                Ok(())
+                    if !self.is_shutting_down
+                        && !self.repo.services.contains_key(&service.name) =>
+                {
+                    self.create(*service)
+                }
+                Ok(()) => vec![],
+                Err(reason) => {
+                    warn!(
+                        "Cannot create {}, it has been denied: {}",
+                        service.name, reason
+                    );
+                    vec![]
+                }
+            },
+            Event::Restart(service_name, mode) if !self.is_shutting_down => {
+                self.restart(service_name, mode)
+            }
+            Event::Stop(service_name) => {
+                let instances = self.instances(&service_name);
+                info!("Stopping {}: {:?}", service_name, instances);
+                instances
+                    .iter()
+                    .for_each(|name| self.repo.get_mut_sh(name).stop_for(StopReason::Requested));
+                vec![]
+            }
+            Event::RequestSnapshot => {
+                let mut statuses: Vec<(ServiceName, ServiceStatus)> = self
+                    .repo
+                    .services
+                    .iter()
+                    .map(|(s_name, sh)| (s_name.clone(), sh.status.clone()))
+                    .collect();
+                statuses.sort_by(|a, b| a.0.cmp(&b.0));
+                vec![Event::StateSnapshot(statuses)]
+            }
+            Event::Mask(service_name) => self.set_masked(service_name, true),
+            Event::Unmask(service_name) => self.set_masked(service_name, false),
+            Event::ShuttingDownInitiated(reason) => {
+                if self.shutdown_reason.is_none() {
+                    match reason.service() {
+                        Some(service) => logging::service_event(
+                            module_path!(),
+                            Level::WARN,
+                            service,
+                            "shutdown",
+                            &format!("Shutting down: {}.", reason),
+                        ),
+                        None => info!("Shutting down: {}.", reason),
+                    }
+                    self.shutdown_reason = Some(reason);
+                }
+                if !self.is_shutting_down {
+                    self.shutdown_deadline = self
+                        .config
+                        .shutdown_timeout
+                        .map(|timeout| Instant::now() + timeout);
+                }
                 self.is_shutting_down = true;
+                self.spawn_queue.shut_down();
                 vec![]
             }
             ev => {
@@ -275,46 +1425,178 @@ impl Runtime {
         }
     }
 
+    /// The events produced by next() are applied right away: they're delivered back by the bus
+    /// only later, and they mustn't be produced again in the meanwhile.
+    /// Returns the events following from them, e.g. the action of a service finished failed.
+    fn apply_next_events(&mut self, next_evs: &[Event]) -> Vec<Event> {
+        let mut evs = vec![];
+        for ev in next_evs {
+            match ev {
+                Event::StatusChanged(s_name, new_status) => {
+                    // Stopped by next_events because a service it's bound to has stopped.
+                    let sh = &self.repo.services[s_name];
+                    let is_unbound = *new_status == ServiceStatus::InKilling
+                        && sh.is_starting_or_up()
+                        && !self.repo.are_bindings_running(sh);
+                    // E.g. failed with the `never` restart strategy, or killed by a healthcheck.
+                    let is_exhausted = *new_status == ServiceStatus::FinishedFailed
+                        && !sh.is_finished_failed()
+                        && !self.repo.has_disabled_dependency(sh);
+                    let mut new_sh = handle_status_changed_event(
+                        self.repo.services.remove(s_name).unwrap(),
+                        new_status,
+                    );
+                    if is_unbound {
+                        new_sh.stop_for(StopReason::Binding);
+                    }
+                    let is_applied = new_sh.status == *new_status;
+                    self.repo.services.insert(s_name.clone(), new_sh);
+                    if is_exhausted && is_applied {
+                        evs.extend(self.handle_attempts_exhausted(s_name));
+                    }
+                }
+                // The service is still Initial until the Run is handled: if the bus is slow, it
+                // would be spawned twice.
+                Event::Run(s_name) => self.repo.get_mut_sh(s_name).is_run_requested = true,
+                _ => (),
+            }
+        }
+        evs
+    }
+
+    /// Checks whether the running services using stop-after-idle are serving any connection.
+    /// It reads the whole /proc, so it's done at most every ACTIVITY_CHECK_INTERVAL.
+    fn update_activity(&mut self) {
+        let now = Instant::now();
+        if self
+            .last_activity_check
+            .is_some_and(|last| now.duration_since(last) < ACTIVITY_CHECK_INTERVAL)
+        {
+            return;
+        }
+        self.last_activity_check = Some(now);
+        let pids: Vec<unistd::Pid> = self
+            .repo
+            .services
+            .values()
+            .filter(|sh| sh.is_running() && sh.service().stop_after_idle.is_some())
+            .filter_map(|sh| sh.pid)
+            .collect();
+        if pids.is_empty() {
+            return;
+        }
+        let active = idle::active_services(&pids);
+        self.repo
+            .services
+            .values_mut()
+            .filter(|sh| sh.pid.is_some_and(|pid| active.contains(&pid)))
+            .for_each(|sh| sh.last_active = Some(now));
+    }
+
+    /// Activates the idle services which have a connection waiting on their sockets: it's
+    /// accepted once they're running again.
+    fn activate_on_connection(&mut self) {
+        if self.is_shutting_down {
+            return;
+        }
+        let listeners = &self.listeners;
+        let activated: Vec<Event> = self
+            .repo
+            .services
+            .values()
+            .filter(|sh| sh.is_idle_stopped() && listeners.has_pending_connection(sh.service()))
+            .map(|sh| Event::Activate(sh.name().clone()))
+            .collect();
+        activated.into_iter().for_each(|ev| self.repo.send_ev(ev));
+    }
+
     /// Blocking call.
     /// This function will run the services and reap dead pids.
-    fn run(mut self) -> ExitStatus {
-        while !self.repo.all_have_finished() {
+    fn run(&mut self) -> ExitStatus {
+        while !self.repo.all_have_finished() || !self.retired.is_empty() {
+            self.heartbeat.beat();
+            if BUS_CRASHED.load(Ordering::SeqCst) {
+                error!("Bus has crashed, terminating all the services.");
+                self.kill_all_children();
+                return self.exit(ExitStatus::Crashed);
+            }
+            if let Some(deadline) = self.shutdown_deadline {
+                if Instant::now() > deadline {
+                    error!(
+                        "Shutdown timeout ({:?}) has expired, killing all the services.",
+                        self.config.shutdown_timeout.unwrap_or_default()
+                    );
+                    self.force_kill_all_children();
+                    return self.exit(ExitStatus::ShutdownTimedOut);
+                }
+            }
+            self.update_activity();
+            self.activate_on_connection();
+            self.retire_previous_instances();
+            if !self.is_shutting_down {
+                self.advance_rollouts();
+                self.restart_recovered_dependents();
+            }
             // Ingest updates
-            let events = self.repo.get_events();
-            debug!("Applying events... {:?}", events);
+            let received = self.repo.get_events();
+            let received_count = received.len();
+            let events = coalesce_status_changes(received);
+            if signal_handling::is_state_dump_requested() {
+                self.dump_state(&events);
+            }
+            if let Some(not_logged) = self.events_log.check() {
+                debug!(
+                    "Applying events... {:?} (coalesced: {}, iterations not logged: {})",
+                    events,
+                    received_count - events.len(),
+                    not_logged
+                );
+            }
             if signal_handling::is_sigterm_received() && !self.is_shutting_down {
-                self.repo.send_ev(Event::ShuttingDownInitiated);
+                let signal = signal_handling::termination_signal();
+                self.repo
+                    .send_ev(Event::ShuttingDownInitiated(ShutdownReason::Signal(signal)));
+            }
+            if signal_handling::is_force_kill_requested() && !self.is_force_killing {
+                warn!("SIGINT received again, going to force kill all the services.");
+                self.is_force_killing = true;
             }
             let produced_evs: Vec<Event> = events
                 .into_iter()
                 .map(|ev| self.handle_event(ev))
                 .flatten()
                 .collect();
+            let adopted_evs = adopter::run(&mut self.repo);
+            let reaped_evs = reaper::run(
+                &mut self.repo,
+                MAX_PROCESS_REAPS_ITERS,
+                &self.config.unknown_children(),
+            );
             let next_evs: Vec<Event> = self
                 .repo
-                .services
-                .iter()
-                .map(|(_s_name, sh)| self.next(sh))
-                .flatten()
-                .chain(reaper::run(&self.repo, MAX_PROCESS_REAPS_ITERS))
+                .by_start_priority()
+                .into_iter()
+                .flat_map(|sh| self.next(sh))
+                .chain(adopted_evs)
+                .chain(reaped_evs)
                 .collect();
-            next_evs.iter().for_each(|ev| {
-                if let Event::StatusChanged(s_name, new_status) = ev {
-                    let new_sh = handle_status_changed_event(
-                        self.repo.services.remove(s_name).unwrap(),
-                        new_status,
-                    );
-                    self.repo.services.insert(s_name.clone(), new_sh);
-                }
-            });
+            let exhausted_evs = self.apply_next_events(&next_evs);
             produced_evs
                 .into_iter()
                 .chain(next_evs)
+                .chain(exhausted_evs)
                 .for_each(|ev| self.repo.send_ev(ev));
-            std::thread::sleep(Duration::from_millis(300));
+            self.publish_status();
+            self.monitor.wait(self.config.tick_interval());
         }
 
         debug!("All services have finished");
+        if self.repo.unknown_children_reaped > 0 {
+            info!(
+                "Reaped {} processes which didn't belong to any service.",
+                self.repo.unknown_children_reaped
+            );
+        }
         // If we're the init system, let's be sure that everything stops before exiting.
         let init_pid = unistd::Pid::from_raw(1);
         // TODO: Test (probably via docker).
@@ -325,13 +1607,235 @@ impl Runtime {
             let _res = signal::kill(all_processes, signal::SIGKILL);
         }
 
-        self.repo.send_ev(Event::ShuttingDownInitiated);
-        if self.repo.any_finished_failed() {
-            ExitStatus::SomeServiceFailed
+        let reason = self
+            .shutdown_reason
+            .get_or_insert(ShutdownReason::ServicesFinished)
+            .clone();
+        let is_stalled = matches!(reason, ShutdownReason::ComponentStalled(_));
+        self.repo.send_ev(Event::ShuttingDownInitiated(reason));
+        if is_stalled {
+            self.exit(ExitStatus::Crashed)
+        } else if self.repo.any_finished_failed() {
+            self.exit(ExitStatus::SomeServiceFailed)
+        } else {
+            self.exit(ExitStatus::Successful)
+        }
+    }
+
+    /// The final report: how horust is exiting, and why.
+    fn exit(&self, exit_status: ExitStatus) -> ExitStatus {
+        let reason = self
+            .shutdown_reason
+            .as_ref()
+            .map_or_else(|| "none".to_string(), ToString::to_string);
+        let failed: Vec<&ServiceName> = self
+            .repo
+            .services
+            .values()
+            .filter(|sh| sh.is_finished_failed())
+            .map(|sh| sh.name())
+            .collect();
+        let message = format!(
+            "Exiting: {:?}, shutdown reason: {}, failed services: {:?}",
+            exit_status, reason, failed
+        );
+        if exit_status == ExitStatus::Successful {
+            info!("{}", message);
         } else {
-            ExitStatus::Successful
+            error!("{}", message);
+        }
+        if let Some(ready_files) = &self.ready_files {
+            ready_files.remove_all();
+        }
+        if let Some(hosts_file) = &self.hosts_file {
+            hosts_file.clear();
+        }
+        for sh in self.repo.services.values().filter(|sh| sh.starts > 0) {
+            let usage = &sh.resource_usage;
+            info!(
+                "Service '{}': starts: {}, user time: {:?}, system time: {:?}, max RSS: {} bytes, \
+                 page faults: {} minor, {} major",
+                sh.name(),
+                sh.starts,
+                usage.user_time,
+                usage.system_time,
+                usage.max_rss,
+                usage.minor_faults,
+                usage.major_faults
+            );
+        }
+        exit_status
+    }
+
+    /// Publishes the state of the services and of the runtime, for the control socket.
+    fn publish_status(&mut self) {
+        let (services, runtime) = self.snapshot();
+        if let Some(state_file) = &mut self.state_file {
+            state_file.update(&services, &runtime);
+        }
+        if let Some(ready_files) = &mut self.ready_files {
+            ready_files.update(&services);
         }
+        if let Some(hosts_file) = &mut self.hosts_file {
+            hosts_file.update(self.repo.services.values());
+        }
+        self.balancers.update(self.repo.services.values());
+        self.status_board.publish(services, runtime);
+    }
+
+    /// State of the services and of the runtime.
+    fn snapshot(&self) -> (Vec<ServiceSnapshot>, RuntimeStats) {
+        let services = self
+            .repo
+            .services
+            .values()
+            .map(|sh| {
+                let mut snapshot = sh.snapshot();
+                if sh.is_initial() {
+                    snapshot.unsatisfied_dependencies = self.repo.unsatisfied_dependencies(sh);
+                }
+                snapshot
+            })
+            .collect();
+        let runtime = RuntimeStats {
+            is_shutting_down: self.is_shutting_down,
+            shutdown_reason: self.shutdown_reason.as_ref().map(ToString::to_string),
+            bus_queue: self.repo.bus.bus_queue_len(),
+            runtime_queue: self.repo.bus.queue_len(),
+            tracked_pids: self.repo.pid_map.len(),
+            adopted_pids: self.repo.adopted.len(),
+            running_hooks: self.repo.hooks.len(),
+            unknown_children_reaped: self.repo.unknown_children_reaped,
+            rollouts: self.rollouts.len(),
+            retired_instances: self.retired.len(),
+        };
+        (services, runtime)
     }
+
+    /// Writes the whole state (SIGQUIT), including the events which are about to be applied.
+    /// Horust keeps running.
+    fn dump_state(&self, pending_events: &[Event]) {
+        let (mut services, runtime) = self.snapshot();
+        services.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut dump = format!(
+            "=== horust state dump, {} ===\n",
+            humantime::format_rfc3339_millis(std::time::SystemTime::now())
+        );
+        dump.push_str(&format_state(&runtime, &services));
+        for ev in pending_events {
+            dump.push_str(&format!("pending-event {:?}\n", ev));
+        }
+        let res = match &self.config.state_dump_path {
+            Some(path) => fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| file.write_all(dump.as_bytes())),
+            None => std::io::stderr().write_all(dump.as_bytes()),
+        };
+        if let Err(error) = res {
+            error!("Cannot dump the state: {}", error);
+        }
+    }
+
+    /// Last resort termination, used if a core component has crashed: the state of the services
+    /// cannot be trusted anymore, so it doesn't rely on events.
+    /// Sends the termination signal to every known process and waits up to the greatest
+    /// termination.wait, until their kill-scopes are empty. Then SIGKILLs whatever is left, and
+    /// reaps it.
+    fn kill_all_children(&mut self) {
+        let mut wait = Duration::from_secs(0);
+        for s_name in self.repo.pid_map.values() {
+            if let Some(sh) = self.repo.services.get(s_name) {
+                kill(sh, None);
+                wait = wait.max(sh.service().termination.wait);
+            }
+        }
+        // Collected upfront: the processes are removed from the pid map once reaped, but what's
+        // left of their scope must still be checked.
+        let scopes = self.kill_scopes();
+        let deadline = Instant::now() + wait;
+        loop {
+            reaper::run(
+                &mut self.repo,
+                MAX_PROCESS_REAPS_ITERS,
+                &self.config.unknown_children(),
+            );
+            let any_alive = scopes
+                .iter()
+                .any(|(pid, scope)| !session::scope_members(*pid, *scope).is_empty());
+            if !any_alive || Instant::now() > deadline {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        self.force_kill_scopes(&scopes);
+    }
+
+    /// SIGKILLs every known process which is still alive (with its kill-scope), and reaps it.
+    fn force_kill_all_children(&mut self) {
+        let scopes = self.kill_scopes();
+        self.force_kill_scopes(&scopes);
+    }
+
+    /// The main process of every known service, with its kill-scope.
+    fn kill_scopes(&self) -> Vec<(unistd::Pid, KillScope)> {
+        self.repo
+            .pid_map
+            .iter()
+            .map(|(pid, s_name)| {
+                let scope = self
+                    .repo
+                    .services
+                    .get(s_name)
+                    .map(|sh| sh.service().termination.kill_scope)
+                    .unwrap_or_default();
+                (*pid, scope)
+            })
+            .collect()
+    }
+
+    fn force_kill_scopes(&mut self, scopes: &[(unistd::Pid, KillScope)]) {
+        for (pid, scope) in scopes {
+            let alive = session::scope_members(*pid, *scope);
+            if alive.is_empty() {
+                continue;
+            }
+            warn!("Processes {:?} are still alive, sending SIGKILL.", alive);
+            let stragglers = session::kill_all(*pid, *scope);
+            if !stragglers.is_empty() {
+                error!("Processes have survived SIGKILL: {:?}", stragglers);
+            }
+        }
+        let is_alive = |pid: &nix::unistd::Pid| signal::kill(*pid, None).is_ok();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while self.repo.pid_map.keys().any(is_alive) && Instant::now() < deadline {
+            reaper::run(
+                &mut self.repo,
+                MAX_PROCESS_REAPS_ITERS,
+                &self.config.unknown_children(),
+            );
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+/// Drops the StatusChanged events which are identical to the previous StatusChanged of the same
+/// service, as long as there are only StatusChanged events in between: they'd have no effect.
+fn coalesce_status_changes(events: Vec<Event>) -> Vec<Event> {
+    let mut last_statuses: HashMap<ServiceName, ServiceStatus> = HashMap::new();
+    events
+        .into_iter()
+        .filter(|ev| match ev {
+            Event::StatusChanged(s_name, status) => {
+                last_statuses.insert(s_name.clone(), status.clone()) != Some(status.clone())
+            }
+            _ => {
+                last_statuses.clear();
+                true
+            }
+        })
+        .collect()
 }
 
 // TODO: test
@@ -342,14 +1846,15 @@ fn handle_status_changed_event(
 ) -> ServiceHandler {
     // A -> [B,C] means that transition to A is allowed only if service is in state B or C.
     let allowed_transitions = hashmap! {
-        ServiceStatus::Initial        => vec![ServiceStatus::Success, ServiceStatus::Failed],
+        ServiceStatus::Initial        => vec![ServiceStatus::Success, ServiceStatus::Failed, ServiceStatus::Idle, ServiceStatus::Disabled],
         ServiceStatus::Started        => vec![ServiceStatus::Starting],
         ServiceStatus::InKilling      => vec![ServiceStatus::Initial,
                                               ServiceStatus::Running,
                                               ServiceStatus::Starting,
                                               ServiceStatus::Started],
         ServiceStatus::Running        => vec![ServiceStatus::Started],
-        ServiceStatus::FinishedFailed => vec![ServiceStatus::Failed, ServiceStatus::InKilling],
+        ServiceStatus::FinishedFailed => vec![ServiceStatus::Failed, ServiceStatus::InKilling, ServiceStatus::Initial],
+        ServiceStatus::PermanentlyFailed => vec![ServiceStatus::Failed, ServiceStatus::InKilling, ServiceStatus::Initial],
         ServiceStatus::Success        => vec![ServiceStatus::Starting,
                                               ServiceStatus::Started,
                                               ServiceStatus::Running,
@@ -359,7 +1864,17 @@ fn handle_status_changed_event(
                                               ServiceStatus::Running,
                                               ServiceStatus::InKilling],
         ServiceStatus::Finished       => vec![ServiceStatus::Success,
-                                             ServiceStatus::Initial],
+                                             ServiceStatus::Initial,
+                                             ServiceStatus::Idle,
+                                             ServiceStatus::Disabled,
+                                             ServiceStatus::Failed],
+        ServiceStatus::Idle           => vec![ServiceStatus::InKilling,
+                                              ServiceStatus::Success,
+                                              ServiceStatus::Failed],
+        ServiceStatus::Disabled       => vec![ServiceStatus::Initial,
+                                              ServiceStatus::Success,
+                                              ServiceStatus::Failed,
+                                              ServiceStatus::Idle],
     };
     let allowed = allowed_transitions.get(&new_status).unwrap();
     let mut new_sh = service_handler.clone();
@@ -367,11 +1882,21 @@ fn handle_status_changed_event(
         match new_status {
             ServiceStatus::Started if allowed.contains(&service_handler.status) => {
                 new_sh.status = ServiceStatus::Started;
-                new_sh.restart_attempts = 0;
+                // Otherwise they're reset once the process exits, if it was up for the window
+                // (or for the min-runtime).
+                let restart = &new_sh.service().restart;
+                if restart.window.is_none() && restart.min_runtime.is_none() {
+                    new_sh.restart_attempts = 0;
+                }
             }
             ServiceStatus::Running if allowed.contains(&service_handler.status) => {
                 new_sh.status = ServiceStatus::Running;
                 new_sh.healthiness_checks_failed = 0;
+                if !new_sh.has_been_running && new_sh.service().restart.startup_retries.is_some() {
+                    // The startup retries don't count against the attempts.
+                    new_sh.restart_attempts = 0;
+                }
+                new_sh.has_been_running = true;
             }
             ServiceStatus::InKilling if allowed.contains(&service_handler.status) => {
                 debug!(
@@ -390,6 +1915,19 @@ fn handle_status_changed_event(
                 new_sh.status = new_status.clone();
             }
         }
+        logging::service_event(
+            module_path!(),
+            Level::DEBUG,
+            service_handler.name(),
+            "status-changed",
+            &format!(
+                "Service: {} {:?}, status: {} -> {}",
+                service_handler.name(),
+                service_handler.service().labels,
+                service_handler.status,
+                new_sh.status
+            ),
+        );
     } else {
         debug!(
             "Tried to make an illegal transition: (current) {} -> {} (received) for service: {}",
@@ -397,13 +1935,19 @@ fn handle_status_changed_event(
             new_status,
             service_handler.name()
         );
+        // Exposed by the control socket: a steady increase is worth a look.
+        new_sh.illegal_transitions += 1;
     }
     new_sh
 }
 
 /// This next function assumes that the system is shutting down.
 /// It will make progress in the direction of shutting everything down.
-fn next_events_shutting_down(service_handler: &ServiceHandler) -> Vec<Event> {
+/// If `is_force_killing`, the services are not given the time to gracefully exit.
+fn next_events_shutting_down(
+    service_handler: &ServiceHandler,
+    is_force_killing: bool,
+) -> Vec<Event> {
     let ev_status =
         |status: ServiceStatus| Event::new_status_changed(service_handler.name(), status);
     let vev_status = |status: ServiceStatus| vec![ev_status(status)];
@@ -414,9 +1958,15 @@ fn next_events_shutting_down(service_handler: &ServiceHandler) -> Vec<Event> {
             ev_status(ServiceStatus::InKilling),
             Event::Kill(service_handler.name().clone()),
         ],
-        ServiceStatus::Success | ServiceStatus::Initial => vev_status(ServiceStatus::Finished),
+        ServiceStatus::Success
+        | ServiceStatus::Initial
+        | ServiceStatus::Idle
+        | ServiceStatus::Disabled => vev_status(ServiceStatus::Finished),
         ServiceStatus::Failed => vev_status(ServiceStatus::FinishedFailed),
-        ServiceStatus::InKilling if should_force_kill(service_handler) => {
+        ServiceStatus::InKilling if has_pid_never_appeared(service_handler) => {
+            vec![Event::PidNeverAppeared(service_handler.name().clone())]
+        }
+        ServiceStatus::InKilling if should_force_kill(service_handler, is_force_killing) => {
             vec![Event::new_force_kill(service_handler.name())]
         }
         _ => vec![],
@@ -427,8 +1977,10 @@ fn next_events_shutting_down(service_handler: &ServiceHandler) -> Vec<Event> {
 fn handle_restart_strategy(service: &Service, is_failed: bool) -> Event {
     let new_status = |status| Event::new_status_changed(&service.name, status);
     let ev = match service.restart.strategy {
-        RestartStrategy::Never if is_failed => new_status(ServiceStatus::FinishedFailed),
         RestartStrategy::OnFailure if is_failed => new_status(ServiceStatus::Initial),
+        // It waits for the next firing of its schedule.
+        _ if service.schedule.is_some() => new_status(ServiceStatus::Idle),
+        RestartStrategy::Never if is_failed => new_status(ServiceStatus::FinishedFailed),
         RestartStrategy::Never | RestartStrategy::OnFailure => new_status(ServiceStatus::Finished),
         RestartStrategy::Always => new_status(ServiceStatus::Initial),
     };
@@ -436,13 +1988,17 @@ fn handle_restart_strategy(service: &Service, is_failed: bool) -> Event {
     ev
 }
 
-/// This is applied to both failed and FinishedFailed services.
-fn handle_failed_service(deps: Vec<ServiceName>, failed_sh: &Service) -> Vec<Event> {
-    match failed_sh.failure.strategy {
-        FailureStrategy::Shutdown => vec![Event::ShuttingDownInitiated],
+/// This is applied to both failed and FinishedFailed services. `targets` are the services
+/// affected by its strategy: its dependents, or its group.
+fn handle_failed_service(targets: Vec<ServiceName>, failed_sh: &Service) -> Vec<Event> {
+    match &failed_sh.failure.strategy {
+        FailureStrategy::Shutdown => vec![Event::ShuttingDownInitiated(
+            ShutdownReason::ServiceFailed(failed_sh.name.clone()),
+        )],
         FailureStrategy::KillDependents => {
             debug!("Failed service has kill-dependents strategy, going to mark them all..");
-            deps.iter()
+            targets
+                .iter()
                 .map(|sh| {
                     vec![
                         Event::new_status_changed(sh, ServiceStatus::InKilling),
@@ -452,26 +2008,51 @@ fn handle_failed_service(deps: Vec<ServiceName>, failed_sh: &Service) -> Vec<Eve
                 .flatten()
                 .collect()
         }
+        FailureStrategy::ShutdownGroup(group) => {
+            info!(
+                "{} has failed, stopping its group {}: {:?}",
+                failed_sh.name, group, targets
+            );
+            // Stopped, so they're not restarted: the failed service as well, if it's a member.
+            targets.into_iter().map(Event::Stop).collect()
+        }
         FailureStrategy::Ignore => vec![],
     }
 }
 
-/// Check if we've waitied enough for the service to exit
-fn should_force_kill(service_handler: &ServiceHandler) -> bool {
+/// Check if the service has been killed before having a pid, and it has not shown up for way
+/// longer than its termination wait.
+fn has_pid_never_appeared(service_handler: &ServiceHandler) -> bool {
+    if !service_handler.is_in_killing() || service_handler.pid.is_some() {
+        return false;
+    }
+    let give_up_after = service_handler.service().termination.wait + MISSING_PID_GRACE;
+    match service_handler.shutting_down_start {
+        Some(shutting_down_start) => shutting_down_start.elapsed() > give_up_after,
+        None => false,
+    }
+}
+
+/// Check if we've waitied enough for the service to exit (or if we don't want to wait at all).
+fn should_force_kill(service_handler: &ServiceHandler, is_force_killing: bool) -> bool {
     if service_handler.pid.is_none() {
         // Since it was in the started state, it doesn't have a pid yet.
         // Let's give it the time to start and exit.
         return false;
     }
-    if let Some(shutting_down_elapsed_secs) = service_handler.shutting_down_start {
-        let shutting_down_elapsed_secs = shutting_down_elapsed_secs.elapsed().as_secs();
+    if is_force_killing {
+        return true;
+    }
+    if let Some(shutting_down_start) = service_handler.shutting_down_start {
+        let shutting_down_elapsed = shutting_down_start.elapsed();
+        let termination_wait = service_handler.service().termination.wait;
         debug!(
-            "{}, should not force kill. Elapsed: {}, termination wait: {}",
+            "{}, should not force kill. Elapsed: {:?}, termination wait: {:?}",
             service_handler.name(),
-            shutting_down_elapsed_secs,
-            service_handler.service().termination.wait.clone().as_secs()
+            shutting_down_elapsed,
+            termination_wait
         );
-        shutting_down_elapsed_secs > service_handler.service().termination.wait.clone().as_secs()
+        shutting_down_elapsed > termination_wait
     } else {
         // this might happen, because InKilling state is emitted before the Kill event.
         // So maybe the runtime has received only the InKilling state change, but hasn't sent the
@@ -487,7 +2068,8 @@ fn kill(sh: &ServiceHandler, signal: Option<signal::Signal>) {
     let signal = signal.unwrap_or_else(|| sh.service().termination.signal.into());
     debug!("Going to send {} signal to pid {:?}", signal, sh.pid());
     if let Some(pid) = sh.pid() {
-        if let Err(error) = signal::kill(pid, signal) {
+        let kill_scope = sh.service().termination.kill_scope;
+        if let Err(error) = session::signal_all(pid, kill_scope, signal) {
             match error.as_errno().expect("errno empty!") {
                 // No process or process group can be found corresponding to that specified by pid
                 // It has exited already, so it's fine.
@@ -511,15 +2093,24 @@ fn kill(sh: &ServiceHandler, signal: Option<signal::Signal>) {
 
 #[cfg(test)]
 mod test {
-    use crate::horust::formats::{FailureStrategy, Service, ServiceStatus};
-    use crate::horust::runtime::service_handler::ServiceHandler;
+    use crate::horust::bus::EventBus;
+    use crate::horust::formats::{
+        expand_replicas, AttemptsExhaustedAction, DisabledDependencyPolicy, FailureStrategy,
+        FailureThreshold, HealthCheckReport, HealthinessStatus, HorustConfig, PortRange,
+        RestartStrategy, Service, ServiceStatus, ShutdownReason, SpawnError,
+    };
+    use crate::horust::runtime::service_handler::{ServiceHandler, StopReason};
     use crate::horust::runtime::{
-        handle_failed_service, handle_restart_strategy, should_force_kill,
+        coalesce_status_changes, handle_failed_service, handle_restart_strategy,
+        handle_status_changed_event, has_pid_never_appeared, should_force_kill, Runtime, SpawnMode,
     };
     use crate::horust::Event;
+    use nix::sys::signal;
     use nix::unistd::Pid;
     use std::ops::Sub;
-    use std::time::Duration;
+    use std::process::Command;
+    use std::thread;
+    use std::time::{Duration, Instant};
     #[test]
     fn test_handle_restart_strategy() {
         let new_status = |status| Event::new_status_changed(&"servicename".to_string(), status);
@@ -546,6 +2137,51 @@ strategy = "{}"
                 let received = handle_restart_strategy(&service, has_failed);
                 assert_eq!(received, expected);
             });
+        let mut scheduled: Service = toml::from_str(
+            r#"name="servicename"
+command = "Not relevant"
+[schedule]
+every = "1m"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            handle_restart_strategy(&scheduled, true),
+            new_status(ServiceStatus::Idle)
+        );
+        scheduled.restart.strategy = RestartStrategy::OnFailure;
+        assert_eq!(
+            handle_restart_strategy(&scheduled, false),
+            new_status(ServiceStatus::Idle)
+        );
+        assert_eq!(
+            handle_restart_strategy(&scheduled, true),
+            new_status(ServiceStatus::Initial)
+        );
+    }
+
+    #[test]
+    fn test_coalesce_status_changes() {
+        let status_changed = |s_name: &str, status| Event::new_status_changed(s_name, status);
+        let events = vec![
+            status_changed("a", ServiceStatus::Started),
+            status_changed("b", ServiceStatus::Started),
+            status_changed("a", ServiceStatus::Started),
+            status_changed("a", ServiceStatus::Running),
+            status_changed("a", ServiceStatus::Running),
+            Event::Kill("a".into()),
+            status_changed("a", ServiceStatus::Running),
+            status_changed("b", ServiceStatus::Started),
+        ];
+        let expected = vec![
+            status_changed("a", ServiceStatus::Started),
+            status_changed("b", ServiceStatus::Started),
+            status_changed("a", ServiceStatus::Running),
+            Event::Kill("a".into()),
+            status_changed("a", ServiceStatus::Running),
+            status_changed("b", ServiceStatus::Started),
+        ];
+        assert_eq!(coalesce_status_changes(events), expected);
     }
 
     #[test]
@@ -556,19 +2192,71 @@ wait = "10s"
 "#;
         let service: Service = toml::from_str(service).unwrap();
         let mut sh: ServiceHandler = service.into();
-        assert!(!should_force_kill(&sh));
+        assert!(!should_force_kill(&sh, false));
         sh.shutting_down_started();
         sh.status = ServiceStatus::InKilling;
-        assert!(!should_force_kill(&sh));
+        assert!(!should_force_kill(&sh, false));
         let old_start = sh.shutting_down_start;
         let past_wait = Some(sh.shutting_down_start.unwrap().sub(Duration::from_secs(20)));
         sh.shutting_down_start = past_wait;
-        assert!(!should_force_kill(&sh));
+        assert!(!should_force_kill(&sh, false));
         sh.pid = Some(Pid::this());
         sh.shutting_down_start = old_start;
-        assert!(!should_force_kill(&sh));
+        assert!(!should_force_kill(&sh, false));
+        // No need to wait, if it's force killing.
+        assert!(should_force_kill(&sh, true));
         sh.shutting_down_start = past_wait;
-        assert!(should_force_kill(&sh));
+        assert!(should_force_kill(&sh, false));
+    }
+
+    #[test]
+    fn test_has_pid_never_appeared() {
+        let service = r#"command="notrelevant"
+[termination]
+wait = "10s"
+"#;
+        let service: Service = toml::from_str(service).unwrap();
+        let mut sh: ServiceHandler = service.into();
+        assert!(!has_pid_never_appeared(&sh));
+        sh.shutting_down_started();
+        sh.status = ServiceStatus::InKilling;
+        assert!(!has_pid_never_appeared(&sh));
+        let within_grace = sh.shutting_down_start.unwrap().sub(Duration::from_secs(12));
+        sh.shutting_down_start = Some(within_grace);
+        assert!(!has_pid_never_appeared(&sh));
+        let past_grace = sh.shutting_down_start.unwrap().sub(Duration::from_secs(10));
+        sh.shutting_down_start = Some(past_grace);
+        assert!(has_pid_never_appeared(&sh));
+        sh.pid = Some(Pid::this());
+        assert!(!has_pid_never_appeared(&sh));
+        sh.pid = None;
+        sh.status = ServiceStatus::FinishedFailed;
+        assert!(!has_pid_never_appeared(&sh));
+    }
+
+    #[test]
+    fn test_should_force_kill_sub_second_wait() {
+        let service = r#"command="notrelevant"
+[termination]
+wait = "500ms"
+"#;
+        let service: Service = toml::from_str(service).unwrap();
+        let mut sh: ServiceHandler = service.into();
+        sh.status = ServiceStatus::InKilling;
+        sh.pid = Some(Pid::this());
+        let now = Instant::now();
+        sh.shutting_down_start = Some(now.sub(Duration::from_millis(400)));
+        assert!(!should_force_kill(&sh, false));
+        sh.shutting_down_start = Some(now.sub(Duration::from_millis(600)));
+        assert!(should_force_kill(&sh, false));
+        // Less than a second, but more than the wait.
+        let mut service = Service::from_name("a");
+        service.termination.wait = Duration::from_millis(100);
+        let mut sh: ServiceHandler = service.into();
+        sh.status = ServiceStatus::InKilling;
+        sh.pid = Some(Pid::this());
+        sh.shutting_down_start = Some(now.sub(Duration::from_millis(200)));
+        assert!(should_force_kill(&sh, false));
     }
 
     #[test]
@@ -585,9 +2273,702 @@ wait = "10s"
         ];
         assert_eq!(evs, exp);
 
+        service.failure.strategy = FailureStrategy::ShutdownGroup("web".into());
+        let evs = handle_failed_service(vec!["a".into(), "b".into()], &service.clone());
+        let exp = vec![Event::Stop("a".into()), Event::Stop("b".into())];
+        assert_eq!(evs, exp);
+
         service.failure.strategy = FailureStrategy::Shutdown;
         let evs = handle_failed_service(vec!["a".into()], &service.into());
-        let exp = vec![Event::ShuttingDownInitiated];
+        let exp = vec![Event::ShuttingDownInitiated(ShutdownReason::ServiceFailed(
+            "b".into(),
+        ))];
         assert_eq!(evs, exp);
     }
+
+    #[test]
+    fn test_failure_threshold() {
+        let mut service = Service::from_name("a");
+        let mut sh: ServiceHandler = service.clone().into();
+        let start = Instant::now();
+        sh.record_failure(start);
+        assert!(sh.is_failure_threshold_reached(start));
+        assert!(sh.failure_history.is_empty());
+
+        service.failure.threshold = Some(FailureThreshold {
+            failures: 3,
+            window: Duration::from_secs(60),
+        });
+        let mut sh: ServiceHandler = service.into();
+        let at = |secs| start + Duration::from_secs(secs);
+        sh.record_failure(at(0));
+        sh.record_failure(at(30));
+        assert!(!sh.is_failure_threshold_reached(at(30)));
+        sh.record_failure(at(61));
+        // The first failure is out of the window.
+        assert!(!sh.is_failure_threshold_reached(at(61)));
+        sh.record_failure(at(62));
+        assert!(sh.is_failure_threshold_reached(at(62)));
+        assert_eq!(sh.failure_history.len(), 3);
+    }
+
+    #[test]
+    fn test_startup_retries() {
+        let mut service = Service::from_name("a");
+        service.restart.attempts = 5;
+        service.restart.backoff = Duration::from_secs(10);
+        let mut sh: ServiceHandler = service.clone().into();
+        sh.restart_attempts = 2;
+        assert!(!sh.restart_attempts_are_over());
+        assert_eq!(sh.restart_backoff(), Duration::from_secs(20));
+
+        service.restart.startup_retries = Some(1);
+        service.restart.startup_backoff = Some(Duration::from_secs(1));
+        let mut sh: ServiceHandler = service.into();
+        sh.restart_attempts = 2;
+        assert!(sh.restart_attempts_are_over());
+        assert_eq!(sh.restart_backoff(), Duration::from_secs(2));
+
+        // Once running, the startup retries are over, and the attempts apply.
+        sh.restart_attempts = 1;
+        sh.status = ServiceStatus::Started;
+        let mut sh = handle_status_changed_event(sh, &ServiceStatus::Running);
+        assert!(sh.has_been_running);
+        assert_eq!(sh.restart_attempts, 0);
+        sh.restart_attempts = 2;
+        assert!(!sh.restart_attempts_are_over());
+        assert_eq!(sh.restart_backoff(), Duration::from_secs(20));
+    }
+
+    #[test]
+    fn test_run_history() {
+        let mut sh: ServiceHandler = Service::from_name("a").into();
+        for exit_code in 0..25 {
+            sh.record_run(exit_code);
+        }
+        assert_eq!(sh.run_history.len(), 20);
+        assert_eq!(sh.run_history[0].exit_code, 5);
+        let run = sh.record_run(137);
+        assert_eq!(run.signal(), Some(signal::SIGKILL));
+        assert_eq!(
+            run.to_string(),
+            "start=- duration=- exit-code=137 signal=SIGKILL"
+        );
+        assert_eq!(sh.run_history.back(), Some(&run));
+        assert_eq!(sh.record_run(1).signal(), None);
+    }
+
+    #[test]
+    fn test_start_priority() {
+        let mut bus = EventBus::new();
+        let mut infra = Service::from_name("infra");
+        infra.start_priority = 10;
+        let mut batch = Service::from_name("batch");
+        batch.start_priority = -1;
+        let services = vec![
+            Service::from_name("web"),
+            batch,
+            infra,
+            Service::from_name("api"),
+        ];
+        let runtime = Runtime::new(
+            bus.join_bus(),
+            services,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            SpawnMode::Processes(None, Default::default()),
+        );
+        let names: Vec<&str> = runtime
+            .repo
+            .by_start_priority()
+            .into_iter()
+            .map(|sh| sh.name().as_str())
+            .collect();
+        assert_eq!(names, vec!["infra", "api", "web", "batch"]);
+    }
+
+    #[test]
+    fn test_disabled_dependencies() {
+        let mut bus = EventBus::new();
+        let mut db = Service::from_name("db");
+        db.enabled = false;
+        let web = Service::start_after("web", vec!["db"]);
+        let mut new_runtime = |config: HorustConfig| {
+            Runtime::new(
+                bus.join_bus(),
+                vec![db.clone(), web.clone()],
+                config,
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                SpawnMode::Processes(None, Default::default()),
+            )
+        };
+        let next =
+            |runtime: &Runtime, name: &str| runtime.next_events(&runtime.repo.services[name]);
+        let mut runtime = new_runtime(Default::default());
+        let disabled = Event::new_status_changed("db", ServiceStatus::Disabled);
+        assert_eq!(next(&runtime, "db"), vec![disabled]);
+        runtime.repo.get_mut_sh("db").status = ServiceStatus::Disabled;
+        let failed = Event::new_status_changed("web", ServiceStatus::FinishedFailed);
+        assert_eq!(next(&runtime, "web"), vec![failed]);
+
+        // Unmasked, it can be started again.
+        assert_eq!(
+            runtime.handle_event(Event::Unmask("db".into())),
+            vec![Event::new_status_changed("db", ServiceStatus::Initial)]
+        );
+        assert!(next(&runtime, "web").is_empty());
+        runtime.handle_event(Event::Mask("db".into()));
+        assert!(runtime
+            .repo
+            .get_sh("db")
+            .is_stopping_for(StopReason::Masked));
+
+        let config = HorustConfig {
+            disabled_dependencies: DisabledDependencyPolicy::Ignore,
+            ..Default::default()
+        };
+        let mut runtime = new_runtime(config);
+        runtime.repo.get_mut_sh("db").status = ServiceStatus::Disabled;
+        assert_eq!(next(&runtime, "web"), vec![Event::Run("web".into())]);
+    }
+
+    #[test]
+    fn test_restart_recovered_dependents() {
+        let mut bus = EventBus::new();
+        let db = Service::from_name("db");
+        let mut api = Service::start_after("api", vec!["db"]);
+        api.restart.on_dependency_recovery = true;
+        let mut web = Service::start_after("web", vec!["api"]);
+        web.restart.on_dependency_recovery = true;
+        let cron = Service::start_after("cron", vec!["db"]);
+        let mut runtime = Runtime::new(
+            bus.join_bus(),
+            vec![db, api, web, cron],
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            SpawnMode::Processes(None, Default::default()),
+        );
+        for sh in runtime.repo.services.values_mut() {
+            sh.status = ServiceStatus::Running;
+        }
+        // Running again after having failed:
+        runtime.repo.get_mut_sh("db").is_recovering = true;
+        runtime.restart_recovered_dependents();
+        assert!(!runtime.repo.get_sh("db").is_recovering);
+        assert!(runtime
+            .repo
+            .get_sh("api")
+            .is_stopping_for(StopReason::Restart));
+        assert!(!runtime
+            .repo
+            .get_sh("cron")
+            .is_stopping_for(StopReason::Restart));
+        // Its dependents follow once it's running again.
+        assert!(!runtime
+            .repo
+            .get_sh("web")
+            .is_stopping_for(StopReason::Restart));
+        runtime.repo.get_mut_sh("api").status = ServiceStatus::Initial;
+        runtime.restart_recovered_dependents();
+        assert!(!runtime
+            .repo
+            .get_sh("web")
+            .is_stopping_for(StopReason::Restart));
+        let api = runtime.repo.get_mut_sh("api");
+        api.status = ServiceStatus::Running;
+        api.end_restart();
+        runtime.restart_recovered_dependents();
+        assert!(runtime
+            .repo
+            .get_sh("web")
+            .is_stopping_for(StopReason::Restart));
+    }
+
+    #[test]
+    fn test_allocate_ports() {
+        let mut bus = EventBus::new();
+        let mut web = Service::from_name("web");
+        web.replicas = 2;
+        web.port_range = Some(PortRange {
+            start: 8000,
+            end: 8009,
+        });
+        web.listen = vec!["127.0.0.1:{port}".into()];
+        let mut api = Service::from_name("api");
+        api.port_range = Some(PortRange {
+            start: 8000,
+            end: 8000,
+        });
+        let mut runtime = Runtime::new(
+            bus.join_bus(),
+            expand_replicas(vec![web, api]),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            SpawnMode::Processes(None, Default::default()),
+        );
+        assert_eq!(runtime.repo.get_sh("api").port, Some(8000));
+        assert_eq!(runtime.repo.get_sh("web-0").port, Some(8001));
+        assert_eq!(runtime.repo.get_sh("web-1").port, Some(8002));
+        let service = runtime.repo.get_sh("web-1").spawned_service();
+        assert_eq!(service.listen, vec!["127.0.0.1:8002".to_string()]);
+        assert_eq!(service.environment.additional["PORT"], "8002");
+        assert_eq!(
+            service.environment.additional["HORUST_INSTANCE_PORT"],
+            "8002"
+        );
+
+        // The new instances never get a port already in use:
+        runtime.scale("web", 3);
+        assert_eq!(runtime.repo.get_sh("web-2").port, Some(8003));
+        // The port is kept while the service is restarted.
+        runtime.repo.get_mut_sh("web-1").status = ServiceStatus::Initial;
+        runtime.repo.allocate_ports();
+        assert_eq!(runtime.repo.get_sh("web-1").port, Some(8002));
+    }
+
+    #[test]
+    fn test_policy_hook() {
+        let mut bus = EventBus::new();
+        let mut web = Service::from_name("web");
+        web.replicas = 2;
+        let config = HorustConfig {
+            policy_hook: Some(
+                r#"sh -c '[ "$HORUST_POLICY_ACTION" = create ] && [ "$HORUST_POLICY_SERVICE" != web-3 ]'"#
+                    .into(),
+            ),
+            ..Default::default()
+        };
+        let mut runtime = Runtime::new(
+            bus.join_bus(),
+            expand_replicas(vec![web]),
+            config,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            SpawnMode::Processes(None, Default::default()),
+        );
+        let receiver = bus.join_bus();
+        thread::spawn(move || bus.run());
+        // Asked on another thread: nothing is created until the decisions are delivered.
+        assert!(runtime.scale("web", 5).is_empty());
+        assert!(!runtime.repo.services.contains_key("web-2"));
+        // web-3 is denied: the scaling stops there.
+        let decisions = receiver.get_n_events_blocking(2);
+        for decision in decisions {
+            runtime.handle_event(decision);
+        }
+        assert!(runtime.repo.services.contains_key("web-2"));
+        assert!(!runtime.repo.services.contains_key("web-3"));
+        assert!(!runtime.repo.services.contains_key("web-4"));
+        // Allowed twice (e.g. by two scalings): it's created once.
+        let web_2 = runtime.repo.get_sh("web-2").service().clone();
+        let allowed = Event::CreationDecided(Box::new(web_2), Ok(()));
+        assert!(runtime.handle_event(allowed).is_empty());
+    }
+
+    #[test]
+    fn test_spawned_service_metadata() {
+        let mut worker = Service::from_name("worker.toml");
+        worker.replicas = 2;
+        let mut sh: ServiceHandler = expand_replicas(vec![worker]).remove(1).into();
+        sh.starts = 3;
+        let environment = sh.spawned_service().environment.additional;
+        assert_eq!(environment["HORUST_SERVICE_NAME"], "worker.toml");
+        assert_eq!(environment["HORUST_INSTANCE"], "worker.toml-1");
+        assert_eq!(environment["HORUST_RESTART_COUNT"], "3");
+        assert_eq!(
+            environment["HORUST_SUPERVISOR_PID"],
+            std::process::id().to_string()
+        );
+    }
+
+    #[test]
+    fn test_single_run() {
+        let mut bus = EventBus::new();
+        let mut runtime = Runtime::new(
+            bus.join_bus(),
+            vec![Service::from_name("a")],
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            SpawnMode::Processes(None, Default::default()),
+        );
+        // Nothing is actually spawned.
+        runtime.is_simulated = true;
+        let next = |runtime: &Runtime| runtime.next(&runtime.repo.services["a"]);
+        let run = vec![Event::Run("a".into())];
+        assert_eq!(next(&runtime), run);
+        runtime.apply_next_events(&run);
+        // The Run hasn't been delivered back yet: it's not emitted again.
+        assert!(next(&runtime).is_empty());
+        assert_eq!(
+            runtime.handle_event(Event::Run("a".into())),
+            vec![
+                Event::StartRequested("a".into()),
+                Event::new_status_changed("a", ServiceStatus::Starting)
+            ]
+        );
+        // A stale Run is ignored.
+        assert!(runtime.handle_event(Event::Run("a".into())).is_empty());
+        // Back to Initial (e.g. the spawn has failed), it's run again.
+        runtime.repo.get_mut_sh("a").status = ServiceStatus::Initial;
+        assert_eq!(next(&runtime), run);
+    }
+
+    #[test]
+    fn test_terminal_states() {
+        let mut bus = EventBus::new();
+        let mut runtime = Runtime::new(
+            bus.join_bus(),
+            vec![Service::from_name("a")],
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            SpawnMode::Processes(None, Default::default()),
+        );
+        // Above the highest pid allowed by Linux: nothing can be killed by mistake.
+        let pid = Pid::from_raw(1 << 23);
+        runtime.repo.add_pid(pid, "a".into());
+        let sh = runtime.repo.get_mut_sh("a");
+        sh.pid = Some(pid);
+        sh.status = ServiceStatus::Finished;
+        assert!(runtime.handle_event(Event::Kill("a".into())).is_empty());
+        assert!(runtime
+            .handle_event(Event::ForceKill("a".into()))
+            .is_empty());
+        assert_eq!(runtime.repo.get_sh("a").status, ServiceStatus::Finished);
+
+        // Reaped once it has finished: it's not restarted, nor does it fail again.
+        runtime.repo.get_mut_sh("a").status = ServiceStatus::FinishedFailed;
+        assert!(runtime
+            .handle_event(Event::new_service_exited("a".into(), 137))
+            .is_empty());
+        assert!(runtime.repo.pid_map.is_empty());
+        let sh = runtime.repo.get_sh("a").clone();
+        assert_eq!(sh.status, ServiceStatus::FinishedFailed);
+        assert_eq!(sh.pid, None);
+
+        let sh = handle_status_changed_event(sh, &ServiceStatus::Running);
+        assert_eq!(sh.status, ServiceStatus::FinishedFailed);
+        assert_eq!(sh.illegal_transitions, 1);
+    }
+
+    #[test]
+    fn test_attempts_exhausted() {
+        let mut bus = EventBus::new();
+        let mut service = Service::from_name("a");
+        service.restart.strategy = RestartStrategy::Never;
+        service.restart.on_attempts_exhausted = AttemptsExhaustedAction::Shutdown;
+        let mut runtime = Runtime::new(
+            bus.join_bus(),
+            vec![service],
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            SpawnMode::Processes(None, Default::default()),
+        );
+        let exhausted = Event::ShuttingDownInitiated(ShutdownReason::AttemptsExhausted("a".into()));
+        // Failed (e.g. killed by its healthchecks), and it's never restarted.
+        runtime.repo.get_mut_sh("a").status = ServiceStatus::Failed;
+        let next = runtime.next(&runtime.repo.services["a"]);
+        assert!(next.contains(&Event::new_status_changed(
+            "a",
+            ServiceStatus::FinishedFailed
+        )));
+        assert_eq!(runtime.apply_next_events(&next), vec![exhausted.clone()]);
+        assert_eq!(
+            runtime.repo.get_sh("a").status,
+            ServiceStatus::FinishedFailed
+        );
+        // Already finished failed: not again.
+        let finished_failed = [Event::new_status_changed(
+            "a",
+            ServiceStatus::FinishedFailed,
+        )];
+        assert!(runtime.apply_next_events(&finished_failed).is_empty());
+
+        // Given up on, before being spawned.
+        let sh = runtime.repo.get_mut_sh("a");
+        sh.status = ServiceStatus::InKilling;
+        sh.shutting_down_start = Some(Instant::now().sub(Duration::from_secs(60)));
+        assert_eq!(
+            runtime.handle_event(Event::PidNeverAppeared("a".into())),
+            vec![
+                Event::new_status_changed("a", ServiceStatus::FinishedFailed),
+                exhausted
+            ]
+        );
+        assert_eq!(
+            runtime.repo.get_sh("a").status,
+            ServiceStatus::FinishedFailed
+        );
+
+        // Not while shutting down.
+        runtime.is_shutting_down = true;
+        runtime.repo.get_mut_sh("a").status = ServiceStatus::Failed;
+        let next = runtime.next(&runtime.repo.services["a"]);
+        assert!(runtime.apply_next_events(&next).is_empty());
+    }
+
+    #[test]
+    fn test_stop_reasons() {
+        let mut sh: ServiceHandler = Service::from_name("web").into();
+        sh.status = ServiceStatus::InKilling;
+        sh.stop_for(StopReason::Restart);
+        // A weaker reason doesn't replace it.
+        sh.stop_for(StopReason::Binding);
+        assert_eq!(sh.stop_reason(), Some(StopReason::Restart));
+        assert_eq!(sh.planned_stop_status(), Some(ServiceStatus::Initial));
+        sh.stop_for(StopReason::Masked);
+        assert_eq!(sh.planned_stop_status(), Some(ServiceStatus::Disabled));
+        // Once restarted, it's still masked.
+        sh.end_restart();
+        assert_eq!(sh.stop_reason(), Some(StopReason::Masked));
+        sh.stop_for(StopReason::ScaledDown);
+        assert_eq!(sh.planned_stop_status(), Some(ServiceStatus::Finished));
+        sh.cancel_stop(StopReason::Masked);
+        assert_eq!(sh.stop_reason(), Some(StopReason::ScaledDown));
+        sh.cancel_stop(StopReason::ScaledDown);
+        assert_eq!(sh.stop_reason(), None);
+        assert_eq!(sh.planned_stop_status(), None);
+    }
+
+    #[test]
+    fn test_stopped_by_binding() {
+        let mut bus = EventBus::new();
+        let mut app = Service::from_name("app");
+        app.binds_to = vec!["vpn".into()];
+        app.restart.strategy = RestartStrategy::Never;
+        let mut runtime = Runtime::new(
+            bus.join_bus(),
+            vec![Service::from_name("vpn"), app],
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            SpawnMode::Processes(None, Default::default()),
+        );
+        // Above the highest pid allowed by Linux: nothing can be killed by mistake.
+        let pid = Pid::from_raw(1 << 23);
+        let run_app = |runtime: &mut Runtime| {
+            runtime.repo.add_pid(pid, "app".into());
+            let sh = runtime.repo.get_mut_sh("app");
+            sh.pid = Some(pid);
+            sh.status = ServiceStatus::Running;
+        };
+        run_app(&mut runtime);
+        runtime.repo.get_mut_sh("vpn").status = ServiceStatus::Failed;
+        let next = runtime.next(&runtime.repo.services["app"]);
+        assert_eq!(
+            next,
+            vec![
+                Event::new_status_changed("app", ServiceStatus::InKilling),
+                Event::Kill("app".into())
+            ]
+        );
+        runtime.apply_next_events(&next);
+        assert!(runtime
+            .repo
+            .get_sh("app")
+            .is_stopping_for(StopReason::Binding));
+        // Killed because of its binding: started again once it's running.
+        runtime.handle_event(Event::new_service_exited("app".into(), 143));
+        let sh = runtime.repo.get_sh("app");
+        assert_eq!(sh.status, ServiceStatus::Initial);
+        assert!(!sh.is_stopping_for(StopReason::Binding));
+
+        // Exited on its own before being stopped: its restart strategy is followed.
+        run_app(&mut runtime);
+        runtime.handle_event(Event::new_service_exited("app".into(), 1));
+        assert_eq!(runtime.repo.get_sh("app").status, ServiceStatus::Failed);
+        assert_eq!(
+            runtime.next(&runtime.repo.services["app"]),
+            vec![Event::new_status_changed(
+                "app",
+                ServiceStatus::FinishedFailed
+            )]
+        );
+    }
+
+    #[test]
+    fn test_spawn_failed() {
+        let mut bus = EventBus::new();
+        let mut service = Service::from_name("a");
+        service.restart.attempts = 1;
+        service.restart.retry_permanent_errors = true;
+        let mut runtime = Runtime::new(
+            bus.join_bus(),
+            vec![service],
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            SpawnMode::Processes(None, Default::default()),
+        );
+        let not_found = || Event::SpawnFailed("a".into(), SpawnError::NotFound);
+        // Like a process exiting right away, it's an attempt.
+        runtime.repo.get_mut_sh("a").status = ServiceStatus::Starting;
+        assert_eq!(
+            runtime.handle_event(not_found()),
+            vec![Event::new_status_changed("a", ServiceStatus::Initial)]
+        );
+        runtime.repo.get_mut_sh("a").status = ServiceStatus::Starting;
+        assert_eq!(
+            runtime.handle_event(not_found()),
+            vec![Event::new_status_changed(
+                "a",
+                ServiceStatus::FinishedFailed
+            )]
+        );
+        runtime.repo.get_mut_sh("a").status = ServiceStatus::Starting;
+        assert_eq!(
+            runtime.handle_event(Event::SpawnFailed("a".into(), SpawnError::Aborted)),
+            vec![Event::new_status_changed("a", ServiceStatus::Failed)]
+        );
+        let spawn_failures = &runtime.repo.get_sh("a").spawn_failures;
+        assert_eq!(spawn_failures["not-found"], 2);
+        assert_eq!(spawn_failures["aborted"], 1);
+
+        // What it waits for hasn't shown up: it's an attempt too.
+        let sh = runtime.repo.get_mut_sh("a");
+        sh.restart_attempts = 0;
+        sh.status = ServiceStatus::Starting;
+        let timed_out = SpawnError::WaitForTimedOut("tcp db:5432".into());
+        assert_eq!(
+            runtime.handle_event(Event::SpawnFailed("a".into(), timed_out)),
+            vec![Event::new_status_changed("a", ServiceStatus::Initial)]
+        );
+        let sh = runtime.repo.get_sh("a");
+        assert_eq!(sh.restart_attempts, 1);
+        assert_eq!(
+            sh.last_spawn_error.as_deref(),
+            Some("timed out waiting for tcp db:5432")
+        );
+    }
+
+    #[test]
+    fn test_exec_failed() {
+        let mut bus = EventBus::new();
+        let mut service = Service::from_name("a");
+        service.restart.attempts = 3;
+        let mut runtime = Runtime::new(
+            bus.join_bus(),
+            vec![service],
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            SpawnMode::Processes(None, Default::default()),
+        );
+        // Not worth retrying, even if there are attempts left.
+        runtime.repo.get_mut_sh("a").status = ServiceStatus::Starting;
+        assert_eq!(
+            runtime.handle_event(Event::ExecFailed("a".into(), libc::ENOEXEC)),
+            vec![Event::new_status_changed(
+                "a",
+                ServiceStatus::PermanentlyFailed
+            )]
+        );
+        let sh = runtime.repo.get_sh("a");
+        assert_eq!(sh.spawn_failures["not-executable"], 1);
+        assert_eq!(
+            sh.last_spawn_error,
+            Some("ENOEXEC: Exec format error".into())
+        );
+        // Other errors are retried.
+        runtime.repo.get_mut_sh("a").status = ServiceStatus::Starting;
+        assert_eq!(
+            runtime.handle_event(Event::ExecFailed("a".into(), libc::EMFILE)),
+            vec![Event::new_status_changed("a", ServiceStatus::Initial)]
+        );
+        assert_eq!(runtime.repo.get_sh("a").spawn_failures["other"], 1);
+    }
+
+    #[test]
+    fn test_state_snapshot() {
+        let mut bus = EventBus::new();
+        let mut runtime = Runtime::new(
+            bus.join_bus(),
+            vec![Service::from_name("b"), Service::from_name("a")],
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            SpawnMode::Processes(None, Default::default()),
+        );
+        runtime.repo.get_mut_sh("b").status = ServiceStatus::Running;
+        assert_eq!(
+            runtime.handle_event(Event::RequestSnapshot),
+            vec![Event::StateSnapshot(vec![
+                ("a".into(), ServiceStatus::Initial),
+                ("b".into(), ServiceStatus::Running),
+            ])]
+        );
+    }
+
+    #[test]
+    fn test_healthcheck_during_shutdown() {
+        let mut bus = EventBus::new();
+        let mut runtime = Runtime::new(
+            bus.join_bus(),
+            vec![Service::from_name("a")],
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            SpawnMode::Processes(None, Default::default()),
+        );
+        let unhealthy = || {
+            let report = HealthCheckReport {
+                status: HealthinessStatus::Unhealthy,
+                latency: Duration::from_millis(1),
+                error: Some("connection refused".into()),
+            };
+            Event::HealthCheck("a".into(), report)
+        };
+        let sh = runtime.repo.get_mut_sh("a");
+        sh.status = ServiceStatus::Running;
+        sh.healthiness_checks_failed = 0;
+        runtime.handle_event(unhealthy());
+        assert_eq!(runtime.repo.get_sh("a").healthiness_checks_failed, 1);
+        // A late result, while the service is being killed.
+        runtime.is_shutting_down = true;
+        runtime.handle_event(unhealthy());
+        let sh = runtime.repo.get_sh("a");
+        assert_eq!(sh.healthiness_checks_failed, 1);
+        assert_eq!(sh.status, ServiceStatus::Running);
+    }
+
+    #[test]
+    fn test_kill_all_children() {
+        let mut bus = EventBus::new();
+        let mut service = Service::from_name("a");
+        service.termination.wait = Duration::from_millis(500);
+        let mut runtime = Runtime::new(
+            bus.join_bus(),
+            vec![service],
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            SpawnMode::Processes(None, Default::default()),
+        );
+        let child = Command::new("sleep").arg("30").spawn().unwrap();
+        let pid = Pid::from_raw(child.id() as i32);
+        runtime.repo.add_pid(pid, "a".into());
+        runtime.repo.get_mut_sh("a").pid = Some(pid);
+        runtime.kill_all_children();
+        assert!(signal::kill(pid, None).is_err());
+    }
 }
@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+/// Waits between two iterations of the event loop.
+/// On Linux it just sleeps: exited children are found by the reaper, and the signal handlers set
+/// the flags checked by the loop.
+#[cfg(not(target_os = "macos"))]
+#[derive(Debug)]
+pub(crate) struct Monitor;
+
+#[cfg(not(target_os = "macos"))]
+impl Monitor {
+    pub(crate) fn new() -> Self {
+        Monitor
+    }
+
+    pub(crate) fn wait(&self, timeout: Duration) {
+        std::thread::sleep(timeout);
+    }
+}
+
+/// On macOS a kqueue watches SIGCHLD, SIGTERM, SIGINT and SIGQUIT: the event loop wakes up as soon as a
+/// child exits or horust is asked to terminate. The signal handlers still run as usual, the
+/// kqueue only records the delivery.
+#[cfg(target_os = "macos")]
+#[derive(Debug)]
+pub(crate) struct Monitor {
+    kq: Option<std::os::unix::io::RawFd>,
+}
+
+#[cfg(target_os = "macos")]
+impl Monitor {
+    pub(crate) fn new() -> Self {
+        match Self::open_kqueue() {
+            Ok(kq) => Self { kq: Some(kq) },
+            Err(error) => {
+                warn!("Cannot setup kqueue, falling back to polling: {}", error);
+                Self { kq: None }
+            }
+        }
+    }
+
+    fn open_kqueue() -> nix::Result<std::os::unix::io::RawFd> {
+        use nix::sys::event::{kevent_ts, kqueue, EventFilter, EventFlag, FilterFlag, KEvent};
+        use nix::sys::signal::Signal;
+        let kq = kqueue()?;
+        let changes: Vec<KEvent> = [
+            Signal::SIGCHLD,
+            Signal::SIGTERM,
+            Signal::SIGINT,
+            Signal::SIGQUIT,
+        ]
+        .iter()
+        .map(|signal| {
+            KEvent::new(
+                *signal as usize,
+                EventFilter::EVFILT_SIGNAL,
+                EventFlag::EV_ADD,
+                FilterFlag::empty(),
+                0,
+                0,
+            )
+        })
+        .collect();
+        if let Err(error) = kevent_ts(kq, &changes, &mut [], None) {
+            let _ = nix::unistd::close(kq);
+            return Err(error);
+        }
+        Ok(kq)
+    }
+
+    /// Blocks up to `timeout`, or until one of the watched signals is delivered.
+    pub(crate) fn wait(&self, timeout: Duration) {
+        use nix::sys::event::{kevent_ts, EventFilter, EventFlag, FilterFlag, KEvent};
+        let kq = match self.kq {
+            Some(kq) => kq,
+            None => return std::thread::sleep(timeout),
+        };
+        let empty = KEvent::new(
+            0,
+            EventFilter::EVFILT_SIGNAL,
+            EventFlag::empty(),
+            FilterFlag::empty(),
+            0,
+            0,
+        );
+        let mut events = [empty; 4];
+        let timespec = libc::timespec {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_nsec: timeout.subsec_nanos() as libc::c_long,
+        };
+        match kevent_ts(kq, &[], &mut events, Some(timespec)) {
+            Ok(received) => events[..received]
+                .iter()
+                .for_each(|event| debug!("kqueue: received signal {}", event.ident())),
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => (),
+            Err(error) => {
+                error!("Error kevent(): {}", error);
+                std::thread::sleep(timeout);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        if let Some(kq) = self.kq {
+            let _ = nix::unistd::close(kq);
+        }
+    }
+}
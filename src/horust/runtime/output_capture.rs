@@ -0,0 +1,128 @@
+use crate::horust::bus::Publisher;
+use crate::horust::error::Result;
+use crate::horust::formats::{Event, LogOutput, ServiceName};
+use nix::fcntl::OFlag;
+use nix::unistd;
+use regex::Regex;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// Pipes standing between the service and its real outputs, used for scanning what the service
+/// writes. The write ends are used by the child, the read ends by horust.
+pub(crate) struct CapturePipes {
+    stdout: (RawFd, RawFd),
+    stderr: (RawFd, RawFd),
+}
+
+impl CapturePipes {
+    pub(crate) fn new() -> Result<Self> {
+        // Close on exec: the child gets its copies using dup2.
+        let stdout = unistd::pipe2(OFlag::O_CLOEXEC)?;
+        let stderr = unistd::pipe2(OFlag::O_CLOEXEC)?;
+        Ok(Self { stdout, stderr })
+    }
+
+    /// Child side: the read ends are not needed, and stdout/stderr are replaced by the pipes.
+    /// Warning: only async-signal-safe calls here.
+    pub(crate) fn redirect_child(&self) -> Result<()> {
+        unistd::dup2(self.stdout.1, libc::STDOUT_FILENO)?;
+        unistd::dup2(self.stderr.1, libc::STDERR_FILENO)?;
+        Ok(())
+    }
+
+    /// The write ends, which replace stdout and stderr in the child.
+    #[cfg(all(target_os = "linux", feature = "vfork-spawn"))]
+    pub(crate) fn write_ends(&self) -> (RawFd, RawFd) {
+        (self.stdout.1, self.stderr.1)
+    }
+
+    /// Parent side: forwards everything to the configured outputs, and notifies the bus as soon
+    /// as a line matches the pattern.
+    pub(crate) fn forward(
+        self,
+        service_name: ServiceName,
+        stdout: LogOutput,
+        stderr: LogOutput,
+        pattern: Regex,
+        bus: Publisher,
+    ) -> Result<()> {
+        let _ = unistd::close(self.stdout.1);
+        let _ = unistd::close(self.stderr.1);
+        let matcher = Arc::new(Matcher {
+            service_name,
+            pattern,
+            is_matched: AtomicBool::new(false),
+            bus,
+        });
+        let outputs = vec![
+            (self.stdout.0, open_output(&stdout)?),
+            (self.stderr.0, open_output(&stderr)?),
+        ];
+        for (read_fd, output) in outputs {
+            let matcher = matcher.clone();
+            thread::spawn(move || {
+                // It's the only owner of the read end, closed once dropped.
+                let input = unsafe { File::from_raw_fd(read_fd) };
+                if let Err(error) = matcher.forward(input, output) {
+                    warn!(
+                        "Error forwarding the output of {}: {}",
+                        matcher.service_name, error
+                    );
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+struct Matcher {
+    service_name: ServiceName,
+    pattern: Regex,
+    is_matched: AtomicBool,
+    bus: Publisher,
+}
+
+impl Matcher {
+    /// Runs until the service (and all of its children) has closed its output.
+    fn forward(&self, input: File, mut output: Box<dyn Write + Send>) -> io::Result<()> {
+        let mut reader = BufReader::new(input);
+        let mut line = vec![];
+        while reader.read_until(b'\n', &mut line)? > 0 {
+            output.write_all(&line)?;
+            output.flush()?;
+            if !self.is_matched.load(Ordering::SeqCst)
+                && self
+                    .pattern
+                    .is_match(String::from_utf8_lossy(&line).as_ref())
+                && !self.is_matched.swap(true, Ordering::SeqCst)
+            {
+                self.bus
+                    .send_event(Event::LogPatternMatched(self.service_name.clone()));
+            }
+            line.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Opens, in horust, the output that the service would have used.
+fn open_output(value: &LogOutput) -> io::Result<Box<dyn Write + Send>> {
+    let writer: Box<dyn Write + Send> = match value {
+        LogOutput::Stdout => Box::new(io::stdout()),
+        LogOutput::Stderr => Box::new(io::stderr()),
+        // Same flags and mode used when the child opens it.
+        LogOutput::Path(path) => Box::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .mode(0o700)
+                .open(path)?,
+        ),
+    };
+    Ok(writer)
+}
@@ -0,0 +1,266 @@
+//! Policy hook (`policy-hook`): an external program asked before the sensitive actions, which can
+//! deny them. It's told about the action through its environment, and it allows it by exiting
+//! successfully: any other outcome (failure, timeout, or not being able to run it) denies it.
+//! It's never run by the runtime thread: the decisions are delivered back as events.
+use crate::horust::bus::Publisher;
+use crate::horust::formats::{Event, Service};
+use crate::horust::runtime::audit::{AuditEntry, AuditLog};
+use nix::unistd::Pid;
+use std::collections::HashSet;
+use std::io::Read;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A policy hook taking longer is killed, and it denies.
+const POLICY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const ACTION_ENV: &str = "HORUST_POLICY_ACTION";
+const SERVICE_ENV: &str = "HORUST_POLICY_SERVICE";
+const COMMAND_ENV: &str = "HORUST_POLICY_COMMAND";
+const PID_ENV: &str = "HORUST_POLICY_PID";
+
+/// The actions needing the approval of the policy hook.
+#[derive(Debug)]
+pub(crate) enum Action<'a> {
+    /// A new instance of a service, created at runtime (`horustctl scale`).
+    Create(&'a Service),
+    /// Adopting an existing process, instead of spawning the service.
+    Adopt(&'a Service, Pid),
+}
+
+impl Action<'_> {
+    fn name(&self) -> &'static str {
+        match self {
+            Action::Create(_) => "create",
+            Action::Adopt(_, _) => "adopt",
+        }
+    }
+
+    fn service(&self) -> &Service {
+        match self {
+            Action::Create(service) | Action::Adopt(service, _) => service,
+        }
+    }
+}
+
+/// The pids of the running policy hooks. They're waited for by the thread which has run them,
+/// so the reaper must leave them alone.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HookPids(Arc<Mutex<HashSet<Pid>>>);
+
+impl HookPids {
+    pub(crate) fn contains(&self, pid: Pid) -> bool {
+        self.0.lock().unwrap().contains(&pid)
+    }
+}
+
+/// Without a command, every action is allowed.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Policy {
+    argv: Option<Vec<String>>,
+    /// Every run of the hook is recorded.
+    audit: AuditLog,
+    running: HookPids,
+}
+
+impl Policy {
+    pub(crate) fn new(command: Option<&str>, audit: AuditLog) -> Self {
+        Self {
+            argv: command
+                .and_then(shlex::split)
+                .filter(|argv| !argv.is_empty()),
+            audit,
+            running: Default::default(),
+        }
+    }
+
+    pub(crate) fn is_configured(&self) -> bool {
+        self.argv.is_some()
+    }
+
+    /// Shared by every clone of this policy.
+    pub(crate) fn running_hooks(&self) -> HookPids {
+        self.running.clone()
+    }
+
+    /// Asks about the new instances on a thread of its own, one at a time, until one is denied:
+    /// every decision is delivered back as `CreationDecided`.
+    pub(crate) fn ask_create(&self, services: Vec<Service>, bus: Publisher) {
+        let policy = self.clone();
+        let spawned = thread::Builder::new()
+            .name("policy-hook".into())
+            .spawn(move || {
+                for service in services {
+                    let decision = policy.check(&Action::Create(&service));
+                    let is_denied = decision.is_err();
+                    bus.send_event(Event::CreationDecided(Box::new(service), decision));
+                    if is_denied {
+                        break;
+                    }
+                }
+            });
+        if let Err(error) = spawned {
+            error!("Failed spawning the policy hook thread: {}", error);
+        }
+    }
+
+    /// Runs the policy hook (blocking), returning why the action is denied.
+    pub(crate) fn check(&self, action: &Action) -> Result<(), String> {
+        let argv = match &self.argv {
+            Some(argv) => argv,
+            None => return Ok(()),
+        };
+        let service = action.service();
+        let mut command = Command::new(&argv[0]);
+        command
+            .args(&argv[1..])
+            .env(ACTION_ENV, action.name())
+            .env(SERVICE_ENV, &service.name)
+            .env(COMMAND_ENV, &service.command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped());
+        if let Action::Adopt(_, pid) = action {
+            command.env(PID_ENV, pid.to_string());
+        }
+        let mut entry = AuditEntry::inherited("policy-hook", &service.name, argv);
+        entry.env.extend(
+            command
+                .get_envs()
+                .map(|(name, _)| name.to_string_lossy().to_string()),
+        );
+        // Locked until the pid is registered: the reaper can't take the hook in the meantime.
+        let mut running = self.running.0.lock().unwrap();
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(error) => {
+                drop(running);
+                self.audit.record(entry, Err(error.to_string()));
+                return Err(format!("cannot run the policy hook: {}", error));
+            }
+        };
+        let pid = Pid::from_raw(child.id() as i32);
+        running.insert(pid);
+        drop(running);
+        self.audit.record(entry, Ok(pid));
+        let status = wait(&mut child);
+        self.running.0.lock().unwrap().remove(&pid);
+        let status = status?;
+        if status.success() {
+            return Ok(());
+        }
+        // The first line of its output explains the decision, e.g. `unsigned image`.
+        let mut output = String::new();
+        if let Some(mut stdout) = child.stdout.take() {
+            let _ = stdout.read_to_string(&mut output);
+        }
+        match output.lines().next().map(str::trim) {
+            Some(reason) if !reason.is_empty() => Err(reason.to_string()),
+            _ => Err(format!("denied by the policy hook ({})", status)),
+        }
+    }
+}
+
+/// Waits for the hook, killing it once it has timed out.
+fn wait(child: &mut Child) -> Result<ExitStatus, String> {
+    let start = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Ok(status),
+            Ok(None) if start.elapsed() < POLICY_TIMEOUT => {
+                thread::sleep(Duration::from_millis(10))
+            }
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!(
+                    "the policy hook has timed out ({:?})",
+                    POLICY_TIMEOUT
+                ));
+            }
+            Err(error) => return Err(format!("cannot wait for the policy hook: {}", error)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Action, Policy};
+    use crate::horust::bus::EventBus;
+    use crate::horust::formats::{Event, Service};
+    use crate::horust::runtime::audit::AuditLog;
+    use nix::unistd::Pid;
+    use std::thread;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_policy() {
+        let web = Service::from_name("web");
+        Policy::default().check(&Action::Create(&web)).unwrap();
+        let policy = Policy::new(
+            Some(
+                r#"sh -c '[ "$HORUST_POLICY_ACTION" = create ] || { echo "no adoptions ($HORUST_POLICY_SERVICE $HORUST_POLICY_PID)"; exit 1; }'"#,
+            ),
+            AuditLog::default(),
+        );
+        policy.check(&Action::Create(&web)).unwrap();
+        assert_eq!(
+            policy
+                .check(&Action::Adopt(&web, Pid::from_raw(42)))
+                .unwrap_err(),
+            "no adoptions (web 42)"
+        );
+        let silent = Policy::new(Some("false"), AuditLog::default());
+        assert!(silent
+            .check(&Action::Create(&web))
+            .unwrap_err()
+            .starts_with("denied by the policy hook"));
+        let missing = Policy::new(Some("/non/existent/policy"), AuditLog::default());
+        missing.check(&Action::Create(&web)).unwrap_err();
+    }
+
+    #[test]
+    fn test_policy_audit() {
+        let tempdir = TempDir::new("policy").unwrap();
+        let path = tempdir.path().join("audit.log");
+        let policy = Policy::new(Some("true"), AuditLog::open(Some(&path)).unwrap());
+        policy
+            .check(&Action::Create(&Service::from_name("web")))
+            .unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        let entry: serde_json::Value = serde_json::from_str(content.trim()).unwrap();
+        assert_eq!(entry["kind"], "policy-hook");
+        assert_eq!(entry["service"], "web");
+        assert_eq!(entry["argv"], serde_json::json!(["true"]));
+        assert!(entry["pid"].is_number());
+        let env = entry["env"].as_array().unwrap();
+        assert!(env.contains(&"HORUST_POLICY_ACTION".into()));
+    }
+
+    #[test]
+    fn test_ask_create() {
+        let mut bus = EventBus::new();
+        let connector = bus.join_bus();
+        let receiver = bus.join_bus();
+        thread::spawn(move || bus.run());
+        let policy = Policy::new(
+            Some(r#"sh -c '[ "$HORUST_POLICY_SERVICE" != web-3 ]'"#),
+            AuditLog::default(),
+        );
+        let replicas: Vec<Service> = (2..5)
+            .map(|index| Service::from_name(&format!("web-{}", index)))
+            .collect();
+        policy.ask_create(replicas.clone(), connector.publisher());
+        // It stops at the first denial.
+        let decisions = receiver.get_n_events_blocking(2);
+        assert_eq!(
+            decisions[0],
+            Event::CreationDecided(Box::new(replicas[0].clone()), Ok(()))
+        );
+        match &decisions[1] {
+            Event::CreationDecided(service, Err(_)) => assert_eq!(service.name, "web-3"),
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+    }
+}
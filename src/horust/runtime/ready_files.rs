@@ -0,0 +1,112 @@
+//! Readiness markers (`--ready-directory`): an empty file named after every Running service, so
+//! the scripts and tools of the container can wait for a service using e.g. `test -f`.
+use crate::horust::formats::{ServiceName, ServiceStatus};
+use crate::horust::status_board::ServiceSnapshot;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub(crate) struct ReadyFiles {
+    directory: PathBuf,
+    /// The services having a marker.
+    ready: BTreeSet<ServiceName>,
+}
+
+impl ReadyFiles {
+    /// The markers left by a previous run are removed: the directory belongs to horust.
+    pub(crate) fn new(directory: PathBuf) -> Self {
+        if let Err(error) = Self::clean(&directory) {
+            error!(
+                "Cannot prepare the ready directory {}: {}",
+                directory.display(),
+                error
+            );
+        }
+        Self {
+            directory,
+            ready: BTreeSet::new(),
+        }
+    }
+
+    fn clean(directory: &Path) -> io::Result<()> {
+        fs::create_dir_all(directory)?;
+        for entry in fs::read_dir(directory)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates the markers of the services which have become Running, and removes the ones of
+    /// the services which aren't anymore.
+    pub(crate) fn update(&mut self, services: &[ServiceSnapshot]) {
+        let running: BTreeSet<ServiceName> = services
+            .iter()
+            .filter(|snapshot| snapshot.status == ServiceStatus::Running)
+            .map(|snapshot| snapshot.name.clone())
+            .collect();
+        for s_name in running.difference(&self.ready) {
+            let path = self.directory.join(s_name);
+            if let Err(error) = fs::write(&path, "") {
+                error!("Cannot create the ready file {}: {}", path.display(), error);
+            }
+        }
+        for s_name in self.ready.difference(&running) {
+            self.remove(s_name);
+        }
+        self.ready = running;
+    }
+
+    /// Horust is exiting: no service is ready anymore.
+    pub(crate) fn remove_all(&self) {
+        self.ready.iter().for_each(|s_name| self.remove(s_name));
+    }
+
+    fn remove(&self, s_name: &str) {
+        let path = self.directory.join(s_name);
+        if let Err(error) = fs::remove_file(&path) {
+            if error.kind() != io::ErrorKind::NotFound {
+                error!("Cannot remove the ready file {}: {}", path.display(), error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ReadyFiles;
+    use crate::horust::formats::{Service, ServiceStatus};
+    use crate::horust::runtime::service_handler::ServiceHandler;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_ready_files() {
+        let tempdir = TempDir::new("ready_files").unwrap();
+        let directory = tempdir.path().join("ready");
+        std::fs::create_dir(&directory).unwrap();
+        std::fs::write(directory.join("stale.toml"), "").unwrap();
+        let mut ready_files = ReadyFiles::new(directory.clone());
+        assert!(!directory.join("stale.toml").exists());
+
+        let mut db: ServiceHandler = Service::from_name("db.toml").into();
+        db.status = ServiceStatus::Running;
+        let mut web: ServiceHandler = Service::from_name("web.toml").into();
+        web.status = ServiceStatus::Started;
+        ready_files.update(&[db.snapshot(), web.snapshot()]);
+        assert!(directory.join("db.toml").is_file());
+        assert!(!directory.join("web.toml").exists());
+
+        db.status = ServiceStatus::InKilling;
+        web.status = ServiceStatus::Running;
+        ready_files.update(&[db.snapshot(), web.snapshot()]);
+        assert!(!directory.join("db.toml").exists());
+        assert!(directory.join("web.toml").is_file());
+
+        ready_files.remove_all();
+        assert!(!directory.join("web.toml").exists());
+    }
+}
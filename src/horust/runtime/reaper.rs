@@ -1,33 +1,177 @@
+use crate::horust::formats::{ServiceStatus, UnknownChildrenPolicy};
+use crate::horust::logging;
 use crate::horust::runtime::repo::Repo;
+use crate::horust::runtime::resource_usage;
+use crate::horust::runtime::session::read_session;
 use crate::horust::Event;
+use nix::sys::wait::WaitStatus;
+use nix::unistd::Pid;
+use tracing::Level;
 
-/// Reaps up to `max_iterations` dead processes
-pub(crate) fn run(repo: &Repo, max_iterations: u32) -> Vec<Event> {
-    use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
-    use nix::unistd::Pid;
-    (0..max_iterations)
-        .filter_map(
-            |_| match waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG)) {
-                Ok(wait_status) => {
-                    if let WaitStatus::Exited(pid, exit_code) = wait_status {
-                        debug!("Pid has exited: {} with exitcode: {}", pid, exit_code);
-                        repo.get_service_by_pid(pid)
-                            .map(|s_name| (s_name, exit_code))
-                    } else {
-                        None
-                    }
+/// Reaps up to `max_iterations` dead processes.
+/// Processes which don't belong to any service are handled according to `policy`.
+pub(crate) fn run(
+    repo: &mut Repo,
+    max_iterations: u32,
+    policy: &UnknownChildrenPolicy,
+) -> Vec<Event> {
+    let mut events = vec![];
+    for _ in 0..max_iterations {
+        let pid = match peek_exited_child() {
+            Some(pid) => pid,
+            None => break,
+        };
+        // It's left to the thread waiting for it, which reaps it shortly.
+        if repo.policy_hooks.contains(pid) {
+            break;
+        }
+        // Should be read before reaping: afterwards /proc/<pid> is gone.
+        let session = if repo.get_service_by_pid(pid).is_none() && !repo.hooks.contains_key(&pid) {
+            read_session(pid)
+        } else {
+            None
+        };
+        let (wait_status, usage) = match resource_usage::wait4(pid) {
+            Ok(reaped) => reaped,
+            Err(err) => {
+                error!("Error wait4(): {}", err);
+                break;
+            }
+        };
+        if let Some(description) = repo.hooks.remove(&pid) {
+            info!("{} has exited: {:?}", description, wait_status);
+            continue;
+        }
+        match repo.get_service_by_pid(pid).cloned() {
+            Some(s_name) => {
+                if let Some(usage) = usage {
+                    repo.get_mut_sh(&s_name).resource_usage.add(&usage);
                 }
-                Err(err) => {
-                    if !err.to_string().contains("ECHILD") {
-                        error!("Error waitpid(): {}", err);
-                    }
-                    None
+                // Killed by a signal (e.g. by the chaos): like the shells, 128 + the signal.
+                let exited = match wait_status {
+                    WaitStatus::Exited(pid, exit_code) => Some((pid, exit_code)),
+                    WaitStatus::Signaled(pid, signal, _) => Some((pid, 128 + signal as i32)),
+                    _ => None,
+                };
+                if let Some((pid, exit_code)) = exited {
+                    logging::service_event(
+                        module_path!(),
+                        Level::DEBUG,
+                        &s_name,
+                        "exited",
+                        &format!(
+                            "Pid {} of service '{}' has exited with exitcode: {}",
+                            pid, s_name, exit_code
+                        ),
+                    );
+                    events.push(Event::new_service_exited(s_name, exit_code));
                 }
-            },
+            }
+            None => events.extend(handle_unknown_child(repo, wait_status, session, policy)),
+        }
+    }
+    events
+}
+
+/// The session of the process is used for finding out which service it belongs to.
+fn handle_unknown_child(
+    repo: &mut Repo,
+    wait_status: WaitStatus,
+    session: Option<Pid>,
+    policy: &UnknownChildrenPolicy,
+) -> Vec<Event> {
+    repo.unknown_children_reaped += 1;
+    let parent_service = session
+        .and_then(|session| repo.get_service_by_pid(session))
+        .cloned();
+    match policy {
+        UnknownChildrenPolicy::Ignore => {
+            trace!("Reaped unknown process: {:?}", wait_status);
+            vec![]
+        }
+        UnknownChildrenPolicy::Log => {
+            warn!(
+                "Reaped a process which doesn't belong to any service: {:?}, it was spawned by service: {:?}",
+                wait_status, parent_service
+            );
+            vec![]
+        }
+        UnknownChildrenPolicy::KillParentService => match parent_service {
+            Some(s_name) => {
+                warn!(
+                    "Reaped a process spawned by service: {}: {:?}. Going to kill the service.",
+                    s_name, wait_status
+                );
+                vec![
+                    Event::new_status_changed(&s_name, ServiceStatus::InKilling),
+                    Event::Kill(s_name),
+                ]
+            }
+            None => {
+                warn!(
+                    "Reaped a process which doesn't belong to any service: {:?}",
+                    wait_status
+                );
+                vec![]
+            }
+        },
+    }
+}
+
+/// Layout of siginfo_t for SIGCHLD. libc doesn't expose si_pid (yet).
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct SigChldInfo {
+    _si_signo: libc::c_int,
+    _si_errno: libc::c_int,
+    _si_code: libc::c_int,
+    fields: SigChldFields,
+}
+
+/// The union holding these fields has the alignment of its largest member: that's why the longs
+/// are needed, they provide the right padding on 64 bits targets.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct SigChldFields {
+    si_pid: libc::pid_t,
+    _si_uid: libc::uid_t,
+    _si_status: libc::c_int,
+    _si_utime: libc::c_long,
+    _si_stime: libc::c_long,
+}
+
+/// Returns the pid of a terminated child, without reaping it.
+fn peek_exited_child() -> Option<Pid> {
+    let mut siginfo: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let res = unsafe {
+        libc::waitid(
+            libc::P_ALL,
+            0,
+            &mut siginfo,
+            libc::WEXITED | libc::WNOHANG | libc::WNOWAIT,
         )
-        .map(|(sname, exit_code)| {
-            debug!("Service '{:?}' has exited.", sname);
-            Event::new_service_exited(sname.into(), exit_code)
-        })
-        .collect()
+    };
+    if res != 0 {
+        let err = nix::Error::last();
+        if err.as_errno() != Some(nix::errno::Errno::ECHILD) {
+            error!("Error waitid(): {}", err);
+        }
+        return None;
+    }
+    // With WNOHANG, si_pid is zero if there are no terminated children.
+    match si_pid(&siginfo) {
+        0 => None,
+        pid => Some(Pid::from_raw(pid)),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn si_pid(siginfo: &libc::siginfo_t) -> libc::pid_t {
+    let info = unsafe { &*(siginfo as *const libc::siginfo_t as *const SigChldInfo) };
+    info.fields.si_pid
+}
+
+#[cfg(not(target_os = "linux"))]
+fn si_pid(siginfo: &libc::siginfo_t) -> libc::pid_t {
+    siginfo.si_pid
 }
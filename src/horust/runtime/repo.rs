@@ -1,27 +1,84 @@
 use crate::horust::bus::BusConnector;
-use crate::horust::formats::{Service, ServiceName};
+use crate::horust::formats::{
+    Dependency, DisabledDependencyPolicy, FailureStrategy, Service, ServiceName, GROUP_LABEL,
+};
+use crate::horust::runtime::policy::HookPids;
 use crate::horust::runtime::service_handler::ServiceHandler;
 use crate::horust::Event;
 use nix::unistd::Pid;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::os::unix::io::RawFd;
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub(crate) struct Repo {
     pub services: HashMap<ServiceName, ServiceHandler>,
-    pub(crate) bus: BusConnector<Event>,
+    pub(crate) bus: BusConnector,
     pub(crate) pid_map: HashMap<Pid, ServiceName>,
+    /// Adopted processes are not children of horust, so they cannot be reaped.
+    /// Their pidfd is used (if available) for detecting their exit.
+    pub(crate) adopted: HashMap<Pid, Option<RawFd>>,
+    /// Reaped processes which didn't belong to any service.
+    pub(crate) unknown_children_reaped: u64,
+    /// Commands spawned by horust on behalf of the services (e.g. on-attempts-exhausted).
+    pub(crate) hooks: HashMap<Pid, String>,
+    /// The policy hooks being run: they're reaped by the threads waiting for them.
+    pub(crate) policy_hooks: HookPids,
+    /// How the services depending on a disabled service are handled.
+    pub(crate) disabled_dependencies: DisabledDependencyPolicy,
 }
 
 impl Repo {
-    pub(crate) fn new(bus: BusConnector<Event>, services: Vec<Service>) -> Self {
+    pub(crate) fn new(
+        bus: BusConnector,
+        services: Vec<Service>,
+        disabled_dependencies: DisabledDependencyPolicy,
+    ) -> Self {
         let services = services
             .into_iter()
             .map(|service| (service.name.clone(), service.into()))
             .collect();
-        Self {
+        let mut repo = Self {
             bus,
             services,
             pid_map: HashMap::new(),
+            adopted: HashMap::new(),
+            unknown_children_reaped: 0,
+            hooks: HashMap::new(),
+            policy_hooks: Default::default(),
+            disabled_dependencies,
+        };
+        repo.allocate_ports();
+        repo
+    }
+
+    /// Allocates a port to the services having a port-range and no port yet: the first one of
+    /// the range which isn't used by any other service. The instances get them in order.
+    pub(crate) fn allocate_ports(&mut self) {
+        let mut used: HashSet<u16> = self.services.values().filter_map(|sh| sh.port).collect();
+        let mut waiting: Vec<(Option<u32>, ServiceName)> = self
+            .services
+            .values()
+            .filter(|sh| sh.service().port_range.is_some() && sh.port.is_none())
+            .map(|sh| {
+                let index = sh.service().replica.as_ref().map(|replica| replica.index);
+                (index, sh.name().clone())
+            })
+            .collect();
+        waiting.sort();
+        for (_index, s_name) in waiting {
+            let service_handler = self.get_mut_sh(&s_name);
+            let range = service_handler
+                .service()
+                .port_range
+                .expect("Without port-range");
+            match (range.start..=range.end).find(|port| !used.contains(port)) {
+                Some(port) => {
+                    debug!("Allocated port {} to {}.", port, s_name);
+                    used.insert(port);
+                    service_handler.port = Some(port);
+                }
+                None => error!("No free port left in {} for {}.", range, s_name),
+            }
         }
     }
 
@@ -41,10 +98,25 @@ impl Repo {
         self.bus.try_get_events()
     }
 
+    /// Targets are not waited for: they'd be Running forever. Neither are the disabled services,
+    /// which won't run unless they're unmasked.
     pub fn all_have_finished(&self) -> bool {
-        self.services
-            .iter()
-            .all(|(_s_name, sh)| sh.is_finished() || sh.is_finished_failed())
+        self.services.iter().all(|(_s_name, sh)| {
+            sh.is_finished() || sh.is_finished_failed() || sh.is_disabled() || sh.service().target
+        })
+    }
+
+    /// Services sorted by start-priority, the highest first (then by name), so the events
+    /// starting the ones with a higher priority are dispatched first.
+    pub(crate) fn by_start_priority(&self) -> Vec<&ServiceHandler> {
+        let mut services: Vec<&ServiceHandler> = self.services.values().collect();
+        services.sort_by(|a, b| {
+            b.service()
+                .start_priority
+                .cmp(&a.service().start_priority)
+                .then_with(|| a.name().cmp(b.name()))
+        });
+        services
     }
 
     /// Get a mutable reference to the Service Handler
@@ -61,12 +133,51 @@ impl Repo {
     pub(crate) fn get_dependents(&self, service_name: &str) -> Vec<ServiceName> {
         self.services
             .iter()
-            .filter(|(_s_name, sh)| sh.service().start_after.contains(&service_name.to_string()))
+            .filter(|(_s_name, sh)| {
+                sh.service()
+                    .start_after
+                    .iter()
+                    .any(|dep| dep.services().contains(&&service_name.to_string()))
+            })
             .map(|(s_name, _sh)| s_name)
             .cloned()
             .collect()
     }
 
+    /// The services of the group (having the `group` label), and the services bound to them.
+    /// Sorted by name.
+    pub(crate) fn get_group(&self, group: &str) -> Vec<ServiceName> {
+        let members: Vec<&ServiceName> = self
+            .services
+            .iter()
+            .filter(|(_s_name, sh)| {
+                sh.service().labels.get(GROUP_LABEL).map(String::as_str) == Some(group)
+            })
+            .map(|(s_name, _sh)| s_name)
+            .collect();
+        let bound = self
+            .services
+            .iter()
+            .filter(|(s_name, sh)| {
+                !members.contains(s_name)
+                    && sh.binds_to().iter().any(|bound| members.contains(&bound))
+            })
+            .map(|(s_name, _sh)| s_name);
+        let mut group: Vec<ServiceName> = members.iter().copied().chain(bound).cloned().collect();
+        group.sort();
+        group
+    }
+
+    /// The services affected by the failure strategy of the service: its dependents, or its
+    /// group.
+    pub(crate) fn get_failure_targets(&self, service: &Service) -> Vec<ServiceName> {
+        match &service.failure.strategy {
+            FailureStrategy::KillDependents => self.get_dependents(&service.name),
+            FailureStrategy::ShutdownGroup(group) => self.get_group(group),
+            FailureStrategy::Shutdown | FailureStrategy::Ignore => vec![],
+        }
+    }
+
     /// Get all the services that have specified "die-if-failed = [`service_name`]" in their config
     pub(crate) fn get_die_if_failed(&self, service_name: &str) -> Vec<&ServiceName> {
         self.services
@@ -85,17 +196,81 @@ impl Repo {
         self.bus.send_event(ev)
     }
 
-    /// Checks if the service is runnable. So the current status is Initial, and
-    /// all the start-after have started or finished.
+    /// Checks if the service is runnable. So the current status is Initial,
+    /// all the start-after have started or finished, and all the binds-to are running.
     pub(crate) fn is_service_runnable(&self, sh: &ServiceHandler) -> bool {
         if !sh.is_initial() {
             return false;
         }
-        let is_started = |service_name: &ServiceName| {
+        sh.start_after()
+            .iter()
+            .all(|dep| dep.is_satisfied(|name| self.is_started(name)))
+            && self.are_bindings_running(sh)
+    }
+
+    /// An idle service has already been running: it's just waiting to be activated.
+    fn is_started(&self, service_name: &ServiceName) -> bool {
+        let sh = self.services.get(service_name).unwrap();
+        sh.is_running() || sh.is_finished() || sh.is_idle_stopped() || self.is_ignored(sh)
+    }
+
+    /// A disabled service is considered as running by the services depending on it, if the
+    /// policy says so.
+    fn is_ignored(&self, sh: &ServiceHandler) -> bool {
+        sh.is_disabled() && self.disabled_dependencies == DisabledDependencyPolicy::Ignore
+    }
+
+    /// Checks if any of the start-after or binds-to is disabled, and the policy is to fail.
+    /// A list of services in start-after is disabled only if all of them are.
+    pub(crate) fn has_disabled_dependency(&self, sh: &ServiceHandler) -> bool {
+        if self.disabled_dependencies != DisabledDependencyPolicy::Fail {
+            return false;
+        }
+        let is_enabled =
+            |service_name: &ServiceName| !self.services.get(service_name).unwrap().is_disabled();
+        sh.start_after()
+            .iter()
+            .any(|dep| !dep.is_satisfied(is_enabled))
+            || !sh.binds_to().iter().all(is_enabled)
+    }
+
+    /// The entries of start-after and binds-to which are not satisfied, e.g. `db.toml` or
+    /// `any-of(a.toml|b.toml)` for start-after, `binds-to:db.toml` for binds-to.
+    pub(crate) fn unsatisfied_dependencies(&self, sh: &ServiceHandler) -> Vec<String> {
+        let start_after = sh
+            .start_after()
+            .iter()
+            .filter(|dep| !dep.is_satisfied(|name| self.is_started(name)))
+            .map(|dep| match dep {
+                Dependency::Service(name) => name.clone(),
+                Dependency::AnyOf(names) => format!("any-of({})", names.join("|")),
+            });
+        let binds_to = sh
+            .binds_to()
+            .iter()
+            .filter(|service_name| {
+                let sh = self.services.get(*service_name).unwrap();
+                !sh.is_running() && sh.retiring.is_none() && !self.is_ignored(sh)
+            })
+            .map(|service_name| format!("binds-to:{}", service_name));
+        start_after.chain(binds_to).collect()
+    }
+
+    /// Checks if all the services in binds-to are running.
+    /// During a blue/green restart, the previous instance is still serving.
+    pub(crate) fn are_bindings_running(&self, sh: &ServiceHandler) -> bool {
+        sh.binds_to().iter().all(|service_name| {
             let sh = self.services.get(service_name).unwrap();
-            sh.is_running() || sh.is_finished()
-        };
-        sh.start_after().iter().all(is_started)
+            sh.is_running() || sh.retiring.is_some() || self.is_ignored(sh)
+        })
+    }
+
+    /// Checks if any of the services in binds-to has finished, so it won't run again.
+    pub(crate) fn is_any_binding_finished(&self, sh: &ServiceHandler) -> bool {
+        sh.binds_to().iter().any(|service_name| {
+            let sh = self.services.get(service_name).unwrap();
+            sh.is_finished() || sh.is_finished_failed()
+        })
     }
 
     pub(crate) fn any_finished_failed(&self) -> bool {
@@ -0,0 +1,108 @@
+use nix::errno::Errno;
+use nix::sys::wait::WaitStatus;
+use nix::unistd::Pid;
+use std::time::Duration;
+
+/// Resources used by the processes of a service, as reported by the kernel once they're reaped.
+/// It includes the descendants they have waited for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct ResourceUsage {
+    pub(crate) user_time: Duration,
+    pub(crate) system_time: Duration,
+    /// Peak resident set size, in bytes.
+    pub(crate) max_rss: u64,
+    pub(crate) minor_faults: u64,
+    pub(crate) major_faults: u64,
+}
+
+impl ResourceUsage {
+    fn from_rusage(rusage: &libc::rusage) -> Self {
+        let duration = |time: libc::timeval| {
+            Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+        };
+        // Linux reports the peak RSS in kilobytes, macOS in bytes.
+        #[cfg(target_os = "macos")]
+        let max_rss = rusage.ru_maxrss as u64;
+        #[cfg(not(target_os = "macos"))]
+        let max_rss = rusage.ru_maxrss as u64 * 1024;
+        Self {
+            user_time: duration(rusage.ru_utime),
+            system_time: duration(rusage.ru_stime),
+            max_rss,
+            minor_faults: rusage.ru_minflt as u64,
+            major_faults: rusage.ru_majflt as u64,
+        }
+    }
+
+    /// Accounts for another process: times and faults are summed up, while the peak RSS is the
+    /// highest one.
+    pub(crate) fn add(&mut self, other: &ResourceUsage) {
+        self.user_time += other.user_time;
+        self.system_time += other.system_time;
+        self.max_rss = self.max_rss.max(other.max_rss);
+        self.minor_faults += other.minor_faults;
+        self.major_faults += other.major_faults;
+    }
+}
+
+/// Like waitpid using WNOHANG, but it also returns the resources used by the reaped process.
+pub(crate) fn wait4(pid: Pid) -> nix::Result<(WaitStatus, Option<ResourceUsage>)> {
+    let mut status: libc::c_int = 0;
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+    let res = unsafe { libc::wait4(pid.as_raw(), &mut status, libc::WNOHANG, &mut rusage) };
+    match Errno::result(res)? {
+        0 => Ok((WaitStatus::StillAlive, None)),
+        pid => Ok((
+            WaitStatus::from_raw(Pid::from_raw(pid), status)?,
+            Some(ResourceUsage::from_rusage(&rusage)),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{wait4, ResourceUsage};
+    use nix::sys::wait::WaitStatus;
+    use nix::unistd::{fork, ForkResult};
+    use std::time::Duration;
+
+    #[test]
+    fn test_wait4() {
+        let pid = match fork().unwrap() {
+            ForkResult::Child => unsafe { libc::_exit(3) },
+            ForkResult::Parent { child } => child,
+        };
+        let (wait_status, usage) = loop {
+            match wait4(pid).unwrap() {
+                (WaitStatus::StillAlive, _) => std::thread::sleep(Duration::from_millis(10)),
+                reaped => break reaped,
+            }
+        };
+        assert_eq!(wait_status, WaitStatus::Exited(pid, 3));
+        assert!(usage.unwrap().max_rss > 0);
+    }
+
+    #[test]
+    fn test_add() {
+        let mut total = ResourceUsage::default();
+        let usage = |secs, max_rss| ResourceUsage {
+            user_time: Duration::from_secs(secs),
+            system_time: Duration::from_secs(1),
+            max_rss,
+            minor_faults: 10,
+            major_faults: 1,
+        };
+        total.add(&usage(2, 300));
+        total.add(&usage(3, 200));
+        assert_eq!(
+            total,
+            ResourceUsage {
+                user_time: Duration::from_secs(5),
+                system_time: Duration::from_secs(2),
+                max_rss: 300,
+                minor_faults: 20,
+                major_faults: 2,
+            }
+        );
+    }
+}
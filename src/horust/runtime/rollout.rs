@@ -0,0 +1,61 @@
+use crate::horust::formats::{ServiceName, ServiceStatus};
+use crate::horust::runtime::repo::Repo;
+use crate::horust::runtime::service_handler::StopReason;
+use std::collections::VecDeque;
+
+/// Restart of the instances of a service, one at a time: the next one is restarted only once
+/// the previous one is running again.
+#[derive(Debug)]
+pub(crate) struct Rollout {
+    pub(crate) service: ServiceName,
+    /// Instances still to be restarted, in order.
+    pending: VecDeque<ServiceName>,
+    /// The instance being restarted.
+    current: Option<ServiceName>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Progress {
+    /// The current instance is not running yet.
+    Waiting,
+    /// Time to restart this instance.
+    Restart(ServiceName),
+    Done,
+    /// This instance has failed after being restarted.
+    Aborted(ServiceName),
+}
+
+impl Rollout {
+    pub(crate) fn new(service: ServiceName, instances: Vec<ServiceName>) -> Self {
+        Self {
+            service,
+            pending: instances.into(),
+            current: None,
+        }
+    }
+
+    pub(crate) fn advance(&mut self, repo: &Repo) -> Progress {
+        if let Some(current) = &self.current {
+            let sh = &repo.services[current];
+            if sh.is_stopping_for(StopReason::Restart) {
+                return Progress::Waiting;
+            }
+            match sh.status {
+                ServiceStatus::Running => (),
+                ServiceStatus::Failed
+                | ServiceStatus::FinishedFailed
+                | ServiceStatus::PermanentlyFailed => return Progress::Aborted(current.clone()),
+                _ => return Progress::Waiting,
+            }
+        }
+        // Instances which are not up (e.g. finished) are left alone.
+        while let Some(name) = self.pending.pop_front() {
+            if repo.services[&name].is_starting_or_up() {
+                self.current = Some(name.clone());
+                return Progress::Restart(name);
+            }
+        }
+        self.current = None;
+        Progress::Done
+    }
+}
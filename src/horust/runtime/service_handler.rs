@@ -1,27 +1,168 @@
-use crate::horust::formats::{Service, ServiceName, ServiceStatus};
+use crate::horust::formats::{
+    Dependency, HealthCheckReport, MaxRuntimeOutcome, RunRecord, Service, ServiceName,
+    ServiceStatus, INSTANCE_ENV, INSTANCE_PORT_ENVS, PORT_PLACEHOLDER, RESTART_COUNT_ENV,
+    SERVICE_NAME_ENV, SUPERVISOR_PID_ENV,
+};
+use crate::horust::runtime::resource_usage::ResourceUsage;
+use crate::horust::status_board::ServiceSnapshot;
 use nix::unistd::Pid;
-use std::time::Instant;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How many of the last runs are kept in the run history of a service.
+const RUN_HISTORY_LEN: usize = 20;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub(crate) struct ServiceHandler {
     service: Service,
     pub(crate) status: ServiceStatus,
     pub(crate) pid: Option<Pid>,
+    /// When the current process has been started, used for enforcing the max-runtime and the
+    /// restart window.
+    pub(crate) started_at: Option<Instant>,
+    /// Wall-clock time of the start of the current process, only for display: the uptime is
+    /// computed using `started_at`, which isn't affected by changes to the system clock.
+    pub(crate) start_time: Option<SystemTime>,
+    /// How many processes have been started (or adopted) for this service.
+    pub(crate) starts: u32,
+    /// How many processes couldn't be spawned, by reason (e.g. `not-found`).
+    pub(crate) spawn_failures: BTreeMap<&'static str, u32>,
+    /// Why the last process couldn't be spawned, e.g. the errno of exec.
+    pub(crate) last_spawn_error: Option<String>,
+    /// How many times it has been killed because of its failing healthchecks.
+    pub(crate) healthcheck_failures: u32,
+    /// Resources used by all the processes of the service which have been reaped.
+    pub(crate) resource_usage: ResourceUsage,
+    /// Last time the service was seen serving a connection, used for enforcing stop-after-idle.
+    pub(crate) last_active: Option<Instant>,
     pub(crate) restart_attempts: u32,
+    /// It has been running at least once: the restart's startup-retries don't apply anymore.
+    pub(crate) has_been_running: bool,
+    /// Failures in a row before being up for the failure's min-runtime.
+    pub(crate) immediate_exits: u32,
     pub(crate) healthiness_checks_failed: u32,
+    pub(crate) last_health_check: Option<HealthCheckReport>,
+    /// Error of the last failed healthcheck.
+    pub(crate) last_health_error: Option<String>,
+    /// The output of the current run has matched the healthiness' log-pattern.
+    pub(crate) log_pattern_matched: bool,
     /// Instant representing at which time we received a shutdown request. Will be used for comparing Service.termination.wait
     pub(crate) shutting_down_start: Option<Instant>,
+    /// Why horust is stopping it (or keeping it stopped) on its own, if it is.
+    stop_reason: Option<StopReason>,
+    /// A Run has been emitted, but it hasn't been handled yet.
+    pub(crate) is_run_requested: bool,
+    /// The termination.exec-before hook, run before sending the termination signal.
+    pub(crate) drain: Option<Drain>,
+    /// Blue/green restart: the previous instance, stopped once the new one is running.
+    pub(crate) retiring: Option<Pid>,
+    /// Its restart attempts have been exhausted: it's run using restart.fallback-command.
+    pub(crate) is_fallback: bool,
+    /// It has failed and is being restarted: once running, its dependents opting in for it are
+    /// restarted as well.
+    pub(crate) is_recovering: bool,
+    /// When it has failed lately, for the failure's threshold: at most `threshold.failures`
+    /// entries, the oldest first.
+    pub(crate) failure_history: VecDeque<Instant>,
+    /// Status changes which weren't allowed from its status at the time, so they were ignored.
+    pub(crate) illegal_transitions: u32,
+    /// Its last processes which have exited, the oldest first.
+    pub(crate) run_history: VecDeque<RunRecord>,
+    /// Allocated from its port-range, it's kept across the restarts.
+    pub(crate) port: Option<u16>,
+}
+
+/// Why horust is stopping a service on its own: it tells the status the service gets once it's
+/// stopped, regardless of its exit code. They're sorted by precedence: a reason only replaces
+/// a weaker one.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub(crate) enum StopReason {
+    /// A service it's bound to has stopped: once stopped, it's started again as soon as they're
+    /// all running.
+    Binding,
+    /// A restart has been requested: once stopped, it's started again.
+    Restart,
+    /// Disabled or masked: once stopped, it's not started again until it's unmasked.
+    Masked,
+    /// A stop has been requested (e.g. using horustctl): once stopped, it's finished.
+    Requested,
+    /// This replica has been removed by scaling down: once stopped, it's finished.
+    ScaledDown,
+}
+
+impl StopReason {
+    /// The status of the service, once it's stopped.
+    pub(crate) fn stopped_status(self) -> ServiceStatus {
+        match self {
+            StopReason::Binding | StopReason::Restart => ServiceStatus::Initial,
+            StopReason::Masked => ServiceStatus::Disabled,
+            StopReason::Requested | StopReason::ScaledDown => ServiceStatus::Finished,
+        }
+    }
+}
+
+impl std::fmt::Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            StopReason::Binding => "binding",
+            StopReason::Restart => "restart",
+            StopReason::Masked => "masked",
+            StopReason::Requested => "requested",
+            StopReason::ScaledDown => "scaled-down",
+        })
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Drain {
+    pub(crate) pid: Pid,
+    /// The signal is sent either after the hook has exited, or after this deadline.
+    pub(crate) deadline: Instant,
 }
 
 impl From<Service> for ServiceHandler {
     fn from(service: Service) -> Self {
+        let stop_reason = if service.enabled {
+            None
+        } else {
+            Some(StopReason::Masked)
+        };
+        // A scheduled service waits for the first firing of its schedule.
+        let status = if service.schedule.is_some() {
+            ServiceStatus::Idle
+        } else {
+            ServiceStatus::Initial
+        };
         ServiceHandler {
             service,
-            status: ServiceStatus::Initial,
+            status,
             pid: None,
+            started_at: None,
+            start_time: None,
+            starts: 0,
+            spawn_failures: BTreeMap::new(),
+            last_spawn_error: None,
+            healthcheck_failures: 0,
+            resource_usage: ResourceUsage::default(),
+            last_active: None,
             shutting_down_start: None,
+            drain: None,
+            stop_reason,
+            is_run_requested: false,
+            retiring: None,
+            is_fallback: false,
+            is_recovering: false,
+            failure_history: VecDeque::new(),
+            illegal_transitions: 0,
+            run_history: VecDeque::new(),
+            port: None,
             restart_attempts: 0,
+            has_been_running: false,
+            immediate_exits: 0,
             healthiness_checks_failed: 1,
+            last_health_check: None,
+            last_health_error: None,
+            log_pattern_matched: false,
         }
     }
 }
@@ -33,10 +174,51 @@ impl From<ServiceHandler> for Service {
 }
 
 impl ServiceHandler {
-    pub fn start_after(&self) -> &Vec<String> {
+    pub fn start_after(&self) -> &Vec<Dependency> {
         self.service.start_after.as_ref()
     }
 
+    pub fn binds_to(&self) -> &Vec<String> {
+        self.service.binds_to.as_ref()
+    }
+
+    pub(crate) fn snapshot(&self) -> ServiceSnapshot {
+        ServiceSnapshot {
+            name: self.name().clone(),
+            status: self.status.clone(),
+            pid: self.pid,
+            restart_attempts: self.restart_attempts,
+            last_health_check: self.last_health_check.clone(),
+            // Before the first check, the counter is set just for preventing the service from running.
+            consecutive_failures: if self.last_health_check.is_some() {
+                self.healthiness_checks_failed
+            } else {
+                0
+            },
+            last_health_error: self.last_health_error.clone(),
+            replica_of: self
+                .service
+                .replica
+                .as_ref()
+                .map(|replica| replica.of.clone()),
+            labels: self.service.labels.clone(),
+            started_at: self.started_at,
+            start_time: self.start_time,
+            starts: self.starts,
+            spawn_failures: self.spawn_failures.clone(),
+            last_spawn_error: self.last_spawn_error.clone(),
+            healthcheck_failures: self.healthcheck_failures,
+            resource_usage: self.resource_usage,
+            shutting_down_start: self.shutting_down_start,
+            stop_reason: self.stop_reason,
+            is_fallback: self.is_fallback,
+            illegal_transitions: self.illegal_transitions,
+            run_history: self.run_history.iter().cloned().collect(),
+            // Needs the other services, it's filled in by the runtime.
+            unsatisfied_dependencies: vec![],
+        }
+    }
+
     pub fn service(&self) -> &Service {
         &self.service
     }
@@ -50,11 +232,152 @@ impl ServiceHandler {
     }
 
     pub fn restart_attempts_are_over(&self) -> bool {
-        self.restart_attempts > self.service.restart.attempts
+        self.restart_attempts > self.allowed_attempts()
+    }
+
+    /// The startup retries if it has never been running (and they're set), otherwise the attempts.
+    fn allowed_attempts(&self) -> u32 {
+        let restart = &self.service.restart;
+        match restart.startup_retries {
+            Some(retries) if !self.has_been_running => retries,
+            _ => restart.attempts,
+        }
+    }
+
+    /// How long to wait before spawning it again, growing with the attempts.
+    pub fn restart_backoff(&self) -> Duration {
+        let restart = &self.service.restart;
+        let backoff = match restart.startup_backoff {
+            Some(backoff) if !self.has_been_running => backoff,
+            _ => restart.backoff,
+        };
+        backoff * self.restart_attempts
+    }
+
+    /// Its attempts are over, but it can still be run using the fallback command.
+    pub fn can_fall_back(&self) -> bool {
+        !self.is_fallback && self.service.restart.fallback_command.is_some()
+    }
+
+    /// What should be spawned: in fallback mode, the command is replaced by the fallback one.
+    /// Its environment describes the service to itself, and holds the allocated port (if any),
+    /// which is in its listen addresses as well.
+    pub fn spawned_service(&self) -> Service {
+        let mut service = self.service.clone();
+        if let (true, Some(command)) = (self.is_fallback, &service.restart.fallback_command) {
+            service.command = command.clone();
+        }
+        let service_name = match &service.replica {
+            Some(replica) => replica.of.clone(),
+            None => service.name.clone(),
+        };
+        let metadata = vec![
+            (SERVICE_NAME_ENV, service_name),
+            (INSTANCE_ENV, service.name.clone()),
+            (RESTART_COUNT_ENV, self.starts.to_string()),
+            (SUPERVISOR_PID_ENV, std::process::id().to_string()),
+        ];
+        for (env, value) in metadata {
+            service
+                .environment
+                .additional
+                .insert(env.to_string(), value);
+        }
+        if let Some(port) = self.port {
+            for env in INSTANCE_PORT_ENVS.iter() {
+                service
+                    .environment
+                    .additional
+                    .insert(env.to_string(), port.to_string());
+            }
+            service.listen = service
+                .listen
+                .iter()
+                .map(|address| address.replace(PORT_PLACEHOLDER, &port.to_string()))
+                .collect();
+        }
+        service
+    }
+
+    /// Finished, for good or not: it's not going to be started again on its own.
+    pub fn is_terminal(&self) -> bool {
+        self.is_finished() || self.is_finished_failed()
     }
 
     pub fn is_finished_failed(&self) -> bool {
-        ServiceStatus::FinishedFailed == self.status
+        [
+            ServiceStatus::FinishedFailed,
+            ServiceStatus::PermanentlyFailed,
+        ]
+        .contains(&self.status)
+    }
+
+    /// The process has exited before being up for the restart's min-runtime.
+    pub fn has_exited_too_soon(&self, uptime: Option<Duration>) -> bool {
+        match self.service.restart.min_runtime {
+            Some(min_runtime) => !matches!(uptime, Some(uptime) if uptime >= min_runtime),
+            None => false,
+        }
+    }
+
+    /// Called when the process has failed: it counts the immediate exits, and tells why the
+    /// failure is permanent, if it is.
+    pub fn classify_failure(&mut self, exit_code: i32, uptime: Option<Duration>) -> Option<String> {
+        let failure = &self.service.failure;
+        if let Some(min_runtime) = failure.min_runtime {
+            if matches!(uptime, Some(uptime) if uptime >= min_runtime) {
+                self.immediate_exits = 0;
+            } else {
+                self.immediate_exits += 1;
+            }
+            if self.immediate_exits >= failure.immediate_exits {
+                return Some(format!(
+                    "{} exits in a row within {:?}",
+                    self.immediate_exits, min_runtime
+                ));
+            }
+        }
+        if failure.is_permanent(exit_code) {
+            return Some(format!("exit code {}", exit_code));
+        }
+        None
+    }
+
+    /// Adds the current process, which has exited, to the run history.
+    pub fn record_run(&mut self, exit_code: i32) -> RunRecord {
+        let run = RunRecord {
+            start_time: self.start_time,
+            duration: self.started_at.map(|started_at| started_at.elapsed()),
+            exit_code,
+        };
+        self.run_history.push_back(run.clone());
+        if self.run_history.len() > RUN_HISTORY_LEN {
+            self.run_history.pop_front();
+        }
+        run
+    }
+
+    /// Keeps track of the failures, if the failure strategy has a threshold.
+    pub fn record_failure(&mut self, now: Instant) {
+        if let Some(threshold) = &self.service.failure.threshold {
+            self.failure_history.push_back(now);
+            while self.failure_history.len() > threshold.failures as usize {
+                self.failure_history.pop_front();
+            }
+        }
+    }
+
+    /// The failure strategy applies: always, unless the service hasn't failed often enough
+    /// within the threshold's window.
+    pub fn is_failure_threshold_reached(&self, now: Instant) -> bool {
+        match &self.service.failure.threshold {
+            Some(threshold) => {
+                self.failure_history.len() >= threshold.failures as usize
+                    && matches!(self.failure_history.front(),
+                        Some(oldest) if now.saturating_duration_since(*oldest) <= threshold.window)
+            }
+            None => true,
+        }
     }
 
     pub fn is_in_killing(&self) -> bool {
@@ -73,6 +396,94 @@ impl ServiceHandler {
         ServiceStatus::Finished == self.status
     }
 
+    pub fn is_idle_stopped(&self) -> bool {
+        ServiceStatus::Idle == self.status
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        ServiceStatus::Disabled == self.status
+    }
+
+    /// The service is ready as far as the log-pattern is concerned: it's matched, or it's not used.
+    pub fn is_log_pattern_satisfied(&self) -> bool {
+        self.service.healthiness.log_pattern.is_none() || self.log_pattern_matched
+    }
+
+    /// The service's process is being spawned or it's alive.
+    pub fn is_starting_or_up(&self) -> bool {
+        [
+            ServiceStatus::Starting,
+            ServiceStatus::Started,
+            ServiceStatus::Running,
+        ]
+        .contains(&self.status)
+    }
+
+    /// The current process has been running for longer than the max-runtime.
+    pub fn has_exceeded_max_runtime(&self) -> bool {
+        match (self.service.max_runtime, self.started_at) {
+            (Some(max_runtime), Some(started_at)) => started_at.elapsed() >= max_runtime,
+            _ => false,
+        }
+    }
+
+    /// The service hasn't served any connection for longer than stop-after-idle.
+    pub fn is_idle(&self) -> bool {
+        match (self.service.stop_after_idle, self.last_active) {
+            (Some(stop_after_idle), Some(last_active)) => last_active.elapsed() >= stop_after_idle,
+            _ => false,
+        }
+    }
+
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.stop_reason
+    }
+
+    pub fn is_stopping_for(&self, reason: StopReason) -> bool {
+        self.stop_reason == Some(reason)
+    }
+
+    /// Records why horust is stopping the service, unless it's already stopping it for a
+    /// stronger reason.
+    pub fn stop_for(&mut self, reason: StopReason) {
+        self.stop_reason = self.stop_reason.max(Some(reason));
+    }
+
+    /// Drops the reason, if it's the one the service is stopping for.
+    pub fn cancel_stop(&mut self, reason: StopReason) {
+        if self.is_stopping_for(reason) {
+            self.stop_reason = None;
+        }
+    }
+
+    /// It has been stopped for being started again: it's done.
+    pub fn end_restart(&mut self) {
+        self.cancel_stop(StopReason::Restart);
+        self.cancel_stop(StopReason::Binding);
+    }
+
+    /// If horust is stopping the service on its own (because of its stop reason, its max-runtime
+    /// or because it's idle), the status it should get once it's over (regardless of its exit
+    /// code).
+    pub fn planned_stop_status(&self) -> Option<ServiceStatus> {
+        if !self.is_in_killing() {
+            return None;
+        }
+        if let Some(reason) = self.stop_reason {
+            return Some(reason.stopped_status());
+        }
+        if self.has_exceeded_max_runtime() {
+            return Some(match self.service.max_runtime_outcome {
+                MaxRuntimeOutcome::Failed => ServiceStatus::Failed,
+                MaxRuntimeOutcome::Success => ServiceStatus::Success,
+            });
+        }
+        if self.is_idle() {
+            return Some(ServiceStatus::Idle);
+        }
+        None
+    }
+
     pub fn shutting_down_started(&mut self) {
         self.shutting_down_start = Some(Instant::now());
     }
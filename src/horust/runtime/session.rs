@@ -0,0 +1,216 @@
+use crate::horust::formats::KillScope;
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+/// How many times the stragglers are SIGKILLed, before giving up.
+const MAX_KILL_ATTEMPTS: u32 = 10;
+const KILL_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Every service is spawned in its own session, so the session id of a process is the pid of the
+/// service it belongs to (unless it has created a new session as well).
+/// Returns the session id read from /proc/<pid>/stat.
+pub(crate) fn read_session(pid: Pid) -> Option<Pid> {
+    read_stat(pid).map(|(_state, session)| session)
+}
+
+/// State and session id of the process.
+fn read_stat(pid: Pid) -> Option<(char, Pid)> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // Skip the process name, it's wrapped in parenthesis and might contain spaces.
+    let after_name = &stat[stat.rfind(')')? + 1..];
+    // Fields after the name: state, ppid, pgrp, session.
+    let mut fields = after_name.split_whitespace();
+    let state = fields.next()?.chars().next()?;
+    let session = fields.nth(2)?;
+    session
+        .parse()
+        .ok()
+        .map(|session| (state, Pid::from_raw(session)))
+}
+
+/// Alive processes, with their session id. Zombies are already dead, they're just waiting to
+/// be reaped.
+fn alive_processes() -> Vec<(Pid, Pid)> {
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(error) => {
+            error!("Cannot list the processes in /proc: {}", error);
+            return vec![];
+        }
+    };
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .map(Pid::from_raw)
+        .filter_map(|pid| match read_stat(pid) {
+            Some((state, session)) if state != 'Z' => Some((pid, session)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Alive processes belonging to the session.
+pub(crate) fn members(session: Pid) -> Vec<Pid> {
+    alive_processes()
+        .into_iter()
+        .filter(|(_pid, pid_session)| *pid_session == session)
+        .map(|(pid, _session)| pid)
+        .collect()
+}
+
+/// Alive processes grouped by session: like `members`, reading /proc once for all of them.
+pub(crate) fn members_by_session() -> HashMap<Pid, Vec<Pid>> {
+    let mut sessions: HashMap<Pid, Vec<Pid>> = HashMap::new();
+    for (pid, session) in alive_processes() {
+        sessions.entry(session).or_default().push(pid);
+    }
+    sessions
+}
+
+/// Alive processes of the service whose main process is `pid`, according to its `kill-scope`.
+/// The main process is always part of it, even if (e.g. adopted) it's not a session leader.
+#[cfg(target_os = "linux")]
+pub(crate) fn scope_members(pid: Pid, scope: KillScope) -> Vec<Pid> {
+    let mut scope_members = match scope {
+        KillScope::Process => vec![],
+        KillScope::Session => members(pid),
+    };
+    if !scope_members.contains(&pid) && is_alive(pid) {
+        scope_members.insert(0, pid);
+    }
+    scope_members
+}
+
+/// Without procfs the processes of the session cannot be listed: only the main process is
+/// known.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn scope_members(pid: Pid, _scope: KillScope) -> Vec<Pid> {
+    match signal::kill(pid, None) {
+        Ok(()) => vec![pid],
+        Err(_) => vec![],
+    }
+}
+
+/// Without procfs, the `session` scope is the process group of the service: it's its leader, so
+/// it's every descendant which hasn't moved to another group.
+#[cfg(not(target_os = "linux"))]
+fn signal_group(pid: Pid, scope: KillScope, signal: Signal) {
+    if scope == KillScope::Session {
+        let _res = signal::killpg(pid, signal);
+    }
+}
+
+/// The members of the group are signaled one by one.
+#[cfg(target_os = "linux")]
+fn signal_group(_pid: Pid, _scope: KillScope, _signal: Signal) {}
+
+#[cfg(target_os = "linux")]
+fn is_alive(pid: Pid) -> bool {
+    matches!(read_stat(pid), Some((state, _session)) if state != 'Z')
+}
+
+/// Sends the signal to every process in the scope. Only the errors of the main process are
+/// returned: the other ones might have exited in the meantime.
+pub(crate) fn signal_all(pid: Pid, scope: KillScope, signal: Signal) -> nix::Result<()> {
+    let result = signal::kill(pid, signal);
+    signal_group(pid, scope, signal);
+    for member in scope_members(pid, scope)
+        .into_iter()
+        .filter(|member| *member != pid)
+    {
+        debug!("Sending {} to {} (main process {})", signal, member, pid);
+        let _res = signal::kill(member, signal);
+    }
+    result
+}
+
+/// SIGKILLs every process in the scope, until none is left.
+/// Returns the processes which are still alive after all the attempts.
+pub(crate) fn kill_all(pid: Pid, scope: KillScope) -> Vec<Pid> {
+    let mut stragglers = scope_members(pid, scope);
+    for _ in 0..MAX_KILL_ATTEMPTS {
+        if stragglers.is_empty() {
+            break;
+        }
+        signal_group(pid, scope, Signal::SIGKILL);
+        for straggler in &stragglers {
+            debug!("Sending SIGKILL to {} (main process {})", straggler, pid);
+            let _res = signal::kill(*straggler, Signal::SIGKILL);
+        }
+        thread::sleep(KILL_RETRY_INTERVAL);
+        stragglers = scope_members(pid, scope);
+    }
+    stragglers
+}
+
+/// Like `kill_all`, but the retries run on their own thread, so the caller isn't stalled while
+/// the processes go away. The ones surviving every attempt are logged, with `owner`.
+pub(crate) fn kill_all_detached(pid: Pid, scope: KillScope, owner: String) {
+    let res = thread::Builder::new()
+        .name(format!("kill-{}", pid))
+        .spawn(move || {
+            let stragglers = kill_all(pid, scope);
+            if !stragglers.is_empty() {
+                error!(
+                    "Processes of {} have survived SIGKILL: {:?}",
+                    owner, stragglers
+                );
+            }
+        });
+    if let Err(error) = res {
+        error!("Cannot spawn the thread to SIGKILL {}: {}", pid, error);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::horust::formats::KillScope;
+    use crate::horust::runtime::session::{
+        kill_all, kill_all_detached, members, read_session, scope_members,
+    };
+    use nix::unistd::Pid;
+    use std::process::Command;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_kill_all() {
+        // Not a process group leader, so setsid won't fork: its pid is the session id.
+        let mut child = Command::new("setsid")
+            .args(vec!["bash", "-c", "sleep 30 & sleep 30 & wait"])
+            .spawn()
+            .unwrap();
+        let session = Pid::from_raw(child.id() as i32);
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(read_session(session), Some(session));
+        assert_eq!(members(session).len(), 3);
+        assert_eq!(scope_members(session, KillScope::Process), vec![session]);
+        // The children outlive their parent.
+        assert!(kill_all(session, KillScope::Process).is_empty());
+        assert_eq!(members(session).len(), 2);
+        assert!(kill_all(session, KillScope::Session).is_empty());
+        assert!(members(session).is_empty());
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_kill_all_detached() {
+        let mut child = Command::new("setsid")
+            .args(vec!["bash", "-c", "sleep 30 & wait"])
+            .spawn()
+            .unwrap();
+        let session = Pid::from_raw(child.id() as i32);
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(members(session).len(), 2);
+        kill_all_detached(session, KillScope::Session, "test".into());
+        child.wait().unwrap();
+        let mut attempts = 0;
+        while !members(session).is_empty() && attempts < 20 {
+            thread::sleep(Duration::from_millis(100));
+            attempts += 1;
+        }
+        assert!(members(session).is_empty());
+    }
+}
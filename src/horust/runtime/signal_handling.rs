@@ -1,12 +1,37 @@
 use crate::horust::signal_safe::ss_panic;
-use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, SIGINT, SIGTERM};
+use nix::sys::signal::{
+    sigaction, SaFlags, SigAction, SigHandler, SigSet, SIGINT, SIGQUIT, SIGTERM,
+};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
 static mut SIGTERM_RECEIVED: bool = false;
+static SIGINT_RECEIVED: AtomicU32 = AtomicU32::new(0);
+static SIGQUIT_RECEIVED: AtomicBool = AtomicBool::new(false);
 
 pub(crate) fn is_sigterm_received() -> bool {
     unsafe { SIGTERM_RECEIVED }
 }
 
+/// The signal which has started the shutdown.
+pub(crate) fn termination_signal() -> &'static str {
+    if SIGINT_RECEIVED.load(Ordering::SeqCst) > 0 {
+        "SIGINT"
+    } else {
+        "SIGTERM"
+    }
+}
+
+/// SIGINT has been received twice (e.g. Ctrl-C pressed again while shutting down): there is no
+/// time for waiting the services to gracefully exit.
+pub(crate) fn is_force_kill_requested() -> bool {
+    SIGINT_RECEIVED.load(Ordering::SeqCst) > 1
+}
+
+/// SIGQUIT has been received since the last call: the state should be dumped.
+pub(crate) fn is_state_dump_requested() -> bool {
+    SIGQUIT_RECEIVED.swap(false, Ordering::SeqCst)
+}
+
 /// Setup the signal handlers
 pub(crate) fn init() {
     // To allow auto restart on some syscalls,
@@ -19,10 +44,17 @@ pub(crate) fn init() {
         ss_panic(error.as_str(), 103);
     };
 
+    let sig_action = SigAction::new(SigHandler::Handler(handle_sigint), flags, SigSet::empty());
     if let Err(err) = unsafe { sigaction(SIGINT, &sig_action) } {
         let error = format!("sigaction() failed: {}", err);
         ss_panic(error.as_str(), 104);
     };
+
+    // Unlike the JVM's thread dump, horust keeps running.
+    let sig_action = SigAction::new(SigHandler::Handler(handle_sigquit), flags, SigSet::empty());
+    if let Err(err) = unsafe { sigaction(SIGQUIT, &sig_action) } {
+        error!("sigaction() failed, SIGQUIT won't dump the state: {}", err);
+    };
 }
 
 extern "C" fn handle_sigterm(_signal: libc::c_int) {
@@ -32,3 +64,12 @@ extern "C" fn handle_sigterm(_signal: libc::c_int) {
         SIGTERM_RECEIVED = true;
     }
 }
+
+extern "C" fn handle_sigint(signal: libc::c_int) {
+    SIGINT_RECEIVED.fetch_add(1, Ordering::SeqCst);
+    handle_sigterm(signal);
+}
+
+extern "C" fn handle_sigquit(_signal: libc::c_int) {
+    SIGQUIT_RECEIVED.store(true, Ordering::SeqCst);
+}
@@ -0,0 +1,126 @@
+//! Dry-run of a whole boot (`horust --simulate`): the runtime runs as usual, but nothing is
+//! spawned. Every service pretends to start and to become healthy after a fake delay (or to fail,
+//! if requested), and to exit as soon as it's killed. The events are printed along with their
+//! timing, so the dependencies and the failure strategies can be checked without side effects.
+use crate::horust::bus::BusConnector;
+use crate::horust::formats::{
+    Event, HealthCheckReport, HealthinessStatus, Service, ServiceName, ServiceStatus,
+    ShutdownReason,
+};
+use nix::unistd::Pid;
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The fake pids are above the highest pid allowed by Linux (2^22), so they can never belong to
+/// an actual process: signals sent to them are harmless.
+static NEXT_FAKE_PID: AtomicI32 = AtomicI32::new(1 << 23);
+
+#[derive(Debug, Clone)]
+pub(crate) struct Simulation {
+    /// How long each step takes: being spawned, becoming healthy, exiting once killed.
+    pub(crate) delay: Duration,
+    /// These services exit with an error instead of becoming healthy.
+    pub(crate) failing: Vec<ServiceName>,
+}
+
+/// Plays the part of the processes of the services, and prints the events on stdout.
+pub(crate) fn spawn(bus: BusConnector, services: Vec<Service>, simulation: Simulation) {
+    thread::Builder::new()
+        .name("simulation".into())
+        .spawn(move || run(bus, services, simulation))
+        .expect("Failed spawning the simulation thread");
+}
+
+fn run(bus: BusConnector, mut services: Vec<Service>, simulation: Simulation) {
+    let start = Instant::now();
+    // Once nothing has happened for this long, every service has settled: time to shut down.
+    let idle_timeout = (simulation.delay * 3).max(Duration::from_secs(1));
+    let pending = Arc::new(AtomicUsize::new(0));
+    let mut last_event = Instant::now();
+    let mut is_shutting_down = false;
+    loop {
+        let events = bus.try_get_events();
+        if !events.is_empty() {
+            last_event = Instant::now();
+        }
+        for ev in events {
+            let is_repeated = is_shutting_down && matches!(ev, Event::ShuttingDownInitiated(_));
+            if let (Some(line), false) = (describe(&ev), is_repeated) {
+                println!("+{:>8.3}s {}", start.elapsed().as_secs_f64(), line);
+            }
+            let after = |delay: Duration, ev: Event| {
+                let bus = bus.publisher();
+                let pending = pending.clone();
+                pending.fetch_add(1, Ordering::SeqCst);
+                thread::spawn(move || {
+                    thread::sleep(delay);
+                    bus.send_event(ev);
+                    pending.fetch_sub(1, Ordering::SeqCst);
+                });
+            };
+            match ev {
+                Event::StartRequested(s_name) => {
+                    let delay = services
+                        .iter()
+                        .find(|service| service.name == s_name)
+                        .map(|service| service.start_delay + service.start_jitter.pick())
+                        .unwrap_or_default();
+                    let pid = Pid::from_raw(NEXT_FAKE_PID.fetch_add(1, Ordering::SeqCst));
+                    after(
+                        delay + simulation.delay,
+                        Event::new_pid_changed(s_name, pid),
+                    );
+                }
+                Event::StatusChanged(s_name, ServiceStatus::Started) => {
+                    if simulation.failing.contains(&s_name) {
+                        after(simulation.delay, Event::ServiceExited(s_name, 1));
+                    } else {
+                        let report = HealthCheckReport {
+                            status: HealthinessStatus::Healthy,
+                            latency: Duration::from_secs(0),
+                            error: None,
+                        };
+                        after(simulation.delay, Event::HealthCheck(s_name, report));
+                    }
+                }
+                Event::StatusChanged(s_name, ServiceStatus::InKilling) => {
+                    after(simulation.delay, Event::ServiceExited(s_name, 0));
+                }
+                Event::ServiceCreated(service) => services.push(*service),
+                Event::ShuttingDownInitiated(_) => is_shutting_down = true,
+                _ => (),
+            }
+        }
+        if !is_shutting_down
+            && pending.load(Ordering::SeqCst) == 0
+            && last_event.elapsed() > idle_timeout
+        {
+            is_shutting_down = true;
+            // The bus doesn't deliver it back to the sender: it's printed here.
+            let ev = Event::ShuttingDownInitiated(ShutdownReason::SimulationOver);
+            println!(
+                "+{:>8.3}s {}",
+                start.elapsed().as_secs_f64(),
+                describe(&ev).unwrap()
+            );
+            bus.send_event(ev);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// The events worth printing: the ones showing the progress of the services.
+fn describe(ev: &Event) -> Option<String> {
+    match ev {
+        Event::StartRequested(s_name) => Some(format!("{}: start requested", s_name)),
+        Event::StatusChanged(s_name, status) => Some(format!("{}: {}", s_name, status)),
+        Event::ServiceExited(s_name, exit_code) => {
+            Some(format!("{}: exited with {}", s_name, exit_code))
+        }
+        Event::HealthcheckFailed(s_name, _) => Some(format!("{}: healthchecks failed", s_name)),
+        Event::ShuttingDownInitiated(reason) => Some(format!("shutting down: {}", reason)),
+        _ => None,
+    }
+}
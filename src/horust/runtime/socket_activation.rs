@@ -0,0 +1,146 @@
+use crate::horust::error::Result;
+use crate::horust::formats::{Service, ServiceName, MAX_LISTENERS};
+use nix::fcntl::{fcntl, FcntlArg};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::unistd;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+/// Number of the first fd passed to the service, after stdin, stdout and stderr.
+const LISTEN_FDS_START: RawFd = 3;
+/// Used for moving the fds out of the way, before placing them starting from LISTEN_FDS_START.
+const LISTEN_FDS_TEMP_START: RawFd = 64;
+const LISTEN_PID_PREFIX: &[u8] = b"LISTEN_PID=";
+
+/// Sockets bound by horust on behalf of the services, so they survive the restarts of the
+/// services. The instances of a replicated service share them, unless they have their own port
+/// (check `port-range`).
+#[derive(Debug, Default)]
+pub(crate) struct Listeners(HashMap<ServiceName, Vec<TcpListener>>);
+
+impl Listeners {
+    /// Binds the sockets of the service, unless they've been already bound.
+    pub(crate) fn get_or_bind(&mut self, service: &Service) -> Result<Vec<RawFd>> {
+        let key = Self::key(service);
+        if !self.0.contains_key(key) {
+            let listeners = service
+                .listen
+                .iter()
+                .map(TcpListener::bind)
+                .collect::<std::io::Result<Vec<TcpListener>>>()?;
+            debug!("Bound the sockets of {}: {:?}", key, listeners);
+            self.0.insert(key.clone(), listeners);
+        }
+        Ok(self.0[key].iter().map(AsRawFd::as_raw_fd).collect())
+    }
+
+    /// A connection is waiting to be accepted on any of the sockets of the service.
+    pub(crate) fn has_pending_connection(&self, service: &Service) -> bool {
+        let mut fds: Vec<PollFd> = match self.0.get(Self::key(service)) {
+            Some(listeners) => listeners
+                .iter()
+                .map(|listener| PollFd::new(listener.as_raw_fd(), PollFlags::POLLIN))
+                .collect(),
+            None => return false,
+        };
+        matches!(poll(&mut fds, 0), Ok(ready) if ready > 0)
+    }
+
+    fn key(service: &Service) -> &ServiceName {
+        match &service.replica {
+            Some(replica) if service.port_range.is_none() => &replica.of,
+            _ => &service.name,
+        }
+    }
+}
+
+/// The listening sockets passed to the service, using systemd's protocol: they're available
+/// from fd 3 on, and `LISTEN_FDS` and `LISTEN_PID` are set in its environment.
+pub(crate) struct ListenFds {
+    fds: Vec<RawFd>,
+    /// `LISTEN_PID=<pid>`, filled by the child: it's preallocated, for being async-signal-safe.
+    listen_pid: Vec<u8>,
+}
+
+impl ListenFds {
+    pub(crate) fn new(fds: Vec<RawFd>) -> Self {
+        let mut listen_pid = LISTEN_PID_PREFIX.to_vec();
+        // Room for the pid and the nul terminator.
+        listen_pid.resize(LISTEN_PID_PREFIX.len() + 21, 0);
+        Self { fds, listen_pid }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.fds.is_empty()
+    }
+
+    pub(crate) fn environment(&self) -> Vec<String> {
+        if self.is_empty() {
+            return vec![];
+        }
+        vec![format!("LISTEN_FDS={}", self.fds.len())]
+    }
+
+    /// Child side: places the sockets from fd 3 on (without close-on-exec), and returns the
+    /// `LISTEN_PID` environment variable.
+    /// Warning: only async-signal-safe calls here.
+    pub(crate) fn redirect_child(&mut self) -> Result<&CStr> {
+        // The target fds might be in use by the sockets themselves, so they're moved away first.
+        let mut temp_fds = [0; MAX_LISTENERS];
+        for (temp_fd, fd) in temp_fds.iter_mut().zip(&self.fds) {
+            *temp_fd = fcntl(*fd, FcntlArg::F_DUPFD_CLOEXEC(LISTEN_FDS_TEMP_START))?;
+        }
+        for (index, temp_fd) in temp_fds.iter().take(self.fds.len()).enumerate() {
+            unistd::dup2(*temp_fd, LISTEN_FDS_START + index as RawFd)?;
+        }
+        let mut pid = unistd::getpid().as_raw() as u32;
+        let mut digits = [0u8; 20];
+        let mut len = 0;
+        loop {
+            digits[len] = b'0' + (pid % 10) as u8;
+            len += 1;
+            pid /= 10;
+            if pid == 0 {
+                break;
+            }
+        }
+        let start = LISTEN_PID_PREFIX.len();
+        for (offset, digit) in digits[..len].iter().rev().enumerate() {
+            self.listen_pid[start + offset] = *digit;
+        }
+        self.listen_pid[start + len] = 0;
+        Ok(CStr::from_bytes_until_nul(&self.listen_pid).expect("LISTEN_PID without nul"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::horust::formats::Service;
+    use crate::horust::runtime::socket_activation::Listeners;
+    use std::net::TcpStream;
+
+    #[test]
+    fn test_listeners_are_bound_once() {
+        let mut service = Service::from_command("cmd".into());
+        service.listen = vec!["127.0.0.1:0".into()];
+        let mut listeners = Listeners::default();
+        let fds = listeners.get_or_bind(&service).unwrap();
+        assert_eq!(fds.len(), 1);
+        assert_eq!(listeners.get_or_bind(&service).unwrap(), fds);
+    }
+
+    #[test]
+    fn test_has_pending_connection() {
+        let mut service = Service::from_command("cmd".into());
+        service.listen = vec!["127.0.0.1:0".into()];
+        let mut listeners = Listeners::default();
+        assert!(!listeners.has_pending_connection(&service));
+        listeners.get_or_bind(&service).unwrap();
+        assert!(!listeners.has_pending_connection(&service));
+        let address = listeners.0[&service.name][0].local_addr().unwrap();
+        let _stream = TcpStream::connect(address).unwrap();
+        assert!(listeners.has_pending_connection(&service));
+    }
+}
@@ -0,0 +1,158 @@
+//! Last known state of the services, kept in a json file for the external tools (sidecars,
+//! debuggers) which would rather read a file than speak the control protocol.
+use crate::horust::formats::{ServiceName, ServiceStatus};
+use crate::horust::runtime::service_handler::StopReason;
+use crate::horust::status_board::{RuntimeStats, ServiceSnapshot};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct State {
+    shutting_down: bool,
+    shutdown_reason: Option<String>,
+    services: BTreeMap<ServiceName, ServiceState>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct ServiceState {
+    status: ServiceStatus,
+    pid: Option<i32>,
+    /// Rfc3339, using the system clock.
+    start_time: Option<String>,
+    restart_attempts: u32,
+    starts: u32,
+    /// Of the last round of healthchecks, if any.
+    health: Option<String>,
+    is_masked: bool,
+}
+
+impl From<&ServiceSnapshot> for ServiceState {
+    fn from(snapshot: &ServiceSnapshot) -> Self {
+        Self {
+            status: snapshot.status.clone(),
+            pid: snapshot.pid.map(|pid| pid.as_raw()),
+            start_time: snapshot.start_time_rfc3339(),
+            restart_attempts: snapshot.restart_attempts,
+            starts: snapshot.starts,
+            health: snapshot
+                .last_health_check
+                .as_ref()
+                .map(|report| format!("{:?}", report.status)),
+            is_masked: snapshot.stop_reason == Some(StopReason::Masked),
+        }
+    }
+}
+
+/// Rewrites the file whenever the state changes.
+#[derive(Debug)]
+pub(crate) struct StateFile {
+    path: PathBuf,
+    /// Content of the last write, so the same state isn't written again at every iteration.
+    last: Option<String>,
+    /// Errors are logged once, until a write succeeds again.
+    is_failing: bool,
+}
+
+impl StateFile {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            last: None,
+            is_failing: false,
+        }
+    }
+
+    pub(crate) fn update(&mut self, services: &[ServiceSnapshot], runtime: &RuntimeStats) {
+        match self.write(services, runtime) {
+            Ok(_) => self.is_failing = false,
+            Err(error) => {
+                if !self.is_failing {
+                    error!(
+                        "Cannot write the state file {}: {}",
+                        self.path.display(),
+                        error
+                    );
+                }
+                self.is_failing = true;
+            }
+        }
+    }
+
+    /// False if the state hasn't changed since the last write.
+    fn write(&mut self, services: &[ServiceSnapshot], runtime: &RuntimeStats) -> io::Result<bool> {
+        let state = State {
+            shutting_down: runtime.is_shutting_down,
+            shutdown_reason: runtime.shutdown_reason.clone(),
+            services: services
+                .iter()
+                .map(|snapshot| (snapshot.name.clone(), snapshot.into()))
+                .collect(),
+        };
+        let mut content = serde_json::to_string_pretty(&state)?;
+        content.push('\n');
+        if self.last.as_ref() == Some(&content) {
+            return Ok(false);
+        }
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Written aside and then renamed, so the readers never see a partial file.
+        let mut tmp_name = self
+            .path
+            .file_name()
+            .map(OsString::from)
+            .unwrap_or_default();
+        tmp_name.push(".tmp");
+        let tmp_path = self.path.with_file_name(tmp_name);
+        fs::write(&tmp_path, &content)?;
+        fs::rename(tmp_path, &self.path)?;
+        self.last = Some(content);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::StateFile;
+    use crate::horust::formats::{Service, ServiceStatus};
+    use crate::horust::runtime::service_handler::ServiceHandler;
+    use crate::horust::status_board::RuntimeStats;
+    use nix::unistd::Pid;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_state_file() {
+        let tempdir = TempDir::new("state_file").unwrap();
+        let path = tempdir.path().join("run").join("state.json");
+        let mut state_file = StateFile::new(path.clone());
+        let mut sh: ServiceHandler = Service::from_name("web.toml").into();
+        sh.status = ServiceStatus::Running;
+        sh.pid = Some(Pid::from_raw(42));
+        let services = vec![sh.snapshot()];
+        let runtime = RuntimeStats::default();
+
+        assert!(state_file.write(&services, &runtime).unwrap());
+        let state: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(state["shutting-down"], false);
+        assert_eq!(state["services"]["web.toml"]["status"], "Running");
+        assert_eq!(state["services"]["web.toml"]["pid"], 42);
+        assert!(!tempdir.path().join("run").join("state.json.tmp").exists());
+        assert!(!state_file.write(&services, &runtime).unwrap());
+
+        let runtime = RuntimeStats {
+            is_shutting_down: true,
+            ..Default::default()
+        };
+        assert!(state_file.write(&services, &runtime).unwrap());
+        let state: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(state["shutting-down"], true);
+    }
+}
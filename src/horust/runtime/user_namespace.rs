@@ -0,0 +1,88 @@
+use crate::horust::error::Result;
+use crate::horust::formats::UserNamespace;
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+#[cfg(target_os = "linux")]
+use nix::sched::{unshare, CloneFlags};
+use nix::unistd::{self, Gid, Pid};
+use std::os::unix::io::RawFd;
+
+/// Moves the child into a new user namespace. The child cannot write arbitrary uid and gid maps
+/// on its own, so they're written by horust: the child waits for them using a pair of pipes.
+pub(crate) struct UserNamespaceSync {
+    uid_map: String,
+    gid_map: String,
+    /// Written by the child, once it has entered the new namespace.
+    unshared: (RawFd, RawFd),
+    /// Written by horust, once the maps are in place.
+    mapped: (RawFd, RawFd),
+}
+
+impl UserNamespaceSync {
+    pub(crate) fn new(user_namespace: &UserNamespace) -> Result<Self> {
+        let parse = |map: &str| {
+            UserNamespace::parse_map(map)
+                .map(|ranges| UserNamespace::format_map(&ranges))
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))
+        };
+        Ok(Self {
+            uid_map: parse(&user_namespace.uid_map)?,
+            gid_map: parse(&user_namespace.gid_map)?,
+            unshared: unistd::pipe2(OFlag::O_CLOEXEC)?,
+            mapped: unistd::pipe2(OFlag::O_CLOEXEC)?,
+        })
+    }
+
+    /// Child side: enters the new namespace, waits for the maps, and drops the groups of horust.
+    /// The uid is set later on, as usual.
+    /// Warning: only async-signal-safe calls here.
+    pub(crate) fn enter_child(&self) -> Result<()> {
+        let _ = unistd::close(self.unshared.0);
+        let _ = unistd::close(self.mapped.1);
+        unshare_user()?;
+        unistd::write(self.unshared.1, &[1])?;
+        let mut buf = [0; 1];
+        if unistd::read(self.mapped.0, &mut buf)? != 1 {
+            // Horust has failed writing the maps.
+            return Err(nix::Error::Sys(Errno::EPERM).into());
+        }
+        #[cfg(target_os = "linux")]
+        unistd::setgroups(&[])?;
+        unistd::setgid(Gid::from_raw(0))?;
+        Ok(())
+    }
+
+    /// Parent side: writes the maps of the child, once it has entered its new namespace.
+    /// If they cannot be written, the child exits.
+    pub(crate) fn write_maps(self, child: Pid) -> Result<()> {
+        let _ = unistd::close(self.unshared.1);
+        let _ = unistd::close(self.mapped.0);
+        let res = self.try_write_maps(child);
+        let _ = unistd::close(self.unshared.0);
+        let _ = unistd::close(self.mapped.1);
+        res
+    }
+
+    fn try_write_maps(&self, child: Pid) -> Result<()> {
+        let mut buf = [0; 1];
+        if unistd::read(self.unshared.0, &mut buf)? != 1 {
+            // The child has exited before entering the namespace.
+            return Err(nix::Error::Sys(Errno::ESRCH).into());
+        }
+        std::fs::write(format!("/proc/{}/uid_map", child), &self.uid_map)?;
+        std::fs::write(format!("/proc/{}/gid_map", child), &self.gid_map)?;
+        unistd::write(self.mapped.1, &[1])?;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn unshare_user() -> nix::Result<()> {
+    unshare(CloneFlags::CLONE_NEWUSER)
+}
+
+/// User namespaces exist only on Linux, the validation doesn't allow them anywhere else.
+#[cfg(not(target_os = "linux"))]
+fn unshare_user() -> nix::Result<()> {
+    Err(nix::Error::Sys(Errno::ENOSYS))
+}
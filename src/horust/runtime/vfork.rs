@@ -0,0 +1,291 @@
+//! Spawner based on `clone(CLONE_VM | CLONE_VFORK)` (feature `vfork-spawn`), for the services
+//! which can't be spawned using posix_spawn. The child runs in the memory of horust until the
+//! exec, so the page tables are not copied: with many services, or services restarted often, fork
+//! is slower the more memory horust uses. horust's thread is suspended until the exec.
+//! Since the memory is shared, the child can't allocate, take a lock or unwind: everything is
+//! prepared by the parent, and the child only makes raw syscalls.
+//! It's clone rather than clone3: only the wrapper of glibc starts the child on its own stack.
+use crate::horust::error::{ErrorKind, Result};
+use crate::horust::formats::LogOutput;
+use nix::errno::Errno;
+use nix::sys::wait::waitpid;
+use nix::unistd::{Pid, Uid};
+use std::ffi::{CStr, CString};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+/// The child only calls a few functions before the exec: a small stack is enough.
+const CHILD_STACK_SIZE: usize = 64 * 1024;
+/// The signals are numbered from 1 to 64 on Linux.
+const NSIG: libc::c_int = 65;
+
+/// How a fd of the child is replaced before the exec.
+pub(crate) enum Redirect {
+    /// dup2(from, to).
+    Dup(RawFd, RawFd),
+    /// The file is opened (appending), and moved to the fd.
+    Open(CString, RawFd),
+}
+
+impl Redirect {
+    /// The redirections needed for the outputs of a service, stdout first.
+    pub(crate) fn outputs(stdout: &LogOutput, stderr: &LogOutput) -> Result<Vec<Self>> {
+        let path = |path: &Path| CString::new(path.as_os_str().as_bytes());
+        let mut redirects = vec![];
+        match stdout {
+            LogOutput::Stderr => {
+                redirects.push(Redirect::Dup(libc::STDERR_FILENO, libc::STDOUT_FILENO))
+            }
+            LogOutput::Path(stdout) => {
+                redirects.push(Redirect::Open(path(stdout)?, libc::STDOUT_FILENO))
+            }
+            LogOutput::Stdout => (),
+        }
+        match stderr {
+            LogOutput::Stdout => {
+                redirects.push(Redirect::Dup(libc::STDOUT_FILENO, libc::STDERR_FILENO))
+            }
+            LogOutput::Path(stderr) => {
+                redirects.push(Redirect::Open(path(stderr)?, libc::STDERR_FILENO))
+            }
+            LogOutput::Stderr => (),
+        }
+        Ok(redirects)
+    }
+}
+
+/// Everything the child needs, owned by the parent (which is suspended until the exec).
+struct ChildSetup {
+    program_name: *const libc::c_char,
+    argv: Vec<*const libc::c_char>,
+    envp: Vec<*const libc::c_char>,
+    cwd: CString,
+    uid: libc::uid_t,
+    redirects: Vec<Redirect>,
+    /// The signal mask of the parent, restored in the child.
+    sigmask: libc::sigset_t,
+    /// Set by the child if anything before the exec has failed.
+    errno: libc::c_int,
+}
+
+/// Spawns the process in a new session, like the fork path does. If the exec fails (or anything
+/// before it), the child has already been reaped and the error is returned.
+pub(crate) fn spawn(
+    program_name: &CStr,
+    arg_cstrings: &[CString],
+    env_cstrings: &[CString],
+    cwd: &Path,
+    uid: Uid,
+    redirects: Vec<Redirect>,
+) -> Result<Pid> {
+    let to_pointers = |cstrings: &[CString]| -> Vec<*const libc::c_char> {
+        cstrings
+            .iter()
+            .map(|cstring| cstring.as_ptr())
+            .chain(std::iter::once(std::ptr::null()))
+            .collect()
+    };
+    let mut setup = ChildSetup {
+        program_name: program_name.as_ptr(),
+        argv: to_pointers(arg_cstrings),
+        envp: to_pointers(env_cstrings),
+        cwd: CString::new(cwd.as_os_str().as_bytes())?,
+        uid: uid.as_raw(),
+        redirects,
+        sigmask: unsafe { std::mem::zeroed() },
+        errno: 0,
+    };
+    let mut stack = vec![0u8; CHILD_STACK_SIZE];
+    // The stack grows downwards, and it must be aligned to 16 bytes.
+    let stack_top = (stack.as_mut_ptr() as usize + CHILD_STACK_SIZE) & !15;
+    let pid = unsafe {
+        // No signal handler of horust can run in the child before it resets them: they'd run on
+        // the memory of horust.
+        let mut all: libc::sigset_t = std::mem::zeroed();
+        libc::sigfillset(&mut all);
+        libc::pthread_sigmask(libc::SIG_SETMASK, &all, &mut setup.sigmask);
+        let pid = libc::clone(
+            child,
+            stack_top as *mut libc::c_void,
+            libc::CLONE_VM | libc::CLONE_VFORK | libc::SIGCHLD,
+            &mut setup as *mut ChildSetup as *mut libc::c_void,
+        );
+        let clone_errno = Errno::last();
+        libc::pthread_sigmask(libc::SIG_SETMASK, &setup.sigmask, std::ptr::null_mut());
+        if pid < 0 {
+            return Err(nix::Error::Sys(clone_errno).into());
+        }
+        Pid::from_raw(pid)
+    };
+    // Here the child has either called exec or exited.
+    let errno = unsafe { std::ptr::read_volatile(&setup.errno) };
+    if errno != 0 {
+        let _ = waitpid(pid, None);
+        return Err(ErrorKind::Exec(Errno::from_i32(errno)).into());
+    }
+    debug!("Spawned child with PID {} (vfork).", pid);
+    Ok(pid)
+}
+
+/// The child, until the exec. Warning: only raw, async-signal-safe syscalls here.
+extern "C" fn child(setup: *mut libc::c_void) -> libc::c_int {
+    let setup = unsafe { &mut *(setup as *mut ChildSetup) };
+    unsafe {
+        // The handlers of horust are not going to be there after the exec anyway.
+        let mut action: libc::sigaction = std::mem::zeroed();
+        for signal in 1..NSIG {
+            if libc::sigaction(signal, std::ptr::null(), &mut action) == 0
+                && action.sa_sigaction != libc::SIG_DFL
+                && action.sa_sigaction != libc::SIG_IGN
+            {
+                action.sa_sigaction = libc::SIG_DFL;
+                libc::sigaction(signal, &action, std::ptr::null_mut());
+            }
+        }
+        libc::pthread_sigmask(libc::SIG_SETMASK, &setup.sigmask, std::ptr::null_mut());
+        if setup_child(setup) {
+            libc::execvpe(setup.program_name, setup.argv.as_ptr(), setup.envp.as_ptr());
+        }
+        setup.errno = match Errno::last() as libc::c_int {
+            0 => libc::EINVAL,
+            errno => errno,
+        };
+        libc::_exit(127)
+    }
+}
+
+/// Same steps of the fork path: outputs, working directory, session and user. False if any fails.
+unsafe fn setup_child(setup: &ChildSetup) -> bool {
+    for redirect in &setup.redirects {
+        match redirect {
+            Redirect::Dup(from, to) => {
+                if libc::dup2(*from, *to) < 0 {
+                    return false;
+                }
+            }
+            Redirect::Open(path, to) => {
+                let fd = libc::open(
+                    path.as_ptr(),
+                    libc::O_CREAT | libc::O_WRONLY | libc::O_APPEND,
+                    libc::S_IRWXU,
+                );
+                if fd < 0 || libc::dup2(fd, *to) < 0 {
+                    return false;
+                }
+                libc::close(fd);
+            }
+        }
+    }
+    libc::chdir(setup.cwd.as_ptr()) == 0 && libc::setsid() >= 0 && libc::setuid(setup.uid) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::{spawn, Redirect};
+    use crate::horust::formats::LogOutput;
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd;
+    use std::ffi::CString;
+    use std::time::{Duration, Instant};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_spawn() {
+        let tempdir = TempDir::new("vfork").unwrap();
+        let output = tempdir.path().join("output.log");
+        let redirects =
+            Redirect::outputs(&LogOutput::Path(output.clone()), &LogOutput::Stdout).unwrap();
+        let program_name = CString::new("sh").unwrap();
+        let args: Vec<CString> = vec![
+            "sh",
+            "-c",
+            "pwd; echo $GREETING; echo oops >&2; test $(ps -o sid= $$) -eq $$",
+        ]
+        .into_iter()
+        .map(|arg| CString::new(arg).unwrap())
+        .collect();
+        let env = vec![
+            CString::new("GREETING=hello").unwrap(),
+            CString::new("PATH=/bin:/usr/bin").unwrap(),
+        ];
+        let pid = spawn(
+            &program_name,
+            &args,
+            &env,
+            tempdir.path(),
+            unistd::getuid(),
+            redirects,
+        )
+        .unwrap();
+        assert_eq!(waitpid(pid, None).unwrap(), WaitStatus::Exited(pid, 0));
+        let expected = format!("{}\nhello\noops\n", tempdir.path().display());
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), expected);
+
+        let program_name = CString::new("/not/a/program").unwrap();
+        let error = spawn(
+            &program_name,
+            &[program_name.clone()],
+            &env,
+            tempdir.path(),
+            unistd::getuid(),
+            vec![],
+        )
+        .unwrap_err();
+        assert_eq!(error.exec_errno(), Some(libc::ENOENT));
+        let error = spawn(
+            &CString::new("sh").unwrap(),
+            &args,
+            &env,
+            &tempdir.path().join("missing"),
+            unistd::getuid(),
+            vec![],
+        )
+        .unwrap_err();
+        assert_eq!(error.exec_errno(), Some(libc::ENOENT));
+    }
+
+    /// Compares fork and clone, with horust using 512MiB of memory:
+    /// `cargo test --release --features vfork-spawn bench_spawn -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_spawn() {
+        const SPAWNS: u32 = 200;
+        // Touched, so that it's actually mapped.
+        let memory = vec![1u8; 512 * 1024 * 1024];
+        let program_name = CString::new("/bin/true").unwrap();
+        let args = [program_name.clone()];
+        let time = |spawn: &dyn Fn() -> unistd::Pid| -> Duration {
+            let start = Instant::now();
+            for _ in 0..SPAWNS {
+                let pid = spawn();
+                waitpid(pid, None).unwrap();
+            }
+            start.elapsed() / SPAWNS
+        };
+        let fork = time(&|| match unistd::fork().unwrap() {
+            unistd::ForkResult::Child => {
+                let _ = unistd::execv(&program_name, &[program_name.as_c_str()]);
+                unsafe { libc::_exit(127) }
+            }
+            unistd::ForkResult::Parent { child } => child,
+        });
+        let clone = time(&|| {
+            spawn(
+                &program_name,
+                &args,
+                &[],
+                std::path::Path::new("/"),
+                unistd::getuid(),
+                vec![],
+            )
+            .unwrap()
+        });
+        println!(
+            "fork: {:?}, clone(CLONE_VM | CLONE_VFORK): {:?} per spawn ({} bytes mapped)",
+            fork,
+            clone,
+            memory.len()
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use crate::horust::formats::{SpawnError, WaitFor};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a precondition is checked.
+const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+/// Timeout for every single tcp connection attempt.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Blocks until all the preconditions are met.
+/// Fails if any of them has timed out, or if `should_stop` returns true (`SpawnError::Aborted`).
+pub(crate) fn wait_all<F>(conditions: &[WaitFor], should_stop: F) -> Result<(), SpawnError>
+where
+    F: Fn() -> bool,
+{
+    for condition in conditions {
+        let deadline = Instant::now() + condition.timeout;
+        loop {
+            if is_met(condition) {
+                debug!("Precondition met: {:?}", condition);
+                break;
+            }
+            if Instant::now() > deadline {
+                error!("Precondition timed out: {:?}", condition);
+                return Err(SpawnError::WaitForTimedOut(condition.to_string()));
+            }
+            if should_stop() {
+                return Err(SpawnError::Aborted);
+            }
+            thread::sleep(CHECK_INTERVAL);
+        }
+    }
+    Ok(())
+}
+
+fn is_met(condition: &WaitFor) -> bool {
+    if let Some(address) = &condition.tcp {
+        is_tcp_reachable(address)
+    } else if let Some(hostname) = &condition.dns {
+        is_resolvable(hostname)
+    } else {
+        true
+    }
+}
+
+fn is_tcp_reachable(address: &str) -> bool {
+    match address.to_socket_addrs() {
+        Ok(mut addrs) => {
+            addrs.any(|addr| TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).is_ok())
+        }
+        Err(error) => {
+            debug!("Cannot resolve: {}, error: {}", address, error);
+            false
+        }
+    }
+}
+
+fn is_resolvable(hostname: &str) -> bool {
+    // The port is needed by the resolver, but it's not used.
+    (hostname, 0)
+        .to_socket_addrs()
+        .map(|mut addrs| addrs.next().is_some())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::horust::formats::{SpawnError, WaitFor};
+    use crate::horust::runtime::wait_for::wait_all;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    fn tcp(address: String) -> WaitFor {
+        WaitFor {
+            tcp: Some(address),
+            dns: None,
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn test_wait_for_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        assert_eq!(wait_all(&[tcp(address.clone())], || false), Ok(()));
+        drop(listener);
+        assert_eq!(
+            wait_all(&[tcp(address.clone())], || false),
+            Err(SpawnError::WaitForTimedOut(format!("tcp {}", address)))
+        );
+        assert_eq!(wait_all(&[tcp(address)], || true), Err(SpawnError::Aborted));
+    }
+
+    #[test]
+    fn test_wait_for_dns() {
+        let dns = |hostname: &str| WaitFor {
+            tcp: None,
+            dns: Some(hostname.into()),
+            timeout: Duration::from_secs(1),
+        };
+        assert_eq!(wait_all(&[dns("localhost")], || false), Ok(()));
+        assert_eq!(
+            wait_all(&[dns("does-not-exist.invalid")], || false),
+            Err(SpawnError::WaitForTimedOut(
+                "dns does-not-exist.invalid".into()
+            ))
+        );
+    }
+}
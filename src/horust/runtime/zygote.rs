@@ -0,0 +1,304 @@
+//! Spawner for the sandboxed services: a single threaded helper process, forked by horust at boot
+//! before any thread is started. Setting up a sandbox between fork and exec is fragile in a
+//! multithreaded process (nothing which might take a lock can be used), while the zygote is free
+//! to do it. It receives the requests over a socket, one json object per line.
+//! The zygote forks twice: once the intermediate process exits, the service is reparented to
+//! horust (a subreaper), which reaps it as usual.
+use crate::horust::error::{ErrorKind, Result};
+use crate::horust::formats::{LogOutput, UserNamespace};
+use crate::horust::runtime::process_spawner::{exec, exec_error_pipe, redirect_output, wait_exec};
+use crate::horust::runtime::user_namespace::UserNamespaceSync;
+use crate::horust::signal_safe::ss_panic;
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::sys::signal::{self, SigHandler, Signal};
+use nix::sys::wait::waitpid;
+use nix::unistd::{self, fork, ForkResult, Pid, Uid};
+use serde::{Deserialize, Serialize};
+use std::ffi::CString;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// Signals sent to the whole process group (e.g. Ctrl-C) which shouldn't stop the zygote: it
+/// exits once horust closes the socket.
+const IGNORED_SIGNALS: [Signal; 3] = [Signal::SIGINT, Signal::SIGTERM, Signal::SIGQUIT];
+
+/// Everything needed for spawning a sandboxed service, prepared by horust.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SpawnRequest {
+    pub(crate) service: String,
+    pub(crate) program_name: CString,
+    pub(crate) arg_cstrings: Vec<CString>,
+    pub(crate) env_cstrings: Vec<CString>,
+    pub(crate) uid: u32,
+    pub(crate) cwd: PathBuf,
+    pub(crate) stdout: LogOutput,
+    pub(crate) stderr: LogOutput,
+    pub(crate) user_namespace: Option<UserNamespace>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum SpawnResponse {
+    Spawned(i32),
+    /// The errno of the exec, or of anything before it.
+    ExecFailed(i32),
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Zygote {
+    pid: Pid,
+    /// One request at a time: the zygote is single threaded anyway.
+    stream: Arc<Mutex<BufReader<UnixStream>>>,
+}
+
+impl Zygote {
+    /// Forks the zygote. Must be called before starting any thread.
+    pub(crate) fn start() -> Result<Self> {
+        let (stream, zygote_stream) = UnixStream::pair()?;
+        match fork()? {
+            ForkResult::Child => {
+                drop(stream);
+                // Whatever happens, the zygote must never return into horust's code.
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| serve(zygote_stream)));
+                unsafe { libc::_exit(0) }
+            }
+            ForkResult::Parent { child } => {
+                debug!("Started the zygote, with PID {}.", child);
+                Ok(Self {
+                    pid: child,
+                    stream: Arc::new(Mutex::new(BufReader::new(stream))),
+                })
+            }
+        }
+    }
+
+    /// None if the zygote cannot be reached anymore: then the service has to be spawned by
+    /// horust itself.
+    pub(crate) fn spawn(&self, request: &SpawnRequest) -> Option<Result<Pid>> {
+        let response = self.send(request).map_err(|error| {
+            warn!(
+                "The zygote (PID {}) cannot be reached, spawning {} without it: {}",
+                self.pid, request.service, error
+            );
+        });
+        match response.ok()? {
+            SpawnResponse::Spawned(pid) => {
+                debug!("Spawned child with PID {} (zygote).", pid);
+                Some(Ok(Pid::from_raw(pid)))
+            }
+            SpawnResponse::ExecFailed(errno) => {
+                Some(Err(ErrorKind::Exec(Errno::from_i32(errno)).into()))
+            }
+            SpawnResponse::Failed(error) => Some(Err(io::Error::other(error).into())),
+        }
+    }
+
+    fn send(&self, request: &SpawnRequest) -> io::Result<SpawnResponse> {
+        let mut stream = self
+            .stream
+            .lock()
+            .map_err(|_| io::Error::other("poisoned lock"))?;
+        let mut line = serde_json::to_string(request)?;
+        line.push('\n');
+        stream.get_mut().write_all(line.as_bytes())?;
+        line.clear();
+        if stream.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(serde_json::from_str(&line)?)
+    }
+}
+
+/// The zygote: serves the requests until horust closes the socket.
+fn serve(stream: UnixStream) {
+    for signal in IGNORED_SIGNALS.iter() {
+        let _ = unsafe { signal::signal(*signal, SigHandler::SigIgn) };
+    }
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => spawn(&request),
+            Err(error) => SpawnResponse::Failed(format!("Invalid request: {}", error)),
+        };
+        let mut line = serde_json::to_string(&response).unwrap_or_default();
+        line.push('\n');
+        if writer.write_all(line.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+/// Zygote side: forks the intermediate process, which reports the pid of the service (or the
+/// error) through a pipe, as a pair of i32 (pid, errno).
+fn spawn(request: &SpawnRequest) -> SpawnResponse {
+    let (report_read, report_write) = match unistd::pipe2(OFlag::O_CLOEXEC) {
+        Ok(pipe) => pipe,
+        Err(error) => return SpawnResponse::Failed(error.to_string()),
+    };
+    match fork() {
+        Ok(ForkResult::Child) => {
+            let _ = unistd::close(report_read);
+            let (pid, errno) = match launch(request) {
+                Ok(pid) => (pid.as_raw(), 0),
+                Err(error) => (0, error.exec_errno().unwrap_or(-1)),
+            };
+            let mut report = pid.to_ne_bytes().to_vec();
+            report.extend(&errno.to_ne_bytes());
+            let _ = unistd::write(report_write, &report);
+            unsafe { libc::_exit(0) }
+        }
+        Ok(ForkResult::Parent { child }) => {
+            let _ = unistd::close(report_write);
+            let mut report = [0; 8];
+            let read = unistd::read(report_read, &mut report);
+            let _ = unistd::close(report_read);
+            let _ = waitpid(child, None);
+            let pid = i32::from_ne_bytes([report[0], report[1], report[2], report[3]]);
+            let errno = i32::from_ne_bytes([report[4], report[5], report[6], report[7]]);
+            match (read, errno) {
+                (Ok(8), 0) => SpawnResponse::Spawned(pid),
+                (Ok(8), -1) => SpawnResponse::Failed(format!(
+                    "Failed spawning {}, check the zygote's log",
+                    request.service
+                )),
+                (Ok(8), errno) => SpawnResponse::ExecFailed(errno),
+                _ => SpawnResponse::Failed("the intermediate process has failed".into()),
+            }
+        }
+        Err(error) => {
+            let _ = unistd::close(report_read);
+            let _ = unistd::close(report_write);
+            SpawnResponse::Failed(error.to_string())
+        }
+    }
+}
+
+/// Intermediate side: forks the service, and waits until it has called exec.
+fn launch(request: &SpawnRequest) -> Result<Pid> {
+    let user_namespace = match &request.user_namespace {
+        Some(user_namespace) => Some(UserNamespaceSync::new(user_namespace)?),
+        None => None,
+    };
+    let exec_error = exec_error_pipe()?;
+    match fork() {
+        Ok(ForkResult::Child) => {
+            let _ = unistd::close(exec_error.0);
+            // Ignored by the zygote, the service gets them back.
+            for signal in IGNORED_SIGNALS.iter() {
+                let _ = unsafe { signal::signal(*signal, SigHandler::SigDfl) };
+            }
+            let res = redirect_output(&request.stdout, LogOutput::Stdout)
+                .and_then(|_| redirect_output(&request.stderr, LogOutput::Stderr))
+                .and_then(|_| match &user_namespace {
+                    Some(user_namespace) => user_namespace.enter_child(),
+                    None => Ok(()),
+                })
+                .and_then(|_| {
+                    exec(
+                        request.program_name.clone(),
+                        request.arg_cstrings.clone(),
+                        request.env_cstrings.clone(),
+                        None,
+                        Uid::from_raw(request.uid),
+                        request.cwd.clone(),
+                    )
+                });
+            if let Err(error) = res {
+                let errno = error.errno().unwrap_or(0);
+                let _ = unistd::write(exec_error.1, &errno.to_ne_bytes());
+                let error = format!("Error spawning process: {}", error);
+                ss_panic(error.as_str(), 102);
+            }
+            unreachable!()
+        }
+        Ok(ForkResult::Parent { child }) => {
+            let _ = unistd::close(exec_error.1);
+            if let Some(user_namespace) = user_namespace {
+                if let Err(error) = user_namespace.write_maps(child) {
+                    error!(
+                        "Failed writing the uid and gid maps of {}: {}",
+                        request.service, error
+                    );
+                }
+            }
+            let res = wait_exec(exec_error.0, child);
+            let _ = unistd::close(exec_error.0);
+            if let Err(error) = &res {
+                if error.exec_errno().is_none() {
+                    error!("Failed spawning {}: {}", request.service, error);
+                }
+            }
+            res.map(|_| child)
+        }
+        Err(error) => {
+            let _ = unistd::close(exec_error.0);
+            let _ = unistd::close(exec_error.1);
+            Err(error.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SpawnRequest, Zygote};
+    use crate::horust::formats::{LogOutput, UserNamespace};
+    use nix::unistd;
+    use std::ffi::CString;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_zygote() {
+        let tempdir = TempDir::new("zygote").unwrap();
+        let output = tempdir.path().join("output.log");
+        let zygote = Zygote::start().unwrap();
+        let cstrings = |strings: &[&str]| -> Vec<CString> {
+            strings.iter().map(|s| CString::new(*s).unwrap()).collect()
+        };
+        let mut request = SpawnRequest {
+            service: "sandboxed.toml".into(),
+            program_name: CString::new("sh").unwrap(),
+            arg_cstrings: cstrings(&["sh", "-c", "cat /proc/self/uid_map; echo $GREETING"]),
+            env_cstrings: cstrings(&["GREETING=hello", "PATH=/bin:/usr/bin"]),
+            uid: 0,
+            cwd: tempdir.path().to_path_buf(),
+            stdout: LogOutput::Path(output.clone()),
+            stderr: LogOutput::Stdout,
+            user_namespace: Some(UserNamespace {
+                uid_map: format!("0 {} 1", unistd::getuid()),
+                gid_map: format!("0 {} 1", unistd::getgid()),
+            }),
+        };
+        zygote.spawn(&request).unwrap().unwrap();
+        // The service is not a child of this process: its output is polled for.
+        let start = Instant::now();
+        let expected = format!("0 {} 1\nhello\n", unistd::getuid());
+        let normalize = |content: String| content.split_whitespace().collect::<Vec<_>>().join(" ");
+        while normalize(std::fs::read_to_string(&output).unwrap_or_default())
+            != normalize(expected.clone())
+        {
+            assert!(start.elapsed() < Duration::from_secs(5), "no output");
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        request.program_name = CString::new("/not/a/program").unwrap();
+        let error = zygote.spawn(&request).unwrap().unwrap_err();
+        assert_eq!(error.exec_errno(), Some(libc::ENOENT));
+        request.user_namespace = None;
+        request.cwd = tempdir.path().join("missing");
+        let error = zygote.spawn(&request).unwrap().unwrap_err();
+        assert_eq!(error.exec_errno(), Some(libc::ENOENT));
+    }
+}
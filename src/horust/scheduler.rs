@@ -0,0 +1,198 @@
+//! Scheduled services (`[schedule]`): they're Idle between the runs, and they're activated at
+//! every firing of their timers. The intervals (`every`) are measured on the boottime clock, so
+//! they aren't affected by the changes of the wall clock, and the time spent suspended counts.
+//! The times of the day (`at`) follow the wall clock in their timezone: if it jumps forward past
+//! some of them, they're fired once; if it goes back, the ones already fired aren't fired again.
+use crate::horust::bus::BusConnector;
+use crate::horust::formats::{Event, Service, ServiceName, TimeOfDay, Timezone};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Time since the boot, including the time spent suspended. Where there is no CLOCK_BOOTTIME,
+/// the monotonic clock.
+fn boottime() -> Duration {
+    #[cfg(target_os = "linux")]
+    let clock = libc::CLOCK_BOOTTIME;
+    #[cfg(not(target_os = "linux"))]
+    let clock = libc::CLOCK_MONOTONIC;
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // It can only fail with an invalid clock or pointer.
+    unsafe { libc::clock_gettime(clock, &mut ts) };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// Seconds since the epoch, on the wall clock.
+fn wall_clock() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs() as i64)
+}
+
+#[derive(Debug)]
+struct Timer {
+    service: ServiceName,
+    every: Option<Duration>,
+    at: Vec<TimeOfDay>,
+    timezone: Timezone,
+    /// Next firing of `every`, on the boottime clock.
+    next_tick: Option<Duration>,
+    /// Next firing of `at`, in seconds since the epoch.
+    next_time: Option<i64>,
+    /// When `at` has last fired, on the wall clock: the times before it are done.
+    last_fired_time: Option<i64>,
+    /// The last reading of the wall clock, to tell whether it has gone back.
+    last_wall: i64,
+}
+
+impl Timer {
+    /// None if the service isn't scheduled.
+    fn new(service: &Service, now: Duration, wall: i64) -> Option<Result<Self, String>> {
+        let schedule = service.schedule.as_ref()?;
+        let timezone = match Timezone::load(schedule.timezone.as_deref()) {
+            Ok(timezone) => timezone,
+            Err(error) => return Some(Err(error)),
+        };
+        let mut timer = Timer {
+            service: service.name.clone(),
+            every: schedule.every,
+            at: schedule.at.clone(),
+            timezone,
+            next_tick: schedule.every.map(|every| now + every),
+            next_time: None,
+            last_fired_time: None,
+            last_wall: wall,
+        };
+        timer.next_time = timer.next_time(wall);
+        Some(Ok(timer))
+    }
+
+    fn next_time(&self, after: i64) -> Option<i64> {
+        self.at
+            .iter()
+            .map(|time| self.timezone.next(time, after))
+            .min()
+    }
+
+    /// Whether the service should be activated.
+    fn poll(&mut self, now: Duration, wall: i64) -> bool {
+        let mut fired = false;
+        if let (Some(every), Some(next)) = (self.every, self.next_tick) {
+            if now >= next {
+                // Once, even if more firings have been missed (e.g. while suspended), and the
+                // next ones keep the pace.
+                let missed = ((now - next).as_nanos() / every.as_nanos()) as u32;
+                self.next_tick = Some(next + every * (missed + 1));
+                fired = true;
+            }
+        }
+        if wall < self.last_wall {
+            let after = self.last_fired_time.map_or(wall, |last| last.max(wall));
+            self.next_time = self.next_time(after);
+        }
+        self.last_wall = wall;
+        if let Some(next) = self.next_time {
+            if wall >= next {
+                self.last_fired_time = Some(wall);
+                self.next_time = self.next_time(wall);
+                fired = true;
+            }
+        }
+        fired
+    }
+}
+
+pub(crate) fn spawn(bus: BusConnector, services: Vec<Service>) {
+    thread::Builder::new()
+        .name("scheduler".into())
+        .spawn(move || run(bus, services))
+        .expect("Failed spawning the scheduler thread");
+}
+
+fn add_timer(timers: &mut Vec<Timer>, service: &Service) {
+    match Timer::new(service, boottime(), wall_clock()) {
+        Some(Ok(timer)) => {
+            debug!("Schedule of {}: {:?}", service.name, timer);
+            timers.push(timer);
+        }
+        Some(Err(error)) => error!("Cannot schedule {}: {}", service.name, error),
+        None => (),
+    }
+}
+
+fn run(bus: BusConnector, services: Vec<Service>) {
+    let mut timers = vec![];
+    services
+        .iter()
+        .for_each(|service| add_timer(&mut timers, service));
+    loop {
+        for ev in bus.try_get_events() {
+            match ev {
+                // The instances of a scheduled service, once it's scaled up.
+                Event::ServiceCreated(service) => add_timer(&mut timers, &service),
+                Event::ShuttingDownInitiated(_) => return,
+                _ => (),
+            }
+        }
+        let (now, wall) = (boottime(), wall_clock());
+        for timer in timers.iter_mut() {
+            if timer.poll(now, wall) {
+                info!("Schedule of {} fired, activating it.", timer.service);
+                bus.send_event(Event::Activate(timer.service.clone()));
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Timer;
+    use crate::horust::formats::Service;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    const DAY: i64 = 86400;
+
+    fn timer(schedule: &str, now: Duration, wall: i64) -> Timer {
+        let service = format!("command = \"backup.sh\"\n[schedule]\n{}", schedule);
+        let service = Service::from_str(&service).unwrap();
+        Timer::new(&service, now, wall).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_every() {
+        let secs = Duration::from_secs;
+        let mut timer = timer("every = \"1m\"", secs(100), 0);
+        assert!(!timer.poll(secs(159), 0));
+        assert!(timer.poll(secs(160), 0));
+        assert!(!timer.poll(secs(161), 0));
+        // The wall clock doesn't matter.
+        assert!(!timer.poll(secs(162), 10 * DAY));
+        // Suspended for a while: once, then at the same pace.
+        assert!(timer.poll(secs(470), 10 * DAY));
+        assert!(!timer.poll(secs(519), 10 * DAY));
+        assert!(timer.poll(secs(520), 10 * DAY));
+    }
+
+    #[test]
+    fn test_at_across_clock_jumps() {
+        let now = Duration::from_secs(0);
+        let three = 3 * 3600;
+        let mut timer = timer("at = [\"03:00\"]\ntimezone = \"UTC0\"", now, DAY / 2);
+        assert!(!timer.poll(now, DAY + three - 1));
+        assert!(timer.poll(now, DAY + three));
+        assert!(!timer.poll(now, DAY + three + 1));
+        // Forward past some of them: once.
+        assert!(timer.poll(now, 5 * DAY));
+        assert!(!timer.poll(now, 5 * DAY + 1));
+        // Back before the last firing: that one isn't fired again.
+        assert!(!timer.poll(now, 4 * DAY + three - 10));
+        assert!(!timer.poll(now, 4 * DAY + three));
+        assert!(timer.poll(now, 5 * DAY + three));
+    }
+}
@@ -0,0 +1,126 @@
+//! Verification of the services directories, using a detached minisign signature: for locked
+//! down containers, where the services shouldn't be changed after the image has been built.
+//! The signature covers the payload returned by `signing_payload`, and it's stored in the
+//! directory itself (`SIGNATURE_FILE`), e.g. using the minisign cli:
+//! `horust config-payload /etc/horust/services > payload`, then
+//! `minisign -S -m payload -x /etc/horust/services/services.minisig`.
+use crate::horust::error::{ErrorKind, HorustError, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The detached signature of a services directory, in the directory itself.
+pub const SIGNATURE_FILE: &str = "services.minisig";
+
+/// The signed content of a services directory: the name, the length and the content of every
+/// service file, sorted by name. Adding, removing or renaming a file changes it as well.
+pub fn signing_payload(dir: &Path) -> Result<Vec<u8>> {
+    // The same files loaded as services.
+    let has_toml_extension = |path: &PathBuf| {
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| extension.ends_with("toml"))
+    };
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(std::result::Result::ok)
+        .map(|dir_entry| dir_entry.path())
+        .filter(|path| path.is_file() && has_toml_extension(path))
+        .collect();
+    files.sort();
+    let mut payload = vec![];
+    for file in files {
+        let content = fs::read(&file)?;
+        let name = file.file_name().unwrap().to_string_lossy();
+        payload.extend(format!("{}\n{}\n", name, content.len()).as_bytes());
+        payload.extend(content);
+    }
+    Ok(payload)
+}
+
+/// Checks the signature of the services directory, using the minisign public key in
+/// `public_key`. A missing signature is an error only if it's `required`.
+pub(crate) fn verify_dir(dir: &Path, public_key: &Path, required: bool) -> Result<()> {
+    let signature_path = dir.join(SIGNATURE_FILE);
+    if !signature_path.exists() {
+        if required {
+            return Err(signature_error(format!(
+                "{} is not signed: {} is missing",
+                dir.display(),
+                signature_path.display()
+            )));
+        }
+        warn!("{} is not signed, it's loaded anyway", dir.display());
+        return Ok(());
+    }
+    verify(&signing_payload(dir)?, &signature_path, public_key)
+        .map_err(|error| signature_error(format!("{}: {}", signature_path.display(), error)))?;
+    info!("The signature of {} has been verified", dir.display());
+    Ok(())
+}
+
+#[cfg(feature = "signed-config")]
+fn verify(payload: &[u8], signature: &Path, public_key: &Path) -> std::result::Result<(), String> {
+    use minisign_verify::{PublicKey, Signature};
+    let public_key = PublicKey::from_file(public_key).map_err(|error| error.to_string())?;
+    let signature = Signature::from_file(signature).map_err(|error| error.to_string())?;
+    public_key
+        .verify(payload, &signature, false)
+        .map_err(|error| error.to_string())
+}
+
+#[cfg(not(feature = "signed-config"))]
+fn verify(
+    _payload: &[u8],
+    _signature: &Path,
+    _public_key: &Path,
+) -> std::result::Result<(), String> {
+    Err("horust was built without the signed-config feature".into())
+}
+
+fn signature_error(message: String) -> HorustError {
+    ErrorKind::Signature(message).into()
+}
+
+#[cfg(all(test, feature = "signed-config"))]
+mod test {
+    use super::{signing_payload, verify_dir, SIGNATURE_FILE};
+    use std::fs;
+    use tempdir::TempDir;
+
+    const PUBLIC_KEY: &str = "untrusted comment: minisign public key 5E1CE5C2E5C2B1A5
+RWSlscLlwuUcXgOhB7/zzhC+HXDdGOdLwJln5NYwm6UNXx3chmQSVTG4
+";
+    /// Signature of the payload of `a.toml` and `b.toml`.
+    const SIGNATURE: &str = "untrusted comment: signature from minisign secret key
+RUSlscLlwuUcXg6C20LwcqtSC9RTxQB0MB+rKNPnD4i0pzc/XL2Ag3JGCWnFV75/uB8q8FhLWQ1xakJxaXGE9zdh5GGgIU9JGQE=
+trusted comment: horust services
+GPmbeM4EaFUMTbE35LYXNtSSy+i6WH3X+FKTFw6pHPlVVowC2ny1Md2AzFH24Ede0TiVHV2+F+XIG+Fu0z9SAw==
+";
+
+    #[test]
+    fn test_verify_dir() {
+        let tempdir = TempDir::new("signature").unwrap();
+        let dir = tempdir.path().join("services");
+        fs::create_dir(&dir).unwrap();
+        let public_key = tempdir.path().join("horust.pub");
+        fs::write(&public_key, PUBLIC_KEY).unwrap();
+        fs::write(dir.join("b.toml"), "command = \"/bin/true\"\n").unwrap();
+        fs::write(dir.join("a.toml"), "command = \"/bin/echo a\"\n").unwrap();
+        fs::write(dir.join("README"), "Not a service").unwrap();
+        assert_eq!(
+            signing_payload(&dir).unwrap(),
+            b"a.toml\n24\ncommand = \"/bin/echo a\"\nb.toml\n22\ncommand = \"/bin/true\"\n"
+                .to_vec()
+        );
+
+        assert!(verify_dir(&dir, &public_key, false).is_ok());
+        assert!(verify_dir(&dir, &public_key, true).is_err());
+        fs::write(dir.join(SIGNATURE_FILE), SIGNATURE).unwrap();
+        verify_dir(&dir, &public_key, true).unwrap();
+
+        fs::write(dir.join("c.toml"), "command = \"/bin/sh\"\n").unwrap();
+        assert!(verify_dir(&dir, &public_key, true).is_err());
+        fs::remove_file(dir.join("c.toml")).unwrap();
+        fs::write(dir.join("b.toml"), "command = \"/bin/false\"\n").unwrap();
+        assert!(verify_dir(&dir, &public_key, false).is_err());
+    }
+}
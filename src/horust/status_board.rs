@@ -0,0 +1,155 @@
+use crate::horust::formats::{HealthCheckReport, RunRecord, ServiceName, ServiceStatus};
+use crate::horust::runtime::resource_usage::ResourceUsage;
+use crate::horust::runtime::service_handler::StopReason;
+use nix::unistd::Pid;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// State of a service, as seen by the runtime.
+#[derive(Debug, Clone)]
+pub(crate) struct ServiceSnapshot {
+    pub(crate) name: ServiceName,
+    pub(crate) status: ServiceStatus,
+    pub(crate) pid: Option<Pid>,
+    pub(crate) restart_attempts: u32,
+    /// Result of the last round of healthchecks, if any.
+    pub(crate) last_health_check: Option<HealthCheckReport>,
+    pub(crate) consecutive_failures: u32,
+    /// It's kept even if the following checks have passed.
+    pub(crate) last_health_error: Option<String>,
+    /// If it's a replica, the name of the replicated service.
+    pub(crate) replica_of: Option<ServiceName>,
+    pub(crate) labels: BTreeMap<String, String>,
+    /// When the current process has been started.
+    pub(crate) started_at: Option<Instant>,
+    /// Same as `started_at`, but using the system clock.
+    pub(crate) start_time: Option<SystemTime>,
+    pub(crate) starts: u32,
+    /// Processes which couldn't be spawned, by reason.
+    pub(crate) spawn_failures: BTreeMap<&'static str, u32>,
+    /// It's kept even if the following processes have been spawned.
+    pub(crate) last_spawn_error: Option<String>,
+    /// Times it has been killed because of its failing healthchecks.
+    pub(crate) healthcheck_failures: u32,
+    /// Resources used by the processes which have exited.
+    pub(crate) resource_usage: ResourceUsage,
+    /// When the termination signal has been sent.
+    pub(crate) shutting_down_start: Option<Instant>,
+    pub(crate) stop_reason: Option<StopReason>,
+    pub(crate) is_fallback: bool,
+    /// Status changes which have been ignored, because they weren't allowed.
+    pub(crate) illegal_transitions: u32,
+    /// Its last processes which have exited, the oldest first.
+    pub(crate) run_history: Vec<RunRecord>,
+    /// Entries of start-after and binds-to which are preventing the service from starting.
+    pub(crate) unsatisfied_dependencies: Vec<String>,
+}
+
+impl ServiceSnapshot {
+    /// For how long the current process has been running.
+    pub(crate) fn uptime(&self) -> Option<Duration> {
+        self.started_at.map(|started_at| started_at.elapsed())
+    }
+
+    pub(crate) fn start_time_rfc3339(&self) -> Option<String> {
+        self.start_time
+            .map(|start_time| humantime::format_rfc3339_seconds(start_time).to_string())
+    }
+}
+
+/// State of the runtime itself, used for troubleshooting.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RuntimeStats {
+    pub(crate) is_shutting_down: bool,
+    pub(crate) shutdown_reason: Option<String>,
+    /// Events waiting to be dispatched by the bus.
+    pub(crate) bus_queue: usize,
+    /// Events dispatched to the runtime, waiting to be applied.
+    pub(crate) runtime_queue: usize,
+    pub(crate) tracked_pids: usize,
+    pub(crate) adopted_pids: usize,
+    pub(crate) running_hooks: usize,
+    pub(crate) unknown_children_reaped: u64,
+    pub(crate) rollouts: usize,
+    pub(crate) retired_instances: usize,
+}
+
+/// Shared snapshot of all the services, updated by the runtime at every iteration of its
+/// event loop. Used for answering the status queries of the control socket.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct StatusBoard {
+    services: Arc<Mutex<Vec<ServiceSnapshot>>>,
+    runtime: Arc<Mutex<RuntimeStats>>,
+}
+
+impl StatusBoard {
+    pub(crate) fn publish(&self, mut services: Vec<ServiceSnapshot>, runtime: RuntimeStats) {
+        services.sort_by(|a, b| a.name.cmp(&b.name));
+        *self.services.lock().unwrap() = services;
+        *self.runtime.lock().unwrap() = runtime;
+    }
+
+    /// Services sorted by name.
+    pub(crate) fn services(&self) -> Vec<ServiceSnapshot> {
+        self.services.lock().unwrap().clone()
+    }
+
+    pub(crate) fn runtime(&self) -> RuntimeStats {
+        self.runtime.lock().unwrap().clone()
+    }
+}
+
+/// The state of the runtime on the first line, then a line for every service.
+pub(crate) fn format_state(runtime: &RuntimeStats, services: &[ServiceSnapshot]) -> String {
+    let mut state = format!(
+        "runtime shutting-down={} bus-queue={} runtime-queue={} pids={} adopted={} hooks={} \
+         unknown-children-reaped={} rollouts={} retired={} shutdown-reason={}\n",
+        runtime.is_shutting_down,
+        runtime.bus_queue,
+        runtime.runtime_queue,
+        runtime.tracked_pids,
+        runtime.adopted_pids,
+        runtime.running_hooks,
+        runtime.unknown_children_reaped,
+        runtime.rollouts,
+        runtime.retired_instances,
+        runtime
+            .shutdown_reason
+            .as_ref()
+            .map_or_else(|| "-".to_string(), |reason| format!("{:?}", reason))
+    );
+    for snapshot in services {
+        state.push_str(&format_service_state(snapshot));
+    }
+    state
+}
+
+/// E.g. `service web Started pid=42 restarts=0 starts=1 ... uptime=1.2s ... unsatisfied=-`
+fn format_service_state(snapshot: &ServiceSnapshot) -> String {
+    let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    let elapsed = |instant: Option<Instant>| or_dash(instant.map(|i| format!("{:?}", i.elapsed())));
+    let unsatisfied = if snapshot.unsatisfied_dependencies.is_empty() {
+        "-".to_string()
+    } else {
+        snapshot.unsatisfied_dependencies.join(",")
+    };
+    format!(
+        "service {} {} pid={} restarts={} starts={} failures={} started={} uptime={} \
+         shutdown-timer={} stop-reason={} fallback={} \
+         illegal-transitions={} unsatisfied={}\n",
+        snapshot.name,
+        snapshot.status,
+        or_dash(snapshot.pid.map(|pid| pid.to_string())),
+        snapshot.restart_attempts,
+        snapshot.starts,
+        snapshot.consecutive_failures,
+        or_dash(snapshot.start_time_rfc3339()),
+        elapsed(snapshot.started_at),
+        elapsed(snapshot.shutting_down_start),
+        or_dash(snapshot.stop_reason.map(|reason| reason.to_string())),
+        snapshot.is_fallback,
+        snapshot.illegal_transitions,
+        unsatisfied
+    )
+}
@@ -1,11 +1,11 @@
 #[macro_use]
-extern crate log;
+extern crate tracing;
 
 #[macro_use]
 extern crate maplit;
 
-#[macro_use]
-extern crate crossbeam;
-
 pub mod horust;
-pub use crate::horust::{get_sample_service, Horust, HorustError};
+pub use crate::horust::{
+    events_schema, exec, get_sample_service, import_services, list_services, migrate_file,
+    signing_payload, HealthProbe, Healthiness, Horust, HorustError,
+};
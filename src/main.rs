@@ -1,11 +1,16 @@
 use horust::horust::ExitStatus;
 use horust::horust::HorustConfig;
+use horust::horust::{init_logging, LogFormat};
 use horust::Horust;
+use std::io::Write;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use structopt::clap::Shell;
 use structopt::StructOpt;
 
 #[macro_use]
-extern crate log;
+extern crate tracing;
 
 #[derive(StructOpt, Debug)]
 #[structopt(author, about)]
@@ -22,51 +27,240 @@ struct Opts {
     /// Prints a sample service file with all the possible options
     sample_service: bool,
 
-    #[structopt(long, default_value = "/etc/horust/services")]
-    /// Path to the directory containing the services
-    services_path: PathBuf,
+    #[structopt(long, default_value = "/etc/horust/services", number_of_values = 1)]
+    /// Path to the directory containing the services. Can be repeated: the services of a path
+    /// replace the ones with the same name in the previous paths
+    services_path: Vec<PathBuf>,
+
+    #[structopt(long = "service", number_of_values = 1)]
+    /// Define a service instead of loading services-path, e.g. `name=web,command=./run.sh,restart=always`. Can be repeated
+    services: Vec<String>,
+
+    #[structopt(long, default_value = "toml")]
+    /// Format of the services: `toml`, or `compose` if services-path is a docker-compose file
+    format: ServicesFormat,
+
+    #[structopt(long, default_value = "text")]
+    /// Format of horust's own logs: `text`, or `json` for one record per line
+    log_format: LogFormat,
+
+    #[structopt(long)]
+    /// Don't run anything: simulate the boot instead, printing the order and the timing of the
+    /// events. Every service pretends to start and to become healthy after simulate-delay
+    simulate: bool,
+
+    #[structopt(long, default_value = "1s", parse(try_from_str = humantime::parse_duration))]
+    /// How long it takes for each simulated service to start, to become healthy and to exit
+    simulate_delay: Duration,
+
+    #[structopt(long, number_of_values = 1)]
+    /// This simulated service exits with an error instead of becoming healthy. Can be repeated
+    simulate_failure: Vec<String>,
+
+    #[structopt(subcommand)]
+    subcommand: Option<SubCommand>,
 
     #[structopt(required = false, multiple = true, min_values = 0, last = true)]
     /// Specify a command to run instead of load services path. Useful if you just want to use the reaping capability. Prefix your command with --
     command: Vec<String>,
 }
 
-fn main() -> Result<(), horust::HorustError> {
-    // Set up logging.
-    let env = env_logger::Env::new()
-        .filter("HORUST_LOG")
-        .write_style("HORUST_LOG_STYLE");
-    env_logger::init_from_env(env);
+#[derive(StructOpt, Debug)]
+enum SubCommand {
+    /// Runs a single command as a minimal init, without any service: reaps the zombies, forwards
+    /// the signals to the command, and exits with its exit code
+    Exec {
+        #[structopt(required = true, last = true)]
+        /// The command and its arguments, after --
+        command: Vec<String>,
+    },
+    /// Translates the services of docker-compose files or systemd units into service files
+    Import {
+        #[structopt(required = true)]
+        /// Paths to the docker-compose files or to the systemd units (`.service` files)
+        files: Vec<PathBuf>,
+        #[structopt(long, default_value = ".")]
+        /// Directory where the service files are written
+        output_dir: PathBuf,
+    },
+    /// Rewrites service files using the current schema, keeping the original ones aside
+    /// (`<file>.bak`)
+    MigrateConfig {
+        #[structopt(required = true)]
+        /// Paths to the service files
+        files: Vec<PathBuf>,
+    },
+    /// Prints the content of a services directory covered by its signature, to be signed using
+    /// minisign (check `--config-public-key`)
+    ConfigPayload {
+        /// Path to the services directory
+        services_path: PathBuf,
+    },
+    /// Prints the services which would be run (using the same options), with their command,
+    /// restart strategy and dependencies
+    List {
+        #[structopt(long)]
+        /// Print a json array instead of a table
+        json: bool,
+    },
+    /// Prints the json schema of the events, as followed by `horustctl events --json` and sent
+    /// to the plugins and to the collector
+    EventsSchema,
+    /// Prints the completion script of horust for the shell: bash|zsh|fish|powershell|elvish
+    Completions {
+        #[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+        shell: Shell,
+    },
+}
+
+#[derive(Debug)]
+enum ServicesFormat {
+    Toml,
+    Compose,
+}
+
+impl FromStr for ServicesFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "toml" => Ok(ServicesFormat::Toml),
+            "compose" => Ok(ServicesFormat::Compose),
+            other => Err(format!("Unknown services format: {}", other)),
+        }
+    }
+}
+
+fn main() {
+    // Printed using Display, e.g. for listing the invalid services one per line.
+    if let Err(error) = run() {
+        eprintln!("Error: {}", error);
+        std::process::exit(1);
+    }
+}
 
+fn run() -> Result<(), horust::HorustError> {
     let opts = Opts::from_args();
+    // Set up logging.
+    init_logging(opts.log_format);
 
     if opts.sample_service {
         println!("{}", horust::get_sample_service());
         return Ok(());
     }
 
+    let mut list_json = None;
+    match opts.subcommand {
+        Some(SubCommand::Import { files, output_dir }) => {
+            for path in horust::import_services(&files, &output_dir)? {
+                println!("{}", path.display());
+            }
+            return Ok(());
+        }
+        Some(SubCommand::MigrateConfig { files }) => {
+            for path in files {
+                match horust::migrate_file(&path)? {
+                    Some(deprecations) => {
+                        println!("{}: migrated", path.display());
+                        for deprecation in deprecations {
+                            println!("  {} -> {}", deprecation.key, deprecation.replacement);
+                        }
+                    }
+                    None => println!("{}: already up to date", path.display()),
+                }
+            }
+            return Ok(());
+        }
+        Some(SubCommand::ConfigPayload { services_path }) => {
+            let payload = horust::signing_payload(&services_path)?;
+            std::io::stdout().write_all(&payload)?;
+            return Ok(());
+        }
+        Some(SubCommand::EventsSchema) => {
+            println!("{}", horust::events_schema());
+            return Ok(());
+        }
+        Some(SubCommand::Exec { command }) => std::process::exit(horust::exec(&command)?),
+        Some(SubCommand::Completions { shell }) => {
+            Opts::clap().gen_completions_to("horust", shell, &mut std::io::stdout());
+            return Ok(());
+        }
+        Some(SubCommand::List { json }) => list_json = Some(json),
+        None => (),
+    }
+
     let config = HorustConfig::load_and_merge(opts.horust_config, &opts.config_path)?;
 
-    let mut horust = if !opts.command.is_empty() {
+    let command = if opts.command.is_empty() {
+        None
+    } else {
         debug!("Running command: {:?}", opts.command);
-
-        Horust::from_command(
+        Some(
             opts.command
                 .into_iter()
                 .fold(String::new(), |acc, w| format!("{} {}", acc, w)),
         )
+    };
+    if config.require_signed_config && (!opts.services.is_empty() || command.is_some()) {
+        eprintln!("Error: only the services directories can be signed");
+        std::process::exit(1);
+    }
+    let mut horust = if !opts.services.is_empty() {
+        debug!("Running services: {:?}", opts.services);
+        Horust::from_service_specs(&opts.services, command)?
+    } else if let Some(command) = command {
+        Horust::from_command(command)
+    } else if !config.services.is_empty() {
+        debug!("Running the services of: {}", opts.config_path.display());
+        Horust::from_inline_services(&config)?
     } else {
-        debug!(
-            "Loading services from directory: {}",
-            opts.services_path.display()
-        );
-        Horust::from_services_dir(&opts.services_path)?
+        match opts.format {
+            ServicesFormat::Toml => {
+                debug!(
+                    "Loading services from directories: {:?}",
+                    opts.services_path
+                );
+                Horust::from_verified_services_dirs(
+                    &opts.services_path,
+                    &config.profiles,
+                    config.config_public_key.as_deref(),
+                    config.require_signed_config,
+                )?
+            }
+            ServicesFormat::Compose => match opts.services_path.as_slice() {
+                _ if config.require_signed_config => {
+                    eprintln!("Error: only the services directories can be signed");
+                    std::process::exit(1);
+                }
+                [path] => Horust::from_compose_file(path, &config.profiles)?,
+                _ => {
+                    eprintln!(
+                        "Error: a single services-path is supported using the compose format"
+                    );
+                    std::process::exit(1);
+                }
+            },
+        }
     };
+    if let Some(json) = list_json {
+        print!("{}", horust::list_services(horust.get_services(), json)?);
+        return Ok(());
+    }
+    horust.set_config(config.clone());
+    if opts.simulate {
+        horust.simulate(opts.simulate_delay, opts.simulate_failure);
+        let exit_status = horust.run();
+        println!("Simulation over: {:?}", exit_status);
+        return Ok(());
+    }
 
-    if let ExitStatus::SomeServiceFailed = horust.run() {
-        if config.unsuccessful_exit_finished_failed {
+    match horust.run() {
+        ExitStatus::SomeServiceFailed if config.unsuccessful_exit_finished_failed => {
             std::process::exit(101);
         }
+        ExitStatus::Crashed => std::process::exit(105),
+        ExitStatus::ShutdownTimedOut => std::process::exit(106),
+        _ => (),
     }
     Ok(())
 }
@@ -1,8 +1,15 @@
 use assert_cmd::prelude::*;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use predicates::prelude::*;
 use predicates::str::contains;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
+use std::path::Path;
+use std::process::Command;
+use std::thread::sleep;
 use std::time::Duration;
+use tempdir::TempDir;
 
 pub mod utils;
 use utils::*;
@@ -27,6 +34,176 @@ exit 1
     recv.recv_or_kill(Duration::from_secs(15));
 }
 
+#[test]
+fn test_config_inline_services() {
+    let (mut cmd, temp_dir) = get_cli();
+    // Not loaded, since the config file defines the services.
+    store_service(temp_dir.path(), "#!/usr/bin/env bash\nexit 1", None, None);
+    let config_dir = TempDir::new("config").unwrap();
+    let config_path = config_dir.path().join("horust.toml");
+    let config = r#"
+[services.first]
+command = "/bin/echo first"
+
+[services.second]
+command = "/bin/echo second"
+start-after = ["first"]
+"#;
+    std::fs::write(&config_path, config).unwrap();
+    cmd.args(vec![
+        "--config-path",
+        config_path.display().to_string().as_str(),
+        "--unsuccessful-exit-finished-failed",
+    ])
+    .assert()
+    .success()
+    .stdout(contains("first\nsecond"));
+}
+
+#[test]
+fn test_require_signed_config() {
+    let (mut cmd, temp_dir) = get_cli();
+    store_service(
+        temp_dir.path(),
+        "#!/usr/bin/env bash\necho never run",
+        None,
+        None,
+    );
+    let public_key = temp_dir.path().join("horust.pub");
+    std::fs::write(&public_key, "").unwrap();
+    cmd.args(vec!["--require-signed-config"])
+        .assert()
+        .failure()
+        .stdout(contains("never run").not())
+        .stderr(contains("config-public-key"));
+    cmd.args(vec![
+        "--config-public-key",
+        public_key.display().to_string().as_str(),
+    ])
+    .assert()
+    .failure()
+    .stdout(contains("never run").not())
+    .stderr(contains("services.minisig is missing"));
+
+    // The command to run isn't signed.
+    let (mut cmd, _temp_dir) = get_cli();
+    cmd.args(vec![
+        "--require-signed-config",
+        "--config-public-key",
+        public_key.display().to_string().as_str(),
+        "--",
+        "echo",
+        "never run",
+    ])
+    .assert()
+    .failure()
+    .stdout(contains("never run").not())
+    .stderr(contains("only the services directories can be signed"));
+}
+
+#[test]
+fn test_audit_log() {
+    let (mut cmd, temp_dir) = get_cli();
+    let service_name = store_service(temp_dir.path(), "exit 0", None, Some("audited"));
+    let audit_path = temp_dir.path().join("audit.log");
+    cmd.args(vec![
+        "--audit-log",
+        audit_path.display().to_string().as_str(),
+    ])
+    .assert()
+    .success();
+    let audit = std::fs::read_to_string(&audit_path).unwrap();
+    let entry: serde_json::Value = serde_json::from_str(audit.lines().next().unwrap()).unwrap();
+    assert_eq!(entry["kind"], "service");
+    assert_eq!(entry["service"], service_name);
+    assert_eq!(entry["argv"][0], "/usr/bin/env");
+    assert!(entry["pid"].as_i64().unwrap() > 0);
+}
+
+#[test]
+fn test_simulate() {
+    let (mut cmd, temp_dir) = get_cli();
+    let script = "#!/usr/bin/env bash\ntouch ran";
+    store_service(temp_dir.path(), script, None, Some("a"));
+    store_service(
+        temp_dir.path(),
+        script,
+        Some(r#"start-after = ["a.toml"]"#),
+        Some("b"),
+    );
+    let output = cmd
+        .current_dir(temp_dir.path())
+        .args(vec!["--simulate", "--simulate-delay", "100ms"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let position = |line: &str| stdout.find(line).unwrap_or_else(|| panic!("{}", stdout));
+    assert!(position("a.toml: Running") < position("b.toml: start requested"));
+    assert!(position("b.toml: Running") < position("shutting down"));
+    assert!(stdout.contains("Simulation over: Successful"));
+    assert!(!temp_dir.path().join("ran").exists());
+}
+
+#[test]
+fn test_chaos() {
+    let (mut cmd, temp_dir) = get_cli();
+    let script = "#!/usr/bin/env bash\nexec sleep 30";
+    store_service(temp_dir.path(), script, None, Some("victim"));
+    let config_dir = TempDir::new("config").unwrap();
+    let config_path = config_dir.path().join("horust.toml");
+    let config = "[chaos]\ninterval = \"500ms\"\nkill-probability = 1.0\n";
+    std::fs::write(&config_path, config).unwrap();
+    cmd.env("HORUST_LOG", "warn")
+        .args(vec![
+            "--config-path",
+            config_path.display().to_string().as_str(),
+            "--chaos",
+            "--unsuccessful-exit-finished-failed",
+        ])
+        .assert()
+        .failure()
+        .stderr(contains(
+            "Chaos: injected a fault in victim.toml: killed its process",
+        ));
+}
+
+#[test]
+fn test_plugins() {
+    let (mut cmd, temp_dir) = get_cli();
+    store_service(temp_dir.path(), "exec sleep 30", None, Some("worker"));
+    let notified = temp_dir.path().join("notified");
+    let plugin = temp_dir.path().join("plugin.sh");
+    // Asks for stopping the worker, then dumps the notifications until its stdin is closed.
+    let script = format!(
+        r#"sleep 1
+printf '\000\000\000\052{{"command":"stop","service":"worker.toml"}}'
+cat > {}
+"#,
+        notified.display()
+    );
+    std::fs::write(&plugin, script).unwrap();
+    let config_dir = TempDir::new("config").unwrap();
+    let config_path = config_dir.path().join("horust.toml");
+    let config = format!(
+        r#"[[plugins]]
+name = "stopper"
+command = "sh {}"
+"#,
+        plugin.display()
+    );
+    std::fs::write(&config_path, config).unwrap();
+    cmd.args(vec![
+        "--config-path",
+        config_path.display().to_string().as_str(),
+    ]);
+    run_async(&mut cmd, true).recv_or_kill(Duration::from_secs(15));
+    let notified = std::fs::read(&notified).unwrap();
+    let notified = String::from_utf8_lossy(&notified);
+    assert!(notified
+        .contains(r#"{"event":"status-changed","service":"worker.toml","status":"Running"}"#));
+}
+
 #[test]
 fn test_command_not_found() {
     let (mut cmd, temp_dir) = get_cli();
@@ -43,6 +220,23 @@ fn test_command_not_found() {
     cmd.assert().failure();
 }
 
+#[test]
+fn test_unknown_dependency() {
+    let (mut cmd, temp_dir) = get_cli();
+    let script = r#"#!/usr/bin/env bash
+echo "never run""#;
+    store_service(temp_dir.path(), script, None, Some("db"));
+    store_service(
+        temp_dir.path(),
+        script,
+        Some(r#"start-after = ["db"]"#),
+        Some("web"),
+    );
+    cmd.assert().failure().stderr(contains(
+        "Service 'web.toml', start-after refers to 'db', but there is no service with such name. Did you mean 'db.toml'?",
+    ));
+}
+
 #[test]
 fn test_single_command() {
     let (mut cmd, _temp_dir) = get_cli();
@@ -50,6 +244,97 @@ fn test_single_command() {
     cmd.assert().success().stdout(contains("hello world"));
 }
 
+#[test]
+fn test_exec() {
+    let (mut cmd, _temp_dir) = get_cli();
+    cmd.args(vec![
+        "exec",
+        "--",
+        "/usr/bin/env",
+        "bash",
+        "-c",
+        "echo hello; exit 3",
+    ]);
+    cmd.assert().code(3).stdout("hello\n");
+
+    // The signals are forwarded to the command.
+    let (mut cmd, _temp_dir) = get_cli();
+    let script = r#"trap "exit 7" TERM; sleep 10 & wait"#;
+    let mut child = cmd
+        .args(vec!["exec", "--", "/usr/bin/env", "bash", "-c", script])
+        .spawn()
+        .unwrap();
+    sleep(Duration::from_secs(1));
+    kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM).unwrap();
+    assert_eq!(child.wait().unwrap().code(), Some(7));
+}
+
+#[test]
+fn test_service_specs() {
+    let (mut cmd, _temp_dir) = get_cli();
+    cmd.args(vec![
+        "--service",
+        "name=second,command=/usr/bin/env bash -c 'echo second',start-after=[\"first\"]",
+        "--service",
+        "name=first,command=/usr/bin/env bash -c 'echo first',restart=never",
+        "--",
+        "/usr/bin/env bash -c 'echo command'",
+    ]);
+    let output = cmd.output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines.contains(&"command"));
+    let position = |line| lines.iter().position(|l| *l == line).unwrap();
+    assert!(position("first") < position("second"));
+
+    let (mut cmd, _temp_dir) = get_cli();
+    cmd.args(vec![
+        "--service",
+        "name=web,command=./run.sh,restart=sometimes",
+    ]);
+    cmd.assert().failure().stderr(contains("restart"));
+}
+
+#[test]
+fn test_log_format_json() {
+    let (mut cmd, temp_dir) = get_cli();
+    let script = r#"#!/usr/bin/env bash
+exit 0"#;
+    store_service(temp_dir.path(), script, None, Some("web"));
+    cmd.env("HORUST_LOG", "debug")
+        .args(vec!["--log-format", "json"])
+        .assert()
+        .success()
+        .stderr(contains(r#""service":"web.toml","event":"exited""#));
+}
+
+#[test]
+fn test_state_dump() {
+    let (mut cmd, temp_dir) = get_cli();
+    let dump_path = temp_dir.path().join("state.dump");
+    let script = r#"#!/usr/bin/env bash
+sleep 30"#;
+    store_service(temp_dir.path(), script, None, Some("web"));
+    let cmd = cmd.args(vec![
+        "--state-dump-path",
+        dump_path.display().to_string().as_str(),
+    ]);
+    let recv = run_async(cmd, true);
+    sleep(Duration::from_secs(1));
+    kill(recv.pid, Signal::SIGQUIT).expect("kill");
+    sleep(Duration::from_secs(1));
+    let dump = std::fs::read_to_string(&dump_path).unwrap();
+    assert!(dump.starts_with("=== horust state dump, "));
+    assert!(dump.contains("runtime shutting-down=false"));
+    assert!(dump.contains("service web.toml Running pid="));
+    // Still running.
+    kill(recv.pid, None).expect("horust has exited");
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(10));
+}
+
 #[test]
 fn test_stress_test_chained_services() {
     let (mut cmd, temp_dir) = get_cli();
@@ -76,3 +361,272 @@ fn test_stress_test_chained_services() {
     let recv = run_async(&mut cmd, true);
     recv.recv_or_kill(Duration::from_secs(max * 2));
 }
+
+#[test]
+fn test_unknown_children_kill_parent_service() {
+    let (mut cmd, temp_dir) = get_cli();
+    // The subshell exits immediately, so its child will be reparented to horust.
+    let script = r#"#!/usr/bin/env bash
+( (sleep 1) & )
+sleep 30"#;
+    let service = r#"[termination]
+wait = "1s""#;
+    store_service(temp_dir.path(), script, Some(service), None);
+    let cmd = cmd.args(vec!["--unknown-children", "kill-parent-service"]);
+    let recv = run_async(cmd, true);
+    recv.recv_or_kill(Duration::from_secs(10));
+}
+
+#[test]
+fn test_compose() {
+    let temp_dir = TempDir::new("horust").unwrap();
+    let compose_path = temp_dir.path().join("docker-compose.yml");
+    let compose = r#"
+services:
+  db:
+    image: postgres
+    command: ["bash", "-c", "echo db-started"]
+  web:
+    command: bash -c 'echo "web-started $GREETING"'
+    depends_on: [db]
+    environment:
+      GREETING: hello
+    ports: ["8080:8080"]
+"#;
+    std::fs::write(&compose_path, compose).unwrap();
+    let mut cmd = Command::cargo_bin("horust").unwrap();
+    cmd.args(vec![
+        "--format",
+        "compose",
+        "--services-path",
+        compose_path.display().to_string().as_str(),
+    ])
+    .assert()
+    .success()
+    .stdout(contains("db-started"))
+    .stdout(contains("web-started hello"));
+
+    // The imported services behave the same:
+    let services_dir = temp_dir.path().join("services");
+    std::fs::create_dir(&services_dir).unwrap();
+    let import = |dir: &Path| {
+        let mut cmd = Command::cargo_bin("horust").unwrap();
+        cmd.args(vec![
+            "import",
+            compose_path.display().to_string().as_str(),
+            "--output-dir",
+            dir.display().to_string().as_str(),
+        ]);
+        cmd
+    };
+    import(&services_dir).assert().success();
+    assert!(services_dir.join("db.toml").is_file());
+    assert!(services_dir.join("web.toml").is_file());
+    // Existing files are not overwritten:
+    import(&services_dir).assert().failure();
+    let mut cmd = Command::cargo_bin("horust").unwrap();
+    cmd.args(vec![
+        "--services-path",
+        services_dir.display().to_string().as_str(),
+    ])
+    .assert()
+    .success()
+    .stdout(contains("web-started hello"));
+}
+
+#[test]
+fn test_import_systemd() {
+    let temp_dir = TempDir::new("horust").unwrap();
+    let db = temp_dir.path().join("db.service");
+    std::fs::write(&db, "[Service]\nExecStart=/bin/bash -c 'echo db-started'\n").unwrap();
+    let web = temp_dir.path().join("web.service");
+    let unit = r#"[Unit]
+After=network.target db.service
+
+[Service]
+ExecStart=/bin/bash -c 'echo "web-started $GREETING"'
+Environment=GREETING=hello
+
+[Install]
+WantedBy=multi-user.target
+"#;
+    std::fs::write(&web, unit).unwrap();
+    let services_dir = temp_dir.path().join("services");
+    std::fs::create_dir(&services_dir).unwrap();
+    let mut cmd = Command::cargo_bin("horust").unwrap();
+    cmd.args(vec![
+        "import",
+        db.display().to_string().as_str(),
+        web.display().to_string().as_str(),
+        "--output-dir",
+        services_dir.display().to_string().as_str(),
+    ])
+    .assert()
+    .success();
+    let web_service = std::fs::read_to_string(services_dir.join("web.toml")).unwrap();
+    assert!(web_service.contains(r#"start-after = ["db"]"#));
+    let mut cmd = Command::cargo_bin("horust").unwrap();
+    cmd.args(vec![
+        "--services-path",
+        services_dir.display().to_string().as_str(),
+    ])
+    .assert()
+    .success()
+    .stdout(contains("db-started"))
+    .stdout(contains("web-started hello"));
+}
+
+#[test]
+fn test_list() {
+    let (mut cmd, temp_dir) = get_cli();
+    store_service(
+        temp_dir.path(),
+        "sleep 1",
+        Some("[restart]\nstrategy = \"always\""),
+        Some("db"),
+    );
+    store_service(
+        temp_dir.path(),
+        "sleep 1",
+        Some(r#"start-after = ["db.toml"]"#),
+        Some("web"),
+    );
+    // Nothing is run:
+    cmd.args(vec!["list"])
+        .assert()
+        .success()
+        .stdout(contains("NAME      RESTART  START-AFTER  COMMAND"))
+        .stdout(contains("db.toml   always   -"))
+        .stdout(contains("web.toml  never    db.toml"));
+
+    let output = Command::cargo_bin("horust")
+        .unwrap()
+        .args(vec![
+            "--services-path",
+            temp_dir.path().display().to_string().as_str(),
+            "list",
+            "--json",
+        ])
+        .output()
+        .unwrap();
+    let services: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(services[1]["name"], "web.toml");
+    assert_eq!(services[1]["start-after"][0], "db.toml");
+}
+
+#[test]
+fn test_completions() {
+    let mut cmd = Command::cargo_bin("horust").unwrap();
+    cmd.args(vec!["completions", "bash"])
+        .assert()
+        .success()
+        .stdout(contains("complete -F _horust"));
+}
+
+#[test]
+fn test_boot_progress() {
+    let (mut cmd, temp_dir) = get_cli();
+    store_service(temp_dir.path(), "sleep 1", None, Some("db"));
+    store_service(
+        temp_dir.path(),
+        "exit 1",
+        Some("start-after = [\"db.toml\"]\n[failure]\nstrategy = \"ignore\""),
+        Some("web"),
+    );
+    cmd.args(vec!["--boot-progress"])
+        .assert()
+        .success()
+        .stdout(contains("[  OK  ] db.toml is running"))
+        .stdout(contains("[FAILED] web.toml has failed, giving up"))
+        .stdout(contains("1 finished, 1 failed, 0 skipped"));
+}
+
+#[test]
+fn test_collector() {
+    let (mut cmd, temp_dir) = get_cli();
+    store_service(temp_dir.path(), "sleep 2", None, Some("db"));
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/reports", listener.local_addr().unwrap());
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = vec![0; 64 * 1024];
+            let read = std::io::Read::read(&mut stream, &mut request).unwrap();
+            std::io::Write::write_all(&mut stream, b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+            let _ = sender.send(String::from_utf8_lossy(&request[..read]).to_string());
+        }
+    });
+    cmd.args(vec![
+        "--collector",
+        url.as_str(),
+        "--collector-node",
+        "worker-1",
+    ])
+    .assert()
+    .success();
+    let reports: Vec<String> = receiver.try_iter().collect();
+    assert!(reports[0].starts_with("POST /reports HTTP/1.1"));
+    assert!(reports
+        .iter()
+        .all(|report| report.contains(r#""node":"worker-1""#)));
+    let is_running = r#""event":"status-changed","#;
+    assert!(
+        reports
+            .iter()
+            .any(|report| report.contains(is_running) && report.contains(r#""status":"Running""#)),
+        "{:?}",
+        reports
+    );
+}
+
+#[test]
+fn test_ready_directory() {
+    let (mut cmd, temp_dir) = get_cli();
+    let ready = temp_dir.path().join("ready");
+    store_service(temp_dir.path(), "sleep 2", None, Some("db"));
+    // Not using start-after: it waits for the marker of db.
+    let waiting = format!(
+        r#"until [ -f {} ]; do sleep 0.1; done
+touch {}"#,
+        ready.join("db.toml").display(),
+        temp_dir.path().join("db-was-ready").display()
+    );
+    store_service(temp_dir.path(), &waiting, None, Some("web"));
+    cmd.args(vec![
+        "--ready-directory",
+        ready.display().to_string().as_str(),
+    ])
+    .assert()
+    .success();
+    assert!(temp_dir.path().join("db-was-ready").is_file());
+    // Removed once the services have stopped.
+    assert_eq!(std::fs::read_dir(ready).unwrap().count(), 0);
+}
+
+#[test]
+fn test_hosts_file() {
+    let (mut cmd, temp_dir) = get_cli();
+    let hosts = temp_dir.path().join("hosts");
+    let original = "127.0.0.1 localhost\n::1 localhost\n";
+    std::fs::write(&hosts, original).unwrap();
+    store_service(
+        temp_dir.path(),
+        "sleep 2",
+        Some(r#"aliases = ["postgres"]"#),
+        Some("db"),
+    );
+    let resolving = format!(
+        r#"until grep -q "^127.0.0.1 db postgres # db.toml$" {}; do sleep 0.1; done
+touch {}"#,
+        hosts.display(),
+        temp_dir.path().join("db-was-listed").display()
+    );
+    store_service(temp_dir.path(), &resolving, None, Some("web"));
+    cmd.args(vec!["--hosts-file", hosts.display().to_string().as_str()])
+        .assert()
+        .success();
+    assert!(temp_dir.path().join("db-was-listed").is_file());
+    // Only the block of horust is removed.
+    assert_eq!(std::fs::read_to_string(hosts).unwrap(), original);
+}
@@ -0,0 +1,861 @@
+use assert_cmd::prelude::*;
+use nix::sys::signal::{kill, Signal};
+use std::process::Command;
+use std::thread::sleep;
+use std::time::Duration;
+
+#[allow(dead_code)]
+mod utils;
+use utils::*;
+
+#[test]
+fn test_ping() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket_path = temp_dir.path().join("control.sock");
+    let script = r#"#!/usr/bin/env bash
+sleep 3"#;
+    store_service(temp_dir.path(), script, None, None);
+    let cmd = cmd.args(vec![
+        "--control-socket",
+        socket_path.display().to_string().as_str(),
+    ]);
+    let recv = run_async(cmd, true);
+    sleep(Duration::from_secs(1));
+    let mut ctl = Command::cargo_bin("horustctl").unwrap();
+    ctl.args(vec![
+        "--socket-path",
+        socket_path.display().to_string().as_str(),
+        "ping",
+    ]);
+    ctl.assert().success();
+    recv.recv_or_kill(Duration::from_secs(10));
+    // Horust has exited, so the socket is gone.
+    ctl.assert().failure();
+}
+
+#[test]
+fn test_status() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket_path = temp_dir.path().join("control.sock");
+    let never_created = temp_dir.path().join("never-created");
+    let service = format!(
+        r#"aliases = ["sick"]
+[healthiness]
+file-path = "{}""#,
+        never_created.display()
+    );
+    let script = r#"#!/usr/bin/env bash
+sleep 4"#;
+    store_service(
+        temp_dir.path(),
+        script,
+        Some(service.as_str()),
+        Some("unhealthy"),
+    );
+    // Permanent errors are not retried.
+    let missing_program = r#"command = "/non/existent/program"
+[restart]
+attempts = 5"#;
+    std::fs::write(temp_dir.path().join("void.toml"), missing_program).unwrap();
+    // Its name has to be escaped in the metrics.
+    let weird_name = temp_dir.path().join(r#"weird"na\me.toml"#);
+    std::fs::write(weird_name, missing_program).unwrap();
+    let cmd = cmd.args(vec![
+        "--control-socket",
+        socket_path.display().to_string().as_str(),
+    ]);
+    let recv = run_async(cmd, true);
+    sleep(Duration::from_secs(2));
+    let horustctl = |args: Vec<&str>| {
+        let mut ctl = Command::cargo_bin("horustctl").unwrap();
+        ctl.args(vec![
+            "--socket-path",
+            socket_path.display().to_string().as_str(),
+        ])
+        .args(args);
+        ctl
+    };
+    let output = horustctl(vec!["status"]).output().unwrap();
+    assert!(output.status.success());
+    let status = String::from_utf8_lossy(&output.stdout);
+    assert!(status.starts_with("unhealthy.toml Started pid="));
+    assert!(status.contains("health=unhealthy"));
+    assert!(status.contains("starts=1 uptime="));
+    assert!(status.contains(&format!(
+        "last-error=\"file-path: {} doesn't exist\"",
+        never_created.display()
+    )));
+    assert!(status.contains(
+        "void.toml PermanentlyFailed pid=- restarts=1 starts=0 uptime=- health=unknown \
+         latency=- failures=0 last-error=- spawn-error=\"ENOENT: No such file or directory\""
+    ));
+    let output = horustctl(vec!["status", "sick"]).output().unwrap();
+    let status = String::from_utf8_lossy(&output.stdout);
+    assert!(status.contains("unhealthy.toml Started pid="));
+    horustctl(vec!["status", "missing"]).assert().failure();
+
+    let output = horustctl(vec!["metrics"]).output().unwrap();
+    let metrics = String::from_utf8_lossy(&output.stdout);
+    assert!(metrics.contains(r#"horust_healthcheck_healthy{service="unhealthy.toml"} 0"#));
+    assert!(metrics.contains(r#"horust_service_starts_total{service="unhealthy.toml"} 1"#));
+    assert!(metrics.contains(r#"horust_service_uptime_seconds{service="unhealthy.toml"} "#));
+    assert!(metrics.contains(
+        r#"horust_service_spawn_failures_total{service="void.toml",reason="not-found"} 1"#
+    ));
+    assert!(metrics.contains(r#"horust_service_starts_total{service="weird\"na\\me.toml"} 0"#));
+    // Still running: nothing has been accounted yet.
+    assert!(metrics.contains(r#"horust_service_max_rss_bytes{service="unhealthy.toml"} 0"#));
+    recv.recv_or_kill(Duration::from_secs(10));
+}
+
+#[test]
+fn test_start_idle() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket_path = temp_dir.path().join("control.sock");
+    let started = temp_dir.path().join("started");
+    let service = r#"stop-after-idle = "1s"
+[termination]
+wait = "1s""#;
+    let script = format!(
+        r#"#!/usr/bin/env bash
+echo started >> {}
+sleep 30"#,
+        started.display()
+    );
+    store_service(
+        temp_dir.path(),
+        script.as_str(),
+        Some(service),
+        Some("idle"),
+    );
+    let cmd = cmd.args(vec![
+        "--control-socket",
+        socket_path.display().to_string().as_str(),
+    ]);
+    let recv = run_async(cmd, true);
+    let horustctl = |args: Vec<&str>| {
+        let mut ctl = Command::cargo_bin("horustctl").unwrap();
+        ctl.args(vec![
+            "--socket-path",
+            socket_path.display().to_string().as_str(),
+        ])
+        .args(args);
+        ctl
+    };
+    let starts = || std::fs::read_to_string(&started).unwrap().lines().count();
+    sleep(Duration::from_secs(5));
+    let output = horustctl(vec!["status", "idle.toml"]).output().unwrap();
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("idle.toml Idle"));
+    assert_eq!(starts(), 1);
+
+    horustctl(vec!["start", "idle.toml"]).assert().success();
+    sleep(Duration::from_secs(1));
+    assert_eq!(starts(), 2);
+    // It's not idle anymore.
+    horustctl(vec!["start", "idle.toml"]).assert().failure();
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(10));
+}
+
+#[test]
+fn test_activate_on_connection() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket_path = temp_dir.path().join("control.sock");
+    let started = temp_dir.path().join("started");
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let service = format!(
+        r#"stop-after-idle = "1s"
+listen = ["127.0.0.1:{}"]
+[termination]
+wait = "1s""#,
+        port
+    );
+    let script = format!(
+        r#"#!/usr/bin/env bash
+echo started >> {}
+sleep 30"#,
+        started.display()
+    );
+    store_service(
+        temp_dir.path(),
+        script.as_str(),
+        Some(service.as_str()),
+        Some("idle"),
+    );
+    let cmd = cmd.args(vec![
+        "--control-socket",
+        socket_path.display().to_string().as_str(),
+    ]);
+    let recv = run_async(cmd, true);
+    let starts = || std::fs::read_to_string(&started).unwrap().lines().count();
+    sleep(Duration::from_secs(5));
+    let output = Command::cargo_bin("horustctl")
+        .unwrap()
+        .args(vec![
+            "--socket-path",
+            socket_path.display().to_string().as_str(),
+            "status",
+            "idle.toml",
+        ])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("idle.toml Idle"));
+    assert_eq!(starts(), 1);
+
+    // The connection is queued on the socket kept by horust, until the service accepts it.
+    let _stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    sleep(Duration::from_secs(1));
+    assert_eq!(starts(), 2);
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(10));
+}
+
+#[test]
+fn test_scale() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket_path = temp_dir.path().join("control.sock");
+    let started = temp_dir.path().join("started");
+    let service = r#"replicas = 2
+[termination]
+wait = "1s""#;
+    let script = format!(
+        r#"#!/usr/bin/env bash
+echo $HORUST_REPLICA_INDEX >> {}
+sleep 30"#,
+        started.display()
+    );
+    store_service(
+        temp_dir.path(),
+        script.as_str(),
+        Some(service),
+        Some("worker"),
+    );
+    let cmd = cmd.args(vec![
+        "--control-socket",
+        socket_path.display().to_string().as_str(),
+    ]);
+    let recv = run_async(cmd, true);
+    let horustctl = |args: Vec<&str>| {
+        let mut ctl = Command::cargo_bin("horustctl").unwrap();
+        ctl.args(vec![
+            "--socket-path",
+            socket_path.display().to_string().as_str(),
+        ])
+        .args(args);
+        ctl
+    };
+    let started_replicas = || {
+        let mut replicas: Vec<String> = std::fs::read_to_string(&started)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        replicas.sort();
+        replicas
+    };
+    sleep(Duration::from_secs(2));
+    assert_eq!(started_replicas(), vec!["0", "1"]);
+
+    horustctl(vec!["scale", "worker.toml", "3"])
+        .assert()
+        .success();
+    sleep(Duration::from_secs(2));
+    assert_eq!(started_replicas(), vec!["0", "1", "2"]);
+
+    horustctl(vec!["scale", "worker.toml", "1"])
+        .assert()
+        .success();
+    sleep(Duration::from_secs(3));
+    let output = horustctl(vec!["status"]).output().unwrap();
+    let status = String::from_utf8_lossy(&output.stdout);
+    assert!(!status.contains("worker.toml-0 Finished"));
+    assert!(status.contains("worker.toml-1 Finished"));
+    assert!(status.contains("worker.toml-2 Finished"));
+    horustctl(vec!["scale", "missing.toml", "2"])
+        .assert()
+        .failure();
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(10));
+}
+
+#[test]
+fn test_scale_policy_hook() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket_path = temp_dir.path().join("control.sock");
+    let service = r#"replicas = 2
+[termination]
+wait = "1s""#;
+    store_service(temp_dir.path(), "sleep 30", Some(service), Some("worker"));
+    // The hook is waited for by horust, while it's reaping the processes (at every tick).
+    let cmd = cmd.args(vec![
+        "--control-socket",
+        socket_path.display().to_string().as_str(),
+        "--policy-hook",
+        "true",
+        "--tick-interval",
+        "1ms",
+    ]);
+    let recv = run_async(cmd, true);
+    let horustctl = |args: Vec<&str>| {
+        let mut ctl = Command::cargo_bin("horustctl").unwrap();
+        ctl.args(vec![
+            "--socket-path",
+            socket_path.display().to_string().as_str(),
+        ])
+        .args(args);
+        ctl
+    };
+    sleep(Duration::from_secs(1));
+    horustctl(vec!["scale", "worker.toml", "6"])
+        .assert()
+        .success();
+    sleep(Duration::from_secs(2));
+    let output = horustctl(vec!["status"]).output().unwrap();
+    let status = String::from_utf8_lossy(&output.stdout);
+    for index in 0..6 {
+        assert!(
+            status.contains(&format!("worker.toml-{} Running", index)),
+            "{}",
+            status
+        );
+    }
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(10));
+}
+
+#[test]
+fn test_rolling_restart() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket_path = temp_dir.path().join("control.sock");
+    let events = temp_dir.path().join("events");
+    let service = r#"replicas = 2"#;
+    let script = format!(
+        r#"#!/usr/bin/env bash
+trap "echo stop $HORUST_REPLICA_INDEX >> {0}; exit 0" TERM
+echo start $HORUST_REPLICA_INDEX >> {0}
+sleep 30 &
+wait"#,
+        events.display()
+    );
+    store_service(
+        temp_dir.path(),
+        script.as_str(),
+        Some(service),
+        Some("worker"),
+    );
+    let cmd = cmd.args(vec![
+        "--control-socket",
+        socket_path.display().to_string().as_str(),
+    ]);
+    let recv = run_async(cmd, true);
+    let horustctl = |args: Vec<&str>| {
+        let mut ctl = Command::cargo_bin("horustctl").unwrap();
+        ctl.args(vec![
+            "--socket-path",
+            socket_path.display().to_string().as_str(),
+        ])
+        .args(args);
+        ctl
+    };
+    sleep(Duration::from_secs(2));
+    horustctl(vec!["restart", "--rolling", "worker.toml"])
+        .assert()
+        .success();
+    sleep(Duration::from_secs(4));
+    let events = std::fs::read_to_string(&events).unwrap();
+    let events: Vec<&str> = events.lines().skip(2).collect();
+    assert_eq!(events, vec!["stop 0", "start 0", "stop 1", "start 1"]);
+    horustctl(vec!["restart", "missing.toml"])
+        .assert()
+        .failure();
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(10));
+}
+
+#[test]
+fn test_blue_green_restart() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket_path = temp_dir.path().join("control.sock");
+    let events = temp_dir.path().join("events");
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let service = format!(r#"listen = ["127.0.0.1:{}"]"#, port);
+    let script = format!(
+        r#"#!/usr/bin/env bash
+trap "echo stop $$ >> {0}; exit 0" TERM
+test -S /proc/$$/fd/3 && echo start $$ $LISTEN_PID $LISTEN_FDS >> {0}
+sleep 30 &
+wait"#,
+        events.display()
+    );
+    store_service(
+        temp_dir.path(),
+        script.as_str(),
+        Some(service.as_str()),
+        Some("web"),
+    );
+    let cmd = cmd.args(vec![
+        "--control-socket",
+        socket_path.display().to_string().as_str(),
+    ]);
+    let recv = run_async(cmd, true);
+    let horustctl = |args: Vec<&str>| {
+        let mut ctl = Command::cargo_bin("horustctl").unwrap();
+        ctl.args(vec![
+            "--socket-path",
+            socket_path.display().to_string().as_str(),
+        ])
+        .args(args);
+        ctl
+    };
+    sleep(Duration::from_secs(2));
+    horustctl(vec!["restart", "--blue-green", "web.toml"])
+        .assert()
+        .success();
+    sleep(Duration::from_secs(3));
+    let events = std::fs::read_to_string(&events).unwrap();
+    let events: Vec<Vec<&str>> = events
+        .lines()
+        .map(|line| line.split_whitespace().collect())
+        .collect();
+    assert_eq!(events.len(), 3, "{:?}", events);
+    let (previous, new) = (events[0][1], events[1][1]);
+    assert_ne!(previous, new);
+    assert_eq!(events[0], vec!["start", previous, previous, "1"]);
+    assert_eq!(events[1], vec!["start", new, new, "1"]);
+    assert_eq!(events[2], vec!["stop", previous]);
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(10));
+}
+
+#[test]
+fn test_labels() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket_path = temp_dir.path().join("control.sock");
+    let script = r#"#!/usr/bin/env bash
+sleep 30"#;
+    for (name, tier) in [("api", "backend"), ("web", "frontend")] {
+        let service = format!(
+            r#"labels = {{ tier = "{}", team = "payments" }}
+[termination]
+wait = "1s""#,
+            tier
+        );
+        store_service(temp_dir.path(), script, Some(service.as_str()), Some(name));
+    }
+    let cmd = cmd.args(vec![
+        "--control-socket",
+        socket_path.display().to_string().as_str(),
+    ]);
+    let recv = run_async(cmd, true);
+    let horustctl = |args: Vec<&str>| {
+        let mut ctl = Command::cargo_bin("horustctl").unwrap();
+        ctl.args(vec![
+            "--socket-path",
+            socket_path.display().to_string().as_str(),
+        ])
+        .args(args);
+        ctl
+    };
+    sleep(Duration::from_secs(2));
+    let status = |selector: &str| {
+        let output = horustctl(vec!["status", "-l", selector]).output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).to_string()
+    };
+    let backend = status("tier=backend");
+    assert!(backend.contains("api.toml Running"));
+    assert!(!backend.contains("web.toml"));
+    assert_eq!(status("team=payments").lines().count(), 2);
+    assert!(status("tier=backend,team=search").is_empty());
+
+    let output = horustctl(vec!["metrics"]).output().unwrap();
+    let metrics = String::from_utf8_lossy(&output.stdout);
+    assert!(metrics.contains(
+        r#"horust_healthcheck_consecutive_failures{service="api.toml",team="payments",tier="backend"} 0"#
+    ));
+
+    horustctl(vec!["stop", "-l", "tier=search"])
+        .assert()
+        .failure();
+    horustctl(vec!["stop", "-l", "tier=backend"])
+        .assert()
+        .success();
+    sleep(Duration::from_secs(3));
+    assert!(status("tier=backend").contains("api.toml Finished"));
+    assert!(status("tier=frontend").contains("web.toml Running"));
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(10));
+}
+
+#[test]
+fn test_log_level() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket_path = temp_dir.path().join("control.sock");
+    let log_path = temp_dir.path().join("horust.log");
+    let script = r#"#!/usr/bin/env bash
+sleep 2"#;
+    store_service(temp_dir.path(), script, None, Some("web"));
+    let cmd = cmd
+        .env_remove("HORUST_LOG")
+        .stderr(std::fs::File::create(&log_path).unwrap())
+        .args(vec![
+            "--control-socket",
+            socket_path.display().to_string().as_str(),
+        ]);
+    let recv = run_async(cmd, true);
+    let horustctl = |args: Vec<&str>| {
+        let mut ctl = Command::cargo_bin("horustctl").unwrap();
+        ctl.args(vec![
+            "--socket-path",
+            socket_path.display().to_string().as_str(),
+            "log-level",
+        ])
+        .args(args);
+        ctl
+    };
+    horustctl(vec!["verbose"]).assert().failure();
+    horustctl(vec!["debug", "--target", "service:missing"])
+        .assert()
+        .failure();
+    horustctl(vec!["debug", "--target", "service:web.toml"])
+        .assert()
+        .success();
+    recv.recv_or_kill(Duration::from_secs(10));
+    let logs = std::fs::read_to_string(&log_path).unwrap();
+    // Only the records about web are affected.
+    assert!(logs.contains("of service 'web.toml' has exited"));
+    assert!(!logs.contains("Applying events"));
+}
+
+#[test]
+fn test_debug() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket_path = temp_dir.path().join("control.sock");
+    let script = r#"#!/usr/bin/env bash
+sleep 30"#;
+    store_service(
+        temp_dir.path(),
+        script,
+        Some(r#"start-delay = "30s""#),
+        Some("db"),
+    );
+    store_service(
+        temp_dir.path(),
+        script,
+        Some(r#"start-after = ["db.toml"]"#),
+        Some("web"),
+    );
+    let cmd = cmd.args(vec![
+        "--control-socket",
+        socket_path.display().to_string().as_str(),
+    ]);
+    let recv = run_async(cmd, true);
+    sleep(Duration::from_secs(1));
+    let output = Command::cargo_bin("horustctl")
+        .unwrap()
+        .args(vec![
+            "--socket-path",
+            socket_path.display().to_string().as_str(),
+            "debug",
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let debug = String::from_utf8_lossy(&output.stdout);
+    assert!(debug.starts_with("runtime shutting-down=false bus-queue="));
+    assert!(debug.contains("service db.toml Starting pid=- "));
+    assert!(debug.contains("service web.toml Initial pid=- "));
+    assert!(debug.contains("unsatisfied=db.toml\n"));
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(10));
+}
+
+#[test]
+fn test_mask() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket_path = temp_dir.path().join("control.sock");
+    let state_directory = temp_dir.path().join("state");
+    let script = r#"#!/usr/bin/env bash
+sleep 30"#;
+    let service = r#"[termination]
+wait = "1s""#;
+    store_service(temp_dir.path(), script, Some(service), Some("masked"));
+    // Otherwise horust would exit once the masked service is stopped.
+    store_service(temp_dir.path(), script, Some(service), Some("other"));
+    let args = vec![
+        "--control-socket".to_string(),
+        socket_path.display().to_string(),
+        "--state-directory".to_string(),
+        state_directory.display().to_string(),
+    ];
+    let recv = run_async(cmd.args(&args), true);
+    let horustctl = |args: Vec<&str>| {
+        let mut ctl = Command::cargo_bin("horustctl").unwrap();
+        ctl.args(vec![
+            "--socket-path",
+            socket_path.display().to_string().as_str(),
+        ])
+        .args(args);
+        ctl
+    };
+    let status = || {
+        let output = horustctl(vec!["status", "masked.toml"]).output().unwrap();
+        String::from_utf8_lossy(&output.stdout).to_string()
+    };
+    sleep(Duration::from_secs(1));
+    horustctl(vec!["mask", "masked.toml"]).assert().success();
+    sleep(Duration::from_secs(3));
+    assert!(status().starts_with("masked.toml Disabled"));
+    let masked = std::fs::read_to_string(state_directory.join("masked")).unwrap();
+    assert_eq!(masked, "masked.toml\n");
+
+    horustctl(vec!["unmask", "masked.toml"]).assert().success();
+    sleep(Duration::from_secs(1));
+    assert!(status().starts_with("masked.toml Running"));
+    horustctl(vec!["mask", "masked.toml"]).assert().success();
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(10));
+
+    // It's still masked after a restart.
+    let recv = run_async(&mut cmd, true);
+    sleep(Duration::from_secs(1));
+    assert!(status().starts_with("masked.toml Disabled"));
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(10));
+}
+
+#[test]
+fn test_shutdown() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket_path = temp_dir.path().join("control.sock");
+    let log_path = temp_dir.path().join("horust.log");
+    let script = r#"#!/usr/bin/env bash
+sleep 30"#;
+    store_service(temp_dir.path(), script, None, Some("web"));
+    let cmd = cmd
+        .env("HORUST_LOG", "info")
+        .stderr(std::fs::File::create(&log_path).unwrap())
+        .args(vec![
+            "--control-socket",
+            socket_path.display().to_string().as_str(),
+        ]);
+    let recv = run_async(cmd, true);
+    sleep(Duration::from_secs(1));
+    Command::cargo_bin("horustctl")
+        .unwrap()
+        .args(vec![
+            "--socket-path",
+            socket_path.display().to_string().as_str(),
+            "shutdown",
+        ])
+        .assert()
+        .success();
+    recv.recv_or_kill(Duration::from_secs(10));
+    let logs = std::fs::read_to_string(&log_path).unwrap();
+    assert!(logs.contains("Shutting down: requested using the control socket."));
+    assert!(logs.contains("shutdown reason: requested using the control socket"));
+}
+
+#[test]
+fn test_events() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket_path = temp_dir.path().join("control.sock");
+    let script = r#"#!/usr/bin/env bash
+sleep 30"#;
+    store_service(
+        temp_dir.path(),
+        script,
+        Some(
+            r#"[termination]
+wait = "1s""#,
+        ),
+        Some("web"),
+    );
+    let cmd = cmd.args(vec![
+        "--control-socket",
+        socket_path.display().to_string().as_str(),
+    ]);
+    let recv = run_async(cmd, true);
+    let horustctl = |command: &str| {
+        let mut ctl = Command::cargo_bin("horustctl").unwrap();
+        ctl.args(vec![
+            "--socket-path",
+            socket_path.display().to_string().as_str(),
+            command,
+        ]);
+        ctl
+    };
+    sleep(Duration::from_secs(2));
+    // Joining late, it gets the current state first.
+    let events = horustctl("events")
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let json_events = horustctl("events")
+        .args(vec!["--json", "--schema-version", "1"])
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    horustctl("events")
+        .args(vec!["--json", "--schema-version", "99"])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "Unsupported events schema version: '99'",
+        ));
+    sleep(Duration::from_secs(1));
+    horustctl("shutdown").assert().success();
+    recv.recv_or_kill(Duration::from_secs(10));
+    // The events stop once horust has exited.
+    let output = events.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let events = String::from_utf8_lossy(&output.stdout);
+    let events: Vec<&str> = events.lines().collect();
+    assert!(events[0].starts_with(r#"PidChanged("web.toml", "#));
+    assert_eq!(events[1], r#"StatusChanged("web.toml", Running)"#);
+    // Then the runtime's snapshot, which is requested for every client.
+    assert_eq!(events[2], "RequestSnapshot");
+    assert!(events.contains(&r#"StateSnapshot([("web.toml", Running)])"#));
+    assert!(events.contains(&"ShuttingDownInitiated(ControlCommand)"));
+    assert!(events
+        .iter()
+        .any(|ev| ev.starts_with(r#"ServiceExited("web.toml", "#)));
+    assert!(events
+        .iter()
+        .any(|ev| ev.starts_with(r#"RunEnded("web.toml", RunRecord { "#)));
+
+    // The records of the events schema, after its version. The internal events are left out.
+    let output = json_events.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let events = String::from_utf8_lossy(&output.stdout);
+    let events: Vec<&str> = events.lines().collect();
+    assert_eq!(events[0], r#"{"schema-version":1}"#);
+    assert!(events[1].starts_with(r#"{"event":"pid-changed","service":"web.toml","pid":"#));
+    assert_eq!(
+        events[2],
+        r#"{"event":"status-changed","service":"web.toml","status":"Running"}"#
+    );
+    assert!(events.contains(
+        &r#"{"event":"state-snapshot","services":[{"service":"web.toml","status":"Running"}]}"#
+    ));
+    assert!(events
+        .contains(&r#"{"event":"shutting-down","reason":"requested using the control socket"}"#));
+}
+
+#[test]
+fn test_history() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket_path = temp_dir.path().join("control.sock");
+    // It exits with 3 the first time, then it's killed by a signal.
+    let script = format!(
+        r#"#!/usr/bin/env bash
+if [ ! -f {0} ]; then
+    touch {0} && exit 3
+fi
+sleep 1
+kill -9 $$"#,
+        temp_dir.path().join("exited").display()
+    );
+    let service = r#"[restart]
+strategy = "always"
+"#;
+    store_service(temp_dir.path(), &script, Some(service), Some("flapping"));
+    let cmd = cmd.args(vec![
+        "--control-socket",
+        socket_path.display().to_string().as_str(),
+    ]);
+    let recv = run_async(cmd, true);
+    let horustctl = |args: Vec<&str>| {
+        let mut ctl = Command::cargo_bin("horustctl").unwrap();
+        ctl.args(vec![
+            "--socket-path",
+            socket_path.display().to_string().as_str(),
+        ])
+        .args(args);
+        ctl
+    };
+    sleep(Duration::from_secs(3));
+    let output = horustctl(vec!["history", "flapping.toml"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let history = String::from_utf8_lossy(&output.stdout);
+    let runs: Vec<&str> = history.lines().collect();
+    assert!(runs.len() >= 2, "{}", history);
+    assert!(runs[0].starts_with("start="));
+    assert!(runs[0].ends_with("exit-code=3 signal=-"));
+    assert!(runs[1].contains(" duration=1."));
+    assert!(runs[1].ends_with("exit-code=137 signal=SIGKILL"));
+    horustctl(vec!["history", "missing"]).assert().failure();
+    horustctl(vec!["shutdown"]).assert().success();
+    recv.recv_or_kill(Duration::from_secs(10));
+}
+
+#[test]
+fn test_completions() {
+    let mut ctl = Command::cargo_bin("horustctl").unwrap();
+    let output = ctl.args(vec!["completions", "bash"]).output().unwrap();
+    assert!(output.status.success());
+    let script = String::from_utf8(output.stdout).unwrap();
+    // The names of the services are completed by asking horust:
+    assert!(script.contains("complete -F _horustctl_services"));
+    assert!(script.contains("status 2>/dev/null"));
+    let mut ctl = Command::cargo_bin("horustctl").unwrap();
+    ctl.args(vec!["completions", "zsh"]).assert().success();
+}
+
+#[test]
+fn test_reload_dry_run() {
+    let (mut cmd, temp_dir) = get_cli();
+    let socket_path = temp_dir.path().join("control.sock");
+    store_service(temp_dir.path(), "sleep 30", None, Some("db"));
+    store_service(temp_dir.path(), "sleep 30", None, Some("web"));
+    store_service(temp_dir.path(), "sleep 30", None, Some("cron"));
+    let cmd = cmd.args(vec![
+        "--control-socket",
+        socket_path.display().to_string().as_str(),
+    ]);
+    let recv = run_async(cmd, true);
+    let horustctl = |args: Vec<&str>| {
+        let mut ctl = Command::cargo_bin("horustctl").unwrap();
+        ctl.args(vec![
+            "--socket-path",
+            socket_path.display().to_string().as_str(),
+        ])
+        .args(args);
+        ctl
+    };
+    std::fs::remove_file(temp_dir.path().join("cron.toml")).unwrap();
+    store_service(temp_dir.path(), "sleep 20", None, Some("web"));
+    store_service(temp_dir.path(), "sleep 30", None, Some("api"));
+    horustctl(vec!["reload", "--dry-run"])
+        .assert()
+        .success()
+        .stdout("add api.toml\nremove cron.toml\nrestart web.toml: command\nkeep db.toml\n");
+    // Nothing has been applied:
+    let output = horustctl(vec!["status"]).output().unwrap();
+    let status = String::from_utf8_lossy(&output.stdout);
+    assert!(status.contains("cron.toml "), "{}", status);
+    assert!(!status.contains("api.toml"), "{}", status);
+    horustctl(vec!["reload"])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("use --dry-run"));
+    // A broken file fails the reload as a whole, it's not taken as a removed service.
+    std::fs::write(temp_dir.path().join("db.toml"), "command = [").unwrap();
+    horustctl(vec!["reload", "--dry-run"])
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr(predicates::str::contains("db.toml"));
+    horustctl(vec!["shutdown"]).assert().success();
+    recv.recv_or_kill(Duration::from_secs(10));
+}
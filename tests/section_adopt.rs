@@ -0,0 +1,48 @@
+#[allow(dead_code)]
+mod utils;
+use std::process::Command;
+use std::thread::sleep;
+use std::time::Duration;
+use utils::*;
+
+#[test]
+fn test_adopt_pid_file() {
+    let (mut cmd, temp_dir) = get_cli();
+    let mut legacy = Command::new("sleep").arg("30").spawn().unwrap();
+    let pid_file = temp_dir.path().join("legacy.pid");
+    std::fs::write(&pid_file, format!("{}\n", legacy.id())).unwrap();
+    let service = format!(
+        r#"[adopt]
+pid-file = "{}"
+"#,
+        pid_file.display()
+    );
+    // The command should never run, since the process is adopted.
+    let script = r#"#!/usr/bin/env bash
+exit 0"#;
+    store_service(temp_dir.path(), script, Some(service.as_str()), None);
+    let cmd = cmd.args(vec!["--unsuccessful-exit-finished-failed"]);
+    let recv = run_async(cmd, false);
+    sleep(Duration::from_secs(1));
+    legacy.kill().unwrap();
+    legacy.wait().unwrap();
+    // The exit status of an adopted process is unknown, so it's considered as failed.
+    recv.recv_or_kill(Duration::from_secs(10));
+}
+
+#[test]
+fn test_adopt_missing_pid_file() {
+    let (mut cmd, temp_dir) = get_cli();
+    let service = format!(
+        r#"[adopt]
+pid-file = "{}"
+"#,
+        temp_dir.path().join("missing.pid").display()
+    );
+    let script = r#"#!/usr/bin/env bash
+exit 0"#;
+    store_service(temp_dir.path(), script, Some(service.as_str()), None);
+    let cmd = cmd.args(vec!["--unsuccessful-exit-finished-failed"]);
+    // Falls back to spawning the command.
+    run_async(cmd, true).recv_or_kill(Duration::from_secs(10));
+}
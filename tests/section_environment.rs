@@ -55,3 +55,72 @@ re-export = [ "DB_PASS" ]
         .success()
         .stdout(contains("MyPassword"));
 }
+
+#[test]
+fn test_environment_instance_port() {
+    let (mut cmd, temp_dir) = get_cli();
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let service = format!(
+        r#"replicas = 2
+listen = ["127.0.0.1:{{port}}"]
+port-range = "{}-{}""#,
+        port,
+        port + 1
+    );
+    let script = r#"#!/usr/bin/env bash
+test -S /proc/$$/fd/3 && echo "instance $HORUST_REPLICA_INDEX: $PORT $HORUST_INSTANCE_PORT""#;
+    store_service(temp_dir.path(), script, Some(service.as_str()), None);
+    // Every instance has its own port, and its own socket bound to it.
+    cmd.assert()
+        .success()
+        .stdout(contains(format!("instance 0: {0} {0}", port)))
+        .stdout(contains(format!("instance 1: {0} {0}", port + 1)));
+}
+
+#[test]
+fn test_environment_templates() {
+    let (mut cmd, temp_dir) = get_cli();
+    let secret = temp_dir.path().join("secret");
+    std::fs::write(&secret, "s3cr3t\n").unwrap();
+    let service = format!(
+        r#"[environment]
+additional = {{ TOKEN = '{{{{ file "{}" }}}}', FORMAT = "{{{{.Names}}}}" }}
+"#,
+        secret.display()
+    );
+    store_service(
+        temp_dir.path(),
+        ENVIRONMENT_SCRIPT,
+        Some(service.as_str()),
+        None,
+    );
+    cmd.assert()
+        .success()
+        .stdout(contains("TOKEN=s3cr3t\n"))
+        .stdout(contains("FORMAT={{.Names}}\n"));
+
+    // Checked before starting anything:
+    let service = r#"[environment]
+additional = { TOKEN = "{{ file }}" }
+"#;
+    store_service(temp_dir.path(), ENVIRONMENT_SCRIPT, Some(service), None);
+    cmd.assert()
+        .failure()
+        .stderr(contains("invalid template in its environment"));
+}
+
+#[test]
+fn test_environment_metadata() {
+    let (mut cmd, temp_dir) = get_cli();
+    store_service(temp_dir.path(), ENVIRONMENT_SCRIPT, None, Some("web"));
+    cmd.assert()
+        .success()
+        .stdout(contains("HORUST_SERVICE_NAME=web.toml\n"))
+        .stdout(contains("HORUST_INSTANCE=web.toml\n"))
+        .stdout(contains("HORUST_RESTART_COUNT=0\n"))
+        .stdout(contains("HORUST_SUPERVISOR_PID="));
+}
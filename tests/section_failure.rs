@@ -1,4 +1,5 @@
 mod utils;
+use assert_cmd::prelude::*;
 use utils::*;
 
 use std::time::Duration;
@@ -46,3 +47,134 @@ fn test_failure_shutdown() {
 fn test_failure_kill_dependents() {
     test_failure_strategy("kill-dependents");
 }
+
+#[test]
+fn test_failure_permanent() {
+    let run = |exit_code: i32, failure: &str| {
+        let (mut cmd, temp_dir) = get_cli();
+        let runs = temp_dir.path().join("runs");
+        let script = format!(
+            r#"#!/usr/bin/env bash
+echo run >> {}
+exit {}"#,
+            runs.display(),
+            exit_code
+        );
+        let service = format!(
+            r#"[restart]
+strategy = "always"
+attempts = 10
+[failure]
+strategy = "ignore"
+{}"#,
+            failure
+        );
+        store_service(temp_dir.path(), &script, Some(service.as_str()), None);
+        cmd.arg("--unsuccessful-exit-finished-failed")
+            .assert()
+            .failure();
+        std::fs::read_to_string(runs).unwrap().lines().count()
+    };
+    // Restarting it is pointless: the remaining attempts are skipped.
+    assert_eq!(run(78, "permanent-exit-code = [78]"), 1);
+    assert_eq!(run(1, "min-runtime = \"10s\"\nimmediate-exits = 2"), 2);
+}
+
+#[test]
+fn test_failure_shutdown_group() {
+    let (mut cmd, temp_dir) = get_cli();
+    let runs = temp_dir.path().join("runs");
+    let failing_script = format!(
+        r#"#!/usr/bin/env bash
+echo run >> {}
+sleep 1
+exit 1"#,
+        runs.display()
+    );
+    let failing_service = r#"labels = { group = "web" }
+[restart]
+strategy = "always"
+[failure]
+strategy = "shutdown-group:web"
+"#;
+    store_service(
+        temp_dir.path(),
+        &failing_script,
+        Some(failing_service),
+        Some("a"),
+    );
+    let sleep_script = r#"#!/usr/bin/env bash
+sleep 30"#;
+    let member = r#"labels = { group = "web" }
+[termination]
+wait = "500millis"
+"#;
+    store_service(temp_dir.path(), sleep_script, Some(member), Some("b"));
+    let bound = r#"binds-to = ["b.toml"]
+[termination]
+wait = "500millis"
+"#;
+    store_service(temp_dir.path(), sleep_script, Some(bound), Some("c"));
+    // Unrelated services keep running: horust exits once this one has finished.
+    let unrelated_script = format!(
+        r#"#!/usr/bin/env bash
+sleep 4
+echo done >> {}"#,
+        temp_dir.path().join("unrelated").display()
+    );
+    store_service(temp_dir.path(), &unrelated_script, None, Some("d"));
+    let recv = run_async(&mut cmd, true);
+    recv.recv_or_kill(Duration::from_secs(15));
+    assert_eq!(std::fs::read_to_string(runs).unwrap().lines().count(), 1);
+    assert!(temp_dir.path().join("unrelated").is_file());
+}
+
+#[test]
+fn test_failure_threshold() {
+    // Whether the other service has been left running, after `a` has failed this many times.
+    let run = |failures: u32| -> bool {
+        let (mut cmd, temp_dir) = get_cli();
+        let failing_script = format!(
+            r#"#!/usr/bin/env bash
+echo run >> {0}
+sleep 1
+if [ $(wc -l < {0}) -le {1} ]; then
+    exit 1
+fi"#,
+            temp_dir.path().join("runs").display(),
+            failures
+        );
+        let failing_service = r#"[restart]
+strategy = "on-failure"
+[failure]
+strategy = "shutdown"
+threshold = { failures = 3, window = "1m" }
+"#;
+        store_service(
+            temp_dir.path(),
+            &failing_script,
+            Some(failing_service),
+            Some("a"),
+        );
+        let other_script = format!(
+            r#"#!/usr/bin/env bash
+sleep 10
+echo done >> {}"#,
+            temp_dir.path().join("done").display()
+        );
+        let other_service = r#"[termination]
+wait = "500millis"
+"#;
+        store_service(
+            temp_dir.path(),
+            &other_script,
+            Some(other_service),
+            Some("b"),
+        );
+        let recv = run_async(&mut cmd, true);
+        recv.recv_or_kill(Duration::from_secs(20));
+        temp_dir.path().join("done").is_file()
+    };
+    assert!(run(2));
+    assert!(!run(3));
+}
@@ -102,6 +102,129 @@ echo "c""#;
     cmd.assert().success().stdout(contains("a\nb\nc"));
 }
 
+#[test]
+fn test_target() {
+    let (mut cmd, temp_dir) = get_cli();
+    let script = r#"#!/usr/bin/env bash
+echo "a"
+sleep 1"#;
+    store_service(temp_dir.path(), script, None, Some("a"));
+    let script = r#"#!/usr/bin/env bash
+echo "b"
+sleep 1"#;
+    store_service(temp_dir.path(), script, None, Some("b"));
+    let target = r#"target = true
+start-after = ["a.toml", "b.toml"]"#;
+    std::fs::write(temp_dir.path().join("boot.toml"), target).unwrap();
+    let service = r#"start-after = ["boot.toml"]"#;
+    let script = r#"#!/usr/bin/env bash
+echo "booted""#;
+    store_service(temp_dir.path(), script, Some(service), None);
+
+    // Horust doesn't wait for the target to finish.
+    let output = cmd.output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[2], "booted");
+}
+
+#[test]
+fn test_start_after_any_of() {
+    let (mut cmd, temp_dir) = get_cli();
+    // The primary never becomes healthy, so the client can start only thanks to the replica.
+    let service_primary = format!(
+        r#"[healthiness]
+file-path = "{}""#,
+        temp_dir.path().join("never-created").display()
+    );
+    let script = r#"#!/usr/bin/env bash
+sleep 2
+echo "primary""#;
+    store_service(
+        temp_dir.path(),
+        script,
+        Some(service_primary.as_str()),
+        Some("primary"),
+    );
+    let script = r#"#!/usr/bin/env bash
+echo "replica""#;
+    store_service(temp_dir.path(), script, None, Some("replica"));
+
+    let service = r#"start-after = [["primary.toml", "replica.toml"]]"#;
+    let script = r#"#!/usr/bin/env bash
+echo "client""#;
+    store_service(temp_dir.path(), script, Some(service), None);
+    cmd.assert()
+        .success()
+        .stdout(contains("replica\nclient\nprimary"));
+}
+
+#[test]
+fn test_wait_for() {
+    let (mut cmd, temp_dir) = get_cli();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let service = format!(
+        r#"wait-for = [{{ tcp = "{}", timeout = "1s" }}]"#,
+        listener.local_addr().unwrap()
+    );
+    let script = r#"#!/usr/bin/env bash
+echo "connected""#;
+    store_service(temp_dir.path(), script, Some(service.as_str()), None);
+    cmd.assert().success().stdout(contains("connected"));
+
+    // Nobody is listening anymore: the service fails without running the command.
+    drop(listener);
+    cmd.arg("--unsuccessful-exit-finished-failed")
+        .assert()
+        .failure()
+        .stdout(is_empty());
+}
+
+#[test]
+fn test_binds_to() {
+    let (mut cmd, temp_dir) = get_cli();
+    let script_vpn = r#"#!/usr/bin/env bash
+sleep 1"#;
+    store_service(temp_dir.path(), script_vpn, None, Some("vpn"));
+
+    // It would run forever, but it is stopped together with the vpn.
+    let service = r#"binds-to = ["vpn.toml"]
+start-after = ["vpn.toml"]
+[termination]
+wait = "1s""#;
+    let script = r#"#!/usr/bin/env bash
+sleep 30"#;
+    store_service(temp_dir.path(), script, Some(service), None);
+
+    let recv = run_async(&mut cmd, true);
+    recv.recv_or_kill(Duration::from_secs(10));
+}
+
+#[test]
+fn test_max_runtime() {
+    let script = r#"#!/usr/bin/env bash
+while true ; do
+    sleep 1
+done
+"#;
+    for (outcome, should_succeed) in vec![("failed", false), ("success", true)] {
+        let (mut cmd, temp_dir) = get_cli();
+        let service = format!(
+            r#"max-runtime = "1s"
+max-runtime-outcome = "{}"
+[termination]
+wait = "1s""#,
+            outcome
+        );
+        store_service(temp_dir.path(), script, Some(service.as_str()), None);
+        let mut cmd = cmd.args(vec!["--unsuccessful-exit-finished-failed"]);
+        let recv = run_async(&mut cmd, should_succeed);
+        recv.recv_or_kill(Duration::from_secs(10));
+    }
+}
+
 // Test user
 #[test]
 #[ignore]
@@ -134,3 +257,134 @@ start-delay = "10s"
     kill(recv.pid, Signal::SIGINT).expect("kill");
     recv.recv_or_kill(Duration::from_secs(5));
 }
+
+#[test]
+fn test_profiles() {
+    let (mut cmd, temp_dir) = get_cli();
+    for (name, profiles) in vec![
+        ("always", "[]"),
+        ("mock", r#"["dev"]"#),
+        ("agent", r#"["prod"]"#),
+    ] {
+        let script = format!(
+            r#"#!/usr/bin/env bash
+echo "{}-ran""#,
+            name
+        );
+        let service = format!("profiles = {}", profiles);
+        store_service(
+            temp_dir.path(),
+            script.as_str(),
+            Some(service.as_str()),
+            Some(name),
+        );
+    }
+    let output = cmd.output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("always-ran"));
+    assert!(!stdout.contains("mock-ran"));
+    assert!(!stdout.contains("agent-ran"));
+
+    let output = cmd.args(vec!["--profile", "dev"]).output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("always-ran"));
+    assert!(stdout.contains("mock-ran"));
+    assert!(!stdout.contains("agent-ran"));
+}
+
+#[test]
+fn test_disabled() {
+    let (mut cmd, temp_dir) = get_cli();
+    let service = r#"enabled = false"#;
+    let script = r#"#!/usr/bin/env bash
+echo "disabled""#;
+    store_service(temp_dir.path(), script, Some(service), Some("disabled"));
+    let script = r#"#!/usr/bin/env bash
+echo "enabled""#;
+    store_service(temp_dir.path(), script, None, None);
+    // Horust doesn't wait for the disabled service.
+    cmd.assert().success().stdout("enabled\n");
+
+    // By default, the services depending on it fail.
+    let service = r#"start-after = ["disabled.toml"]"#;
+    store_service(temp_dir.path(), script, Some(service), None);
+    cmd.arg("--unsuccessful-exit-finished-failed")
+        .assert()
+        .failure()
+        .stdout("enabled\n");
+    cmd.args(vec!["--disabled-dependencies", "ignore"])
+        .assert()
+        .success()
+        .stdout("enabled\nenabled\n");
+}
+
+#[test]
+fn test_balance() {
+    let (mut cmd, temp_dir) = get_cli();
+    let free_address = || {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    };
+    let public = free_address();
+    let port = free_address().port();
+    let service = format!(
+        r#"replicas = 2
+listen = ["127.0.0.1:{{port}}"]
+port-range = "{}-{}"
+balance = "{}""#,
+        port,
+        port + 1,
+        public
+    );
+    // Every instance serves a single connection, using the socket bound by horust.
+    let script = r#"#!/usr/bin/env bash
+python3 -c "import socket
+client, _ = socket.socket(fileno=3).accept()
+client.sendall(b'instance $HORUST_REPLICA_INDEX')""#;
+    store_service(temp_dir.path(), script, Some(service.as_str()), None);
+    let recv = run_async(&mut cmd, true);
+    let mut served = vec![];
+    for _ in 0..50 {
+        let mut response = String::new();
+        if let Ok(mut client) = std::net::TcpStream::connect(public) {
+            let _ = std::io::Read::read_to_string(&mut client, &mut response);
+        }
+        if !response.is_empty() {
+            served.push(response);
+        }
+        if served.len() == 2 {
+            break;
+        }
+        sleep(Duration::from_millis(100));
+    }
+    served.sort();
+    assert_eq!(served, vec!["instance 0", "instance 1"]);
+    recv.recv_or_kill(Duration::from_secs(10));
+}
+
+#[test]
+fn test_schedule() {
+    let (mut cmd, temp_dir) = get_cli();
+    let runs = temp_dir.path().join("runs");
+    let script = format!(
+        r#"#!/usr/bin/env bash
+echo run >> {}"#,
+        runs.display()
+    );
+    let service = r#"[schedule]
+every = "1s""#;
+    store_service(temp_dir.path(), script.as_str(), Some(service), None);
+    let recv = run_async(&mut cmd, true);
+    // Not run at the start, but at every firing.
+    sleep(Duration::from_millis(500));
+    assert!(!runs.exists());
+    sleep(Duration::from_millis(3300));
+    let count = std::fs::read_to_string(&runs).unwrap().lines().count();
+    assert!((2..=4).contains(&count), "runs: {}", count);
+    // Waiting for the next firing, until the shutdown.
+    kill(recv.pid, None).expect("horust has exited");
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(10));
+}
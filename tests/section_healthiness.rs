@@ -1,3 +1,5 @@
+use assert_cmd::prelude::*;
+use predicates::str::contains;
 mod utils;
 use std::io::{Read, Write};
 use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
@@ -66,3 +68,218 @@ http-endpoint = "{}""#,
         .expect("Failed to received response from handle_request");
     Ok(())
 }
+
+#[test]
+fn test_liveness() {
+    let (mut cmd, tempdir) = get_cli();
+    let ready = tempdir.path().join("ready");
+    let service = format!(
+        r#"
+[termination]
+wait = "1s"
+[healthiness]
+file-path = "{}"
+[liveness]
+file-path = "{}""#,
+        ready.display(),
+        tempdir.path().join("never-alive").display()
+    );
+    // It becomes ready, but it's never alive: it will be killed instead of running forever.
+    let script = format!(
+        r#"#!/usr/bin/env bash
+touch {}
+sleep 30
+"#,
+        ready.display()
+    );
+    store_service(
+        tempdir.path(),
+        script.as_str(),
+        Some(service.as_str()),
+        None,
+    );
+    run_async(&mut cmd, true).recv_or_kill(Duration::from_secs(15));
+}
+
+#[test]
+fn test_liveness_path() {
+    let (mut cmd, tempdir) = get_cli();
+    let socket = tempdir.path().join("app.sock");
+    let counter = tempdir.path().join("counter");
+    let service = format!(
+        r#"
+[restart]
+strategy = "on-failure"
+[termination]
+wait = "1s"
+[liveness]
+path = "{}""#,
+        socket.display()
+    );
+    // The first run loses its socket while it's still up: it fails, and it's restarted.
+    let script = format!(
+        r#"#!/usr/bin/env bash
+echo run >> {counter}
+if [ $(wc -l < {counter}) -gt 1 ]; then exit 0; fi
+touch {socket}
+sleep 1
+rm {socket}
+sleep 30
+"#,
+        counter = counter.display(),
+        socket = socket.display()
+    );
+    store_service(
+        tempdir.path(),
+        script.as_str(),
+        Some(service.as_str()),
+        None,
+    );
+    run_async(&mut cmd, true).recv_or_kill(Duration::from_secs(20));
+    let runs = std::fs::read_to_string(counter).unwrap();
+    assert_eq!(runs.lines().count(), 2);
+}
+
+#[test]
+fn test_log_pattern() {
+    let (mut cmd, tempdir) = get_cli();
+    let service = r#"
+[healthiness]
+log-pattern = "Server started on port \\d+""#;
+    let script = r#"#!/usr/bin/env bash
+echo "Booting..."
+sleep 1
+echo "Server started on port 8080"
+sleep 1
+"#;
+    store_service(tempdir.path(), script, Some(service), Some("server"));
+    let service = r#"start-after = ["server.toml"]"#;
+    let script = r#"#!/usr/bin/env bash
+echo "client""#;
+    store_service(tempdir.path(), script, Some(service), None);
+    cmd.assert()
+        .success()
+        .stdout(contains("Booting...\nServer started on port 8080\nclient"));
+}
+
+#[test]
+fn test_initial_delay() {
+    let (mut cmd, tempdir) = get_cli();
+    let ready = tempdir.path().join("ready");
+    let service = format!(
+        r#"
+[healthiness]
+file-path = "{}"
+initial-delay = "3s""#,
+        ready.display()
+    );
+    // The file is there since the beginning, but no check runs before the delay has elapsed.
+    let script = format!(
+        r#"#!/usr/bin/env bash
+touch {}
+sleep 1
+echo "booted"
+sleep 4
+"#,
+        ready.display()
+    );
+    store_service(
+        tempdir.path(),
+        script.as_str(),
+        Some(service.as_str()),
+        Some("slow"),
+    );
+    let service = r#"start-after = ["slow.toml"]"#;
+    let script = r#"#!/usr/bin/env bash
+echo "client""#;
+    store_service(tempdir.path(), script, Some(service), None);
+    cmd.assert().success().stdout(contains("booted\nclient"));
+}
+
+#[test]
+fn test_liveness_initial_delay() {
+    let (mut cmd, tempdir) = get_cli();
+    let alive = tempdir.path().join("alive");
+    let service = format!(
+        r#"
+[termination]
+wait = "1s"
+[liveness]
+file-path = "{}"
+initial-delay = "6s""#,
+        alive.display()
+    );
+    // It's running right away, but alive only after a while: it would be killed without a delay.
+    let script = format!(
+        r#"#!/usr/bin/env bash
+sleep 5
+touch {}
+sleep 2
+"#,
+        alive.display()
+    );
+    store_service(
+        tempdir.path(),
+        script.as_str(),
+        Some(service.as_str()),
+        None,
+    );
+    cmd.args(vec!["--unsuccessful-exit-finished-failed"])
+        .assert()
+        .success();
+}
+
+#[test]
+fn test_delegate() {
+    let (mut cmd, tempdir) = get_cli();
+    // The nested horust supervises the group: it's healthy once all of its services are up.
+    let group = tempdir::TempDir::new("group").unwrap();
+    let ready = group.path().join("ready");
+    let service = format!(
+        r#"
+[healthiness]
+file-path = "{}""#,
+        ready.display()
+    );
+    let script = format!(
+        r#"#!/usr/bin/env bash
+sleep 2
+echo "db ready"
+touch {}
+sleep 2
+"#,
+        ready.display()
+    );
+    store_service(
+        group.path(),
+        script.as_str(),
+        Some(service.as_str()),
+        Some("db"),
+    );
+    let socket = tempdir.path().join("group.sock");
+    let service = format!(
+        r#"
+[healthiness]
+delegate = "{}""#,
+        socket.display()
+    );
+    let script = format!(
+        r#"#!/usr/bin/env bash
+exec {} --services-path {} --control-socket {}
+"#,
+        assert_cmd::cargo::cargo_bin("horust").display(),
+        group.path().display(),
+        socket.display()
+    );
+    store_service(
+        tempdir.path(),
+        script.as_str(),
+        Some(service.as_str()),
+        Some("group"),
+    );
+    let service = r#"start-after = ["group.toml"]"#;
+    let script = r#"#!/usr/bin/env bash
+echo "client""#;
+    store_service(tempdir.path(), script, Some(service), None);
+    cmd.assert().success().stdout(contains("db ready\nclient"));
+}
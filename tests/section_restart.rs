@@ -84,3 +84,194 @@ strategy = "on-failure"
     let recv = run_async(&mut cmd, true);
     recv.recv_or_kill(Duration::from_secs(15));
 }
+
+#[test]
+fn test_restart_on_attempts_exhausted() {
+    let (mut cmd, temp_dir) = get_cli();
+    let exhausted = temp_dir.path().join("exhausted");
+    let script = r#"#!/usr/bin/env bash
+exit 1"#;
+    let service = format!(
+        r#"[restart]
+attempts = 0
+on-attempts-exhausted = "exec:touch {}"
+"#,
+        exhausted.display()
+    );
+    store_service(temp_dir.path(), script, Some(service.as_str()), None);
+    cmd.assert().success();
+    // The command is not awaited by horust.
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while !exhausted.exists() && std::time::Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    assert!(exhausted.exists());
+
+    // Shutdown: the other service is terminated, even if it would run forever.
+    let service = r#"[restart]
+attempts = 0
+on-attempts-exhausted = "shutdown"
+"#;
+    let (mut cmd, temp_dir) = get_cli();
+    store_service(temp_dir.path(), script, Some(service), None);
+    let script = r#"#!/usr/bin/env bash
+sleep 30"#;
+    store_service(temp_dir.path(), script, None, None);
+    let recv = run_async(&mut cmd, true);
+    recv.recv_or_kill(Duration::from_secs(10));
+}
+
+#[test]
+fn test_restart_window() {
+    let (mut cmd, temp_dir) = get_cli();
+    let runs = temp_dir.path().join("runs");
+    // It fails once Running: without a window it would be restarted forever.
+    let script = format!(
+        r#"#!/usr/bin/env bash
+echo run >> {}
+sleep 1
+exit 1"#,
+        runs.display()
+    );
+    let service = r#"[restart]
+strategy = "always"
+attempts = 2
+window = "1m"
+"#;
+    store_service(temp_dir.path(), script.as_str(), Some(service), None);
+    let recv = run_async(&mut cmd, true);
+    recv.recv_or_kill(Duration::from_secs(20));
+    let runs = std::fs::read_to_string(runs).unwrap();
+    assert_eq!(runs.lines().count(), 3);
+}
+
+#[test]
+fn test_fallback_command() {
+    let script = r#"#!/usr/bin/env bash
+exit 1"#;
+    let fallback = |command: &str| {
+        format!(
+            r#"[restart]
+attempts = 1
+fallback-command = "{}"
+"#,
+            command
+        )
+    };
+    // Its attempts are over, but the fallback command succeeds.
+    let (mut cmd, temp_dir) = get_cli();
+    let service = fallback("/bin/echo safe mode");
+    store_service(temp_dir.path(), script, Some(service.as_str()), None);
+    cmd.arg("--unsuccessful-exit-finished-failed")
+        .assert()
+        .success()
+        .stdout(contains("safe mode"));
+
+    // The fallback command has failed as well.
+    let (mut cmd, temp_dir) = get_cli();
+    let service = fallback("/bin/false");
+    store_service(temp_dir.path(), script, Some(service.as_str()), None);
+    cmd.arg("--unsuccessful-exit-finished-failed")
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_restart_min_runtime() {
+    let (mut cmd, temp_dir) = get_cli();
+    let runs = temp_dir.path().join("runs");
+    // It exits successfully, but too soon: it's a failed start.
+    let script = format!(
+        r#"#!/usr/bin/env bash
+echo run >> {}"#,
+        runs.display()
+    );
+    let service = r#"[restart]
+attempts = 2
+min-runtime = "10s"
+"#;
+    store_service(temp_dir.path(), &script, Some(service), None);
+    cmd.arg("--unsuccessful-exit-finished-failed")
+        .assert()
+        .failure();
+    let runs = std::fs::read_to_string(runs).unwrap();
+    assert_eq!(runs.lines().count(), 3);
+}
+
+#[test]
+fn test_restart_on_dependency_recovery() {
+    let (mut cmd, temp_dir) = get_cli();
+    // It fails once after having started, and then recovers.
+    let db_script = format!(
+        r#"#!/usr/bin/env bash
+if [ ! -f {0} ]; then
+    touch {0} && sleep 1 && exit 1
+fi
+sleep 3"#,
+        temp_dir.path().join("failed").display()
+    );
+    let db = r#"[restart]
+strategy = "on-failure"
+"#;
+    store_service(temp_dir.path(), &db_script, Some(db), Some("db"));
+    let dependent_script = |runs: &str| {
+        format!(
+            r#"#!/usr/bin/env bash
+echo run >> {}
+exec sleep 2"#,
+            temp_dir.path().join(runs).display()
+        )
+    };
+    let api = r#"start-after = ["db.toml"]
+[restart]
+on-dependency-recovery = true
+[termination]
+wait = "500millis"
+"#;
+    store_service(
+        temp_dir.path(),
+        &dependent_script("api-runs"),
+        Some(api),
+        Some("api"),
+    );
+    let cron = r#"start-after = ["db.toml"]"#;
+    store_service(
+        temp_dir.path(),
+        &dependent_script("cron-runs"),
+        Some(cron),
+        Some("cron"),
+    );
+    let recv = run_async(&mut cmd, true);
+    recv.recv_or_kill(Duration::from_secs(15));
+    let runs = |name: &str| {
+        std::fs::read_to_string(temp_dir.path().join(name))
+            .unwrap()
+            .lines()
+            .count()
+    };
+    assert_eq!(runs("api-runs"), 2);
+    assert_eq!(runs("cron-runs"), 1);
+}
+
+#[test]
+fn test_restart_startup_retries() {
+    let (mut cmd, temp_dir) = get_cli();
+    let runs = temp_dir.path().join("runs");
+    // It never comes up.
+    let script = format!(
+        r#"#!/usr/bin/env bash
+echo run >> {}
+exit 1"#,
+        runs.display()
+    );
+    let service = r#"[restart]
+attempts = 5
+startup-retries = 1
+"#;
+    store_service(temp_dir.path(), &script, Some(service), None);
+    cmd.arg("--unsuccessful-exit-finished-failed")
+        .assert()
+        .failure();
+    let runs = std::fs::read_to_string(runs).unwrap();
+    assert_eq!(runs.lines().count(), 2);
+}
@@ -0,0 +1,24 @@
+use assert_cmd::prelude::*;
+use predicates::str::contains;
+
+#[allow(dead_code)]
+mod utils;
+use utils::*;
+
+#[test]
+fn test_user_namespace() {
+    let (mut cmd, temp_dir) = get_cli();
+    // Written without using a script: the service cannot read horust's temp dir.
+    let service = r#"command = "/bin/sh -c 'cat /proc/self/uid_map /proc/self/gid_map; id -u; id -g'"
+user = 1000
+
+[sandbox]
+user-namespace = { uid-map = "0 100000 1, 1000 101000 1", gid-map = "0 200000 65536" }
+"#;
+    std::fs::write(temp_dir.path().join("ns.toml"), service).unwrap();
+    cmd.assert()
+        .success()
+        .stdout(contains("1000     101000          1"))
+        .stdout(contains("0     200000      65536"))
+        .stdout(contains("1000\n0\n"));
+}
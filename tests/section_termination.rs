@@ -1,4 +1,7 @@
 use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::process::Stdio;
+use std::thread;
 use std::time::Duration;
 
 pub mod utils;
@@ -36,6 +39,164 @@ wait = "1s""#;
     recv.recv_or_kill(Duration::from_secs(5));
 }
 
+/// Running processes only: zombies are dead already.
+fn is_process_alive(pid: &str) -> bool {
+    std::fs::read_to_string(format!("/proc/{}/stat", pid))
+        .map(|stat| {
+            !stat[stat.rfind(')').unwrap() + 1..]
+                .trim_start()
+                .starts_with('Z')
+        })
+        .unwrap_or(false)
+}
+
+#[test]
+fn test_termination_force_kill_children() {
+    let (mut cmd, temp_dir) = get_cli();
+    let pid_file = temp_dir.path().join("child.pid");
+    // The child ignores the signals as well, it would survive the service.
+    let script = format!(
+        r#"#!/usr/bin/env bash
+trap "" INT TERM
+sleep 30 &
+echo $! > {}
+while true ; do
+    sleep 1
+done
+"#,
+        pid_file.display()
+    );
+    let service = r#"[termination]
+wait = "1s"
+kill-scope = "session""#;
+    store_service(temp_dir.path(), script.as_str(), Some(service), None);
+
+    let recv = run_async(&mut cmd, true);
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(5));
+    let child_pid = std::fs::read_to_string(pid_file).unwrap();
+    assert!(!is_process_alive(child_pid.trim()));
+}
+
+#[test]
+fn test_termination_process_scope() {
+    let (mut cmd, temp_dir) = get_cli();
+    let pid_file = temp_dir.path().join("child.pid");
+    let script = format!(
+        r#"#!/usr/bin/env bash
+sleep 30 &
+echo $! > {}
+wait
+"#,
+        pid_file.display()
+    );
+    let service = r#"[termination]
+wait = "1s""#;
+    store_service(temp_dir.path(), script.as_str(), Some(service), None);
+
+    let recv = run_async(&mut cmd, true);
+    thread::sleep(Duration::from_secs(1));
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(5));
+    // Only the main process is stopped by default.
+    let child_pid = std::fs::read_to_string(pid_file).unwrap();
+    assert!(is_process_alive(child_pid.trim()));
+    let child_pid = Pid::from_raw(child_pid.trim().parse().unwrap());
+    kill(child_pid, Signal::SIGKILL).expect("kill");
+}
+
+#[test]
+fn test_termination_exec_before() {
+    let (mut cmd, temp_dir) = get_cli();
+    let drained = temp_dir.path().join("drained");
+    let drain_script = temp_dir.path().join("drain.sh");
+    std::fs::write(
+        &drain_script,
+        format!(
+            r#"#!/usr/bin/env bash
+sleep 1
+touch {}
+"#,
+            drained.display()
+        ),
+    )
+    .unwrap();
+    let script = format!(
+        r#"#!/usr/bin/env bash
+on_term() {{
+    if [ -f {} ]; then
+        echo "Drained before stopping"
+    fi
+    exit 0
+}}
+trap on_term TERM
+while true ; do
+    sleep 0.1
+done
+"#,
+        drained.display()
+    );
+    let service = format!(
+        r#"[termination]
+exec-before = "/usr/bin/env bash {}""#,
+        drain_script.display()
+    );
+    store_service(
+        temp_dir.path(),
+        script.as_str(),
+        Some(service.as_str()),
+        None,
+    );
+
+    let cmd = cmd.stdout(Stdio::piped());
+    let child = cmd.spawn().unwrap();
+    thread::sleep(Duration::from_secs(1));
+    kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM).expect("kill");
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Drained before stopping"));
+}
+
+#[test]
+fn test_shutdown_timeout() {
+    let (mut cmd, temp_dir) = get_cli();
+    let script = r#"#!/usr/bin/env bash
+trap "" INT TERM
+while true ; do
+    sleep 1
+done
+"#;
+    let service = r#"[termination]
+wait = "30s""#;
+    store_service(temp_dir.path(), script, Some(service), None);
+
+    let cmd = cmd.args(vec!["--shutdown-timeout", "2s"]);
+    let recv = run_async(cmd, false);
+    kill(recv.pid, Signal::SIGTERM).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(6));
+}
+
+#[test]
+fn test_termination_double_sigint() {
+    let (mut cmd, temp_dir) = get_cli();
+    let script = r#"#!/usr/bin/env bash
+trap "" INT TERM
+while true ; do
+    sleep 1
+done
+"#;
+    // Pressing Ctrl-C twice shouldn't wait the termination.wait.
+    let service = r#"[termination]
+wait = "30s""#;
+    store_service(temp_dir.path(), script, Some(service), None);
+
+    let recv = run_async(&mut cmd, true);
+    kill(recv.pid, Signal::SIGINT).expect("kill");
+    thread::sleep(Duration::from_secs(1));
+    kill(recv.pid, Signal::SIGINT).expect("kill");
+    recv.recv_or_kill(Duration::from_secs(5));
+}
+
 fn test_termination_custom_signal(friendly_name: &str) {
     let (mut cmd, temp_dir) = get_cli();
     // this script captures traps signals